@@ -0,0 +1,50 @@
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use zkc_state_manager::kvpair::{Hash, DEFAULT_HASH_VEC};
+use zkc_state_manager::poseidon;
+
+fn bench_hash_children(c: &mut Criterion) {
+    let (left, right) = (DEFAULT_HASH_VEC[0], DEFAULT_HASH_VEC[1]);
+    c.bench_function("hash_children", |b| {
+        b.iter(|| Hash::hash_children(black_box(&left), black_box(&right)))
+    });
+}
+
+fn bench_hash_data(c: &mut Criterion) {
+    let data = [1_u8; 32];
+    c.bench_function("hash_data", |b| {
+        b.iter(|| Hash::hash_data(black_box(&data)))
+    });
+}
+
+// The leaf data hashed by the server, see poseidon::hash_leaf_data. Arrays of field elements
+// are hashed with poseidon::hash, other data with poseidon::hash_long_data.
+fn bench_hash_leaf_data(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hash_leaf_data");
+    for size in [32, 64, 256] {
+        group.throughput(Throughput::Bytes(size as u64));
+        let field_elements = vec![1_u8; size];
+        group.bench_with_input(
+            BenchmarkId::new("field_elements", size),
+            &field_elements,
+            |b, data| b.iter(|| poseidon::hash_leaf_data(black_box(data))),
+        );
+        let long_data = vec![0xff_u8; size];
+        group.bench_with_input(
+            BenchmarkId::new("long_data", size),
+            &long_data,
+            |b, data| b.iter(|| poseidon::hash_leaf_data(black_box(data))),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_hash_children,
+    bench_hash_data,
+    bench_hash_leaf_data
+);
+criterion_main!(benches);
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+use zkc_state_manager::kvpair::{Hash, MerkleRecord, DEFAULT_HASH_VEC, MERKLE_TREE_HEIGHT};
+use zkc_state_manager::merkle::{MerkleError, MerkleErrorCode, MerkleTree};
+
+const FIRST_LEAF: u64 = (1 << MERKLE_TREE_HEIGHT) - 1;
+const BATCH_SIZE: u64 = 1000;
+
+/// A merkle tree keeping its records in memory, so that the tree operations are measured
+/// without MongoDB. As in the merkle collections, the records are looked up by index and hash,
+/// and the records of the default tree are not stored.
+#[derive(Clone)]
+struct MemoryMerkle<const H: usize> {
+    root_hash: Hash,
+    records: HashMap<(u64, Hash), MerkleRecord>,
+}
+
+impl<const H: usize> MerkleTree<Hash, H> for MemoryMerkle<H> {
+    type Id = ();
+    type Root = Hash;
+    type Node = MerkleRecord;
+
+    fn construct(_addr: Self::Id, root: Self::Root) -> Self {
+        Self {
+            root_hash: root,
+            records: HashMap::new(),
+        }
+    }
+
+    fn hash(a: &Hash, b: &Hash) -> Hash {
+        Hash::hash_children(a, b)
+    }
+
+    fn set_parent(
+        &mut self,
+        index: u64,
+        hash: &Hash,
+        left: &Hash,
+        right: &Hash,
+    ) -> Result<(), MerkleError> {
+        self.boundary_check(index)?;
+        let record = MerkleRecord {
+            hash: *hash,
+            left: *left,
+            right: *right,
+            ..MerkleRecord::new(index)
+        };
+        self.records.insert((index, *hash), record);
+        Ok(())
+    }
+
+    fn set_leaf(&mut self, leaf: &MerkleRecord) -> Result<(), MerkleError> {
+        self.leaf_check(leaf.index)?;
+        self.records.insert((leaf.index, leaf.hash), *leaf);
+        Ok(())
+    }
+
+    fn get_node_with_hash(&mut self, index: u64, hash: &Hash) -> Result<Self::Node, MerkleError> {
+        if let Some(record) = self.records.get(&(index, *hash)) {
+            return Ok(*record);
+        }
+        let record = MerkleRecord::get_default_record_with_height::<H>(index)?;
+        if record.hash != *hash {
+            return Err(MerkleError::new(
+                *hash,
+                index,
+                MerkleErrorCode::NodeNotFound,
+            ));
+        }
+        Ok(record)
+    }
+
+    fn get_root_hash(&self) -> Hash {
+        self.root_hash
+    }
+
+    fn update_root_hash(&mut self, hash: &Hash) {
+        self.root_hash = *hash;
+    }
+}
+
+type StandardMemoryMerkle = MemoryMerkle<MERKLE_TREE_HEIGHT>;
+
+fn empty_tree() -> StandardMemoryMerkle {
+    StandardMemoryMerkle::construct((), DEFAULT_HASH_VEC[MERKLE_TREE_HEIGHT])
+}
+
+fn leaf(offset: u64) -> MerkleRecord {
+    let mut data = [0_u8; 32];
+    data[..8].copy_from_slice(&offset.to_le_bytes());
+    MerkleRecord::new_leaf(FIRST_LEAF + offset, Hash::hash_data(&data))
+}
+
+// A tree with the first BATCH_SIZE leaves set, so that the paths are not all default records.
+fn filled_tree() -> StandardMemoryMerkle {
+    let mut tree = empty_tree();
+    for offset in 0..BATCH_SIZE {
+        tree.set_leaf_with_proof(&leaf(offset)).unwrap();
+    }
+    tree
+}
+
+fn bench_set_leaf(c: &mut Criterion) {
+    let tree = filled_tree();
+    let new_leaf = leaf(BATCH_SIZE / 2);
+    c.bench_function("set_leaf_with_proof", |b| {
+        b.iter_batched(
+            || tree.clone(),
+            |mut tree| tree.set_leaf_with_proof(black_box(&new_leaf)).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+fn bench_set_leaves(c: &mut Criterion) {
+    let leaves = (0..BATCH_SIZE).map(leaf).collect::<Vec<_>>();
+    c.bench_function("set_1000_leaves_with_proof", |b| {
+        b.iter_batched(
+            empty_tree,
+            |mut tree| {
+                for leaf in &leaves {
+                    tree.set_leaf_with_proof(black_box(leaf)).unwrap();
+                }
+                tree.get_root_hash()
+            },
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+fn bench_get_leaf_with_proof(c: &mut Criterion) {
+    let mut tree = filled_tree();
+    let index = FIRST_LEAF + BATCH_SIZE / 2;
+    c.bench_function("get_leaf_with_proof", |b| {
+        b.iter(|| tree.get_leaf_with_proof(black_box(index)).unwrap())
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_set_leaf,
+    bench_set_leaves,
+    bench_get_leaf_with_proof
+);
+criterion_main!(benches);
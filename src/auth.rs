@@ -0,0 +1,350 @@
+use std::sync::Arc;
+
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+use crate::kvpair::ContractId;
+use crate::Error;
+
+/// The contract id of a request authenticated with a JWT. It is inserted into the request
+/// extensions by `JwtAuth` and read by `MongoKvPair::get_contract_id`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct AuthenticatedContractId(pub ContractId);
+
+#[derive(Deserialize)]
+struct Claims {
+    // Base64 encoded, same as the x-auth-contract-id header.
+    contract_id: String,
+}
+
+/// Authenticate requests with the JWT in the header `authorization: Bearer <token>`. The
+/// token must be signed with the configured HMAC secret (HS256) or one of the keys in the
+/// configured JWKS, must not be expired, and must have a `contract_id` claim.
+#[derive(Clone)]
+pub struct JwtAuth {
+    // The key id (None for the HMAC secret) and the decoding key with its algorithm.
+    keys: Arc<Vec<(Option<String>, DecodingKey, Algorithm)>>,
+    // Requests without a token are passed through, only in dev mode.
+    dev_mode: bool,
+}
+
+impl JwtAuth {
+    pub fn with_secret(secret: &[u8]) -> Self {
+        let key = DecodingKey::from_secret(secret);
+        Self {
+            keys: Arc::new(vec![(None, key, Algorithm::HS256)]),
+            dev_mode: false,
+        }
+    }
+
+    pub fn with_jwks(jwks: &JwkSet) -> Result<Self, Error> {
+        let keys = jwks
+            .keys
+            .iter()
+            .map(|jwk| {
+                let key = DecodingKey::from_jwk(jwk)
+                    .map_err(|e| Error::InvalidArgument(format!("Invalid JWK: {e}")))?;
+                Ok((jwk.common.key_id.clone(), key, Self::jwk_algorithm(jwk)?))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(Self {
+            keys: Arc::new(keys),
+            dev_mode: false,
+        })
+    }
+
+    // The algorithm of the key, i.e. its alg parameter, or the one its curve implies for the
+    // elliptic curve keys without one. The other keys (RSA and HMAC) can be used with several
+    // algorithms, so they must specify it, instead of accepting the one in the header of the
+    // tokens.
+    fn jwk_algorithm(jwk: &Jwk) -> Result<Algorithm, Error> {
+        if let Some(algorithm) = jwk.common.algorithm {
+            return Ok(algorithm);
+        }
+        let algorithm = match &jwk.algorithm {
+            AlgorithmParameters::EllipticCurve(params) => match params.curve {
+                EllipticCurve::P256 => Some(Algorithm::ES256),
+                EllipticCurve::P384 => Some(Algorithm::ES384),
+                _ => None,
+            },
+            AlgorithmParameters::OctetKeyPair(params) => match params.curve {
+                EllipticCurve::Ed25519 => Some(Algorithm::EdDSA),
+                _ => None,
+            },
+            AlgorithmParameters::RSA(_) | AlgorithmParameters::OctetKey(_) => None,
+        };
+        algorithm.ok_or_else(|| {
+            Error::InvalidArgument(format!(
+                "Invalid JWK: alg required for key {:?}",
+                jwk.common.key_id
+            ))
+        })
+    }
+
+    pub fn with_dev_mode(mut self, dev_mode: bool) -> Self {
+        self.dev_mode = dev_mode;
+        self
+    }
+
+    /// Configure JWT authentication with the HMAC secret in the environment variable
+    /// KVPAIR_JWT_SECRET, or the JWKS in the file KVPAIR_JWT_JWKS_FILE. Returns None if
+    /// neither is set, and an error if the JWKS file can not be read or is invalid.
+    pub fn from_env() -> Result<Option<Self>, Error> {
+        if let Ok(secret) = std::env::var("KVPAIR_JWT_SECRET") {
+            return Ok(Some(Self::with_secret(secret.as_bytes())));
+        }
+        let path = match std::env::var("KVPAIR_JWT_JWKS_FILE") {
+            Ok(path) => path,
+            Err(_) => return Ok(None),
+        };
+        let jwks = std::fs::read(&path).map_err(|e| {
+            Error::InvalidArgument(format!("Read KVPAIR_JWT_JWKS_FILE {path:?}: {e}"))
+        })?;
+        let jwks: JwkSet = serde_json::from_slice(&jwks).map_err(|e| {
+            Error::InvalidArgument(format!("Parse KVPAIR_JWT_JWKS_FILE {path:?}: {e}"))
+        })?;
+        Self::with_jwks(&jwks).map(Some)
+    }
+
+    /// Validate the token and return the contract id in its claims. A token with a key id is
+    /// checked with the key with this id, or if there is none, with the keys without an id. A
+    /// token without a key id is checked with every key, e.g. while keys are rotated.
+    pub fn authenticate(&self, token: &str) -> Result<ContractId, Status> {
+        let header = decode_header(token)
+            .map_err(|e| Status::unauthenticated(format!("Invalid token: {e}")))?;
+        let key_id = header
+            .kid
+            .as_ref()
+            .filter(|kid| self.keys.iter().any(|(id, _, _)| id.as_ref() == Some(*kid)));
+        let keys = self
+            .keys
+            .iter()
+            .filter(|(kid, _, _)| header.kid.is_none() || kid.as_ref() == key_id);
+        let mut error = None;
+        for (_, key, algorithm) in keys {
+            // Only accept the algorithm of the key.
+            let validation = Validation::new(*algorithm);
+            match decode::<Claims>(token, key, &validation) {
+                Ok(data) => return Self::contract_id(data.claims),
+                // The error of a key the token is signed with (e.g. an expired token) is reported
+                // rather than the mismatches with the other keys.
+                Err(e) if error.is_none() || !is_key_mismatch(&e) => error = Some(e),
+                Err(_) => (),
+            }
+        }
+        match error {
+            Some(e) => Err(Status::unauthenticated(format!("Invalid token: {e}"))),
+            None => Err(Status::unauthenticated("Unknown key id")),
+        }
+    }
+
+    fn contract_id(claims: Claims) -> Result<ContractId, Status> {
+        claims
+            .contract_id
+            .as_str()
+            .try_into()
+            .map_err(|e| Status::unauthenticated(format!("Invalid Contract id: {e}")))
+    }
+}
+
+// Whether the token was rejected because it is not signed with the key, or not with its
+// algorithm.
+fn is_key_mismatch(error: &jsonwebtoken::errors::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::InvalidSignature | ErrorKind::InvalidAlgorithm | ErrorKind::InvalidKeyFormat
+    )
+}
+
+impl Interceptor for JwtAuth {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let token = match request.metadata().get("authorization") {
+            Some(value) => value
+                .to_str()
+                .ok()
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .ok_or_else(|| Status::unauthenticated("Invalid authorization header"))?,
+            None if self.dev_mode => return Ok(request),
+            None => return Err(Status::unauthenticated("Bearer token not found")),
+        };
+        let contract_id = self.authenticate(token)?;
+        request
+            .extensions_mut()
+            .insert(AuthenticatedContractId(contract_id));
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde::Serialize;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const SECRET: &[u8] = b"secret";
+    const CONTRACT_ID: &str = "FX6glXnwnPljB/ayPW/WHDz/EjB21Ewn4um+3wITXoc=";
+
+    #[derive(Serialize)]
+    struct TestClaims<'a> {
+        contract_id: &'a str,
+        exp: u64,
+    }
+
+    fn token(secret: &[u8], contract_id: &str, expires_in: i64) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let claims = TestClaims {
+            contract_id,
+            exp: (now as i64 + expires_in) as u64,
+        };
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret),
+        )
+        .unwrap()
+    }
+
+    fn token_with_key_id(secret: &[u8], kid: Option<&str>) -> String {
+        let claims = TestClaims {
+            contract_id: CONTRACT_ID,
+            exp: u64::MAX,
+        };
+        let header = Header {
+            kid: kid.map(str::to_string),
+            ..Header::default()
+        };
+        encode(&header, &claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    fn request_with_token(token: &str) -> Request<()> {
+        let mut request = Request::new(());
+        request
+            .metadata_mut()
+            .insert("authorization", format!("Bearer {token}").parse().unwrap());
+        request
+    }
+
+    #[test]
+    fn test_jwt_auth() {
+        let mut auth = JwtAuth::with_secret(SECRET);
+        let contract_id = ContractId::try_from(CONTRACT_ID).unwrap();
+        let request = auth
+            .call(request_with_token(&token(SECRET, CONTRACT_ID, 3600)))
+            .unwrap();
+        assert_eq!(
+            request.extensions().get::<AuthenticatedContractId>(),
+            Some(&AuthenticatedContractId(contract_id))
+        );
+
+        // Expired (beyond the default leeway of 60 seconds), wrongly signed or malformed tokens.
+        for token in [
+            token(SECRET, CONTRACT_ID, -3600),
+            token(b"another secret", CONTRACT_ID, 3600),
+            token(SECRET, "not base64", 3600),
+            "not a token".to_string(),
+        ] {
+            let status = auth.call(request_with_token(&token)).unwrap_err();
+            assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        }
+    }
+
+    // Keys without alg are rejected unless their curve implies it, so that tokens can not choose
+    // the algorithm their key is used with.
+    #[test]
+    fn test_jwks_algorithm() {
+        let jwks: JwkSet =
+            serde_json::from_str(r#"{"keys":[{"kty":"oct","k":"c2VjcmV0"}]}"#).unwrap();
+        assert!(JwtAuth::with_jwks(&jwks).is_err());
+
+        let jwks: JwkSet =
+            serde_json::from_str(r#"{"keys":[{"kty":"oct","alg":"HS256","k":"c2VjcmV0"}]}"#)
+                .unwrap();
+        let auth = JwtAuth::with_jwks(&jwks).unwrap();
+        let contract_id = ContractId::try_from(CONTRACT_ID).unwrap();
+        let token_contract_id = auth
+            .authenticate(&token(SECRET, CONTRACT_ID, 3600))
+            .unwrap();
+        assert_eq!(token_contract_id, contract_id);
+        let claims = TestClaims {
+            contract_id: CONTRACT_ID,
+            exp: u64::MAX,
+        };
+        let token = encode(
+            &Header::new(Algorithm::HS512),
+            &claims,
+            &EncodingKey::from_secret(SECRET),
+        )
+        .unwrap();
+        let status = auth.authenticate(&token).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    // "c2VjcmV0" and "b3RoZXI" are the secrets "secret" and "other".
+    #[test]
+    fn test_jwks_key_id() {
+        let contract_id = ContractId::try_from(CONTRACT_ID).unwrap();
+
+        // A token without a key id is checked with every key, e.g. with the new key while the
+        // old one is still in the set.
+        let jwks: JwkSet = serde_json::from_str(
+            r#"{"keys":[
+                {"kty":"oct","alg":"HS256","kid":"old","k":"b3RoZXI"},
+                {"kty":"oct","alg":"HS256","kid":"new","k":"c2VjcmV0"}
+            ]}"#,
+        )
+        .unwrap();
+        let auth = JwtAuth::with_jwks(&jwks).unwrap();
+        for kid in [None, Some("new")] {
+            let token = token_with_key_id(SECRET, kid);
+            assert_eq!(auth.authenticate(&token).unwrap(), contract_id);
+        }
+        // A token with a key id is only checked with this key.
+        for kid in [Some("old"), Some("unknown")] {
+            let status = auth
+                .authenticate(&token_with_key_id(SECRET, kid))
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        }
+
+        // A key without a key id does not shadow the key with the key id of the token, and
+        // checks the tokens with unknown key ids.
+        let jwks: JwkSet = serde_json::from_str(
+            r#"{"keys":[
+                {"kty":"oct","alg":"HS256","k":"b3RoZXI"},
+                {"kty":"oct","alg":"HS256","kid":"new","k":"c2VjcmV0"}
+            ]}"#,
+        )
+        .unwrap();
+        let auth = JwtAuth::with_jwks(&jwks).unwrap();
+        let token = token_with_key_id(SECRET, Some("new"));
+        assert_eq!(auth.authenticate(&token).unwrap(), contract_id);
+        let token = token_with_key_id(b"other", Some("unknown"));
+        assert_eq!(auth.authenticate(&token).unwrap(), contract_id);
+        let status = auth
+            .authenticate(&token_with_key_id(SECRET, Some("unknown")))
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[test]
+    fn test_jwt_auth_without_token() {
+        let mut auth = JwtAuth::with_secret(SECRET);
+        let status = auth.call(Request::new(())).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let mut auth = auth.with_dev_mode(true);
+        let request = auth.call(Request::new(())).unwrap();
+        assert!(request
+            .extensions()
+            .get::<AuthenticatedContractId>()
+            .is_none());
+    }
+}
@@ -0,0 +1,182 @@
+use tonic::codec::CompressionEncoding;
+use tonic::transport::Channel;
+use tonic::Request;
+
+use crate::kvpair::{ContractId, Hash, LeafData, MongoMerkle, MERKLE_TREE_HEIGHT};
+use crate::merkle::MerkleProof;
+use crate::proto::kv_pair_client::KvPairClient;
+use crate::proto::node::NodeData;
+use crate::proto::{GetLeafRequest, GetRootRequest, Proof, ProofType, SetLeafRequest};
+use crate::Error;
+
+/// A client of the kvpair server for a single contract, which takes and returns the types of
+/// this crate instead of the proto messages, and fails with Error instead of Status.
+///
+/// Unlike MongoMerkle, it is not bound to a root: the leaves are read from and written to the
+/// current root of the contract. Requests are not retried, set_leaf_idempotent makes retrying
+/// a write safe.
+#[derive(Debug, Clone)]
+pub struct KvClient<const H: usize = MERKLE_TREE_HEIGHT> {
+    contract_id: ContractId,
+    client: KvPairClient<Channel>,
+}
+
+impl<const H: usize> KvClient<H> {
+    /// Connect to the server KVPAIR_GRPC_SERVER_URL (http://localhost:50051 by default).
+    pub fn new(contract_id: ContractId) -> Result<Self, Error> {
+        Self::new_with_url(contract_id, &MongoMerkle::<H>::get_server_url())
+    }
+
+    /// The channel connects lazily, so this only fails if the URL is invalid.
+    pub fn new_with_url(contract_id: ContractId, url: &str) -> Result<Self, Error> {
+        let client = MongoMerkle::<H>::get_client(url)?;
+        Ok(Self::with_client(contract_id, client))
+    }
+
+    /// Use an existing client, e.g. one with interceptors or over a custom channel.
+    pub fn with_client(contract_id: ContractId, client: KvPairClient<Channel>) -> Self {
+        Self {
+            contract_id,
+            client,
+        }
+    }
+
+    /// Compress the requests with encoding, which the server must accept. The responses may be
+    /// compressed with gzip regardless, see MongoMerkle::get_client.
+    pub fn with_compression(mut self, encoding: CompressionEncoding) -> Self {
+        self.client = self.client.send_compressed(encoding);
+        self
+    }
+
+    pub fn contract_id(&self) -> ContractId {
+        self.contract_id
+    }
+
+    /// The current root of the contract.
+    pub async fn get_root(&mut self) -> Result<Hash, Error> {
+        let response = self
+            .client
+            .get_root(Request::new(GetRootRequest {
+                contract_id: Some(self.contract_id.into()),
+                consistent_read: false,
+                include_node: false,
+            }))
+            .await
+            .map_err(|status| Error::from_status(&status))?
+            .into_inner();
+        response.root.try_into()
+    }
+
+    /// The data of the leaf at index in the current root, None if it is not available (e.g. the
+    /// leaf was set with only its hash), and the proof of the leaf, None if the server did not
+    /// send one. The proof is checked before it is returned.
+    pub async fn get_leaf(
+        &mut self,
+        index: u64,
+    ) -> Result<(Option<LeafData>, Option<MerkleProof<Hash, H>>), Error> {
+        let response = self
+            .client
+            .get_leaf(Request::new(GetLeafRequest {
+                index,
+                address: None,
+                hash: None,
+                proof_type: ProofType::ProofV1.into(),
+                contract_id: Some(self.contract_id.into()),
+                require_data: false,
+                consistent_read: false,
+            }))
+            .await
+            .map_err(|status| Error::from_status(&status))?
+            .into_inner();
+        let node = response
+            .node
+            .ok_or_else(|| missing_field_error(index, "node"))?;
+        let data = match node.node_data {
+            Some(NodeData::Data(data)) if response.data_available => Some(data.into()),
+            _ => None,
+        };
+        let proof = response
+            .proof
+            .map(|proof| check_proof(index, proof))
+            .transpose()?;
+        Ok((data, proof))
+    }
+
+    /// Set the data of the leaf at index, and return the proof of the leaf in the new root of
+    /// the contract. The proof is checked before it is returned.
+    pub async fn set_leaf(
+        &mut self,
+        index: u64,
+        data: LeafData,
+    ) -> Result<MerkleProof<Hash, H>, Error> {
+        self.send_set_leaf(index, data, None).await
+    }
+
+    /// Same as set_leaf, with an idempotency key identifying the write. If the server already
+    /// applied a write with the key (e.g. the response of a previous attempt was lost), the leaf
+    /// is not set again and the proof returned then is returned, even if the leaf has changed
+    /// since. The key must not be reused for another write.
+    pub async fn set_leaf_idempotent(
+        &mut self,
+        index: u64,
+        data: LeafData,
+        idempotency_key: impl Into<String>,
+    ) -> Result<MerkleProof<Hash, H>, Error> {
+        self.send_set_leaf(index, data, Some(idempotency_key.into()))
+            .await
+    }
+
+    async fn send_set_leaf(
+        &mut self,
+        index: u64,
+        data: LeafData,
+        idempotency_key: Option<String>,
+    ) -> Result<MerkleProof<Hash, H>, Error> {
+        let response = self
+            .client
+            .set_leaf(Request::new(SetLeafRequest {
+                index,
+                address: None,
+                hash: None,
+                data: Some(data.into()),
+                proof_type: ProofType::ProofV1.into(),
+                contract_id: Some(self.contract_id.into()),
+                sign_root: false,
+                idempotency_key,
+            }))
+            .await
+            .map_err(|status| Error::from_status(&status))?
+            .into_inner();
+        let proof = response
+            .proof
+            .ok_or_else(|| missing_field_error(index, "proof"))?;
+        check_proof(index, proof)
+    }
+}
+
+pub(crate) fn missing_field_error(index: u64, field: &str) -> Error {
+    Error::InconsistentData(format!("No {field} in the response for leaf {index}"))
+        .with_detail("index", index)
+}
+
+// Convert the proof of the leaf at index, and check that it is a valid proof of a leaf at index
+// in the root it claims. The hash of the leaf is not checked against the node returned with the
+// proof, as default leaves are returned with an empty hash (see Hash::empty).
+pub(crate) fn check_proof<const H: usize>(
+    index: u64,
+    proof: Proof,
+) -> Result<MerkleProof<Hash, H>, Error> {
+    let proof: MerkleProof<Hash, H> = proof
+        .proof_v1
+        .ok_or_else(|| missing_field_error(index, "proof_v1"))?
+        .try_into()?;
+    if proof.index != index {
+        return Err(Error::InconsistentData(format!(
+            "Proof of leaf {} returned for leaf {index}",
+            proof.index
+        ))
+        .with_detail("index", index));
+    }
+    proof.try_verify()?;
+    Ok(proof)
+}
@@ -1,7 +1,15 @@
+use std::collections::{BTreeMap, HashMap};
+
+use mongodb::error::{ErrorKind, WriteFailure};
 use thiserror::Error;
-use tonic::Status;
+use tonic::{Code, Status};
+use tonic_types::{ErrorDetails, StatusExt};
+
+use crate::merkle::{MerkleError, MerkleErrorCode};
+use crate::proto::ErrorCode;
 
-use crate::merkle::MerkleError;
+// Domain of the google.rpc.ErrorInfo attached to every error status.
+pub const ERROR_DOMAIN: &str = "zkc_state_manager";
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -9,23 +17,205 @@ pub enum Error {
     Mongodb(#[from] mongodb::error::Error),
     #[error("Merkle tree error: {0:?}")]
     Merkle(#[from] MerkleError),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    // Failed to connect to the kvpair server, e.g. in MongoMerkle.
+    #[error("Transport error: {0}")]
+    Transport(#[from] tonic::transport::Error),
     #[error("Invalid argument: {0}")]
     InvalidArgument(String),
+    #[error("Invalid index: {0}")]
+    InvalidIndex(String),
+    #[error("Invalid hash: {0}")]
+    InvalidHash(String),
+    #[error("Hash mismatch: {0}")]
+    HashMismatch(String),
+    #[error("Not found: {0}")]
+    NotFound(String),
+    #[error("Leaf not found: {0}")]
+    LeafNotFound(String),
+    #[error("Root not found: {0}")]
+    RootNotFound(String),
     #[error("Inconsistent data: {0}")]
     InconsistentData(String),
     #[error("Precondition not satisfied: {0}")]
     Precondition(String),
+    // The request conflicts with concurrent requests, and may be retried.
+    #[error("Aborted: {0}")]
+    Aborted(String),
+    // The root has been replaced by another writer since it was read.
+    #[error("Root changed: {0}")]
+    RootChanged(String),
+    // The deadline of the request (grpc-timeout) passed before it completed.
+    #[error("Deadline exceeded: {0}")]
+    DeadlineExceeded(String),
+    // The server is shutting down, the request may be retried with another server.
+    #[error("Unavailable: {0}")]
+    Unavailable(String),
+    // Too many requests are in flight, the request may be retried later.
+    #[error("Resource exhausted: {0}")]
+    ResourceExhausted(String),
+    // An error with machine readable fields (e.g. the index, or hashes in hex), which are sent
+    // as the metadata of the ErrorInfo. See with_detail.
+    #[error("{error}")]
+    WithDetails {
+        error: Box<Error>,
+        details: BTreeMap<String, String>,
+    },
+    // A status returned by the server which can not be converted to any of the above.
+    #[error("RPC error: {0}")]
+    Rpc(Status),
+}
+
+// https://www.mongodb.com/docs/manual/reference/error-codes/
+const MAX_TIME_MS_EXPIRED_ERROR_CODE: i32 = 50;
+
+pub(crate) fn is_max_time_expired_error(error: &mongodb::error::Error) -> bool {
+    match error.kind.as_ref() {
+        ErrorKind::Command(e) => e.code == MAX_TIME_MS_EXPIRED_ERROR_CODE,
+        ErrorKind::Write(WriteFailure::WriteConcernError(e)) => {
+            e.code == MAX_TIME_MS_EXPIRED_ERROR_CODE
+        }
+        _ => false,
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+impl Error {
+    // The machine readable reason sent along with the status, see ErrorCode in kvpair.proto.
+    pub fn error_code(&self) -> ErrorCode {
+        use Error::*;
+        match self {
+            Mongodb(e) if is_max_time_expired_error(e) => ErrorCode::ErrorDeadlineExceeded,
+            Mongodb(_) => ErrorCode::ErrorDatabase,
+            Io(_) => ErrorCode::ErrorIo,
+            Transport(_) => ErrorCode::ErrorUnavailable,
+            Merkle(e) => match e.code() {
+                MerkleErrorCode::InvalidHash => ErrorCode::ErrorInvalidHash,
+                MerkleErrorCode::InvalidLeafIndex
+                | MerkleErrorCode::InvalidIndex
+                | MerkleErrorCode::InvalidDepth => ErrorCode::ErrorInvalidIndex,
+                MerkleErrorCode::NodeNotFound => ErrorCode::ErrorNotFound,
+                MerkleErrorCode::InvalidNode => ErrorCode::ErrorInconsistentData,
+                MerkleErrorCode::InvalidOther => ErrorCode::ErrorMerkle,
+            },
+            InvalidArgument(_) => ErrorCode::ErrorInvalidArgument,
+            InvalidIndex(_) => ErrorCode::ErrorInvalidIndex,
+            InvalidHash(_) => ErrorCode::ErrorInvalidHash,
+            HashMismatch(_) => ErrorCode::ErrorHashMismatch,
+            NotFound(_) => ErrorCode::ErrorNotFound,
+            LeafNotFound(_) => ErrorCode::ErrorLeafNotFound,
+            RootNotFound(_) => ErrorCode::ErrorRootNotFound,
+            InconsistentData(_) => ErrorCode::ErrorInconsistentData,
+            Precondition(_) => ErrorCode::ErrorPrecondition,
+            Aborted(_) => ErrorCode::ErrorAborted,
+            RootChanged(_) => ErrorCode::ErrorRootChanged,
+            DeadlineExceeded(_) => ErrorCode::ErrorDeadlineExceeded,
+            Unavailable(_) => ErrorCode::ErrorUnavailable,
+            ResourceExhausted(_) => ErrorCode::ErrorResourceExhausted,
+            WithDetails { error, .. } => error.error_code(),
+            Rpc(_) => ErrorCode::ErrorUnspecified,
+        }
+    }
+
+    fn status_code(&self) -> Code {
+        use Error::*;
+        match self {
+            // Reads are only limited with maxTimeMS to the deadline of the request, the commits
+            // limited by max_commit_time are aborted before the error gets here.
+            Mongodb(e) if is_max_time_expired_error(e) => Code::DeadlineExceeded,
+            Mongodb(_) | Io(_) | Merkle(_) | InconsistentData(_) => Code::Internal,
+            InvalidArgument(_) | InvalidIndex(_) | InvalidHash(_) | HashMismatch(_) => {
+                Code::InvalidArgument
+            }
+            NotFound(_) | LeafNotFound(_) | RootNotFound(_) => Code::NotFound,
+            Precondition(_) => Code::FailedPrecondition,
+            Aborted(_) | RootChanged(_) => Code::Aborted,
+            DeadlineExceeded(_) => Code::DeadlineExceeded,
+            Transport(_) | Unavailable(_) => Code::Unavailable,
+            ResourceExhausted(_) => Code::ResourceExhausted,
+            WithDetails { error, .. } => error.status_code(),
+            Rpc(status) => status.code(),
+        }
+    }
+
+    /// Attach a machine readable field to this error, e.g. `with_detail("index", index)`.
+    /// Hashes should be hex encoded.
+    pub fn with_detail(self, key: &str, value: impl ToString) -> Self {
+        let (error, mut details) = match self {
+            Error::WithDetails { error, details } => (error, details),
+            error => (Box::new(error), BTreeMap::new()),
+        };
+        details.insert(key.to_string(), value.to_string());
+        Error::WithDetails { error, details }
+    }
+
+    pub fn detail(&self, key: &str) -> Option<&str> {
+        match self {
+            Error::WithDetails { details, .. } => details.get(key).map(String::as_str),
+            _ => None,
+        }
+    }
+
+    /// The error without the details attached with with_detail.
+    pub fn inner(&self) -> &Error {
+        match self {
+            Error::WithDetails { error, .. } => &**error,
+            error => error,
+        }
+    }
+
+    /// Parse the error details of a status returned by the server, e.g. in gRPC clients.
+    /// Statuses without our ErrorInfo (e.g. returned by tonic itself) and errors which can not
+    /// be reconstructed (e.g. database errors) are kept as Error::Rpc.
+    pub fn from_status(status: &Status) -> Self {
+        use Error::*;
+        let info = match status.get_error_details().error_info() {
+            Some(info) if info.domain == ERROR_DOMAIN => info.clone(),
+            _ => return Rpc(status.clone()),
+        };
+        // Strip the prefix added by the Display implementation of the error.
+        let message = status
+            .message()
+            .split_once(": ")
+            .map_or(status.message(), |(_, message)| message)
+            .to_string();
+        let error = match ErrorCode::from_str_name(&info.reason) {
+            Some(ErrorCode::ErrorInvalidArgument) => InvalidArgument(message),
+            Some(ErrorCode::ErrorInvalidIndex) => InvalidIndex(message),
+            Some(ErrorCode::ErrorInvalidHash) => InvalidHash(message),
+            Some(ErrorCode::ErrorHashMismatch) => HashMismatch(message),
+            Some(ErrorCode::ErrorNotFound) => NotFound(message),
+            Some(ErrorCode::ErrorLeafNotFound) => LeafNotFound(message),
+            Some(ErrorCode::ErrorRootNotFound) => RootNotFound(message),
+            Some(ErrorCode::ErrorInconsistentData) => InconsistentData(message),
+            Some(ErrorCode::ErrorPrecondition) => Precondition(message),
+            Some(ErrorCode::ErrorAborted) => Aborted(message),
+            Some(ErrorCode::ErrorRootChanged) => RootChanged(message),
+            Some(ErrorCode::ErrorDeadlineExceeded) => DeadlineExceeded(message),
+            Some(ErrorCode::ErrorUnavailable) => Unavailable(message),
+            Some(ErrorCode::ErrorResourceExhausted) => ResourceExhausted(message),
+            _ => return Rpc(status.clone()),
+        };
+        info.metadata
+            .into_iter()
+            .fold(error, |error, (key, value)| error.with_detail(&key, value))
+    }
+}
+
 impl From<Error> for Status {
     fn from(error: Error) -> Self {
-        use Error::*;
-        let s = format!("{error}");
-        match error {
-            Mongodb(_) | Merkle(_) | InconsistentData(_) | Precondition(_) => Status::internal(s),
-            InvalidArgument(_) => Status::invalid_argument(s),
+        if let Error::Rpc(status) = error {
+            return status;
         }
+        let s = format!("{error}");
+        let metadata = match &error {
+            Error::WithDetails { details, .. } => details.clone().into_iter().collect(),
+            _ => HashMap::new(),
+        };
+        let details =
+            ErrorDetails::with_error_info(error.error_code().as_str_name(), ERROR_DOMAIN, metadata);
+        Status::with_error_details(error.status_code(), s, details)
     }
 }
@@ -0,0 +1,330 @@
+use std::collections::BTreeMap;
+
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+use tonic::metadata::MetadataMap;
+use tonic::service::Interceptor;
+use tonic::{Code, Request, Status};
+
+use crate::auth::{AuthenticatedContractId, JwtAuth};
+use crate::handle::{GetLeafOptions, KvPairHandle, SetLeafOptions};
+use crate::kvpair::{ContractId, Hash, LeafData, MerkleProofJson};
+use crate::proto::PoseidonHashAlgorithm;
+use crate::service::MongoKvPair;
+use crate::Error;
+
+/// An HTTP/JSON gateway to the KvPair service, for clients which can speak neither gRPC nor
+/// gRPC-web. The routes are served by the same handlers as the gRPC server (see KvPairHandle):
+///
+/// - `GET /v1/contracts/{contract_id}/root` and `POST` with `{"root": hash}` to set it.
+/// - `GET /v1/contracts/{contract_id}/leaves/{index}?proof=true`, optionally with `hash`,
+///   `require_data` and `consistent_read` as in GetLeafRequest.
+/// - `POST /v1/contracts/{contract_id}/leaves/{index}?proof=true` with `{"data": data}`, and
+///   optionally `idempotency_key`.
+/// - `POST /v1/contracts/{contract_id}/poseidon` with `{"data": data}`, and optionally the
+///   `algorithm` (e.g. `"HashPadded16Bytes"`).
+/// - `GET /v1/contracts/{contract_id}/stats`.
+///
+/// Contract ids and hashes are hex encoded, data is base64 encoded, and proofs are returned as
+/// MerkleProofJson. Errors are returned as `{"code": ErrorCode, "message", "details"}` with the
+/// HTTP status of their gRPC code. The `authorization` and `x-auth-contract-id` headers are
+/// honored as the gRPC metadata of the same name.
+#[derive(Clone)]
+pub struct Gateway {
+    server: MongoKvPair,
+    auth: Option<JwtAuth>,
+}
+
+impl Gateway {
+    pub fn new(server: MongoKvPair) -> Self {
+        Self { server, auth: None }
+    }
+
+    /// Authenticate the requests as the gRPC server does, see JwtAuth.
+    pub fn with_auth(mut self, auth: Option<JwtAuth>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    pub fn into_router(self) -> Router {
+        Router::new()
+            .route(
+                "/v1/contracts/:contract_id/root",
+                get(get_root).post(set_root),
+            )
+            .route(
+                "/v1/contracts/:contract_id/leaves/:index",
+                get(get_leaf).post(set_leaf),
+            )
+            .route("/v1/contracts/:contract_id/poseidon", post(poseidon_hash))
+            .route("/v1/contracts/:contract_id/stats", get(get_stats))
+            .with_state(self)
+    }
+
+    // A handle sending the headers of the request as metadata, after rejecting the request as
+    // the interceptor of the gRPC server would.
+    fn handle(&self, headers: HeaderMap) -> Result<KvPairHandle, GatewayError> {
+        if self.server.is_shutting_down() {
+            return Err(Error::Unavailable("Server is shutting down".to_string()).into());
+        }
+        let mut request = Request::new(());
+        *request.metadata_mut() = MetadataMap::from_headers(headers);
+        let request = match self.auth.clone() {
+            Some(mut auth) => auth.call(request).map_err(GatewayError::from_status)?,
+            None => request,
+        };
+        let authenticated = request
+            .extensions()
+            .get::<AuthenticatedContractId>()
+            .copied();
+        Ok(KvPairHandle::new(self.server.clone())
+            .with_request_context(request.metadata().clone(), authenticated))
+    }
+}
+
+/// The error returned by the gateway routes, see Gateway.
+#[derive(Debug)]
+pub struct GatewayError(Error);
+
+impl GatewayError {
+    fn from_status(status: Status) -> Self {
+        Self(Error::from_status(&status))
+    }
+}
+
+impl From<Error> for GatewayError {
+    fn from(error: Error) -> Self {
+        Self(error)
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    code: &'static str,
+    message: String,
+    details: BTreeMap<String, String>,
+}
+
+impl IntoResponse for GatewayError {
+    fn into_response(self) -> Response {
+        let code = self.0.error_code().as_str_name();
+        let details = match &self.0 {
+            Error::WithDetails { details, .. } => details.clone(),
+            _ => BTreeMap::new(),
+        };
+        let status = Status::from(self.0);
+        let body = ErrorResponse {
+            code,
+            message: status.message().to_string(),
+            details,
+        };
+        (http_status(status.code()), Json(body)).into_response()
+    }
+}
+
+// The mapping of https://github.com/googleapis/googleapis/blob/master/google/rpc/code.proto,
+// which is also the one of the envoy gRPC-JSON transcoder.
+fn http_status(code: Code) -> StatusCode {
+    match code {
+        Code::Ok => StatusCode::OK,
+        Code::Cancelled => StatusCode::from_u16(499).unwrap(),
+        Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => {
+            StatusCode::BAD_REQUEST
+        }
+        Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Code::Unknown | Code::Internal | Code::DataLoss => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn parse_contract_id(contract_id: &str) -> Result<ContractId, Error> {
+    let contract_id = hex::decode(contract_id)
+        .map_err(|e| Error::InvalidArgument(format!("Invalid hex contract id: {e}")))?;
+    contract_id.as_slice().try_into()
+}
+
+fn parse_hash(hash: &str) -> Result<Hash, Error> {
+    hex::decode(hash)
+        .map_err(|e| Error::InvalidHash(format!("Invalid hex hash: {e}")))?
+        .try_into()
+}
+
+fn parse_data(data: &str) -> Result<Vec<u8>, Error> {
+    general_purpose::STANDARD
+        .decode(data)
+        .map_err(|e| Error::InvalidArgument(format!("Base64 decoding failed: {e}")))
+}
+
+#[derive(Serialize, Deserialize)]
+struct RootBody {
+    root: String,
+}
+
+#[derive(Deserialize)]
+struct LeafQuery {
+    #[serde(default)]
+    proof: bool,
+    hash: Option<String>,
+    #[serde(default)]
+    require_data: bool,
+    #[serde(default)]
+    consistent_read: bool,
+}
+
+#[derive(Deserialize)]
+struct SetLeafBody {
+    data: String,
+    idempotency_key: Option<String>,
+}
+
+#[derive(Serialize)]
+struct LeafResponse {
+    index: u64,
+    hash: String,
+    // Only returned by GET, null if the data is not available.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Option<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proof: Option<MerkleProofJson>,
+}
+
+#[derive(Deserialize)]
+struct PoseidonHashBody {
+    data: String,
+    algorithm: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PoseidonHashResponse {
+    hash: String,
+}
+
+#[derive(Serialize)]
+struct StatsResponse {
+    node_count: u64,
+    leaf_count: u64,
+    current_root: String,
+    tree_height: u32,
+}
+
+async fn get_root(
+    State(gateway): State<Gateway>,
+    headers: HeaderMap,
+    Path(contract_id): Path<String>,
+) -> Result<Json<RootBody>, GatewayError> {
+    let contract_id = parse_contract_id(&contract_id)?;
+    let root = gateway.handle(headers)?.get_root(contract_id).await?;
+    Ok(Json(RootBody {
+        root: hex::encode(root.0),
+    }))
+}
+
+async fn set_root(
+    State(gateway): State<Gateway>,
+    headers: HeaderMap,
+    Path(contract_id): Path<String>,
+    Json(body): Json<RootBody>,
+) -> Result<Json<RootBody>, GatewayError> {
+    let contract_id = parse_contract_id(&contract_id)?;
+    let root = parse_hash(&body.root)?;
+    gateway.handle(headers)?.set_root(contract_id, root).await?;
+    Ok(Json(body))
+}
+
+async fn get_leaf(
+    State(gateway): State<Gateway>,
+    headers: HeaderMap,
+    Path((contract_id, index)): Path<(String, u64)>,
+    Query(query): Query<LeafQuery>,
+) -> Result<Json<LeafResponse>, GatewayError> {
+    let contract_id = parse_contract_id(&contract_id)?;
+    let options = GetLeafOptions {
+        hash: query.hash.as_deref().map(parse_hash).transpose()?,
+        proof: query.proof,
+        require_data: query.require_data,
+        consistent_read: query.consistent_read,
+    };
+    let (record, datahash_record, proof) = gateway
+        .handle(headers)?
+        .get_leaf(contract_id, index, options)
+        .await?;
+    Ok(Json(LeafResponse {
+        index: record.index,
+        hash: hex::encode(record.hash.0),
+        data: Some(datahash_record.map(|record| general_purpose::STANDARD.encode(record.data))),
+        proof: proof.as_ref().map(MerkleProofJson::from),
+    }))
+}
+
+async fn set_leaf(
+    State(gateway): State<Gateway>,
+    headers: HeaderMap,
+    Path((contract_id, index)): Path<(String, u64)>,
+    Query(query): Query<LeafQuery>,
+    Json(body): Json<SetLeafBody>,
+) -> Result<Json<LeafResponse>, GatewayError> {
+    let contract_id = parse_contract_id(&contract_id)?;
+    let data = LeafData::from(parse_data(&body.data)?);
+    let options = SetLeafOptions {
+        proof: query.proof,
+        idempotency_key: body.idempotency_key,
+    };
+    let (record, proof) = gateway
+        .handle(headers)?
+        .set_leaf(contract_id, index, data, options)
+        .await?;
+    Ok(Json(LeafResponse {
+        index: record.index,
+        hash: hex::encode(record.hash.0),
+        data: None,
+        proof: proof.as_ref().map(MerkleProofJson::from),
+    }))
+}
+
+async fn poseidon_hash(
+    State(gateway): State<Gateway>,
+    headers: HeaderMap,
+    Path(contract_id): Path<String>,
+    Json(body): Json<PoseidonHashBody>,
+) -> Result<Json<PoseidonHashResponse>, GatewayError> {
+    let contract_id = parse_contract_id(&contract_id)?;
+    let algorithm = match body.algorithm.as_deref() {
+        Some(algorithm) => PoseidonHashAlgorithm::from_str_name(algorithm).ok_or_else(|| {
+            Error::InvalidArgument(format!("Unknown poseidon hash algorithm {algorithm:?}"))
+        })?,
+        None => PoseidonHashAlgorithm::HashFieldElements,
+    };
+    let hash = gateway
+        .handle(headers)?
+        .poseidon_hash(contract_id, parse_data(&body.data)?, algorithm)
+        .await?;
+    Ok(Json(PoseidonHashResponse {
+        hash: hex::encode(hash.0),
+    }))
+}
+
+async fn get_stats(
+    State(gateway): State<Gateway>,
+    headers: HeaderMap,
+    Path(contract_id): Path<String>,
+) -> Result<Json<StatsResponse>, GatewayError> {
+    let contract_id = parse_contract_id(&contract_id)?;
+    let stats = gateway.handle(headers)?.get_stats(contract_id).await?;
+    Ok(Json(StatsResponse {
+        node_count: stats.node_count,
+        leaf_count: stats.leaf_count,
+        current_root: hex::encode(stats.current_root),
+        tree_height: stats.tree_height,
+    }))
+}
@@ -0,0 +1,302 @@
+use tonic::metadata::MetadataMap;
+use tonic::Request;
+
+use crate::auth::AuthenticatedContractId;
+use crate::client::{check_proof, missing_field_error};
+use crate::kvpair::{
+    ContractId, DataHashRecord, Hash, LeafData, MerkleRecord, DEFAULT_HASH_VEC, MERKLE_TREE_HEIGHT,
+};
+use crate::merkle::MerkleProof;
+use crate::proto::kv_pair_server::KvPair;
+use crate::proto::node::NodeData;
+use crate::proto::{
+    DeleteLeafRequest, GetLeafRequest, GetRootRequest, GetStatsRequest, GetStatsResponse, Node,
+    PoseidonHashAlgorithm, PoseidonHashRequest, ProofType, SetLeafRequest, SetRootRequest,
+};
+use crate::service::MongoKvPair;
+use crate::Error;
+
+pub type LeafProof = MerkleProof<Hash, MERKLE_TREE_HEIGHT>;
+
+/// The options of KvPairHandle::get_leaf, see GetLeafRequest.
+#[derive(Debug, Clone, Default)]
+pub struct GetLeafOptions {
+    // Only return the leaf if it has this hash. Without a proof, the leaf is looked up by its
+    // hash, and may not be in the current root.
+    pub hash: Option<Hash>,
+    // Return the proof of the leaf in the current root.
+    pub proof: bool,
+    // Fail with Error::LeafNotFound if the data of the leaf is not available.
+    pub require_data: bool,
+    // Read from the primary, see GetLeafRequest::consistent_read.
+    pub consistent_read: bool,
+}
+
+/// The options of KvPairHandle::set_leaf, see SetLeafRequest.
+#[derive(Debug, Clone, Default)]
+pub struct SetLeafOptions {
+    // Return the proof of the leaf in the new root.
+    pub proof: bool,
+    // Make retrying the write safe, see KvClient::set_leaf_idempotent.
+    pub idempotency_key: Option<String>,
+}
+
+/// Call the KvPair logic of a MongoKvPair in the same process, without a gRPC server. Like
+/// KvClient, it takes and returns the types of this crate instead of the proto messages, and
+/// fails with Error instead of Status.
+///
+/// The requests are handed to the KvPair handlers as they are, without being encoded, so that
+/// they go through the same checks, locks and audit log as those received by the server. By
+/// default they carry no authentication, so the contract ids are trusted (or replaced by the one
+/// of the test config, if any). See with_request_context to attach the metadata and the
+/// authenticated contract id of another request, as the HTTP gateway does.
+///
+/// ```no_run
+/// use zkc_state_manager::handle::{GetLeafOptions, KvPairHandle, SetLeafOptions};
+/// use zkc_state_manager::kvpair::{ContractId, LeafData, MERKLE_TREE_HEIGHT};
+/// use zkc_state_manager::service::MongoKvPair;
+///
+/// # async fn example() -> Result<(), zkc_state_manager::errors::Error> {
+/// let handle = KvPairHandle::new(MongoKvPair::new().await);
+/// let contract_id = ContractId::default();
+/// let index = (1 << MERKLE_TREE_HEIGHT) - 1;
+///
+/// let options = SetLeafOptions {
+///     proof: true,
+///     ..Default::default()
+/// };
+/// let data = LeafData::from(vec![42; 32]);
+/// let (_, proof) = handle.set_leaf(contract_id, index, data, options).await?;
+/// assert_eq!(proof.unwrap().root, handle.get_root(contract_id).await?);
+///
+/// let (record, datahash_record, _) = handle
+///     .get_leaf(contract_id, index, GetLeafOptions::default())
+///     .await?;
+/// assert_eq!(record.index, index);
+/// assert_eq!(datahash_record.unwrap().data, vec![42; 32]);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvPairHandle {
+    server: MongoKvPair,
+    // Attached to every request, see with_request_context.
+    metadata: MetadataMap,
+    authenticated: Option<AuthenticatedContractId>,
+}
+
+impl KvPairHandle {
+    pub fn new(server: MongoKvPair) -> Self {
+        Self {
+            server,
+            metadata: MetadataMap::new(),
+            authenticated: None,
+        }
+    }
+
+    /// Send the requests with this metadata (e.g. x-auth-contract-id in dev mode, or
+    /// grpc-timeout) and the contract id authenticated by JwtAuth, if any. The contract ids
+    /// passed to the methods must then be the authenticated one.
+    pub fn with_request_context(
+        mut self,
+        metadata: MetadataMap,
+        authenticated: Option<AuthenticatedContractId>,
+    ) -> Self {
+        self.metadata = metadata;
+        self.authenticated = authenticated;
+        self
+    }
+
+    pub fn server(&self) -> &MongoKvPair {
+        &self.server
+    }
+
+    fn request<T>(&self, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        *request.metadata_mut() = self.metadata.clone();
+        if let Some(authenticated) = self.authenticated {
+            request.extensions_mut().insert(authenticated);
+        }
+        request
+    }
+
+    /// The current root of the contract.
+    pub async fn get_root(&self, contract_id: ContractId) -> Result<Hash, Error> {
+        let response = KvPair::get_root(
+            &self.server,
+            self.request(GetRootRequest {
+                contract_id: Some(contract_id.into()),
+                consistent_read: false,
+                include_node: false,
+            }),
+        )
+        .await
+        .map_err(|status| Error::from_status(&status))?
+        .into_inner();
+        response.root.try_into()
+    }
+
+    /// Replace the root of the contract with hash, which must be a root of the contract.
+    pub async fn set_root(&self, contract_id: ContractId, hash: Hash) -> Result<(), Error> {
+        KvPair::set_root(
+            &self.server,
+            self.request(SetRootRequest {
+                contract_id: Some(contract_id.into()),
+                hash: hash.into(),
+                verify: false,
+            }),
+        )
+        .await
+        .map_err(|status| Error::from_status(&status))?;
+        Ok(())
+    }
+
+    /// The leaf at index, its data record (None if the data is not available, e.g. the leaf was
+    /// set with only its hash, and for the default leaf) and its proof, if requested. The proof
+    /// is checked before it is returned.
+    pub async fn get_leaf(
+        &self,
+        contract_id: ContractId,
+        index: u64,
+        options: GetLeafOptions,
+    ) -> Result<(MerkleRecord, Option<DataHashRecord>, Option<LeafProof>), Error> {
+        let response = KvPair::get_leaf(
+            &self.server,
+            self.request(GetLeafRequest {
+                index,
+                address: None,
+                hash: options.hash.map(Into::into),
+                proof_type: proof_type(options.proof).into(),
+                contract_id: Some(contract_id.into()),
+                require_data: options.require_data,
+                consistent_read: options.consistent_read,
+            }),
+        )
+        .await
+        .map_err(|status| Error::from_status(&status))?
+        .into_inner();
+        let node = response
+            .node
+            .ok_or_else(|| missing_field_error(index, "node"))?;
+        let (record, datahash_record) =
+            leaf_records(node, response.data_available && !response.is_default)?;
+        let proof = match response.proof {
+            Some(proof) if options.proof => Some(check_proof(index, proof)?),
+            _ => None,
+        };
+        Ok((record, datahash_record, proof))
+    }
+
+    /// Set the data of the leaf at index, and return the new leaf and its proof in the new root
+    /// of the contract, if requested. The proof is checked before it is returned.
+    pub async fn set_leaf(
+        &self,
+        contract_id: ContractId,
+        index: u64,
+        data: LeafData,
+        options: SetLeafOptions,
+    ) -> Result<(MerkleRecord, Option<LeafProof>), Error> {
+        let response = KvPair::set_leaf(
+            &self.server,
+            self.request(SetLeafRequest {
+                index,
+                address: None,
+                hash: None,
+                data: Some(data.into()),
+                proof_type: proof_type(options.proof).into(),
+                contract_id: Some(contract_id.into()),
+                sign_root: false,
+                idempotency_key: options.idempotency_key,
+            }),
+        )
+        .await
+        .map_err(|status| Error::from_status(&status))?
+        .into_inner();
+        let node = response
+            .node
+            .ok_or_else(|| missing_field_error(index, "node"))?;
+        let (record, _) = leaf_records(node, false)?;
+        let proof = match response.proof {
+            Some(proof) if options.proof => Some(check_proof(index, proof)?),
+            _ => None,
+        };
+        Ok((record, proof))
+    }
+
+    /// The statistics of the contract, see GetStatsResponse.
+    pub async fn get_stats(&self, contract_id: ContractId) -> Result<GetStatsResponse, Error> {
+        let response = KvPair::get_stats(
+            &self.server,
+            self.request(GetStatsRequest {
+                contract_id: Some(contract_id.into()),
+            }),
+        )
+        .await
+        .map_err(|status| Error::from_status(&status))?;
+        Ok(response.into_inner())
+    }
+
+    /// Hash the data with the poseidon hasher, see PoseidonHashRequest for the algorithms.
+    pub async fn poseidon_hash(
+        &self,
+        contract_id: ContractId,
+        data: Vec<u8>,
+        algorithm: PoseidonHashAlgorithm,
+    ) -> Result<Hash, Error> {
+        let response = KvPair::poseidon_hash(
+            &self.server,
+            self.request(PoseidonHashRequest {
+                contract_id: Some(contract_id.into()),
+                data,
+                algorithm: algorithm.into(),
+            }),
+        )
+        .await
+        .map_err(|status| Error::from_status(&status))?
+        .into_inner();
+        response.hash.try_into()
+    }
+
+    /// Reset the leaf at index to the default leaf, and return the new root of the contract.
+    pub async fn delete_leaf(&self, contract_id: ContractId, index: u64) -> Result<Hash, Error> {
+        let response = KvPair::delete_leaf(
+            &self.server,
+            self.request(DeleteLeafRequest {
+                index,
+                proof_type: ProofType::ProofEmpty.into(),
+                contract_id: Some(contract_id.into()),
+            }),
+        )
+        .await
+        .map_err(|status| Error::from_status(&status))?
+        .into_inner();
+        response.root.try_into()
+    }
+}
+
+fn proof_type(proof: bool) -> ProofType {
+    if proof {
+        ProofType::ProofV1
+    } else {
+        ProofType::ProofEmpty
+    }
+}
+
+// The merkle record of a leaf node, and its data record if the data is available. The default
+// leaf is returned with an empty hash (see Hash::empty), its record has the default hash.
+fn leaf_records(
+    node: Node,
+    data_available: bool,
+) -> Result<(MerkleRecord, Option<DataHashRecord>), Error> {
+    let hash: Hash = node.hash.try_into()?;
+    let hash = if hash == Hash::empty() {
+        DEFAULT_HASH_VEC[0]
+    } else {
+        hash
+    };
+    let datahash_record = match node.node_data {
+        Some(NodeData::Data(data)) if data_available => Some(DataHashRecord::new(hash, data)),
+        _ => None,
+    };
+    Ok((MerkleRecord::new_leaf(node.index, hash), datahash_record))
+}
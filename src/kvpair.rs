@@ -1,39 +1,70 @@
-use crate::merkle::get_node_type;
-use crate::poseidon::{gen_merkle_hasher, gen_merkle_leaf_hasher};
+use crate::merkle::{boundary_check, get_depth, get_node_type, get_offset, validate_leaf_index};
+use crate::poseidon::{merkle_hasher, merkle_leaf_hasher};
 use crate::proto::kv_pair_client::KvPairClient;
 
 use crate::proto::node::NodeData;
 use crate::proto::{
     GetLeafRequest, GetLeafResponse, GetNonLeafRequest, GetNonLeafResponse, GetRootRequest,
-    GetRootResponse, Node, NodeChildren, NodeType, ProofType, SetLeafRequest, SetLeafResponse,
-    SetNonLeafRequest, SetNonLeafResponse, SetRootRequest, SetRootResponse,
+    GetRootResponse, MerkleProofV1, MutatedNode, Mutation, Node, NodeChildren, NodeType, ProofType,
+    SetLeafRequest, SetLeafResponse, SetNonLeafRequest, SetNonLeafResponse, SetRootRequest,
+    SetRootResponse,
 };
 
 use crate::Error;
 
-use super::merkle::{MerkleError, MerkleErrorCode, MerkleNode, MerkleTree};
+use super::merkle::{MerkleError, MerkleErrorCode, MerkleNode, MerkleProof, MerkleTree};
 use ff::PrimeField;
 use futures::executor;
 use halo2_proofs::pairing::bn256::Fr;
 
 use mongodb::bson::doc;
-use mongodb::bson::{spec::BinarySubtype, Bson};
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{spec::BinarySubtype, Bson, DateTime};
+use rand::RngCore;
 use serde::{
     de::{Error as SerdeError, Unexpected},
     Deserialize, Deserializer, Serialize, Serializer,
 };
 
-use tonic::transport::Channel;
-use tonic::{Request, Status};
+use std::future::Future;
+use std::time::Duration;
+use tonic::codec::CompressionEncoding;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Request, Status};
 
 pub const MERKLE_TREE_HEIGHT: usize = 32;
 
+/// The index of the leaf at address, i.e. the address-th leaf of the tree (counting from 0).
+/// Fails with Error::InvalidIndex if the tree has no more than address leaves.
+pub fn leaf_index_from_address(address: u64) -> Result<u64, Error> {
+    let leaf_count = 1u64 << MERKLE_TREE_HEIGHT;
+    if address >= leaf_count {
+        return Err(Error::InvalidIndex(format!(
+            "Invalid address {address}, must be less than {leaf_count}"
+        ))
+        .with_detail("address", address));
+    }
+    Ok(address + leaf_count - 1)
+}
+
+/// The inverse of leaf_index_from_address, fails with Error::InvalidIndex if index is not a leaf.
+pub fn address_from_leaf_index(index: u64) -> Result<u64, Error> {
+    validate_leaf_index(index, MERKLE_TREE_HEIGHT)?;
+    Ok(index - ((1u64 << MERKLE_TREE_HEIGHT) - 1))
+}
+
+// The retries of MongoMerkle when the kvpair server is unavailable, with exponential backoff.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_ATTEMPTS: u32 = 5;
+const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
 // In default_hash vec, it is from leaf to root.
 // For example, height of merkle tree is 20.
 // DEFAULT_HASH_VEC[0] leaf's default hash. DEFAULT_HASH_VEC[20] is root default hash. It has 21 layers including the leaf layer and root layer.
 lazy_static::lazy_static! {
     pub static ref DEFAULT_HASH_VEC: [Hash; MERKLE_TREE_HEIGHT + 1] = {
-        let mut leaf_hash = MongoMerkle::empty_leaf(0).hash();
+        let mut leaf_hash = StandardMerkle::empty_leaf(0).hash();
         let mut default_hash = vec![leaf_hash];
         for _ in 0..MERKLE_TREE_HEIGHT {
             leaf_hash = Hash::hash_children(&leaf_hash, &leaf_hash);
@@ -43,7 +74,7 @@ lazy_static::lazy_static! {
     };
 }
 
-#[derive(Copy, Debug, Clone, Eq, PartialEq, Default, Serialize, Deserialize)]
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Hash, Default, Serialize, Deserialize)]
 pub struct ContractId(
     #[serde(serialize_with = "self::serialize_bytes_as_binary")]
     #[serde(deserialize_with = "self::deserialize_u256_from_binary")]
@@ -90,7 +121,7 @@ impl From<[u8; 32]> for ContractId {
 
 /// Note that the hash here must represents a valid field element.
 /// TODO: Maybe we should wrap Fr instead of [u8; 32] here.
-#[derive(Copy, Debug, Clone, Eq, PartialEq, Default, Serialize, Deserialize)]
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Hash, Default, Serialize, Deserialize)]
 pub struct Hash(
     #[serde(serialize_with = "self::serialize_bytes_as_binary")]
     #[serde(deserialize_with = "self::deserialize_u256_from_binary")]
@@ -102,9 +133,9 @@ impl TryFrom<&[u8]> for Hash {
     type Error = Error;
 
     fn try_from(a: &[u8]) -> Result<Hash, Self::Error> {
-        let a: [u8; 32] = a.try_into().map_err(|_e| {
-            Error::InvalidArgument("Hash malformed (must be [u8; 32])".to_string())
-        })?;
+        let a: [u8; 32] = a
+            .try_into()
+            .map_err(|_e| Error::InvalidHash("Hash malformed (must be [u8; 32])".to_string()))?;
         a.try_into()
     }
 }
@@ -123,6 +154,12 @@ impl TryFrom<[u8; 32]> for Hash {
     type Error = Error;
 
     fn try_from(hash: [u8; 32]) -> Result<Hash, Self::Error> {
+        if Fr::from_repr(hash).is_none().into() {
+            return Err(Error::InvalidHash(
+                "Hash malformed (must be a valid field element)".to_string(),
+            )
+            .with_detail("hash", hex::encode(hash)));
+        }
         Ok(Self(hash))
     }
 }
@@ -133,6 +170,8 @@ impl From<Fr> for Hash {
     }
 }
 
+// Panics if the hash is not a field element, which is only possible if it was built from its
+// bytes directly (e.g. read from the database). Use Hash::to_fr for such hashes.
 impl From<Hash> for Fr {
     fn from(h: Hash) -> Fr {
         Fr::from_repr(h.0).unwrap()
@@ -152,13 +191,57 @@ impl From<Hash> for Vec<u8> {
 }
 
 impl Hash {
+    /// The hash as four little endian u64 limbs, which is how zkWasm represents it.
+    pub fn to_u64_limbs(&self) -> [u64; 4] {
+        let limbs = self
+            .0
+            .chunks_exact(8)
+            .map(|x| u64::from_le_bytes(x.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        limbs.try_into().unwrap()
+    }
+
+    pub fn from_u64_limbs(limbs: [u64; 4]) -> Result<Self, Error> {
+        let bytes = limbs
+            .iter()
+            .flat_map(|limb| limb.to_le_bytes())
+            .collect::<Vec<_>>();
+        bytes.try_into()
+    }
+
+    /// The hash as a field element. Fails with InconsistentData if it is not one, as hashes
+    /// given by clients are checked on conversion, so such a hash can only come from a record
+    /// corrupted in the database.
+    pub fn to_fr(&self) -> Result<Fr, Error> {
+        Option::from(Fr::from_repr(self.0)).ok_or_else(|| {
+            Error::InconsistentData(format!(
+                "Hash {} is not a valid field element",
+                hex::encode(self.0)
+            ))
+            .with_detail("hash", hex::encode(self.0))
+        })
+    }
+
+    /// Panics if either hash is not a field element, see try_hash_children.
     pub fn hash_children(left: &Self, right: &Self) -> Self {
-        let mut hasher = gen_merkle_hasher();
+        let mut hasher = merkle_hasher();
         let a = Fr::from(*left);
         let b = Fr::from(*right);
         hasher.update_exact(&[a, b]).into()
     }
 
+    /// Same as hash_children, but fails with InconsistentData instead of panicking if either
+    /// hash is not a field element. Use it for hashes read from the database.
+    pub fn try_hash_children(left: &Self, right: &Self) -> Result<Self, Error> {
+        let mut hasher = merkle_hasher();
+        Ok(hasher.update_exact(&[left.to_fr()?, right.to_fr()?]).into())
+    }
+
+    /// Hash 32 bytes of leaf data with the MERKLE_LEAF_HASHER, which splits the data into two
+    /// 16 bytes halves, converts each of them into a field element and hashes the two with
+    /// update_exact. The MERKLE_LEAF_HASHER and the MERKLE_HASHER used by hash_children have
+    /// the same parameters upstream, so a leaf and a non-leaf node differ only in how the two
+    /// field elements are obtained.
     pub fn hash_data(data: &[u8]) -> Self {
         let data: [u8; 32] = data.clone().try_into().unwrap();
         let batchdata = data
@@ -171,7 +254,7 @@ impl Hash {
             })
             .collect::<Vec<Fr>>();
         let values: [Fr; 2] = batchdata.try_into().unwrap();
-        let mut hasher = gen_merkle_leaf_hasher();
+        let mut hasher = merkle_leaf_hasher();
         // Upstream uses `update_exact` to obtain the hash result.
         // https://github.com/DelphinusLab/zkWasm-host-circuits/pull/75/files#diff-569acc27d1b9b0aa262ff90201af200d25432920c537df3c945fee07271ca2ed
         // Note that update_exact is not equvilent to update and suqeeze.
@@ -187,8 +270,17 @@ impl Hash {
 
     /// depth start from 0 up to Self::height(). Example 20 height MongoMerkle, root depth=0, leaf depth=20
     pub fn get_default_hash_for_depth(depth: usize) -> Result<Hash, MerkleError> {
-        if depth <= MERKLE_TREE_HEIGHT {
-            Ok(DEFAULT_HASH_VEC[MERKLE_TREE_HEIGHT - depth])
+        Self::get_default_hash_for_depth_with_height::<MERKLE_TREE_HEIGHT>(depth)
+    }
+
+    /// Same as get_default_hash_for_depth, but for a merkle tree of height H.
+    /// The subtrees of a default merkle tree are also default merkle trees,
+    /// so we can reuse DEFAULT_HASH_VEC as long as H is not larger than MERKLE_TREE_HEIGHT.
+    pub fn get_default_hash_for_depth_with_height<const H: usize>(
+        depth: usize,
+    ) -> Result<Hash, MerkleError> {
+        if depth <= H && H <= MERKLE_TREE_HEIGHT {
+            Ok(DEFAULT_HASH_VEC[H - depth])
         } else {
             Err(MerkleError::new(
                 [0; 32].try_into().unwrap(),
@@ -199,22 +291,26 @@ impl Hash {
     }
 
     pub fn validate_children(hash: &Self, left: &Self, right: &Self) -> Result<(), Error> {
-        let new_hash = Hash::hash_children(left, right);
+        let new_hash = Hash::try_hash_children(left, right)?;
         if *hash != new_hash {
-            return Err(Error::InvalidArgument(format!(
+            return Err(Error::HashMismatch(format!(
                 "Hash not matching: {:?} and {:?} hashed to {:?}, not {:?}",
                 &left, &right, &new_hash, &hash
-            )));
+            ))
+            .with_detail("expected_hash", hex::encode(hash.0))
+            .with_detail("actual_hash", hex::encode(new_hash.0)));
         }
         Ok(())
     }
     pub fn validate_data(hash: &Hash, data: &LeafData) -> Result<(), Error> {
-        let new_hash = Self::hash_data(&data.0);
+        let new_hash: Hash = crate::poseidon::hash_leaf_data(&data.0).try_into()?;
         if *hash != new_hash {
-            return Err(Error::InvalidArgument(format!(
+            return Err(Error::HashMismatch(format!(
                 "Hash not matching: {:?} hashed to {:?}, not {:?}",
                 &data, &new_hash, &hash
-            )));
+            ))
+            .with_detail("expected_hash", hex::encode(hash.0))
+            .with_detail("actual_hash", hex::encode(new_hash.0)));
         }
         Ok(())
     }
@@ -234,15 +330,12 @@ impl Default for LeafData {
 }
 
 // TODO: Maybe use something like protovalidate to automatically validate fields.
+// Leaf data may be of arbitrary length, see `crate::poseidon::hash_leaf_data`.
 impl TryFrom<&[u8]> for LeafData {
     type Error = Error;
 
     fn try_from(a: &[u8]) -> Result<LeafData, Self::Error> {
-        a.try_into()
-            .map_err(|_e| {
-                Error::InvalidArgument("LeafData malformed (must be [u8; 32])".to_string())
-            })
-            .map(LeafData)
+        Ok(LeafData(a.to_vec()))
     }
 }
 
@@ -269,10 +362,14 @@ where
     D: Deserializer<'de>,
 {
     match Bson::deserialize(deserializer) {
-        Ok(Bson::Binary(bytes)) => Ok({
-            let c: [u8; 8] = bytes.bytes.try_into().unwrap();
-            u64::from_le_bytes(c)
-        }),
+        Ok(Bson::Binary(bytes)) => {
+            let len = bytes.bytes.len();
+            let c: [u8; 8] = bytes
+                .bytes
+                .try_into()
+                .map_err(|_| SerdeError::invalid_length(len, &"8 bytes"))?;
+            Ok(u64::from_le_bytes(c))
+        }
         Ok(..) => Err(SerdeError::invalid_value(Unexpected::Enum, &"Bson::Binary")),
         Err(e) => Err(e),
     }
@@ -294,7 +391,13 @@ where
     D: Deserializer<'de>,
 {
     match Bson::deserialize(deserializer) {
-        Ok(Bson::Binary(bytes)) => Ok(bytes.bytes.try_into().unwrap()),
+        Ok(Bson::Binary(bytes)) => {
+            let len = bytes.bytes.len();
+            bytes
+                .bytes
+                .try_into()
+                .map_err(|_| SerdeError::invalid_length(len, &"32 bytes"))
+        }
         Ok(..) => Err(SerdeError::invalid_value(Unexpected::Enum, &"Bson::Binary")),
         Err(e) => Err(e),
     }
@@ -322,6 +425,33 @@ where
     }
 }
 
+pub fn serialize_optional_bytes_as_binary<S>(
+    bytes: &Option<Vec<u8>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match bytes {
+        Some(bytes) => serialize_bytes_as_binary(bytes, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub fn deserialize_optional_bytes_from_binary<'de, D>(
+    deserializer: D,
+) -> Result<Option<Vec<u8>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Bson::deserialize(deserializer) {
+        Ok(Bson::Binary(bytes)) => Ok(Some(bytes.bytes.to_vec())),
+        Ok(Bson::Null) => Ok(None),
+        Ok(..) => Err(SerdeError::invalid_value(Unexpected::Enum, &"Bson::Binary")),
+        Err(e) => Err(e),
+    }
+}
+
 pub fn u256_to_bson(x: &[u8; 32]) -> Bson {
     Bson::Binary(mongodb::bson::Binary {
         subtype: BinarySubtype::Generic,
@@ -343,14 +473,19 @@ pub fn hash_to_bson(x: &Hash) -> Bson {
     })
 }
 
+/// A merkle tree of height H stored in the kvpair service.
+/// Note that the service currently only serves merkle trees of height MERKLE_TREE_HEIGHT.
 #[derive(Debug)]
-pub struct MongoMerkle {
+pub struct MongoMerkle<const H: usize = MERKLE_TREE_HEIGHT> {
     root_hash: Hash,
     contract_id: ContractId,
+    url: String,
     client: KvPairClient<Channel>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, Eq, PartialEq)]
+pub type StandardMerkle = MongoMerkle<MERKLE_TREE_HEIGHT>;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
 pub struct MerkleRecord {
     #[serde(serialize_with = "self::serialize_u64_as_binary")]
     #[serde(deserialize_with = "self::deserialize_u64_as_binary")]
@@ -358,44 +493,89 @@ pub struct MerkleRecord {
     pub hash: Hash,
     pub left: Hash,
     pub right: Hash,
-    #[serde(serialize_with = "self::serialize_bytes_as_binary")]
+    // Unused and always zero. It is no longer stored since schema version 2 (see
+    // crate::migrations), but still read from the records written before.
+    #[serde(default, skip_serializing)]
     #[serde(deserialize_with = "self::deserialize_u256_from_binary")]
     pub data: [u8; 32],
+    // When the record was first inserted, see MongoCollection::insert_merkle_records. None for
+    // the records written before it was recorded, and for those which are not inserted as such,
+    // e.g. the default records and the current root document.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime>,
+}
+
+// The creation time is not part of the content of a record, the records of a node with the
+// same hash are equal whenever they were inserted.
+impl PartialEq for MerkleRecord {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index
+            && self.hash == other.hash
+            && self.left == other.left
+            && self.right == other.right
+            && self.data == other.data
+    }
+}
+
+impl Eq for MerkleRecord {}
+
+// Attach the index and the type of the node to an error in converting it.
+fn with_node_details(error: Error, index: u64, node_type: i32) -> Error {
+    let node_type = NodeType::from_i32(node_type).unwrap_or(NodeType::NodeInvalid);
+    error
+        .with_detail("index", index)
+        .with_detail("node_type", node_type.as_str_name())
 }
 
 impl TryFrom<Node> for MerkleRecord {
     type Error = Error;
 
     fn try_from(n: Node) -> Result<Self, Self::Error> {
-        let hash: Hash = n.hash.as_slice().try_into()?;
-        if n.node_type == NodeType::NodeLeaf as i32 {
-            match n.node_data {
-                Some(NodeData::Data(_)) => {
-                    let record = MerkleRecord::new_leaf(n.index, hash);
-                    assert_eq!(record.hash.0.to_vec(), n.hash);
-                    Ok(record)
-                }
-                _ => {
-                    dbg!(&n);
-                    panic!("Invalid node data");
-                }
+        let (index, node_type) = (n.index, n.node_type);
+        let hash: Hash = n
+            .hash
+            .as_slice()
+            .try_into()
+            .map_err(|e| with_node_details(e, index, node_type))?;
+        match (NodeType::from_i32(node_type), n.node_data) {
+            // The data of a leaf may be omitted (see Node::new_simple_leaf),
+            // so the hash of a leaf can not be checked against its data.
+            (Some(NodeType::NodeLeaf), Some(NodeData::Data(_))) => {
+                Ok(MerkleRecord::new_leaf(index, hash))
             }
-        } else if n.node_type == NodeType::NodeNonLeaf as i32 {
-            match n.node_data {
-                Some(NodeData::Children(children)) => {
-                    let left: Hash = children.left_child_hash.as_slice().try_into()?;
-                    let right: Hash = children.right_child_hash.as_slice().try_into()?;
-                    let record = MerkleRecord::new_non_leaf(n.index, left, right);
-                    assert_eq!(record.hash.0.to_vec(), n.hash);
-                    Ok(record)
-                }
-                _ => {
-                    dbg!(&n);
-                    panic!("Invalid node data");
+            (Some(NodeType::NodeNonLeaf), Some(NodeData::Children(children))) => {
+                let left: Hash = children
+                    .left_child_hash
+                    .as_slice()
+                    .try_into()
+                    .map_err(|e| with_node_details(e, index, node_type))?;
+                let right: Hash = children
+                    .right_child_hash
+                    .as_slice()
+                    .try_into()
+                    .map_err(|e| with_node_details(e, index, node_type))?;
+                let record = MerkleRecord::new_non_leaf(index, left, right);
+                if record.hash != hash {
+                    let error = Error::InconsistentData(format!(
+                        "Children of node {index} hashed to {:?}, not {:?}",
+                        record.hash, hash
+                    ))
+                    .with_detail("expected_hash", hex::encode(hash.0))
+                    .with_detail("actual_hash", hex::encode(record.hash.0));
+                    return Err(with_node_details(error, index, node_type));
                 }
+                Ok(record)
             }
-        } else {
-            Err(Error::InvalidArgument("Invalid node type".to_string()))
+            (Some(NodeType::NodeLeaf | NodeType::NodeNonLeaf), _) => Err(with_node_details(
+                Error::InvalidArgument(format!("Invalid node data of node {index}")),
+                index,
+                node_type,
+            )),
+            _ => Err(with_node_details(
+                Error::InvalidArgument(format!("Invalid node type of node {index}")),
+                index,
+                node_type,
+            )),
         }
     }
 }
@@ -411,13 +591,18 @@ impl TryFrom<(MerkleRecord, DataHashRecord)> for Node {
             return node;
         }
 
+        let index = merkle_record.index();
+        let node_type = get_node_type(index, MERKLE_TREE_HEIGHT);
         if merkle_record.hash != datahash_record.hash {
-            return Err(Error::InvalidArgument("Hash mismatched".to_string()));
+            let error = Error::HashMismatch("Hash mismatched".to_string())
+                .with_detail("expected_hash", hex::encode(merkle_record.hash.0))
+                .with_detail("actual_hash", hex::encode(datahash_record.hash.0));
+            return Err(with_node_details(error, index, node_type.into()));
         }
 
-        let node_type = get_node_type(merkle_record.index(), MERKLE_TREE_HEIGHT);
         if node_type != NodeType::NodeLeaf {
-            return Err(Error::InvalidArgument("Unknown node type".to_string()));
+            let error = Error::InvalidArgument("Unknown node type".to_string());
+            return Err(with_node_details(error, index, node_type.into()));
         }
         let node_data = { NodeData::Data(datahash_record.data) };
         Ok(Node {
@@ -437,7 +622,8 @@ impl TryFrom<MerkleRecord> for Node {
         let hash = merkle_record.hash().into();
         let node_type = get_node_type(index, MERKLE_TREE_HEIGHT);
         if node_type != NodeType::NodeNonLeaf {
-            return Err(Error::InconsistentData("Unknown node type".to_string()));
+            let error = Error::InconsistentData("Unknown node type".to_string());
+            return Err(with_node_details(error, index, node_type.into()));
         }
         let node_data = {
             let left_child_hash = merkle_record
@@ -492,6 +678,7 @@ impl MerkleRecord {
             left: [0; 32].try_into().unwrap(),
             right: [0; 32].try_into().unwrap(),
             data: [0; 32],
+            created_at: None,
         }
     }
 
@@ -517,13 +704,30 @@ impl MerkleRecord {
         record
     }
 
+    /// The same record, inserted at created_at.
+    pub fn with_created_at(self, created_at: DateTime) -> Self {
+        Self {
+            created_at: Some(created_at),
+            ..self
+        }
+    }
+
     pub fn get_default_record(index: u64) -> Result<Self, MerkleError> {
-        let height = (index + 1).ilog2() as usize;
-        let default = Hash::get_default_hash_for_depth(height)?;
-        let child_hash = if height == MERKLE_TREE_HEIGHT {
+        Self::get_default_record_with_height::<MERKLE_TREE_HEIGHT>(index)
+    }
+
+    /// The record of the node at index in a default merkle tree of height H. Fails with
+    /// MerkleErrorCode::InvalidIndex if the index is not in the tree.
+    pub fn get_default_record_with_height<const H: usize>(index: u64) -> Result<Self, MerkleError> {
+        // Check the index first, otherwise an index beyond the last level would be given the
+        // default hash of a level which does not exist.
+        boundary_check(index, H)?;
+        let height = get_depth(index);
+        let default = Hash::get_default_hash_for_depth_with_height::<H>(height)?;
+        let child_hash = if height == H {
             [0; 32].try_into().unwrap()
         } else {
-            Hash::get_default_hash_for_depth(height + 1)?
+            Hash::get_default_hash_for_depth_with_height::<H>(height + 1)?
         };
         Ok(MerkleRecord {
             index,
@@ -531,21 +735,38 @@ impl MerkleRecord {
             left: child_hash,
             right: child_hash,
             data: [0; 32],
+            created_at: None,
         })
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DataHashRecord {
     pub hash: Hash,
     #[serde(serialize_with = "self::serialize_bytes_as_binary")]
     #[serde(deserialize_with = "self::deserialize_bytes_from_binary")]
     pub data: Vec<u8>,
+    // When the record was first inserted, see MongoCollection::insert_datahash_record.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime>,
 }
 
+// Like MerkleRecord, the creation time is not part of the content.
+impl PartialEq for DataHashRecord {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash && self.data == other.data
+    }
+}
+
+impl Eq for DataHashRecord {}
+
 impl DataHashRecord {
     pub fn new(hash: Hash, data: Vec<u8>) -> Self {
-        Self { hash, data }
+        Self {
+            hash,
+            data,
+            created_at: None,
+        }
     }
 
     pub const fn empty() -> Self {
@@ -553,21 +774,259 @@ impl DataHashRecord {
             // Note that we use the hash of [0u8; 32] as default hash, while empty vector to represent empty data
             hash: Hash::empty(),
             data: vec![],
+            created_at: None,
         }
     }
+
+    /// Whether `data` hashes to `hash`, see leaf_data_matches.
+    pub fn is_valid(&self) -> bool {
+        leaf_data_matches(&self.data, &self.hash)
+    }
 }
 
-impl MongoMerkle {
-    pub async fn get_client() -> KvPairClient<Channel> {
-        let server =
-            std::env::var("KVPAIR_GRPC_SERVER_URL").unwrap_or("http://localhost:50051".to_string());
-        KvPairClient::connect(server)
-            .await
-            .expect("Connect gRPC server")
+// Leaves are hashed with hash_leaf_data by the server, and with hash_data (i.e. the
+// MERKLE_LEAF_HASHER) by zkWasm, e.g. the default leaf.
+fn leaf_data_matches(data: &[u8], hash: &Hash) -> bool {
+    Hash(crate::poseidon::hash_leaf_data(data)) == *hash
+        || (data.len() == 32 && Hash::hash_data(data) == *hash)
+}
+
+/// Per contract settings stored in the contracts metadata collection.
+/// Settings that are not specified fall back to the server-wide defaults.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ContractMetadata {
+    pub contract_id: ContractId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_leaf_data_bytes: Option<u64>,
+    // The retention policy of the roots, see MongoCollection::run_retention. The number of
+    // most recently used roots kept in the merkle collection, and how long unused roots are kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_hot_roots: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_root_age_secs: Option<u64>,
+    // Roots which are never archived, e.g. those of snapshots which must stay readable.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pinned_roots: Vec<Hash>,
+    // The schema version of the records of the contract, None for the initial version. Only
+    // changed by MongoKvPair::migrate_contract, see crate::migrations.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema_version: Option<u32>,
+    // The height of the merkle tree of the contract, recorded once checked by
+    // MongoKvPair::open_contract. None for the contracts not opened since it is recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tree_height: Option<u32>,
+    // The LEAFINDEX_ collection of the contract has an entry for each non-default leaf, i.e. it
+    // has been rebuilt by MongoCollection::rebuild_leaf_index or maintained since the contract
+    // was created. Lookups scan the merkle records otherwise.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub leaf_index_complete: bool,
+}
+
+impl ContractMetadata {
+    pub fn new(contract_id: ContractId) -> Self {
+        Self {
+            contract_id,
+            ..Default::default()
+        }
+    }
+}
+
+/// A node written by a mutation, as recorded in the audit log.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AuditNode {
+    #[serde(serialize_with = "self::serialize_u64_as_binary")]
+    #[serde(deserialize_with = "self::deserialize_u64_as_binary")]
+    pub index: u64,
+    pub hash: Hash,
+    // The leaf data, None for non-leaf nodes, deleted leaves, leaves set with only their hashes,
+    // or if the data is not captured (see MongoKvPairConfig::audit_capture_data).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(serialize_with = "self::serialize_optional_bytes_as_binary")]
+    #[serde(deserialize_with = "self::deserialize_optional_bytes_from_binary")]
+    pub data: Option<Vec<u8>>,
+}
+
+/// An entry of the append-only audit log of a contract, recorded for every successful mutation.
+/// The old_root of an entry is the new_root of the previous entry, unless the root is changed
+/// by other means (e.g. by the merkle tree functions of MongoCollection used directly).
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    // Assigned by MongoDB on insertion. Entries are paginated in the order of their ids.
+    #[serde(rename = "_id", default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub timestamp: DateTime,
+    // The name of the RPC, e.g. SetLeaf.
+    pub rpc: String,
+    pub nodes: Vec<AuditNode>,
+    pub old_root: Hash,
+    pub new_root: Hash,
+    // Who made the request, see MongoKvPair::get_principal.
+    pub principal: String,
+}
+
+/// A root moved out of the merkle collection of a contract to its ROOTARCHIVE_ collection by
+/// the retention policy of the contract. The other nodes of its tree are not moved.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ArchivedRoot {
+    pub record: MerkleRecord,
+    // When the root was last made the current root, according to the audit log.
+    pub last_used: DateTime,
+    pub archived_at: DateTime,
+}
+
+/// An entry of the LEAFINDEX_ collection of a contract, which maps the hashes of the non-default
+/// leaves of the current tree to their indices (see MongoCollection::find_leaf_indices_by_hash).
+/// There is one entry per leaf, so overwriting a leaf replaces the entry of its old hash.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct LeafIndexRecord {
+    #[serde(rename = "_id")]
+    #[serde(serialize_with = "self::serialize_u64_as_binary")]
+    #[serde(deserialize_with = "self::deserialize_u64_as_binary")]
+    pub index: u64,
+    pub hash: Hash,
+}
+
+/// An entry of the IDEMPOTENCY_ collection of a contract, which records the response of a
+/// SetLeaf request made with an idempotency key, so that the retries of the request return it
+/// instead of setting the leaf again. MongoDB removes the entries once they expire.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct IdempotencyRecord {
+    #[serde(rename = "_id")]
+    pub key: String,
+    // The SHA-256 digest of the request without its contract id and idempotency key, to tell
+    // the retries of the request from other requests reusing the key.
+    #[serde(serialize_with = "self::serialize_bytes_as_binary")]
+    #[serde(deserialize_with = "self::deserialize_bytes_from_binary")]
+    pub request_digest: Vec<u8>,
+    // The encoded SetLeafResponse.
+    #[serde(serialize_with = "self::serialize_bytes_as_binary")]
+    #[serde(deserialize_with = "self::deserialize_bytes_from_binary")]
+    pub response: Vec<u8>,
+    pub created_at: DateTime,
+    pub expires_at: DateTime,
+    // Set while the request is being applied without a transaction, in which case the entry is
+    // inserted before the root is replaced and the response is recorded after, see
+    // MongoCollection::with_pending_idempotency_record.
+    #[serde(default)]
+    pub pending: bool,
+}
+
+impl From<AuditNode> for MutatedNode {
+    fn from(node: AuditNode) -> Self {
+        MutatedNode {
+            index: node.index,
+            hash: node.hash.into(),
+            data: node.data,
+        }
+    }
+}
+
+impl From<AuditRecord> for Mutation {
+    fn from(record: AuditRecord) -> Self {
+        Mutation {
+            id: record.id.map(|id| id.to_hex()).unwrap_or_default(),
+            timestamp_ms: record.timestamp.timestamp_millis(),
+            rpc: record.rpc,
+            nodes: record.nodes.into_iter().map(Into::into).collect(),
+            old_root: record.old_root.into(),
+            new_root: record.new_root.into(),
+            principal: record.principal,
+        }
+    }
+}
+
+/// Parse the name of a gRPC compression encoding. Only gzip is supported by tonic 0.9.
+pub fn parse_compression_encoding(encoding: &str) -> Result<CompressionEncoding, Error> {
+    match encoding.trim() {
+        "gzip" => Ok(CompressionEncoding::Gzip),
+        _ => Err(Error::InvalidArgument(format!(
+            "Unsupported compression encoding {encoding:?}"
+        ))),
+    }
+}
+
+impl<const H: usize> MongoMerkle<H> {
+    pub fn get_server_url() -> String {
+        std::env::var("KVPAIR_GRPC_SERVER_URL").unwrap_or("http://localhost:50051".to_string())
+    }
+
+    /// The channel connects lazily, so this only fails if the URL (or the encoding) is invalid.
+    /// The client accepts gzip compressed responses, and compresses its requests with the
+    /// encoding KVPAIR_GRPC_CLIENT_COMPRESSION (e.g. gzip) if set, which the server must accept.
+    pub fn get_client(url: &str) -> Result<KvPairClient<Channel>, Error> {
+        let channel = Endpoint::from_shared(url.to_string())?
+            .connect_timeout(CONNECT_TIMEOUT)
+            .connect_lazy();
+        let client = KvPairClient::new(channel).accept_compressed(CompressionEncoding::Gzip);
+        match std::env::var("KVPAIR_GRPC_CLIENT_COMPRESSION") {
+            Ok(encoding) => Ok(client.send_compressed(parse_compression_encoding(&encoding)?)),
+            Err(_) => Ok(client),
+        }
+    }
+
+    /// Connect to the server KVPAIR_GRPC_SERVER_URL (http://localhost:50051 by default).
+    pub fn new(contract_id: ContractId, root: Hash) -> Result<Self, Error> {
+        Self::new_with_url(contract_id, root, Self::get_server_url())
+    }
+
+    pub fn new_with_url(contract_id: ContractId, root: Hash, url: String) -> Result<Self, Error> {
+        let client = Self::get_client(&url)?;
+        Ok(MongoMerkle {
+            root_hash: root,
+            contract_id,
+            url,
+            client,
+        })
+    }
+
+    /// Replace the channel with a new one, e.g. after the server is restarted.
+    pub fn reconnect(&mut self) -> Result<(), Error> {
+        self.client = Self::get_client(&self.url)?;
+        Ok(())
+    }
+
+    // Send a request with a clone of the client, retrying with exponential backoff (and a new
+    // channel) if the server is unavailable, e.g. while it is restarting. The request may have
+    // been applied even though it failed, so only reads and writes with an idempotency key are
+    // sent with this, the other writes are sent once with call_once.
+    async fn call<T, F, Fut>(&mut self, f: F) -> Result<T, Status>
+    where
+        F: Fn(KvPairClient<Channel>) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<T>, Status>>,
+    {
+        self.call_with_attempts(RECONNECT_ATTEMPTS, f).await
+    }
+
+    async fn call_once<T, F, Fut>(&mut self, f: F) -> Result<T, Status>
+    where
+        F: Fn(KvPairClient<Channel>) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<T>, Status>>,
+    {
+        self.call_with_attempts(0, f).await
+    }
+
+    async fn call_with_attempts<T, F, Fut>(&mut self, max_attempts: u32, f: F) -> Result<T, Status>
+    where
+        F: Fn(KvPairClient<Channel>) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<T>, Status>>,
+    {
+        let mut backoff = RECONNECT_INITIAL_BACKOFF;
+        let mut attempts = 0;
+        loop {
+            match f(self.client.clone()).await {
+                Err(status) if status.code() == Code::Unavailable && attempts < max_attempts => {
+                    tokio::time::sleep(backoff).await;
+                    self.reconnect()?;
+                    backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+                    attempts += 1;
+                }
+                result => return result.map(tonic::Response::into_inner),
+            }
+        }
     }
 
     pub fn height() -> usize {
-        MERKLE_TREE_HEIGHT
+        H
     }
     fn empty_leaf(index: u64) -> MerkleRecord {
         let mut leaf = MerkleRecord::new(index);
@@ -576,28 +1035,39 @@ impl MongoMerkle {
     }
 
     pub async fn get_root(&mut self) -> Result<GetRootResponse, Status> {
+        let contract_id = self.contract_id;
         let response = self
-            .client
-            .get_root(Request::new(GetRootRequest {
-                contract_id: Some(self.contract_id.into()),
-            }))
+            .call(|mut client| async move {
+                client
+                    .get_root(Request::new(GetRootRequest {
+                        contract_id: Some(contract_id.into()),
+                        consistent_read: false,
+                        include_node: false,
+                    }))
+                    .await
+            })
             .await?;
         dbg!(&response);
 
-        Ok(response.into_inner())
+        Ok(response)
     }
 
     pub async fn set_root(&mut self, hash: Hash) -> Result<SetRootResponse, Status> {
+        let contract_id = self.contract_id;
         let response = self
-            .client
-            .set_root(Request::new(SetRootRequest {
-                contract_id: Some(self.contract_id.into()),
-                hash: hash.into(),
-            }))
+            .call_once(|mut client| async move {
+                client
+                    .set_root(Request::new(SetRootRequest {
+                        contract_id: Some(contract_id.into()),
+                        hash: hash.into(),
+                        verify: false,
+                    }))
+                    .await
+            })
             .await?;
         dbg!(&response);
 
-        Ok(response.into_inner())
+        Ok(response)
     }
 
     pub async fn get_leaf(
@@ -606,18 +1076,51 @@ impl MongoMerkle {
         hash: Option<Hash>,
         proof_type: ProofType,
     ) -> Result<GetLeafResponse, Status> {
+        let contract_id = self.contract_id;
         let response = self
-            .client
-            .get_leaf(Request::new(GetLeafRequest {
-                index,
-                hash: hash.map(|h| h.into()),
-                proof_type: proof_type.into(),
-                contract_id: Some(self.contract_id.into()),
-            }))
+            .call(|mut client| async move {
+                client
+                    .get_leaf(Request::new(GetLeafRequest {
+                        index,
+                        address: None,
+                        hash: hash.map(|h| h.into()),
+                        proof_type: proof_type.into(),
+                        contract_id: Some(contract_id.into()),
+                        require_data: false,
+                        consistent_read: false,
+                    }))
+                    .await
+            })
             .await?;
         dbg!(&response);
 
-        Ok(response.into_inner())
+        Ok(response)
+    }
+
+    /// Get the leaf at index together with its proof, which is checked to be a valid proof of
+    /// the leaf in the root of this tree (see MerkleTree::get_root_hash). Unlike the default
+    /// MerkleTree::get_leaf_with_proof, this takes a single request to the server.
+    pub async fn get_leaf_with_proof(
+        &mut self,
+        index: u64,
+    ) -> Result<(MerkleRecord, MerkleProof<Hash, H>), Error> {
+        let response = self
+            .get_leaf(index, None, ProofType::ProofV0)
+            .await
+            .map_err(|status| Error::from_status(&status))?;
+        let record: MerkleRecord = response
+            .node
+            .ok_or_else(|| {
+                Error::InconsistentData(format!("Node {index} not found in the response"))
+            })?
+            .try_into()?;
+        let proof = response.proof.ok_or_else(|| {
+            Error::InconsistentData(format!("Proof of leaf {index} not found in the response"))
+        })?;
+        let proof: MerkleProof<Hash, H> = bincode::deserialize(&proof.proof)
+            .map_err(|e| Error::InconsistentData(format!("Invalid bincode proof: {e}")))?;
+        check_leaf_proof(&record, &proof, &self.get_root_hash())?;
+        Ok((record, proof))
     }
 
     pub async fn set_leaf(
@@ -626,20 +1129,57 @@ impl MongoMerkle {
         leaf_data: LeafData,
         proof_type: ProofType,
     ) -> Result<SetLeafResponse, Status> {
+        let contract_id = self.contract_id;
         let proof_type = proof_type.into();
+        // The retries of the request carry the same key, so that the leaf is only set once even
+        // if the response of an attempt is lost.
+        let mut key = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut key);
+        let idempotency_key = hex::encode(key);
         let response = self
-            .client
-            .set_leaf(Request::new(SetLeafRequest {
-                index,
-                hash: None,
-                data: Some(leaf_data.0),
-                proof_type,
-                contract_id: Some(self.contract_id.into()),
-            }))
+            .call(|mut client| {
+                let data = leaf_data.0.clone();
+                let idempotency_key = idempotency_key.clone();
+                async move {
+                    client
+                        .set_leaf(Request::new(SetLeafRequest {
+                            index,
+                            address: None,
+                            hash: None,
+                            data: Some(data),
+                            proof_type,
+                            contract_id: Some(contract_id.into()),
+                            sign_root: false,
+                            idempotency_key: Some(idempotency_key),
+                        }))
+                        .await
+                }
+            })
             .await?;
         dbg!(&response);
 
-        Ok(response.into_inner())
+        Ok(response)
+    }
+
+    /// Same as get_leaf, but the leaf is given by its address (see leaf_index_from_address).
+    pub async fn get_value(
+        &mut self,
+        address: u64,
+        proof_type: ProofType,
+    ) -> Result<GetLeafResponse, Status> {
+        let index = leaf_index_from_address(address)?;
+        self.get_leaf(index, None, proof_type).await
+    }
+
+    /// Same as set_leaf, but the leaf is given by its address (see leaf_index_from_address).
+    pub async fn set_value(
+        &mut self,
+        address: u64,
+        leaf_data: LeafData,
+        proof_type: ProofType,
+    ) -> Result<SetLeafResponse, Status> {
+        let index = leaf_index_from_address(address)?;
+        self.set_leaf(index, leaf_data, proof_type).await
     }
 
     pub async fn get_non_leaf(
@@ -647,17 +1187,22 @@ impl MongoMerkle {
         index: u64,
         hash: Hash,
     ) -> Result<GetNonLeafResponse, Status> {
+        let contract_id = self.contract_id;
         let response = self
-            .client
-            .get_non_leaf(Request::new(GetNonLeafRequest {
-                index,
-                hash: hash.into(),
-                contract_id: Some(self.contract_id.into()),
-            }))
+            .call(|mut client| async move {
+                client
+                    .get_non_leaf(Request::new(GetNonLeafRequest {
+                        index,
+                        hash: hash.into(),
+                        contract_id: Some(contract_id.into()),
+                        proof_type: ProofType::ProofEmpty.into(),
+                    }))
+                    .await
+            })
             .await?;
         dbg!(&response);
 
-        Ok(response.into_inner())
+        Ok(response)
     }
 
     pub async fn set_non_leaf(
@@ -667,35 +1212,49 @@ impl MongoMerkle {
         left: Hash,
         right: Hash,
     ) -> Result<SetNonLeafResponse, Status> {
+        let contract_id = self.contract_id;
         let response = self
-            .client
-            .set_non_leaf(Request::new(SetNonLeafRequest {
-                index,
-                hash: hash.map(|x| x.into()),
-                left_child_hash: left.into(),
-                right_child_hash: right.into(),
-                contract_id: Some(self.contract_id.into()),
-            }))
+            .call_once(|mut client| async move {
+                client
+                    .set_non_leaf(Request::new(SetNonLeafRequest {
+                        index,
+                        hash: hash.map(|x| x.into()),
+                        left_child_hash: left.into(),
+                        right_child_hash: right.into(),
+                        contract_id: Some(contract_id.into()),
+                    }))
+                    .await
+            })
             .await?;
         dbg!(&response);
 
-        Ok(response.into_inner())
+        Ok(response)
+    }
+}
+
+// The MerkleErrorCode which best describes an error returned by the server.
+fn merkle_error_code(error: &Error) -> MerkleErrorCode {
+    match error.inner() {
+        Error::Merkle(e) => *e.code(),
+        Error::InvalidHash(_) | Error::HashMismatch(_) => MerkleErrorCode::InvalidHash,
+        Error::InvalidIndex(_) => MerkleErrorCode::InvalidIndex,
+        Error::NotFound(_) | Error::LeafNotFound(_) | Error::RootNotFound(_) => {
+            MerkleErrorCode::NodeNotFound
+        }
+        Error::InvalidArgument(_) | Error::InconsistentData(_) => MerkleErrorCode::InvalidNode,
+        _ => MerkleErrorCode::InvalidOther,
     }
 }
 
-impl MerkleTree<Hash, MERKLE_TREE_HEIGHT> for MongoMerkle {
+impl<const H: usize> MerkleTree<Hash, H> for MongoMerkle<H> {
     type Id = ContractId;
     type Root = Hash;
     type Node = MerkleRecord;
 
+    // The signature of the trait does not allow to return an error here, use MongoMerkle::new
+    // instead to handle an invalid KVPAIR_GRPC_SERVER_URL.
     fn construct(addr: Self::Id, root: Self::Root) -> Self {
-        let client = executor::block_on(Self::get_client());
-
-        MongoMerkle {
-            root_hash: root,
-            client,
-            contract_id: addr,
-        }
+        Self::new(addr, root).expect("Invalid KVPAIR_GRPC_SERVER_URL")
     }
 
     fn get_root_hash(&self) -> Hash {
@@ -727,23 +1286,30 @@ impl MerkleTree<Hash, MERKLE_TREE_HEIGHT> for MongoMerkle {
     }
 
     fn get_node_with_hash(&mut self, index: u64, hash: &Hash) -> Result<Self::Node, MerkleError> {
-        let node_type = get_node_type(index, MERKLE_TREE_HEIGHT);
+        let node_type = get_node_type(index, H);
         let node = if node_type == NodeType::NodeLeaf {
             executor::block_on(self.get_leaf(index, Some(*hash), ProofType::ProofEmpty))
-                .map(|x| x.node.unwrap())
+                .map(|x| x.node)
         } else {
-            executor::block_on(self.get_non_leaf(index, *hash)).map(|x| x.node.unwrap())
+            executor::block_on(self.get_non_leaf(index, *hash)).map(|x| x.node)
         }
-        .and_then(|x| Ok(MerkleRecord::try_from(x)?))
+        .map_err(|status| Error::from_status(&status))
+        .and_then(|node| {
+            node.ok_or_else(|| {
+                Error::InconsistentData(format!("Node {index} not found in the response"))
+            })
+        })
+        .and_then(MerkleRecord::try_from)
         .map_err(|e| {
-            dbg!(e);
-            MerkleError::new(*hash, index, MerkleErrorCode::InvalidOther)
+            dbg!(&e);
+            MerkleError::new(*hash, index, merkle_error_code(&e))
         })?;
         Ok(node)
     }
 
     fn set_leaf(&mut self, leaf: &MerkleRecord) -> Result<(), MerkleError> {
-        self.boundary_check(leaf.index())?; //should be leaf check?
+        // Only leaves may be set here, internal nodes are set with set_parent.
+        self.leaf_check(leaf.index())?;
         executor::block_on(self.set_leaf(leaf.index, Default::default(), ProofType::ProofEmpty))
             .map_err(|e| {
                 dbg!(e);
@@ -753,7 +1319,503 @@ impl MerkleTree<Hash, MERKLE_TREE_HEIGHT> for MongoMerkle {
     }
 }
 
+// Check that a proof of the node at index has one assist hash for each level above the node.
+fn check_assist_len<const D: usize>(index: u64, assist_len: usize) -> Result<(), Error> {
+    if get_node_type(index, D) == NodeType::NodeInvalid {
+        return Err(
+            Error::InvalidIndex(format!("Merkle proof malformed, invalid index {index}"))
+                .with_detail("index", index),
+        );
+    }
+    let depth = get_depth(index);
+    if assist_len != depth {
+        return Err(Error::InvalidArgument(format!(
+            "Merkle proof malformed, {depth} assist hashes expected, given {assist_len}"
+        ))
+        .with_detail("index", index));
+    }
+    Ok(())
+}
+
+// Check that proof is a valid proof of the leaf record in the tree with the given root.
+fn check_leaf_proof<const D: usize>(
+    record: &MerkleRecord,
+    proof: &MerkleProof<Hash, D>,
+    root: &Hash,
+) -> Result<(), Error> {
+    let index = record.index;
+    if proof.index != index || proof.source != record.hash {
+        return Err(Error::InconsistentData(format!(
+            "Proof of leaf {} returned for leaf {index}",
+            proof.index
+        ))
+        .with_detail("index", index)
+        .with_detail("expected_hash", hex::encode(record.hash.0))
+        .with_detail("actual_hash", hex::encode(proof.source.0)));
+    }
+    if proof.root != *root {
+        return Err(
+            Error::HashMismatch(format!("Proof of leaf {index} not in the expected root"))
+                .with_detail("index", index)
+                .with_detail("expected_hash", hex::encode(root.0))
+                .with_detail("actual_hash", hex::encode(proof.root.0)),
+        );
+    }
+    if !proof.verify() {
+        return Err(
+            Error::InconsistentData(format!("Invalid proof of leaf {index}"))
+                .with_detail("index", index),
+        );
+    }
+    Ok(())
+}
+
+impl<const D: usize> MerkleProof<Hash, D> {
+    /// Check that `source` is the leaf at `index` of the merkle tree with root `root`.
+    /// Note that `assist` starts from the sibling of the root's child, and ends with
+    /// the sibling of the leaf.
+    pub fn verify(&self) -> bool {
+        self.try_verify().is_ok()
+    }
+
+    /// Same as verify, but return why the proof is invalid: InvalidArgument if `assist` does
+    /// not have one hash for each level of the tree, InvalidIndex if `index` is not a leaf
+    /// index, InconsistentData if a hash is not a field element, and HashMismatch if `root` is
+    /// not the root computed from `source` and `assist`.
+    pub fn try_verify(&self) -> Result<(), Error> {
+        if self.assist.len() != D {
+            return Err(Error::InvalidArgument(format!(
+                "Merkle proof malformed, {D} assist hashes expected, given {}",
+                self.assist.len()
+            ))
+            .with_detail("index", self.index));
+        }
+        validate_leaf_index(self.index, D)?;
+        let root = self.compute_root()?;
+        if root != self.root {
+            return Err(Error::HashMismatch(format!(
+                "Merkle proof of leaf {} invalid",
+                self.index
+            ))
+            .with_detail("index", self.index)
+            .with_detail("expected_hash", hex::encode(self.root.0))
+            .with_detail("actual_hash", hex::encode(root.0)));
+        }
+        Ok(())
+    }
+
+    /// Same as verify, but `source` may be the node at any depth (e.g. a non-leaf node), with
+    /// one hash in `assist` for each level above the node.
+    pub fn verify_node(&self) -> bool {
+        if check_assist_len::<D>(self.index, self.assist.len()).is_err() {
+            return false;
+        }
+        matches!(self.compute_root(), Ok(root) if root == self.root)
+    }
+
+    // Fold `assist` into `source` up to the root. The bits of the offset of `index` in its
+    // level tell whether the node is the left (0) or right (1) child at each level. Proofs
+    // decoded from bincode may have hashes which are not field elements, hence the Result.
+    fn compute_root(&self) -> Result<Hash, Error> {
+        let mut offset = get_offset(self.index);
+        self.assist
+            .iter()
+            .rev()
+            .try_fold(self.source, |acc, sibling| {
+                let (left, right) = if offset % 2 == 1 {
+                    (sibling, &acc)
+                } else {
+                    (&acc, sibling)
+                };
+                offset /= 2;
+                Hash::try_hash_children(left, right)
+            })
+    }
+
+    /// Serialize the proof as JSON, with all hashes encoded as hex strings.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(&MerkleProofJson::from(self)).unwrap()
+    }
+
+    pub fn from_json(json: &[u8]) -> Result<Self, Error> {
+        let proof: MerkleProofJson = serde_json::from_slice(json)
+            .map_err(|e| Error::InvalidArgument(format!("Invalid JSON proof: {e}")))?;
+        let decode = |hash: String| {
+            hex::decode(hash).map_err(|e| Error::InvalidHash(format!("Invalid hex hash: {e}")))
+        };
+        MerkleProofV1 {
+            source: decode(proof.source)?,
+            root: decode(proof.root)?,
+            assist: proof
+                .assist
+                .into_iter()
+                .map(decode)
+                .collect::<Result<Vec<_>, _>>()?,
+            index: proof.index,
+            data: None,
+        }
+        .try_into()
+    }
+}
+
+/// The JSON representation of MerkleProof, with all hashes encoded as hex strings. We can not
+/// serialize MerkleProof with serde_json directly, as Hash is serialized as bson binary (i.e. an
+/// array of numbers in JSON).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProofJson {
+    pub source: String,
+    pub root: String,
+    pub assist: Vec<String>,
+    pub index: u64,
+}
+
+impl<const D: usize> From<&MerkleProof<Hash, D>> for MerkleProofJson {
+    fn from(proof: &MerkleProof<Hash, D>) -> Self {
+        Self {
+            source: hex::encode(proof.source.0),
+            root: hex::encode(proof.root.0),
+            assist: proof
+                .assist
+                .iter()
+                .map(|hash| hex::encode(hash.0))
+                .collect(),
+            index: proof.index,
+        }
+    }
+}
+
+/// MerkleProof in the layout of the merkle host calls of zkWasm, i.e. with every hash
+/// represented as four little endian u64 limbs (see Hash::to_u64_limbs).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ZkwasmMerkleProof {
+    pub source: [u64; 4],
+    pub root: [u64; 4],
+    pub assist: Vec<[u64; 4]>,
+    pub index: u64,
+}
+
+impl<const D: usize> From<&MerkleProof<Hash, D>> for ZkwasmMerkleProof {
+    fn from(proof: &MerkleProof<Hash, D>) -> Self {
+        ZkwasmMerkleProof {
+            source: proof.source.to_u64_limbs(),
+            root: proof.root.to_u64_limbs(),
+            assist: proof.assist.iter().map(Hash::to_u64_limbs).collect(),
+            index: proof.index,
+        }
+    }
+}
+
+impl<const D: usize> TryFrom<ZkwasmMerkleProof> for MerkleProof<Hash, D> {
+    type Error = Error;
+
+    fn try_from(proof: ZkwasmMerkleProof) -> Result<Self, Self::Error> {
+        check_assist_len::<D>(proof.index, proof.assist.len())?;
+        Ok(MerkleProof {
+            source: Hash::from_u64_limbs(proof.source)?,
+            root: Hash::from_u64_limbs(proof.root)?,
+            assist: proof
+                .assist
+                .into_iter()
+                .map(Hash::from_u64_limbs)
+                .collect::<Result<Vec<_>, _>>()?,
+            index: proof.index,
+        })
+    }
+}
+
+impl<const D: usize> From<&MerkleProof<Hash, D>> for MerkleProofV1 {
+    fn from(proof: &MerkleProof<Hash, D>) -> Self {
+        MerkleProofV1 {
+            source: proof.source.into(),
+            root: proof.root.into(),
+            assist: proof.assist.iter().map(|hash| (*hash).into()).collect(),
+            index: proof.index,
+            data: None,
+        }
+    }
+}
+
+impl<const D: usize> TryFrom<MerkleProofV1> for MerkleProof<Hash, D> {
+    type Error = Error;
+
+    fn try_from(proof: MerkleProofV1) -> Result<Self, Self::Error> {
+        check_assist_len::<D>(proof.index, proof.assist.len())?;
+        Ok(MerkleProof {
+            source: proof.source.try_into()?,
+            root: proof.root.try_into()?,
+            assist: proof
+                .assist
+                .into_iter()
+                .map(Hash::try_from)
+                .collect::<Result<Vec<_>, _>>()?,
+            index: proof.index,
+        })
+    }
+}
+
+/// A merkle proof of a leaf bound to the data of the leaf, as returned with ProofV1WithData, so
+/// that the data is checked along with the path. The data of the default leaf is 32 zero bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MerkleProofWithData<const D: usize> {
+    pub proof: MerkleProof<Hash, D>,
+    pub data: Vec<u8>,
+}
+
+impl<const D: usize> MerkleProofWithData<D> {
+    /// Check that `data` hashes to the `source` of the proof, and that the proof is valid (see
+    /// MerkleProof::verify).
+    pub fn verify(&self) -> bool {
+        self.try_verify().is_ok()
+    }
+
+    /// Same as verify, but return why the proof is invalid: HashMismatch if `data` does not
+    /// hash to `source`, otherwise the error of MerkleProof::try_verify.
+    pub fn try_verify(&self) -> Result<(), Error> {
+        let source = self.proof.source;
+        if !leaf_data_matches(&self.data, &source) {
+            return Err(Error::HashMismatch(format!(
+                "Data of leaf {} does not match its hash",
+                self.proof.index
+            ))
+            .with_detail("index", self.proof.index)
+            .with_detail("expected_hash", hex::encode(source.0)));
+        }
+        self.proof.try_verify()
+    }
+}
+
+impl<const D: usize> From<&MerkleProofWithData<D>> for MerkleProofV1 {
+    fn from(proof: &MerkleProofWithData<D>) -> Self {
+        MerkleProofV1 {
+            data: Some(proof.data.clone()),
+            ..(&proof.proof).into()
+        }
+    }
+}
+
+impl<const D: usize> TryFrom<MerkleProofV1> for MerkleProofWithData<D> {
+    type Error = Error;
+
+    fn try_from(mut proof: MerkleProofV1) -> Result<Self, Self::Error> {
+        let data = proof.data.take().ok_or_else(|| {
+            Error::InvalidArgument(format!("No data in the proof of leaf {}", proof.index))
+                .with_detail("index", proof.index)
+        })?;
+        Ok(MerkleProofWithData {
+            proof: proof.try_into()?,
+            data,
+        })
+    }
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, Error> {
+    hex::decode(hex).map_err(|e| Error::InvalidArgument(format!("Invalid hex string: {e}")))
+}
+
+fn decode_hex_hash(hash: &str) -> Result<Hash, Error> {
+    hex::decode(hash)
+        .map_err(|e| Error::InvalidHash(format!("Invalid hex hash: {e}")))?
+        .try_into()
+}
+
+fn format_created_at(created_at: DateTime) -> String {
+    // Only dates beyond year 9999 can not be formatted.
+    created_at
+        .try_to_rfc3339_string()
+        .unwrap_or_else(|_| created_at.to_string())
+}
+
+fn parse_created_at(created_at: &str) -> Result<DateTime, Error> {
+    DateTime::parse_rfc3339_str(created_at)
+        .map_err(|e| Error::InvalidArgument(format!("Invalid created_at {created_at:?}: {e}")))
+}
+
+/// The human readable JSON representation of MerkleRecord, with all hashes hex encoded.
+/// Merkle records are still stored as BSON binary, this is only meant for debugging.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleRecordJson {
+    pub index: u64,
+    pub hash: String,
+    pub left: String,
+    pub right: String,
+    pub data: String,
+    // RFC 3339, e.g. "2023-07-01T12:00:00Z".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+}
+
+impl From<&MerkleRecord> for MerkleRecordJson {
+    fn from(record: &MerkleRecord) -> Self {
+        MerkleRecordJson {
+            index: record.index,
+            hash: hex::encode(record.hash.0),
+            left: hex::encode(record.left.0),
+            right: hex::encode(record.right.0),
+            data: hex::encode(record.data),
+            created_at: record.created_at.map(format_created_at),
+        }
+    }
+}
+
+impl TryFrom<MerkleRecordJson> for MerkleRecord {
+    type Error = Error;
+
+    fn try_from(record: MerkleRecordJson) -> Result<Self, Self::Error> {
+        Ok(MerkleRecord {
+            index: record.index,
+            hash: decode_hex_hash(&record.hash)?,
+            left: decode_hex_hash(&record.left)?,
+            right: decode_hex_hash(&record.right)?,
+            data: decode_hex(&record.data)?.try_into().map_err(|_| {
+                Error::InvalidArgument("Merkle record data must be 32 bytes".to_string())
+            })?,
+            created_at: record
+                .created_at
+                .as_deref()
+                .map(parse_created_at)
+                .transpose()?,
+        })
+    }
+}
+
+impl MerkleRecord {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(&MerkleRecordJson::from(self)).unwrap()
+    }
+
+    pub fn from_json(json: &[u8]) -> Result<Self, Error> {
+        let record: MerkleRecordJson = serde_json::from_slice(json)
+            .map_err(|e| Error::InvalidArgument(format!("Invalid JSON merkle record: {e}")))?;
+        record.try_into()
+    }
+}
+
+/// The human readable JSON representation of DataHashRecord, with the hash and data hex encoded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DataHashRecordJson {
+    pub hash: String,
+    pub data: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+}
+
+impl From<&DataHashRecord> for DataHashRecordJson {
+    fn from(record: &DataHashRecord) -> Self {
+        DataHashRecordJson {
+            hash: hex::encode(record.hash.0),
+            data: hex::encode(&record.data),
+            created_at: record.created_at.map(format_created_at),
+        }
+    }
+}
+
+impl TryFrom<DataHashRecordJson> for DataHashRecord {
+    type Error = Error;
+
+    fn try_from(record: DataHashRecordJson) -> Result<Self, Self::Error> {
+        Ok(DataHashRecord {
+            hash: decode_hex_hash(&record.hash)?,
+            data: decode_hex(&record.data)?,
+            created_at: record
+                .created_at
+                .as_deref()
+                .map(parse_created_at)
+                .transpose()?,
+        })
+    }
+}
+
+impl DataHashRecord {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(&DataHashRecordJson::from(self)).unwrap()
+    }
+
+    pub fn from_json(json: &[u8]) -> Result<Self, Error> {
+        let record: DataHashRecordJson = serde_json::from_slice(json)
+            .map_err(|e| Error::InvalidArgument(format!("Invalid JSON data hash record: {e}")))?;
+        record.try_into()
+    }
+}
+
+/// The human readable JSON representation of Node. The node type is the name of the enum value
+/// (e.g. "NodeLeaf"). A leaf has the field data, while a non-leaf node has the fields left and
+/// right. All hashes and data are hex encoded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeJson {
+    pub index: u64,
+    pub hash: String,
+    pub node_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub left: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub right: Option<String>,
+}
+
+impl From<&Node> for NodeJson {
+    fn from(node: &Node) -> Self {
+        let node_type = NodeType::from_i32(node.node_type).unwrap_or(NodeType::NodeInvalid);
+        let (data, left, right) = match &node.node_data {
+            Some(NodeData::Data(data)) => (Some(hex::encode(data)), None, None),
+            Some(NodeData::Children(children)) => (
+                None,
+                Some(hex::encode(&children.left_child_hash)),
+                Some(hex::encode(&children.right_child_hash)),
+            ),
+            None => (None, None, None),
+        };
+        NodeJson {
+            index: node.index,
+            hash: hex::encode(&node.hash),
+            node_type: node_type.as_str_name().to_string(),
+            data,
+            left,
+            right,
+        }
+    }
+}
+
+impl TryFrom<NodeJson> for Node {
+    type Error = Error;
+
+    fn try_from(node: NodeJson) -> Result<Self, Self::Error> {
+        let node_type = NodeType::from_str_name(&node.node_type).ok_or_else(|| {
+            Error::InvalidArgument(format!("Invalid node type {}", node.node_type))
+        })?;
+        let node_data = match (node.data, node.left, node.right) {
+            (Some(data), None, None) => Some(NodeData::Data(decode_hex(&data)?)),
+            (None, Some(left), Some(right)) => Some(NodeData::Children(NodeChildren {
+                left_child_hash: decode_hex(&left)?,
+                right_child_hash: decode_hex(&right)?,
+            })),
+            (None, None, None) => None,
+            _ => {
+                return Err(Error::InvalidArgument(
+                    "Node must have either data or both children".to_string(),
+                ))
+            }
+        };
+        Ok(Node {
+            index: node.index,
+            hash: decode_hex(&node.hash)?,
+            node_type: node_type.into(),
+            node_data,
+        })
+    }
+}
+
 impl Node {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(&NodeJson::from(self)).unwrap()
+    }
+
+    pub fn from_json(json: &[u8]) -> Result<Self, Error> {
+        let node: NodeJson = serde_json::from_slice(json)
+            .map_err(|e| Error::InvalidArgument(format!("Invalid JSON node: {e}")))?;
+        node.try_into()
+    }
+
     /// This corresponds to data in simple_get/simple_set of zkWasm-rust.
     /// Here we create a Node that has empty vector as its data, although
     /// technically an empty vector does not hash to the hash in the merkle record.
@@ -772,12 +1834,543 @@ mod tests {
     use super::*;
 
     pub fn bytes_to_u64(bytes: &[u8; 32]) -> [u64; 4] {
-        let r = bytes
-            .to_vec()
-            .chunks_exact(8)
-            .map(|x| u64::from_le_bytes(x.try_into().unwrap()))
+        Hash(*bytes).to_u64_limbs()
+    }
+
+    #[test]
+    fn test_hash_data() {
+        // The default leaf, whose hash results in the root of zkWasm-rust in test_new_merkle_root.
+        assert_eq!(Hash::hash_data(&[0; 32]), DEFAULT_HASH_VEC[0]);
+        // Computed with an independent implementation of the MERKLE_LEAF_HASHER of upstream.
+        let data = (1..=32).collect::<Vec<u8>>();
+        assert_eq!(
+            hex::encode(Hash::hash_data(&data).0),
+            "f5549fc35b9c4f6b85839d07a4a3950db7f15ba080fcfe13c6f2665ef2d8802c"
+        );
+    }
+
+    #[test]
+    fn test_hash_must_be_field_element() {
+        assert!(Hash::try_from([0u8; 32]).is_ok());
+        assert!(Hash::try_from([42u8; 32]).is_ok());
+        assert!(Hash::try_from([0xffu8; 32]).is_err());
+        assert!(Hash::try_from([0xffu8; 32].as_slice()).is_err());
+        assert!(Hash::try_from([0xffu8; 32].to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_non_canonical_hash() {
+        // Not a field element, as if read from a corrupted record.
+        let corrupted = Hash([0xffu8; 32]);
+        let valid = DEFAULT_HASH_VEC[0];
+        assert!(valid.to_fr().is_ok());
+        let error = corrupted.to_fr().unwrap_err();
+        assert!(matches!(error.inner(), Error::InconsistentData(_)));
+        assert_eq!(
+            error.detail("hash"),
+            Some(hex::encode([0xffu8; 32]).as_str())
+        );
+
+        assert_eq!(
+            Hash::try_hash_children(&valid, &valid).unwrap(),
+            Hash::hash_children(&valid, &valid)
+        );
+        for (left, right) in [(corrupted, valid), (valid, corrupted)] {
+            let error = Hash::try_hash_children(&left, &right).unwrap_err();
+            assert!(matches!(error.inner(), Error::InconsistentData(_)));
+            let error = Hash::validate_children(&valid, &left, &right).unwrap_err();
+            assert!(matches!(error.inner(), Error::InconsistentData(_)));
+        }
+
+        // A proof with such a hash is invalid instead of panicking.
+        let mut proof = MerkleProof::<Hash, MERKLE_TREE_HEIGHT> {
+            source: DEFAULT_HASH_VEC[0],
+            root: DEFAULT_HASH_VEC[MERKLE_TREE_HEIGHT],
+            assist: (0..MERKLE_TREE_HEIGHT)
+                .map(|i| DEFAULT_HASH_VEC[MERKLE_TREE_HEIGHT - i - 1])
+                .collect(),
+            index: (1 << MERKLE_TREE_HEIGHT) - 1,
+        };
+        proof.assist[0] = corrupted;
+        let error = proof.try_verify().unwrap_err();
+        assert!(matches!(error.inner(), Error::InconsistentData(_)));
+        assert!(!proof.verify());
+        assert!(!proof.verify_node());
+    }
+
+    #[test]
+    fn test_default_hash_with_height() {
+        assert_eq!(
+            Hash::get_default_hash_for_depth_with_height::<20>(0).unwrap(),
+            DEFAULT_HASH_VEC[20]
+        );
+        assert_eq!(
+            Hash::get_default_hash_for_depth_with_height::<20>(20).unwrap(),
+            DEFAULT_HASH_VEC[0]
+        );
+        assert!(Hash::get_default_hash_for_depth_with_height::<20>(21).is_err());
+        assert!(Hash::get_default_hash_for_depth_with_height::<33>(0).is_err());
+
+        let root = MerkleRecord::get_default_record_with_height::<20>(0).unwrap();
+        assert_eq!(root.hash, DEFAULT_HASH_VEC[20]);
+        assert_eq!(root.left, DEFAULT_HASH_VEC[19]);
+        let leaf = MerkleRecord::get_default_record_with_height::<20>((1 << 20) - 1).unwrap();
+        assert_eq!(leaf.hash, DEFAULT_HASH_VEC[0]);
+        assert_eq!(StandardMerkle::height(), MERKLE_TREE_HEIGHT);
+    }
+
+    #[test]
+    fn test_default_record_bounds() {
+        fn check<const H: usize>() {
+            for depth in 0..=H {
+                // The first and the last node of each level.
+                for index in [(1 << depth) - 1, (1 << (depth + 1)) - 2] {
+                    let record = MerkleRecord::get_default_record_with_height::<H>(index).unwrap();
+                    assert_eq!(record.index, index);
+                    assert_eq!(record.hash, DEFAULT_HASH_VEC[H - depth]);
+                    if depth < H {
+                        assert_eq!(record.left, DEFAULT_HASH_VEC[H - depth - 1]);
+                        assert_eq!(record.right, DEFAULT_HASH_VEC[H - depth - 1]);
+                        assert_eq!(
+                            Hash::hash_children(&record.left, &record.right),
+                            record.hash
+                        );
+                    }
+                }
+            }
+            // Just beyond the last leaf, and an index whose successor overflows.
+            for index in [(1 << (H + 1)) - 1, 1 << (H + 1), u64::MAX] {
+                let error = MerkleRecord::get_default_record_with_height::<H>(index).unwrap_err();
+                assert_eq!(*error.code(), MerkleErrorCode::InvalidIndex);
+            }
+        }
+        check::<1>();
+        check::<2>();
+        check::<20>();
+        check::<MERKLE_TREE_HEIGHT>();
+        assert!(MerkleRecord::get_default_record(0).is_ok());
+        assert!(MerkleRecord::get_default_record((1 << (MERKLE_TREE_HEIGHT + 1)) - 1).is_err());
+    }
+
+    #[test]
+    fn test_merkle_proof_v1_conversion() {
+        let assist = (0..MERKLE_TREE_HEIGHT)
+            .map(|i| DEFAULT_HASH_VEC[MERKLE_TREE_HEIGHT - i - 1])
+            .collect::<Vec<_>>();
+        let proof = MerkleProof::<Hash, MERKLE_TREE_HEIGHT> {
+            source: DEFAULT_HASH_VEC[0],
+            root: DEFAULT_HASH_VEC[MERKLE_TREE_HEIGHT],
+            assist,
+            index: (1 << MERKLE_TREE_HEIGHT) - 1,
+        };
+        assert!(proof.verify());
+        let proof_v1 = MerkleProofV1::from(&proof);
+        assert_eq!(
+            MerkleProof::<Hash, MERKLE_TREE_HEIGHT>::try_from(proof_v1.clone()).unwrap(),
+            proof
+        );
+
+        let mut short = proof_v1.clone();
+        short.assist.pop();
+        assert!(MerkleProof::<Hash, MERKLE_TREE_HEIGHT>::try_from(short).is_err());
+        let mut malformed = proof_v1;
+        malformed.root = vec![0; 31];
+        assert!(MerkleProof::<Hash, MERKLE_TREE_HEIGHT>::try_from(malformed).is_err());
+    }
+
+    #[test]
+    fn test_merkle_proof_try_verify() {
+        let index = (1 << MERKLE_TREE_HEIGHT) - 1;
+        let assist = (0..MERKLE_TREE_HEIGHT)
+            .map(|i| DEFAULT_HASH_VEC[MERKLE_TREE_HEIGHT - i - 1])
+            .collect::<Vec<_>>();
+        let proof = MerkleProof::<Hash, MERKLE_TREE_HEIGHT> {
+            source: DEFAULT_HASH_VEC[0],
+            root: DEFAULT_HASH_VEC[MERKLE_TREE_HEIGHT],
+            assist,
+            index,
+        };
+        assert!(proof.try_verify().is_ok());
+
+        // Too few or too many assist hashes, even if they fold into the root (e.g. the proof
+        // of a non-leaf node).
+        let mut short = proof.clone();
+        short.assist.remove(0);
+        short.root = DEFAULT_HASH_VEC[MERKLE_TREE_HEIGHT - 1];
+        let mut long = proof.clone();
+        long.assist.push(DEFAULT_HASH_VEC[0]);
+        for proof in [short, long] {
+            let error = proof.try_verify().unwrap_err();
+            assert!(matches!(error.inner(), Error::InvalidArgument(_)));
+            assert!(!proof.verify());
+        }
+
+        // The right number of assist hashes, but not a leaf index.
+        for index in [0, index - 1, 2 * index + 1] {
+            let proof = MerkleProof {
+                index,
+                ..proof.clone()
+            };
+            let error = proof.try_verify().unwrap_err();
+            assert!(matches!(error.inner(), Error::InvalidIndex(_)));
+            assert!(!proof.verify());
+        }
+
+        // The proof of a leaf only verifies with its own index, as the offset of the index
+        // decides which side each hash is folded on.
+        let moved = MerkleProof {
+            source: Hash::try_from([1_u8; 32]).unwrap(),
+            index: index + 1,
+            ..proof.clone()
+        };
+        let moved = MerkleProof {
+            root: moved.compute_root().unwrap(),
+            ..moved
+        };
+        assert!(moved.try_verify().is_ok());
+        let tampered = MerkleProof { index, ..moved };
+        let error = tampered.try_verify().unwrap_err();
+        assert!(matches!(error.inner(), Error::HashMismatch(_)));
+        assert!(!tampered.verify());
+    }
+
+    #[test]
+    fn test_merkle_proof_with_data() {
+        let index = (1 << MERKLE_TREE_HEIGHT) - 1;
+        let assist = (0..MERKLE_TREE_HEIGHT)
+            .map(|i| DEFAULT_HASH_VEC[MERKLE_TREE_HEIGHT - i - 1])
             .collect::<Vec<_>>();
-        r.try_into().unwrap()
+        // The default leaf, whose data is 32 zero bytes.
+        let default_proof = MerkleProof::<Hash, MERKLE_TREE_HEIGHT> {
+            source: DEFAULT_HASH_VEC[0],
+            root: DEFAULT_HASH_VEC[MERKLE_TREE_HEIGHT],
+            assist,
+            index,
+        };
+        let proof = MerkleProofWithData {
+            proof: default_proof.clone(),
+            data: vec![0; 32],
+        };
+        assert!(proof.try_verify().is_ok());
+        let proof_v1 = MerkleProofV1::from(&proof);
+        assert_eq!(proof_v1.data, Some(vec![0; 32]));
+        assert_eq!(
+            MerkleProofWithData::try_from(proof_v1.clone()).unwrap(),
+            proof
+        );
+        let without_data = MerkleProofV1 {
+            data: None,
+            ..proof_v1
+        };
+        let error = MerkleProofWithData::<MERKLE_TREE_HEIGHT>::try_from(without_data).unwrap_err();
+        assert!(matches!(error.inner(), Error::InvalidArgument(_)));
+
+        // A leaf set by the server, with data which is not an array of field elements.
+        let data = vec![0xff; 40];
+        let source = Hash(crate::poseidon::hash_leaf_data(&data));
+        let proof = MerkleProof {
+            source,
+            root: source,
+            ..default_proof
+        };
+        let proof = MerkleProofWithData {
+            proof: MerkleProof {
+                root: proof.compute_root().unwrap(),
+                ..proof
+            },
+            data,
+        };
+        assert!(proof.verify());
+
+        // Tampered data fails even though the path is valid.
+        for data in [vec![0xff; 39], vec![0xfe; 40], vec![0; 32], vec![]] {
+            let tampered = MerkleProofWithData {
+                data,
+                ..proof.clone()
+            };
+            assert!(tampered.proof.verify());
+            let error = tampered.try_verify().unwrap_err();
+            assert!(matches!(error.inner(), Error::HashMismatch(_)));
+            assert!(!tampered.verify());
+        }
+
+        // Valid data with a tampered path fails too.
+        let mut tampered = proof;
+        tampered.proof.assist[0] = DEFAULT_HASH_VEC[0];
+        let error = tampered.try_verify().unwrap_err();
+        assert!(matches!(error.inner(), Error::HashMismatch(_)));
+    }
+
+    #[test]
+    fn test_leaf_index_from_address() {
+        let first_leaf = (1 << MERKLE_TREE_HEIGHT) - 1;
+        let max_address = (1 << MERKLE_TREE_HEIGHT) - 1;
+        assert_eq!(leaf_index_from_address(0).unwrap(), first_leaf);
+        assert_eq!(
+            leaf_index_from_address(max_address).unwrap(),
+            2 * first_leaf
+        );
+        for address in [0, 1, max_address] {
+            let index = leaf_index_from_address(address).unwrap();
+            assert_eq!(address_from_leaf_index(index).unwrap(), address);
+        }
+        for address in [max_address + 1, u64::MAX] {
+            let error = leaf_index_from_address(address).unwrap_err();
+            assert!(matches!(error.inner(), Error::InvalidIndex(_)));
+        }
+        for index in [0, first_leaf - 1, 2 * first_leaf + 1, u64::MAX] {
+            assert!(address_from_leaf_index(index).is_err());
+        }
+    }
+
+    #[test]
+    fn test_check_leaf_proof() {
+        let index = (1 << MERKLE_TREE_HEIGHT) - 1;
+        let root = DEFAULT_HASH_VEC[MERKLE_TREE_HEIGHT];
+        let record = MerkleRecord::new_leaf(index, DEFAULT_HASH_VEC[0]);
+        let proof = MerkleProof::<Hash, MERKLE_TREE_HEIGHT> {
+            source: DEFAULT_HASH_VEC[0],
+            root,
+            assist: (0..MERKLE_TREE_HEIGHT)
+                .map(|i| DEFAULT_HASH_VEC[MERKLE_TREE_HEIGHT - i - 1])
+                .collect(),
+            index,
+        };
+        assert!(check_leaf_proof(&record, &proof, &root).is_ok());
+
+        let other_root = Hash::hash_children(&root, &root);
+        let error = check_leaf_proof(&record, &proof, &other_root).unwrap_err();
+        assert!(matches!(error.inner(), Error::HashMismatch(_)));
+
+        let other_leaf = MerkleRecord::new_leaf(index + 1, DEFAULT_HASH_VEC[0]);
+        let error = check_leaf_proof(&other_leaf, &proof, &root).unwrap_err();
+        assert!(matches!(error.inner(), Error::InconsistentData(_)));
+
+        let mut forged = proof;
+        forged.assist[0] = DEFAULT_HASH_VEC[0];
+        let error = check_leaf_proof(&record, &forged, &root).unwrap_err();
+        assert!(matches!(error.inner(), Error::InconsistentData(_)));
+    }
+
+    #[test]
+    fn test_merkle_proof_json() {
+        let proof = MerkleProof::<Hash, MERKLE_TREE_HEIGHT> {
+            source: DEFAULT_HASH_VEC[0],
+            root: DEFAULT_HASH_VEC[MERKLE_TREE_HEIGHT],
+            assist: DEFAULT_HASH_VEC[..MERKLE_TREE_HEIGHT]
+                .iter()
+                .rev()
+                .copied()
+                .collect(),
+            index: (1 << MERKLE_TREE_HEIGHT) - 1,
+        };
+        let json = proof.to_json();
+        assert!(json.contains(&hex::encode(DEFAULT_HASH_VEC[0].0)));
+        assert_eq!(
+            MerkleProof::<Hash, MERKLE_TREE_HEIGHT>::from_json(json.as_bytes()).unwrap(),
+            proof
+        );
+
+        assert!(MerkleProof::<Hash, MERKLE_TREE_HEIGHT>::from_json(b"{}").is_err());
+        let malformed = json.replace(&hex::encode(DEFAULT_HASH_VEC[0].0), "zz");
+        assert!(MerkleProof::<Hash, MERKLE_TREE_HEIGHT>::from_json(malformed.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_inconsistent_node() {
+        let leaf_index = (1 << MERKLE_TREE_HEIGHT) - 1;
+        let children = NodeChildren {
+            left_child_hash: DEFAULT_HASH_VEC[0].into(),
+            right_child_hash: DEFAULT_HASH_VEC[0].into(),
+        };
+        let node = |index, hash: Hash, node_type: NodeType, node_data| Node {
+            index,
+            hash: hash.into(),
+            node_type: node_type.into(),
+            node_data: Some(node_data),
+        };
+
+        // Leaf with children data, or non-leaf with leaf data.
+        let error = MerkleRecord::try_from(node(
+            leaf_index,
+            DEFAULT_HASH_VEC[0],
+            NodeType::NodeLeaf,
+            NodeData::Children(children.clone()),
+        ))
+        .unwrap_err();
+        assert!(matches!(error.inner(), Error::InvalidArgument(_)));
+        assert_eq!(error.detail("index"), Some(leaf_index.to_string().as_str()));
+        assert_eq!(error.detail("node_type"), Some("NodeLeaf"));
+        let error = MerkleRecord::try_from(node(
+            1,
+            DEFAULT_HASH_VEC[1],
+            NodeType::NodeNonLeaf,
+            NodeData::Data(vec![]),
+        ))
+        .unwrap_err();
+        assert!(matches!(error.inner(), Error::InvalidArgument(_)));
+        assert_eq!(error.detail("node_type"), Some("NodeNonLeaf"));
+
+        // Children not hashed to the hash of the node.
+        let error = MerkleRecord::try_from(node(
+            1,
+            DEFAULT_HASH_VEC[2],
+            NodeType::NodeNonLeaf,
+            NodeData::Children(children.clone()),
+        ))
+        .unwrap_err();
+        assert!(matches!(error.inner(), Error::InconsistentData(_)));
+        assert_eq!(error.detail("index"), Some("1"));
+        assert_eq!(
+            error.detail("actual_hash"),
+            Some(hex::encode(DEFAULT_HASH_VEC[1].0).as_str())
+        );
+        let record = MerkleRecord::try_from(node(
+            1,
+            DEFAULT_HASH_VEC[1],
+            NodeType::NodeNonLeaf,
+            NodeData::Children(children),
+        ))
+        .unwrap();
+        assert_eq!(record.hash, DEFAULT_HASH_VEC[1]);
+
+        // Invalid node type and malformed hash.
+        let mut invalid = node(
+            1,
+            DEFAULT_HASH_VEC[1],
+            NodeType::NodeInvalid,
+            NodeData::Data(vec![]),
+        );
+        let error = MerkleRecord::try_from(invalid.clone()).unwrap_err();
+        assert!(matches!(error.inner(), Error::InvalidArgument(_)));
+        invalid.node_type = NodeType::NodeLeaf.into();
+        invalid.hash = vec![1; 31];
+        let error = MerkleRecord::try_from(invalid).unwrap_err();
+        assert!(matches!(error.inner(), Error::InvalidHash(_)));
+        assert_eq!(error.detail("index"), Some("1"));
+
+        // Leaf whose data hash record has another hash.
+        let leaf = MerkleRecord::new_leaf(leaf_index, DEFAULT_HASH_VEC[0]);
+        let datahash_record = DataHashRecord::new(Hash::hash_data(&[1; 32]), vec![1; 32]);
+        let error = Node::try_from((leaf, datahash_record)).unwrap_err();
+        assert!(matches!(error.inner(), Error::HashMismatch(_)));
+        assert_eq!(error.detail("node_type"), Some("NodeLeaf"));
+    }
+
+    #[tokio::test]
+    async fn test_mongo_merkle_unavailable() {
+        let root = DEFAULT_HASH_VEC[MERKLE_TREE_HEIGHT];
+        let merkle = StandardMerkle::new_with_url(ContractId::default(), root, "".to_string());
+        assert!(matches!(merkle, Err(Error::Transport(_))));
+
+        // Nothing listens on this port. The channel connects lazily, so this only fails after
+        // all the retries.
+        let url = "http://127.0.0.1:1".to_string();
+        let mut merkle = StandardMerkle::new_with_url(ContractId::default(), root, url).unwrap();
+        let status = merkle.get_root().await.unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+
+        // Writes without an idempotency key are not retried, as they may have been applied.
+        let start = std::time::Instant::now();
+        let status = merkle.set_root(root).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+        assert!(start.elapsed() < RECONNECT_INITIAL_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn test_merkle_tree_set_leaf_bounds() {
+        let root = DEFAULT_HASH_VEC[MERKLE_TREE_HEIGHT];
+        // Indices out of the leaf range are rejected before any request is sent.
+        let url = "http://127.0.0.1:1".to_string();
+        let mut merkle = StandardMerkle::new_with_url(ContractId::default(), root, url).unwrap();
+        let first_leaf = (1 << MERKLE_TREE_HEIGHT) - 1;
+        let last_leaf = (1 << (MERKLE_TREE_HEIGHT + 1)) - 2;
+        for index in [0, first_leaf - 1, last_leaf + 1] {
+            let leaf = MerkleRecord::new_leaf(index, DEFAULT_HASH_VEC[0]);
+            let error = MerkleTree::set_leaf(&mut merkle, &leaf).unwrap_err();
+            assert_eq!(error.code(), &MerkleErrorCode::InvalidLeafIndex);
+        }
+        for index in [first_leaf, last_leaf] {
+            assert!(merkle.leaf_check(index).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_record_json() {
+        let leaf = MerkleRecord::new_leaf((1 << MERKLE_TREE_HEIGHT) - 1, Hash::hash_data(&[1; 32]));
+        let record = MerkleRecord::new_non_leaf(1, leaf.hash, DEFAULT_HASH_VEC[0]);
+        let json = record.to_json();
+        assert!(json.contains(&hex::encode(DEFAULT_HASH_VEC[0].0)));
+        assert_eq!(MerkleRecord::from_json(json.as_bytes()).unwrap(), record);
+        assert!(MerkleRecord::from_json(b"{}").is_err());
+
+        let datahash_record = DataHashRecord::new(leaf.hash, vec![1; 32]);
+        let json = datahash_record.to_json();
+        assert_eq!(
+            DataHashRecord::from_json(json.as_bytes()).unwrap(),
+            datahash_record
+        );
+
+        // The creation time is kept, but does not tell records apart.
+        let created_at = DateTime::from_millis(1_688_212_800_000);
+        let stamped = record.with_created_at(created_at);
+        assert_eq!(stamped, record);
+        let json = stamped.to_json();
+        assert!(json.contains("2023-07-01T12:00:00"));
+        let parsed = MerkleRecord::from_json(json.as_bytes()).unwrap();
+        assert_eq!(parsed.created_at, Some(created_at));
+        let stamped = DataHashRecord {
+            created_at: Some(created_at),
+            ..datahash_record.clone()
+        };
+        assert_eq!(stamped, datahash_record);
+        let parsed = DataHashRecord::from_json(stamped.to_json().as_bytes()).unwrap();
+        assert_eq!(parsed.created_at, Some(created_at));
+
+        for node in [
+            Node::try_from(record).unwrap(),
+            Node::try_from((leaf, datahash_record)).unwrap(),
+        ] {
+            let json = node.to_json();
+            assert_eq!(Node::from_json(json.as_bytes()).unwrap(), node);
+        }
+        let json = Node::try_from(record).unwrap().to_json();
+        assert!(json.contains("\"NodeNonLeaf\""));
+        let malformed = json.replace("NodeNonLeaf", "NodeUnknown");
+        assert!(Node::from_json(malformed.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_zkwasm_merkle_proof() {
+        let proof = MerkleProof::<Hash, MERKLE_TREE_HEIGHT> {
+            source: DEFAULT_HASH_VEC[0],
+            root: DEFAULT_HASH_VEC[MERKLE_TREE_HEIGHT],
+            assist: DEFAULT_HASH_VEC[..MERKLE_TREE_HEIGHT]
+                .iter()
+                .rev()
+                .copied()
+                .collect(),
+            index: (1 << MERKLE_TREE_HEIGHT) - 1,
+        };
+        let zkwasm_proof = ZkwasmMerkleProof::from(&proof);
+        // Same as the root in test_new_merkle_root.
+        assert_eq!(
+            zkwasm_proof.root,
+            [
+                14789582351289948625,
+                10919489180071018470,
+                10309858136294505219,
+                2839580074036780766
+            ]
+        );
+        assert_eq!(
+            MerkleProof::<Hash, MERKLE_TREE_HEIGHT>::try_from(zkwasm_proof.clone()).unwrap(),
+            proof
+        );
+
+        let mut malformed = zkwasm_proof;
+        malformed.source = [u64::MAX; 4];
+        assert!(MerkleProof::<Hash, MERKLE_TREE_HEIGHT>::try_from(malformed).is_err());
     }
 
     #[test]
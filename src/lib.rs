@@ -1,8 +1,15 @@
+pub mod attestation;
+pub mod auth;
+pub mod client;
 pub mod errors;
+pub mod gateway;
+pub mod handle;
 pub mod kvpair;
 pub mod merkle;
+pub mod migrations;
 pub mod poseidon;
 pub mod service;
+pub mod snapshot;
 
 pub mod proto {
     pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("kvpair_descriptor");
@@ -1,54 +1,252 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+
 use futures::{channel::oneshot, FutureExt};
-use http::Method;
+use http::header::HeaderName;
+use http::{HeaderValue, Method};
 use tokio::signal;
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
 use tonic::transport::Server;
 use tonic_web::GrpcWebLayer;
-use tower_http::cors::{Any, CorsLayer};
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tower::util::option_layer;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
 
+use zkc_state_manager::auth::JwtAuth;
+use zkc_state_manager::errors::Error;
+use zkc_state_manager::gateway::Gateway;
 use zkc_state_manager::proto::{kv_pair_server::KvPairServer, FILE_DESCRIPTOR_SET};
 use zkc_state_manager::service::MongoKvPair;
 
+const DEFAULT_HOST: &str = "0.0.0.0";
+const DEFAULT_PORT: &str = "50051";
+const DEFAULT_CORS_METHODS: &str = "GET,POST";
+// The request headers sent by gRPC-web clients, plus the headers used for authentication.
+const DEFAULT_CORS_HEADERS: &str =
+    "content-type,x-grpc-web,x-user-agent,grpc-timeout,authorization,x-auth-contract-id";
+// gRPC-web clients need to read the status from the response headers.
+const CORS_EXPOSE_HEADERS: &str = "grpc-status,grpc-message,grpc-status-details-bin";
+
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// Read a comma separated list from the environment variable name.
+fn env_list(name: &str) -> Option<Vec<String>> {
+    std::env::var(name).ok().map(|list| split_list(&list))
+}
+
+fn parse_headers(headers: &[String]) -> Result<Vec<HeaderName>, String> {
+    headers
+        .iter()
+        .map(|header| {
+            HeaderName::from_bytes(header.as_bytes())
+                .map_err(|e| format!("Invalid header name {header:?}: {e}"))
+        })
+        .collect()
+}
+
+fn cors_layer(dev_mode: bool) -> Result<CorsLayer, String> {
+    let origin = match env_list("KVPAIR_CORS_ORIGINS") {
+        Some(origins) => AllowOrigin::list(
+            origins
+                .iter()
+                .map(|origin| {
+                    HeaderValue::from_str(origin)
+                        .map_err(|e| format!("Invalid KVPAIR_CORS_ORIGINS {origin:?}: {e}"))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+        // Allowing requests from any origin is only acceptable for local development.
+        None if dev_mode => AllowOrigin::from(Any),
+        None => {
+            println!("KVPAIR_CORS_ORIGINS not set, cross-origin requests are not allowed");
+            AllowOrigin::list(Vec::<HeaderValue>::new())
+        }
+    };
+    let methods = env_list("KVPAIR_CORS_METHODS")
+        .unwrap_or_else(|| split_list(DEFAULT_CORS_METHODS))
+        .iter()
+        .map(|method| {
+            Method::from_bytes(method.as_bytes())
+                .map_err(|e| format!("Invalid KVPAIR_CORS_METHODS {method:?}: {e}"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let headers =
+        env_list("KVPAIR_CORS_HEADERS").unwrap_or_else(|| split_list(DEFAULT_CORS_HEADERS));
+
+    Ok(CorsLayer::new()
+        .allow_origin(origin)
+        .allow_methods(methods)
+        .allow_headers(parse_headers(&headers)?)
+        .expose_headers(parse_headers(&split_list(CORS_EXPOSE_HEADERS))?))
+}
+
+// The address to listen on, from KVPAIR_HOST (an IP address or a host name) and the port read
+// from the environment variable port_name.
+fn listen_addr(port_name: &str, port: String) -> Result<SocketAddr, String> {
+    let host = std::env::var("KVPAIR_HOST").unwrap_or(DEFAULT_HOST.to_string());
+    let port: u16 = port
+        .parse()
+        .map_err(|e| format!("Invalid {port_name} {port:?}: {e}"))?;
+    // IPv6 addresses may be written with brackets, as in URLs.
+    let ip = host.trim_start_matches('[').trim_end_matches(']');
+    (ip, port)
+        .to_socket_addrs()
+        .map_err(|e| format!("Invalid KVPAIR_HOST {host:?}: {e}"))?
+        .next()
+        .ok_or_else(|| format!("KVPAIR_HOST {host:?} does not resolve to any address"))
+}
+
+// Wait for ctrl-c, or SIGTERM which is what Kubernetes sends to stop a pod.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                eprintln!("Unable to listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        result = signal::ctrl_c() => {
+            if let Err(err) = result {
+                eprintln!("Unable to listen for shutdown signal: {}", err);
+            }
+        }
+        _ = terminate => {}
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = format!(
-        "0.0.0.0:{}",
-        std::env::var("KVPAIR_PORT").unwrap_or("50051".to_string())
-    )
-    .parse()
-    .unwrap();
+    // Returning the error from main would print its Debug representation, which is hard to
+    // read in the logs, so print it ourselves and exit with a non-zero status.
+    if let Err(e) = run().await {
+        eprintln!("Error: {e}");
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let port = std::env::var("KVPAIR_PORT").unwrap_or(DEFAULT_PORT.to_string());
+    let addr = listen_addr("KVPAIR_PORT", port)?;
+    // The HTTP/JSON gateway is only served if a port is configured for it.
+    let http_addr = std::env::var("KVPAIR_HTTP_PORT")
+        .ok()
+        .map(|port| listen_addr("KVPAIR_HTTP_PORT", port))
+        .transpose()?;
 
     let reflection_service = tonic_reflection::server::Builder::configure()
         .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
-        .build()
-        .unwrap();
+        .build()?;
 
-    let server = MongoKvPair::new().await;
-    let server = KvPairServer::new(server);
+    let server = MongoKvPair::try_new().await?;
+    let cors = cors_layer(server.config().dev_mode)?;
+    let config = server.config().clone();
+    if config.signing_key.is_none() {
+        println!("KVPAIR_SIGNING_KEY not set, roots are not signed");
+    }
+    if config.migrate_on_startup {
+        let failed = server.migrate_all_contracts().await?;
+        if failed > 0 {
+            eprintln!("{failed} contracts not migrated to the latest schema version");
+        }
+    }
+    if let Some(interval) = config.retention_interval {
+        tokio::spawn(server.clone().run_retention_periodically(interval));
+    }
+    if config.watch_root_changes {
+        tokio::spawn(server.clone().watch_root_changes());
+    }
+    let kvpair = server.clone();
+    // Reject oversized messages before decoding them.
+    let mut server = KvPairServer::new(server)
+        .max_decoding_message_size(config.max_decoding_message_size)
+        .max_encoding_message_size(config.max_encoding_message_size);
+    // Responses are only compressed for the clients accepting the encoding, so gRPC-web clients,
+    // which usually don't, still get uncompressed responses.
+    for encoding in &config.compression_encodings {
+        server = server
+            .accept_compressed(*encoding)
+            .send_compressed(*encoding);
+    }
+    let mut jwt_auth = JwtAuth::from_env()?.map(|auth| auth.with_dev_mode(config.dev_mode));
+    if jwt_auth.is_none() {
+        println!("JWT authentication not configured, contract ids are not authenticated");
+    }
+    // Shared by all the connections, of the gRPC server and of the HTTP gateway. The permit of a
+    // streaming RPC is released once its response starts, so long-lived streams do not hold it.
+    let concurrency_limit = config
+        .max_concurrent_requests
+        .map(GlobalConcurrencyLimitLayer::new);
+    if let Some(http_addr) = http_addr {
+        let mut gateway = Gateway::new(kvpair.clone())
+            .with_auth(jwt_auth.clone())
+            .into_router();
+        if let Some(concurrency_limit) = &concurrency_limit {
+            gateway = gateway.layer(concurrency_limit.clone());
+        }
+        let gateway = gateway.layer(cors.clone());
+        let http_server = axum::Server::try_bind(&http_addr)?.serve(gateway.into_make_service());
+        println!("HTTP gateway listening on {}", http_addr);
+        // The gateway rejects new requests while the server drains, and stops with it.
+        tokio::spawn(async move {
+            if let Err(e) = http_server.await {
+                eprintln!("HTTP gateway error: {e}");
+            }
+        });
+    }
+    let draining = kvpair.clone();
+    let server = InterceptedService::new(server, move |request: tonic::Request<()>| {
+        // Clients should retry new requests with another server while this one drains.
+        if draining.is_shutting_down() {
+            return Err(Error::Unavailable("Server is shutting down".to_string()).into());
+        }
+        match jwt_auth.as_mut() {
+            Some(auth) => auth.call(request),
+            None => Ok(request),
+        }
+    });
 
     println!("Server listening on {}", addr);
     let (send, recv) = oneshot::channel();
+    let drain_period = config.shutdown_drain_period;
     tokio::spawn(async move {
-        match signal::ctrl_c().await {
-            Ok(()) => {}
-            Err(err) => {
-                eprintln!("Unable to listen for shutdown signal: {}", err);
-            }
-        };
-        println!("Shutting down");
+        shutdown_signal().await;
+        println!(
+            "Shutting down, waiting up to {drain_period:?} for {} writes in flight",
+            kvpair.writes_in_flight()
+        );
+        if !kvpair.shutdown(drain_period).await {
+            eprintln!(
+                "{} writes still in flight after {drain_period:?}, aborting them",
+                kvpair.writes_in_flight()
+            );
+        }
         send.send(()).expect("Send shutdown signal");
     });
 
-    let cors = CorsLayer::new()
-        // allow `GET` and `POST` when accessing the resource
-        .allow_methods([Method::GET, Method::POST])
-        // allow requests from any origin
-        .allow_origin(Any);
-
     Server::builder()
+        .http2_keepalive_interval(config.http2_keepalive_interval)
+        .http2_keepalive_timeout(Some(config.http2_keepalive_timeout))
         // GrpcWeb is over http1 so we must enable it.
         .accept_http1(true)
         .layer(GrpcWebLayer::new())
         .layer(cors)
+        .layer(option_layer(concurrency_limit))
         .add_service(reflection_service)
         .add_service(tonic_web::enable(server))
         .serve_with_shutdown(addr, recv.map(drop))
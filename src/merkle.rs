@@ -11,6 +11,12 @@ pub mod utils {
     use super::*;
     use crate::proto::NodeType;
 
+    /// The depth of the node, i.e. 0 for the root and the height of the tree for the leaves.
+    /// The index must be a node of the tree, see boundary_check.
+    pub fn get_depth(index: u64) -> usize {
+        (index + 1).ilog2() as usize
+    }
+
     pub fn get_offset(index: u64) -> u64 {
         let height = (index + 1).ilog2();
         let full = (1u64 << height) - 1;
@@ -65,6 +71,32 @@ pub mod utils {
         }
     }
 
+    /// Check that index is a leaf of a tree of the given height, i.e. in
+    /// [2^height - 1, 2^(height + 1) - 2], with an error stating the valid range otherwise.
+    pub fn validate_leaf_index(index: u64, height: usize) -> Result<(), crate::Error> {
+        let (first, last) = ((1u64 << height) - 1, (1u64 << (height + 1)) - 2);
+        if index < first || index > last {
+            return Err(crate::Error::InvalidIndex(format!(
+                "Invalid leaf index {index}, must be in [{first}, {last}]"
+            ))
+            .with_detail("index", index));
+        }
+        Ok(())
+    }
+
+    /// Check that index is a non-leaf node of a tree of the given height, i.e. less than
+    /// 2^height - 1, with an error stating the valid range otherwise.
+    pub fn validate_internal_index(index: u64, height: usize) -> Result<(), crate::Error> {
+        let first_leaf = (1u64 << height) - 1;
+        if index >= first_leaf {
+            return Err(crate::Error::InvalidIndex(format!(
+                "Invalid non-leaf index {index}, must be less than {first_leaf}"
+            ))
+            .with_detail("index", index));
+        }
+        Ok(())
+    }
+
     pub fn get_sibling_index(index: u64) -> u64 {
         if index % 2 == 1 {
             index + 1
@@ -84,6 +116,14 @@ pub mod utils {
     /// get_path(15) = [6, 2]
     pub fn get_path(index: u64, height: usize) -> Result<Vec<u64>, MerkleError> {
         leaf_check(index, height)?;
+        get_node_path(index, height)
+    }
+
+    /// Same as get_path, but the index may be any node of the tree, not only a leaf.
+    /// Example: Given the tree above, get_node_path(3) = [1, 3] and get_node_path(0) = [].
+    /// The length of the path is the depth of the node.
+    pub fn get_node_path(index: u64, height: usize) -> Result<Vec<u64>, MerkleError> {
+        boundary_check(index, height)?;
         let mut height = (index + 1).ilog2();
         let round = height;
         let full = (1u64 << height) - 1;
@@ -108,12 +148,16 @@ const LEAF_SIG: u8 = 0u8;
 const INTERNAL_SIG: u8 = 1u8;
 */
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MerkleErrorCode {
     InvalidLeafIndex,
     InvalidHash,
     InvalidDepth,
     InvalidIndex,
+    // The node with the given index and hash is not stored.
+    NodeNotFound,
+    // The node is malformed or its hash does not match its children.
+    InvalidNode,
     InvalidOther,
 }
 
@@ -132,6 +176,10 @@ impl MerkleError {
             code,
         }
     }
+
+    pub fn code(&self) -> &MerkleErrorCode {
+        &self.code
+    }
 }
 
 impl fmt::Display for MerkleError {
@@ -154,7 +202,7 @@ pub trait MerkleNode<H: Debug + Clone + PartialEq> {
     fn right(&self) -> Option<H>; // hash of right child
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct MerkleProof<H: Debug + Clone + PartialEq + Serialize, const D: usize> {
     pub source: H,
     pub root: H, // last is root
@@ -380,6 +428,53 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_node_path() {
+        use super::utils::{get_depth, get_node_path, get_path};
+        assert_eq!(get_node_path(0, 3).unwrap(), Vec::<u64>::new());
+        assert_eq!(get_node_path(3, 3).unwrap(), [1, 3]);
+        assert_eq!(get_node_path(7, 3).unwrap(), get_path(7, 3).unwrap());
+        assert!(get_node_path(15, 3).is_err());
+        assert!(get_path(3, 3).is_err());
+        assert_eq!(get_depth(0), 0);
+        assert_eq!(get_depth(6), 2);
+        assert_eq!(get_depth(7), 3);
+    }
+
+    #[test]
+    fn test_validate_index() {
+        use super::utils::{validate_internal_index, validate_leaf_index};
+        use crate::Error;
+        for index in [7, 10, 14] {
+            assert!(validate_leaf_index(index, 3).is_ok());
+            assert!(validate_internal_index(index, 3).is_err());
+        }
+        for index in [0, 3, 6] {
+            assert!(validate_internal_index(index, 3).is_ok());
+            assert!(validate_leaf_index(index, 3).is_err());
+        }
+        // Just beyond the last leaf.
+        for index in [15, u64::MAX] {
+            let error = validate_leaf_index(index, 3).unwrap_err();
+            assert!(matches!(error.inner(), Error::InvalidIndex(_)));
+            assert_eq!(error.detail("index"), Some(index.to_string().as_str()));
+            assert!(validate_internal_index(index, 3).is_err());
+        }
+        let error = validate_internal_index(7, 3).unwrap_err();
+        assert!(matches!(error.inner(), Error::InvalidIndex(_)));
+        // A tree of height 0 only has the root, which is a leaf.
+        assert!(validate_leaf_index(0, 0).is_ok());
+        assert!(validate_internal_index(0, 0).is_err());
+
+        let height = crate::kvpair::MERKLE_TREE_HEIGHT;
+        assert!(validate_leaf_index((1 << height) - 2, height).is_err());
+        assert!(validate_leaf_index((1 << height) - 1, height).is_ok());
+        assert!(validate_leaf_index((1 << (height + 1)) - 2, height).is_ok());
+        assert!(validate_leaf_index((1 << (height + 1)) - 1, height).is_err());
+        assert!(validate_internal_index((1 << height) - 2, height).is_ok());
+        assert!(validate_internal_index((1 << height) - 1, height).is_err());
+    }
+
     #[test]
     fn test_merkle_path() {
         let mut mt = MerkleAsArray::construct("test".to_string(), "test".to_string());
@@ -0,0 +1,75 @@
+use futures::future::BoxFuture;
+
+use crate::kvpair::{DataHashRecord, MerkleRecord};
+use crate::service::MongoCollection;
+use crate::Error;
+
+/// The schema version of the contracts whose metadata does not record one, i.e. those written
+/// before schema versions were introduced.
+pub const INITIAL_SCHEMA_VERSION: u32 = 1;
+/// The oldest schema version whose records this binary reads. Contracts at older versions are
+/// refused until they are upgraded with MongoKvPair::migrate_contract.
+pub const MIN_SCHEMA_VERSION: u32 = INITIAL_SCHEMA_VERSION;
+/// The schema version of the records written by this binary. Contracts with an older version
+/// (down to MIN_SCHEMA_VERSION) are still served and can be upgraded with
+/// MongoKvPair::migrate_contract, contracts with a newer version are refused.
+pub const SCHEMA_VERSION: u32 = 2;
+// The number of records a migration step changes per query.
+pub const MIGRATION_BATCH_SIZE: usize = 1000;
+
+type MigrationStep =
+    for<'a> fn(&'a mut MongoCollection<MerkleRecord, DataHashRecord>, usize) -> MigrationFuture<'a>;
+type MigrationFuture<'a> = BoxFuture<'a, Result<(usize, bool), Error>>;
+
+/// A step upgrading the records of a contract from the previous schema version to version.
+///
+/// run changes at most the given number of records, and returns how many it changed and whether
+/// the step is done. Steps find the records left to change from the records themselves, so that
+/// a step interrupted (or stopped by the limit) continues where it stopped when run again, and
+/// running a step which is done changes nothing.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub run: MigrationStep,
+}
+
+/// All the migrations, ordered by version.
+pub const MIGRATIONS: &[Migration] = &[Migration {
+    version: 2,
+    description: "Remove the unused data field of merkle records",
+    run: remove_merkle_record_data,
+}];
+
+fn remove_merkle_record_data(
+    collection: &mut MongoCollection<MerkleRecord, DataHashRecord>,
+    limit: usize,
+) -> MigrationFuture<'_> {
+    Box::pin(collection.remove_merkle_record_data(limit))
+}
+
+/// The migrations upgrading a contract at the given version to SCHEMA_VERSION, in order.
+pub fn pending_migrations(version: u32) -> impl Iterator<Item = &'static Migration> {
+    MIGRATIONS
+        .iter()
+        .filter(move |migration| migration.version > version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrations_are_consecutive() {
+        let versions = MIGRATIONS
+            .iter()
+            .map(|migration| migration.version)
+            .collect::<Vec<_>>();
+        let expected = (INITIAL_SCHEMA_VERSION + 1..=SCHEMA_VERSION).collect::<Vec<_>>();
+        assert_eq!(versions, expected);
+        assert_eq!(
+            pending_migrations(INITIAL_SCHEMA_VERSION).count(),
+            MIGRATIONS.len()
+        );
+        assert_eq!(pending_migrations(SCHEMA_VERSION).count(), 0);
+    }
+}
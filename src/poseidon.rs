@@ -1,13 +1,147 @@
+use std::sync::OnceLock;
+
 use ff::PrimeField;
 use halo2_proofs::pairing::bn256::Fr;
 use poseidon::Poseidon;
 
 use crate::errors::Error;
+use crate::kvpair::Hash;
+use crate::proto::PoseidonHashAlgorithm;
 
 pub const PREFIX_CHALLENGE: u64 = 0u64;
 pub const PREFIX_POINT: u64 = 1u64;
 pub const PREFIX_SCALAR: u64 = 2u64;
 
+/// The numbers of full and partial rounds of a Poseidon hasher.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PoseidonSpec {
+    pub full_rounds: usize,
+    pub partial_rounds: usize,
+}
+
+impl PoseidonSpec {
+    pub const fn new(full_rounds: usize, partial_rounds: usize) -> Self {
+        Self {
+            full_rounds,
+            partial_rounds,
+        }
+    }
+}
+
+/// The parameters of the three hashers below. Their widths are fixed by their types, only their
+/// rounds can be changed, e.g. for a deployment with another Poseidon parameterization. The
+/// default is the parameterization of zkWasm-host-circuits.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct HashConfig {
+    // The POSEIDON_HASHER, which hashes arbitrary field elements (see hash_field_elements).
+    pub poseidon: PoseidonSpec,
+    // The MERKLE_HASHER, which hashes the children of a non-leaf node.
+    pub merkle: PoseidonSpec,
+    // The MERKLE_LEAF_HASHER, which hashes the 32 bytes of leaf data.
+    pub merkle_leaf: PoseidonSpec,
+}
+
+impl Default for HashConfig {
+    fn default() -> Self {
+        Self {
+            poseidon: PoseidonSpec::new(8, 63),
+            merkle: PoseidonSpec::new(8, 57),
+            merkle_leaf: PoseidonSpec::new(8, 57),
+        }
+    }
+}
+
+impl HashConfig {
+    /// Read the rounds of each hasher from KVPAIR_POSEIDON_ROUNDS, KVPAIR_MERKLE_ROUNDS and
+    /// KVPAIR_MERKLE_LEAF_ROUNDS, given as `<full rounds>,<partial rounds>` (e.g. `8,57`).
+    /// The hashers not configured keep their default rounds.
+    pub fn from_env() -> Result<Self, String> {
+        let default = Self::default();
+        Ok(Self {
+            poseidon: spec_from_env("KVPAIR_POSEIDON_ROUNDS")?.unwrap_or(default.poseidon),
+            merkle: spec_from_env("KVPAIR_MERKLE_ROUNDS")?.unwrap_or(default.merkle),
+            merkle_leaf: spec_from_env("KVPAIR_MERKLE_LEAF_ROUNDS")?.unwrap_or(default.merkle_leaf),
+        })
+    }
+}
+
+fn spec_from_env(name: &str) -> Result<Option<PoseidonSpec>, String> {
+    let rounds = match std::env::var(name) {
+        Ok(rounds) => rounds,
+        Err(_) => return Ok(None),
+    };
+    let (full_rounds, partial_rounds) = rounds
+        .split_once(',')
+        .ok_or_else(|| format!("Parse {name}: expected <full rounds>,<partial rounds>"))?;
+    Ok(Some(PoseidonSpec::new(
+        full_rounds
+            .trim()
+            .parse()
+            .map_err(|e| format!("Parse {name}: {e}"))?,
+        partial_rounds
+            .trim()
+            .parse()
+            .map_err(|e| format!("Parse {name}: {e}"))?,
+    )))
+}
+
+static HASH_CONFIG: OnceLock<HashConfig> = OnceLock::new();
+
+/// Set the config all the hashes of this process are computed with. As some hashes are computed
+/// once and kept (e.g. DEFAULT_HASH_VEC), this fails if another config is already in use, i.e.
+/// if it was set before or if anything was hashed with the default config.
+pub fn set_hash_config(config: HashConfig) -> Result<(), Error> {
+    let current = HASH_CONFIG.get_or_init(|| config);
+    if *current != config {
+        return Err(Error::Precondition(format!(
+            "Hash config {current:?} already in use, can not switch to {config:?}"
+        )));
+    }
+    Ok(())
+}
+
+/// The config all the hashes of this process are computed with, see set_hash_config.
+pub fn hash_config() -> &'static HashConfig {
+    HASH_CONFIG.get_or_init(HashConfig::default)
+}
+
+// The hashers of the process-wide config. Constructing a hasher computes its round constants and
+// MDS matrices, which is far more expensive than a hash, so they are constructed once and cloned
+// for each hash. A clone starts from the initial state, so no state is shared between hashes.
+struct Hashers {
+    poseidon: Poseidon<Fr, 9, 8>,
+    merkle: Poseidon<Fr, 3, 2>,
+    merkle_leaf: Poseidon<Fr, 3, 2>,
+}
+
+static HASHERS: OnceLock<Hashers> = OnceLock::new();
+
+fn hashers() -> &'static Hashers {
+    HASHERS.get_or_init(|| {
+        let config = hash_config();
+        Hashers {
+            poseidon: gen_poseidon_hasher(config),
+            merkle: gen_merkle_hasher(config),
+            merkle_leaf: gen_merkle_leaf_hasher(config),
+        }
+    })
+}
+
+/// A fresh POSEIDON_HASHER of the process-wide config, see hash_config.
+pub fn poseidon_hasher() -> Poseidon<Fr, 9, 8> {
+    hashers().poseidon.clone()
+}
+
+/// A fresh MERKLE_HASHER of the process-wide config, see hash_config.
+pub fn merkle_hasher() -> Poseidon<Fr, 3, 2> {
+    hashers().merkle.clone()
+}
+
+/// A fresh MERKLE_LEAF_HASHER of the process-wide config, see hash_config.
+pub fn merkle_leaf_hasher() -> Poseidon<Fr, 3, 2> {
+    hashers().merkle_leaf.clone()
+}
+
 /// There are three variants of haser used in upstream.
 /// https://github.com/DelphinusLab/zkWasm-host-circuits/blob/e3a2eff4583b2fd8be7fc3e54f2789cbfbfd72d4/src/host/poseidon.rs#L9-L20
 /// This function creates a hasher equivalent to the POSEIDON_HASHER.
@@ -21,8 +155,10 @@ pub const PREFIX_SCALAR: u64 = 2u64;
 ///     pub static ref MERKLE_LEAF_HASHER_SPEC: poseidon::Spec<Fr, 3, 2> = Spec::new(8, 57);
 /// }
 /// ```
-pub fn gen_poseidon_hasher() -> Poseidon<Fr, 9, 8> {
-    Poseidon::<Fr, 9, 8>::new(8, 63)
+/// The rounds are the `poseidon` rounds of the config.
+pub fn gen_poseidon_hasher(config: &HashConfig) -> Poseidon<Fr, 9, 8> {
+    let spec = config.poseidon;
+    Poseidon::<Fr, 9, 8>::new(spec.full_rounds, spec.partial_rounds)
 }
 
 /// There are three variants of haser used in upstream.
@@ -38,8 +174,10 @@ pub fn gen_poseidon_hasher() -> Poseidon<Fr, 9, 8> {
 ///     pub static ref MERKLE_LEAF_HASHER_SPEC: poseidon::Spec<Fr, 3, 2> = Spec::new(8, 57);
 /// }
 /// ```
-pub fn gen_merkle_hasher() -> Poseidon<Fr, 3, 2> {
-    Poseidon::<Fr, 3, 2>::new(8, 57)
+/// The rounds are the `merkle` rounds of the config.
+pub fn gen_merkle_hasher(config: &HashConfig) -> Poseidon<Fr, 3, 2> {
+    let spec = config.merkle;
+    Poseidon::<Fr, 3, 2>::new(spec.full_rounds, spec.partial_rounds)
 }
 
 /// There are three variants of haser used in upstream.
@@ -55,13 +193,19 @@ pub fn gen_merkle_hasher() -> Poseidon<Fr, 3, 2> {
 ///     pub static ref MERKLE_LEAF_HASHER_SPEC: poseidon::Spec<Fr, 3, 2> = Spec::new(8, 57);
 /// }
 /// ```
-pub fn gen_merkle_leaf_hasher() -> Poseidon<Fr, 3, 2> {
-    Poseidon::<Fr, 3, 2>::new(8, 57)
+/// The rounds are the `merkle_leaf` rounds of the config.
+pub fn gen_merkle_leaf_hasher(config: &HashConfig) -> Poseidon<Fr, 3, 2> {
+    let spec = config.merkle_leaf;
+    Poseidon::<Fr, 3, 2>::new(spec.full_rounds, spec.partial_rounds)
 }
 
+/// Hash field elements with the POSEIDON_HASHER (width 9, rate 8), the hasher upstream uses to
+/// hash data, see gen_poseidon_hasher. All the data hashes below (hash, hash_with_padding and
+/// hash_long_data) go through it; only the merkle nodes and hash_merkle_leaf are hashed with the
+/// 3,2 hashers.
 pub fn hash_field_elements(frs: &[Fr]) -> <Fr as PrimeField>::Repr {
     dbg!(frs);
-    let mut hasher = gen_poseidon_hasher();
+    let mut hasher = poseidon_hasher();
     hasher.update(frs);
     let hash = hasher.squeeze().to_repr();
     dbg!(&hash);
@@ -77,9 +221,10 @@ pub fn hash_field_elements(frs: &[Fr]) -> <Fr as PrimeField>::Repr {
 pub fn hash_with_padding(data_to_hash: &[u8]) -> Result<<Fr as PrimeField>::Repr, Error> {
     let num_of_bytes: usize = 32;
     if data_to_hash.len() % num_of_bytes != 0 {
-        return Err(Error::InvalidArgument(
-            "Invalid data to hash, must be an array of field elements".to_string(),
-        ));
+        return Err(Error::InvalidArgument(format!(
+            "Invalid data to hash, length must be a multiple of {num_of_bytes} bytes, given {}",
+            data_to_hash.len()
+        )));
     }
     let frs = data_to_hash
         .chunks(16)
@@ -95,23 +240,28 @@ pub fn hash_with_padding(data_to_hash: &[u8]) -> Result<<Fr as PrimeField>::Repr
 }
 
 /// Hash data from an array of 32 bytes. Each 32 bytes must be a valid field element.
+/// The field elements are hashed with hash_field_elements, i.e. the 9,8 POSEIDON_HASHER.
 pub fn hash(data_to_hash: &[u8]) -> Result<<Fr as PrimeField>::Repr, Error> {
     dbg!(data_to_hash);
     let num_of_bytes: usize = 32;
     if data_to_hash.len() % num_of_bytes != 0 {
-        return Err(Error::InvalidArgument(
-            "Invalid data to hash, must be an array of field elements".to_string(),
-        ));
+        return Err(Error::InvalidArgument(format!(
+            "Invalid data to hash, length must be a multiple of {num_of_bytes} bytes, given {}",
+            data_to_hash.len()
+        )));
     }
     let frs = data_to_hash
         .chunks(num_of_bytes)
-        .map(|x| {
+        .enumerate()
+        .map(|(i, x)| {
             let v = x.try_into().unwrap();
             let f = Fr::from_repr(v);
             if f.is_none().into() {
-                return Err(Error::InvalidArgument(
-                    "Invalid data to hash, must be an array of field elements".to_string(),
-                ));
+                return Err(Error::InvalidArgument(format!(
+                    "Invalid data to hash, bytes {}..{} is not a valid field element",
+                    i * num_of_bytes,
+                    (i + 1) * num_of_bytes
+                )));
             }
             Ok(f.unwrap())
         })
@@ -119,6 +269,67 @@ pub fn hash(data_to_hash: &[u8]) -> Result<<Fr as PrimeField>::Repr, Error> {
     Ok(hash_field_elements(&frs))
 }
 
+/// Hash data of exactly 32 bytes in the same way as we hash the merkle leaves,
+/// i.e. with the `update_exact` method of the hasher from `gen_merkle_leaf_hasher`.
+pub fn hash_merkle_leaf(data_to_hash: &[u8]) -> Result<<Fr as PrimeField>::Repr, Error> {
+    let num_of_bytes: usize = 32;
+    if data_to_hash.len() != num_of_bytes {
+        return Err(Error::InvalidArgument(format!(
+            "Invalid data to hash, length must be exactly {num_of_bytes} bytes, given {}",
+            data_to_hash.len()
+        )));
+    }
+    Ok(Hash::hash_data(data_to_hash).0)
+}
+
+/// The last field element absorbed by `hash_long_data`. `hash_field_elements` pads the last block
+/// with a one followed by zeros, so that its last element is either 0 or 1, and whatever the data
+/// no short leaf can absorb the same blocks as a long one.
+pub const LONG_DATA_DOMAIN_TAG: u64 = 2;
+
+/// Hash data of arbitrary length. The length of the data in bytes is absorbed first,
+/// then the data is split into 16 bytes chunks (the last one padded with zeros),
+/// each of which is converted into a field element. The elements are padded with zeros
+/// and `LONG_DATA_DOMAIN_TAG` to whole blocks, which are absorbed with `update_exact`.
+pub fn hash_long_data(data_to_hash: &[u8]) -> <Fr as PrimeField>::Repr {
+    const RATE: usize = 8;
+    let mut frs = vec![Fr::from(data_to_hash.len() as u64)];
+    frs.extend(data_to_hash.chunks(16).map(|x| {
+        let mut v = x.to_vec();
+        v.resize(32, 0);
+        let f = v.try_into().unwrap();
+        Fr::from_repr(f).unwrap()
+    }));
+    frs.resize(frs.len() / RATE * RATE + RATE - 1, Fr::from(0u64));
+    frs.push(Fr::from(LONG_DATA_DOMAIN_TAG));
+    let mut hasher = poseidon_hasher();
+    let mut hash = Fr::from(0u64);
+    for block in frs.chunks(RATE) {
+        let block: &[Fr; RATE] = block.try_into().unwrap();
+        hash = hasher.update_exact(block);
+    }
+    hash.to_repr()
+}
+
+/// Hash the data of a merkle leaf. Data that is an array of valid field elements
+/// is hashed with `hash` as before, so that existing leaves keep their hashes.
+/// Everything else is hashed with `hash_long_data`.
+pub fn hash_leaf_data(data_to_hash: &[u8]) -> <Fr as PrimeField>::Repr {
+    hash(data_to_hash).unwrap_or_else(|_| hash_long_data(data_to_hash))
+}
+
+/// Hash data with the algorithm specified in the PoseidonHash RPC.
+pub fn hash_with_algorithm(
+    algorithm: PoseidonHashAlgorithm,
+    data_to_hash: &[u8],
+) -> Result<<Fr as PrimeField>::Repr, Error> {
+    match algorithm {
+        PoseidonHashAlgorithm::HashFieldElements => hash(data_to_hash),
+        PoseidonHashAlgorithm::HashPadded16Bytes => hash_with_padding(data_to_hash),
+        PoseidonHashAlgorithm::HashMerkleLeaf => hash_merkle_leaf(data_to_hash),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -129,28 +340,124 @@ mod tests {
     fn test_merkle_leaf_hash_zero() {
         const ZERO_HASHER_SQUEEZE: &str =
             "0x0ac6c5f29f5187473a70dfde3329ef18f01a4d84edb01e6c21813f629a6b5f50";
-        let mut hasher = super::gen_merkle_leaf_hasher();
-        hasher.update(&[Fr::zero()]);
+        let mut hasher = super::gen_merkle_leaf_hasher(&HashConfig::default());
+        hasher.update(&[Fr::from(0u64)]);
         let result = hasher.squeeze();
         println!("hash result is {:?}", result);
         assert_eq!(result.to_string(), ZERO_HASHER_SQUEEZE);
     }
 
+    // The leaf hasher and the merkle hasher share the same parameters upstream. Check that
+    // neither of them drifts.
+    #[test]
+    fn test_merkle_leaf_hasher_same_as_merkle_hasher() {
+        let config = HashConfig::default();
+        let values = [Fr::from(1u64), Fr::from(2u64)];
+        let leaf_hash = super::gen_merkle_leaf_hasher(&config).update_exact(&values);
+        let hash = super::gen_merkle_hasher(&config).update_exact(&values);
+        assert_eq!(leaf_hash, hash);
+    }
+
+    // The cached hashers give the same hashes as new ones, however many times they are used.
+    #[test]
+    fn test_cached_hashers() {
+        let config = HashConfig::default();
+        let values = [Fr::from(1u64), Fr::from(2u64)];
+        for _ in 0..3 {
+            assert_eq!(
+                super::merkle_hasher().update_exact(&values),
+                super::gen_merkle_hasher(&config).update_exact(&values)
+            );
+            assert_eq!(
+                super::merkle_leaf_hasher().update_exact(&values),
+                super::gen_merkle_leaf_hasher(&config).update_exact(&values)
+            );
+            let mut hasher = super::poseidon_hasher();
+            hasher.update(&values);
+            let mut expected = super::gen_poseidon_hasher(&config);
+            expected.update(&values);
+            assert_eq!(hasher.squeeze(), expected.squeeze());
+        }
+    }
+
+    // Compare the time to compute the paths of a batch of 1000 leaves with the cached hashers and
+    // with hashers constructed for each hash, run with
+    // `cargo test --release bench_merkle_hashers -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_merkle_hashers() {
+        const LEAVES: u64 = 1000;
+        let config = HashConfig::default();
+        let time = |gen: &dyn Fn() -> Poseidon<Fr, 3, 2>| {
+            let start = std::time::Instant::now();
+            let mut roots = vec![];
+            for leaf in 0..LEAVES {
+                let mut hash = gen().update_exact(&[Fr::from(leaf), Fr::from(0u64)]);
+                for _ in 0..crate::kvpair::MERKLE_TREE_HEIGHT {
+                    hash = gen().update_exact(&[hash, hash]);
+                }
+                roots.push(hash);
+            }
+            (start.elapsed(), roots)
+        };
+        let (cached, cached_roots) = time(&super::merkle_hasher);
+        let (constructed, constructed_roots) = time(&|| super::gen_merkle_hasher(&config));
+        assert_eq!(cached_roots, constructed_roots);
+        println!("{LEAVES} leaves: cached hashers {cached:?}, constructed hashers {constructed:?}");
+    }
+
+    #[test]
+    fn test_hash_config() {
+        let values = [Fr::from(1u64), Fr::from(2u64)];
+        let default = HashConfig::default();
+        let config = HashConfig {
+            merkle: PoseidonSpec::new(8, 56),
+            ..default
+        };
+        assert_ne!(
+            super::gen_merkle_hasher(&config).update_exact(&values),
+            super::gen_merkle_hasher(&default).update_exact(&values)
+        );
+        assert_eq!(
+            super::gen_merkle_leaf_hasher(&config).update_exact(&values),
+            super::gen_merkle_leaf_hasher(&default).update_exact(&values)
+        );
+
+        // The process-wide config can not be switched once in use.
+        assert_eq!(*hash_config(), default);
+        assert!(set_hash_config(default).is_ok());
+        assert!(set_hash_config(config).is_err());
+        assert_eq!(*hash_config(), default);
+    }
+
     #[test]
     fn test_poseidon_hash_zero() {
         const ZERO_HASHER_SQUEEZE: &str =
             "0x03f943aabd67cd7b72a539f3de686c3280c36c572be09f2b9193f5ef78761c6b";
-        let mut hasher = super::gen_poseidon_hasher();
-        hasher.update(&[Fr::zero()]);
+        let mut hasher = super::gen_poseidon_hasher(&HashConfig::default());
+        hasher.update(&[Fr::from(0u64)]);
         let result = hasher.squeeze();
         println!("hash result is {:?}", result);
         assert_eq!(result.to_string(), ZERO_HASHER_SQUEEZE);
     }
 
+    // hash uses the POSEIDON_HASHER, not the 3,2 hashers: hashing a zero field element gives the
+    // upstream test vector of the POSEIDON_HASHER (see test_poseidon_hash_zero).
+    #[test]
+    fn test_hash_upstream_vector() {
+        const ZERO_HASHER_SQUEEZE: &str =
+            "0x03f943aabd67cd7b72a539f3de686c3280c36c572be09f2b9193f5ef78761c6b";
+        let result = Fr::from_repr(hash(&[0; 32]).unwrap()).unwrap();
+        assert_eq!(result.to_string(), ZERO_HASHER_SQUEEZE);
+        let mut hasher = super::gen_merkle_leaf_hasher(&HashConfig::default());
+        hasher.update(&[Fr::from(0u64)]);
+        assert_ne!(hasher.squeeze(), result);
+    }
+
     #[test]
     fn test_poseidon_hash_equivalent() {
-        let mut hasher = super::gen_poseidon_hasher();
-        hasher.update(&[Fr::zero()]);
+        let mut hasher = super::gen_poseidon_hasher(&HashConfig::default());
+        hasher.update(&[Fr::from(0u64)]);
         let result = hasher.squeeze().to_repr();
         println!("hash result is {:?}", result);
         let result2 = hash(&[0; 32]).expect("Hash succeeded");
@@ -159,11 +466,99 @@ mod tests {
 
     #[test]
     fn test_poseidon_hash_with_padding_equivalent() {
-        let mut hasher = super::gen_poseidon_hasher();
-        hasher.update(&[Fr::zero(), Fr::zero()]);
+        let mut hasher = super::gen_poseidon_hasher(&HashConfig::default());
+        hasher.update(&[Fr::from(0u64), Fr::from(0u64)]);
         let result = hasher.squeeze().to_repr();
         println!("hash result is {:?}", result);
         let result2 = hash_with_padding(&[0; 32]).expect("Hash succeeded");
         assert_eq!(result, result2);
     }
+
+    // Pin the digests of all the algorithms, so that any accidental change of the hasher
+    // parameters will be caught.
+    #[test]
+    fn test_hash_with_algorithm_digests() {
+        let data = (0..32).collect::<Vec<u8>>();
+        for (algorithm, expected) in [
+            (
+                PoseidonHashAlgorithm::HashFieldElements,
+                "0e4958ed4520044443639ad7ebe9676ff26510b5ac960441160873ee4cd33221",
+            ),
+            (
+                PoseidonHashAlgorithm::HashPadded16Bytes,
+                "f57882d9182040e45e2610366e0f65adeec7d5c77d9927d953d4cb6c93ee1a13",
+            ),
+            (
+                PoseidonHashAlgorithm::HashMerkleLeaf,
+                "5744b2781699f3c0c83bb65aae5d76a04af9692bf65102ca89a14cd1e4413d1c",
+            ),
+        ] {
+            let result = hash_with_algorithm(algorithm, &data).expect("Hash succeeded");
+            assert_eq!(hex::encode(result), expected, "{:?}", algorithm);
+        }
+    }
+
+    #[test]
+    fn test_hash_with_algorithm_invalid_length() {
+        for algorithm in [
+            PoseidonHashAlgorithm::HashFieldElements,
+            PoseidonHashAlgorithm::HashPadded16Bytes,
+            PoseidonHashAlgorithm::HashMerkleLeaf,
+        ] {
+            assert!(hash_with_algorithm(algorithm, &[0; 31]).is_err());
+        }
+        assert!(hash_with_algorithm(PoseidonHashAlgorithm::HashMerkleLeaf, &[0; 64]).is_err());
+        assert!(hash_with_algorithm(PoseidonHashAlgorithm::HashPadded16Bytes, &[0xff; 64]).is_ok());
+        assert!(
+            hash_with_algorithm(PoseidonHashAlgorithm::HashFieldElements, &[0xff; 64]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_hash_leaf_data() {
+        // Arrays of field elements are hashed as before.
+        assert_eq!(hash_leaf_data(&[42; 32]), hash(&[42; 32]).unwrap());
+        assert_eq!(hash_leaf_data(&[]), hash(&[]).unwrap());
+        for (data, expected) in [
+            (
+                vec![1, 2, 3, 4, 5],
+                "4f03e500abdf75f3e04441cec9245d42009c14c28c43639ce61da714a64dc30d",
+            ),
+            (
+                vec![0xff; 32],
+                "a8a8f75471cc3ebcc0179ac04d87e31006a39bd7c4a0ee5bb0fb194a51cd2d2e",
+            ),
+        ] {
+            assert_eq!(hex::encode(hash_leaf_data(&data)), expected);
+        }
+        assert_eq!(
+            hex::encode(hash_long_data(&[])),
+            "a69ba1e149e82c6e2d046b60597b6fff60fe54174541b9666bffec7303bb9109"
+        );
+        // Trailing zeros are not ignored thanks to the length prefix.
+        assert_ne!(hash_long_data(&[1]), hash_long_data(&[1, 0]));
+    }
+
+    // Short leaves whose field elements are the ones absorbed for a long leaf do not collide
+    // with it. The first one collided when long data was hashed with hash_field_elements.
+    #[test]
+    fn test_hash_leaf_data_domain_separation() {
+        let long = [0xff; 32];
+        let to_bytes = |frs: &[Fr]| frs.iter().flat_map(|f| f.to_repr()).collect::<Vec<u8>>();
+        let mut frs = vec![Fr::from(long.len() as u64)];
+        frs.extend(long.chunks(16).map(|x| {
+            let mut v = x.to_vec();
+            v.resize(32, 0);
+            Fr::from_repr(v.try_into().unwrap()).unwrap()
+        }));
+        let short = to_bytes(&frs);
+        assert_eq!(hash_leaf_data(&short), hash_field_elements(&frs));
+        assert_ne!(hash_leaf_data(&short), hash_leaf_data(&long));
+
+        frs.resize(7, Fr::from(0u64));
+        frs.push(Fr::from(LONG_DATA_DOMAIN_TAG));
+        let short = to_bytes(&frs);
+        assert_eq!(hash_leaf_data(&short), hash(&short).unwrap());
+        assert_ne!(hash_leaf_data(&short), hash_leaf_data(&long));
+    }
 }
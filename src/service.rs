@@ -1,21 +1,68 @@
 use std::borrow::Borrow;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crate::kvpair::{u256_to_bson, MERKLE_TREE_HEIGHT};
-use crate::merkle::{get_offset, get_path, get_sibling_index, leaf_check, MerkleNode, MerkleProof};
+use crate::attestation::{RootAttestation, RootSigner};
+use crate::auth::AuthenticatedContractId;
+use crate::errors::is_max_time_expired_error;
+use crate::kvpair::{
+    leaf_index_from_address, parse_compression_encoding, u256_to_bson, DEFAULT_HASH_VEC,
+    MERKLE_TREE_HEIGHT,
+};
+use crate::merkle::{
+    get_node_path, get_node_type, get_offset, get_path, get_sibling_index, leaf_check,
+    validate_internal_index, validate_leaf_index, MerkleNode, MerkleProof,
+};
+use crate::migrations::{
+    pending_migrations, INITIAL_SCHEMA_VERSION, MIGRATION_BATCH_SIZE, MIN_SCHEMA_VERSION,
+    SCHEMA_VERSION,
+};
+use crate::poseidon::HashConfig;
+use crate::snapshot::Snapshot;
 use crate::Error;
 
-use super::kvpair::{hash_to_bson, u64_to_bson, ContractId, DataHashRecord, Hash, MerkleRecord};
-use mongodb::bson::{doc, to_bson, Document};
-use mongodb::error::{TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT};
+use super::kvpair::{
+    hash_to_bson, u64_to_bson, ArchivedRoot, AuditNode, AuditRecord, ContractId, ContractMetadata,
+    DataHashRecord, Hash, IdempotencyRecord, LeafIndexRecord, MerkleProofWithData, MerkleRecord,
+    ZkwasmMerkleProof,
+};
+use futures::future::{join_all, BoxFuture};
+use futures::{Stream, StreamExt, TryStreamExt};
+use lru::LruCache;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::spec::BinarySubtype;
+use mongodb::bson::{
+    doc, from_bson, from_document, to_bson, to_document, Binary, Bson, DateTime, Document,
+};
+use mongodb::change_stream::event::{ChangeStreamEvent, ResumeToken};
+use mongodb::change_stream::ChangeStream;
+use mongodb::error::{
+    ErrorKind, WriteFailure, TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT,
+};
 use mongodb::options::{
-    Acknowledgment, CreateIndexOptions, FindOneOptions, InsertOneOptions, ReadConcern,
-    ReplaceOptions, TransactionOptions, UpdateModifications, UpdateOptions, WriteConcern,
+    Acknowledgment, AggregateOptions, ChangeStreamOptions, ClientOptions, CollectionOptions,
+    CountOptions, CreateCollectionOptions, CreateIndexOptions, DeleteOptions, DistinctOptions,
+    FindOneOptions, FindOptions, FullDocumentType, IndexOptions, InsertManyOptions,
+    InsertOneOptions, ReadConcern, ReadPreference, ReplaceOptions, SelectionCriteria,
+    SessionOptions, Tls, TransactionOptions, UpdateModifications, UpdateOptions, ValidationAction,
+    ValidationLevel, WriteConcern,
 };
-use mongodb::results::{InsertOneResult, UpdateResult};
+use mongodb::results::{DeleteResult, InsertManyResult, InsertOneResult, UpdateResult};
 use mongodb::{Client, ClientSession, Collection, IndexModel};
-use tonic::{Request, Response, Status};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use tokio::sync::{mpsc, Notify, OwnedMutexGuard, OwnedSemaphorePermit, Semaphore};
+use tonic::codec::CompressionEncoding;
+use tonic::{Request, Response, Status, Streaming};
 
 use super::proto::kv_pair_server::KvPair;
+use super::proto::node::NodeData;
 use super::proto::Proof;
 use super::proto::ProofType;
 use super::proto::*;
@@ -25,24 +72,587 @@ pub struct MongoKvPairTestConfig {
     pub contract_id: ContractId,
 }
 
+#[derive(Clone, Debug)]
+pub struct MongoKvPairConfig {
+    // Relax some checks to facilitate local development, e.g. fall back to the default
+    // contract id when the request does not specify any. MUST NOT be enabled in production.
+    pub dev_mode: bool,
+    // The maximum number of merkle records kept in the in-memory cache, 0 to disable the cache.
+    pub merkle_record_cache_size: usize,
+    // The maximum length of leaf data, may be overridden per contract in the contract metadata.
+    pub max_leaf_data_bytes: usize,
+    // The maximum size of a decoded gRPC message. Larger messages are rejected by tonic
+    // before they reach the handlers.
+    pub max_decoding_message_size: usize,
+    // The maximum size of an encoded gRPC message. Larger responses fail with OUT_OF_RANGE.
+    pub max_encoding_message_size: usize,
+    // The interval of HTTP/2 keepalive pings, which keep idle connections (e.g. of long-lived
+    // streaming RPCs) from being closed by intermediaries. None to disable keepalive pings.
+    pub http2_keepalive_interval: Option<Duration>,
+    // How long to wait for the acknowledgement of a keepalive ping before closing the connection.
+    pub http2_keepalive_timeout: Duration,
+    // How long the counts returned by GetStats are cached.
+    pub stats_cache_ttl: Duration,
+    // The number of leaves SetLeafStream applies at a time.
+    pub set_leaf_stream_chunk_size: usize,
+    // Allow destructive operations (e.g. dropping collections) without a test config.
+    pub allow_destructive: bool,
+    // How often SubscribeRoot polls the root when MongoDB change streams are not available.
+    pub root_poll_interval: Duration,
+    // The directory of the snapshot files read and written by ImportSnapshot and ExportSnapshot,
+    // None to disallow snapshot files on the server.
+    pub snapshot_dir: Option<PathBuf>,
+    // The maximum size of a snapshot streamed to ImportSnapshot, which is held in memory until
+    // it is imported. Larger snapshots fail with RESOURCE_EXHAUSTED.
+    pub max_snapshot_size: usize,
+    // How long a write waits for the other writes of the same contract before failing with
+    // ABORTED, see MongoKvPair::lock_contract.
+    pub write_lock_timeout: Duration,
+    // Whether the audit log records the data of the leaves written, or only their hashes.
+    pub audit_capture_data: bool,
+    // Reject SetNonLeaf if any of the children is not a record in the database (or a default
+    // record of an empty subtree), so that proof walks never reach missing nodes.
+    pub require_existing_children: bool,
+    // Allow SetNonLeaf, i.e. inserting arbitrary internal nodes, without a test config. There
+    // is no use for it in normal operation, and the nodes it inserts may later be made the root.
+    pub allow_raw_node_writes: bool,
+    // How often the retention policies of the contracts are applied in the background, None to
+    // only apply them with RunRetention.
+    pub retention_interval: Option<Duration>,
+    // The maxTimeMS of commitTransaction, None for the default of MongoDB (no limit).
+    pub max_commit_time: Option<Duration>,
+    // Transactions running longer than this are aborted instead of committed, None for no
+    // limit other than the transactionLifetimeLimitSeconds of MongoDB.
+    pub transaction_timeout: Option<Duration>,
+    // Send all reads to the primary, regardless of the read preference in the MongoDB URI.
+    // Reads with consistent_read set are always sent to the primary.
+    pub read_from_primary: bool,
+    // The PKCS#8 file of the ed25519 key roots are signed with (see GetSignedRoot), None to
+    // disable signing.
+    pub signing_key: Option<PathBuf>,
+    // The parameters of the Poseidon hashers. All the hashes of the process are computed with
+    // them, so this can not be changed once set (see poseidon::set_hash_config).
+    pub hash_config: HashConfig,
+    // Batches of at least this many leaves (e.g. the chunks of SetLeafStream, or ComputeRoot) are
+    // hashed in parallel on the blocking thread pool, see MongoCollection::set_leaves.
+    pub parallel_hash_threshold: usize,
+    // Watch the roots of all the contracts with a change stream, and drop the cached counts of a
+    // contract as soon as its root changes (e.g. by a write through another replica) instead of
+    // after stats_cache_ttl, see MongoKvPair::watch_root_changes.
+    pub watch_root_changes: bool,
+    // Store merkle records with a deterministic _id derived from the contract id, index and hash
+    // instead of an ObjectId, so that the merkle collections can be sharded on _id. See
+    // MongoCollection::with_sharded_layout and MigrateLayout.
+    pub sharded_layout: bool,
+    // How long MongoKvPair::shutdown waits for the writes in flight to finish before aborting
+    // them. Kubernetes kills the pod 30 seconds after SIGTERM by default.
+    pub shutdown_drain_period: Duration,
+    // Upgrade all the contracts to the latest schema version before serving, see
+    // MongoKvPair::migrate_all_contracts.
+    pub migrate_on_startup: bool,
+    // How long the response of a SetLeaf request with an idempotency key is returned to the
+    // retries of the request, see MongoCollection::find_idempotency_record.
+    pub idempotency_key_ttl: Duration,
+    // The maximum time a request may take, None for no limit. Requests without a deadline get
+    // this one, and the deadlines set by the clients are capped to it, see get_deadline.
+    pub max_request_time: Option<Duration>,
+    // The encodings the gRPC messages may be compressed with. Responses are only compressed for
+    // the clients accepting one of them (see grpc-accept-encoding), others get them uncompressed.
+    pub compression_encodings: Vec<CompressionEncoding>,
+    // The maximum number of requests served at a time by main, gRPC and HTTP gateway requests
+    // together, the others wait for their turn. None for no limit.
+    pub max_concurrent_requests: Option<usize>,
+    // The maximum number of writes in flight, see lock_contract. Writes waiting longer than
+    // write_lock_timeout for their turn fail with RESOURCE_EXHAUSTED. None for no limit.
+    pub max_concurrent_writes: Option<usize>,
+}
+
+pub const DEFAULT_MERKLE_RECORD_CACHE_SIZE: usize = 100_000;
+pub const DEFAULT_MAX_LEAF_DATA_BYTES: usize = 64 * 1024;
+// Large enough for requests setting many leaves, while still bounding the memory used
+// by a single request. The default of tonic is 4 MiB.
+pub const DEFAULT_MAX_DECODING_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+pub const DEFAULT_MAX_ENCODING_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+// Most load balancers close connections idle for more than 60 seconds.
+pub const DEFAULT_HTTP2_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+pub const DEFAULT_HTTP2_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(20);
+pub const DEFAULT_STATS_CACHE_TTL: Duration = Duration::from_secs(5);
+pub const DEFAULT_SET_LEAF_STREAM_CHUNK_SIZE: usize = 1000;
+pub const DEFAULT_ROOT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+pub const DEFAULT_WRITE_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_SHUTDOWN_DRAIN_PERIOD: Duration = Duration::from_secs(25);
+// Long enough for clients to retry after an outage of the server.
+pub const DEFAULT_IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+// Keys are meant to be e.g. UUIDs, this only bounds the size of the IDEMPOTENCY_ collections.
+pub const MAX_IDEMPOTENCY_KEY_BYTES: usize = 256;
+// Below this, handing the hashing over to the blocking thread pool costs more than it saves.
+pub const DEFAULT_PARALLEL_HASH_THRESHOLD: usize = 256;
+// Well below the default maximum message sizes.
+pub const SNAPSHOT_CHUNK_SIZE: usize = 1024 * 1024;
+pub const DEFAULT_MAX_SNAPSHOT_SIZE: usize = 256 * 1024 * 1024;
+pub const DEFAULT_MUTATIONS_PAGE_SIZE: usize = 100;
+pub const MAX_MUTATIONS_PAGE_SIZE: usize = 1000;
+pub const DEFAULT_LIST_CONTRACTS_PAGE_SIZE: usize = 100;
+pub const MAX_LIST_CONTRACTS_PAGE_SIZE: usize = 1000;
+// The number of merkle records MigrateLayout converts per query.
+pub const MIGRATE_LAYOUT_BATCH_SIZE: usize = 1000;
+// The maximum number of leaves of all the groups of a MultiContractGetLeaves request.
+pub const MAX_MULTI_CONTRACT_LEAVES: usize = 10_000;
+// The maximum number of nodes of a GetNonLeaves request.
+pub const MAX_NON_LEAVES: usize = 10_000;
+
+impl Default for MongoKvPairConfig {
+    fn default() -> Self {
+        Self {
+            dev_mode: false,
+            merkle_record_cache_size: DEFAULT_MERKLE_RECORD_CACHE_SIZE,
+            max_leaf_data_bytes: DEFAULT_MAX_LEAF_DATA_BYTES,
+            max_decoding_message_size: DEFAULT_MAX_DECODING_MESSAGE_SIZE,
+            max_encoding_message_size: DEFAULT_MAX_ENCODING_MESSAGE_SIZE,
+            http2_keepalive_interval: Some(DEFAULT_HTTP2_KEEPALIVE_INTERVAL),
+            http2_keepalive_timeout: DEFAULT_HTTP2_KEEPALIVE_TIMEOUT,
+            stats_cache_ttl: DEFAULT_STATS_CACHE_TTL,
+            set_leaf_stream_chunk_size: DEFAULT_SET_LEAF_STREAM_CHUNK_SIZE,
+            allow_destructive: false,
+            root_poll_interval: DEFAULT_ROOT_POLL_INTERVAL,
+            snapshot_dir: None,
+            max_snapshot_size: DEFAULT_MAX_SNAPSHOT_SIZE,
+            write_lock_timeout: DEFAULT_WRITE_LOCK_TIMEOUT,
+            audit_capture_data: true,
+            require_existing_children: false,
+            allow_raw_node_writes: false,
+            retention_interval: None,
+            max_commit_time: None,
+            transaction_timeout: None,
+            read_from_primary: false,
+            signing_key: None,
+            hash_config: HashConfig::default(),
+            parallel_hash_threshold: DEFAULT_PARALLEL_HASH_THRESHOLD,
+            watch_root_changes: false,
+            sharded_layout: false,
+            shutdown_drain_period: DEFAULT_SHUTDOWN_DRAIN_PERIOD,
+            migrate_on_startup: false,
+            idempotency_key_ttl: DEFAULT_IDEMPOTENCY_KEY_TTL,
+            max_request_time: None,
+            compression_encodings: vec![CompressionEncoding::Gzip],
+            max_concurrent_requests: None,
+            max_concurrent_writes: None,
+        }
+    }
+}
+
+impl MongoKvPairConfig {
+    /// Read the config from the KVPAIR_* environment variables, failing on the first variable
+    /// which can not be parsed.
+    pub fn from_env() -> Result<Self, String> {
+        Ok(Self {
+            dev_mode: std::env::var("KVPAIR_DEV_MODE").is_ok(),
+            merkle_record_cache_size: parse_env("KVPAIR_MERKLE_RECORD_CACHE_SIZE")?
+                .unwrap_or(DEFAULT_MERKLE_RECORD_CACHE_SIZE),
+            max_leaf_data_bytes: parse_env("KVPAIR_MAX_LEAF_DATA_BYTES")?
+                .unwrap_or(DEFAULT_MAX_LEAF_DATA_BYTES),
+            max_decoding_message_size: parse_env("KVPAIR_MAX_DECODING_MESSAGE_SIZE")?
+                .unwrap_or(DEFAULT_MAX_DECODING_MESSAGE_SIZE),
+            max_encoding_message_size: parse_env("KVPAIR_MAX_ENCODING_MESSAGE_SIZE")?
+                .unwrap_or(DEFAULT_MAX_ENCODING_MESSAGE_SIZE),
+            // 0 disables keepalive pings.
+            http2_keepalive_interval: parse_env("KVPAIR_HTTP2_KEEPALIVE_INTERVAL_SECS")?
+                .map(|secs| Some(Duration::from_secs(secs)).filter(|secs| !secs.is_zero()))
+                .unwrap_or(Some(DEFAULT_HTTP2_KEEPALIVE_INTERVAL)),
+            http2_keepalive_timeout: parse_env("KVPAIR_HTTP2_KEEPALIVE_TIMEOUT_SECS")?
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_HTTP2_KEEPALIVE_TIMEOUT),
+            stats_cache_ttl: parse_env("KVPAIR_STATS_CACHE_TTL_SECS")?
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_STATS_CACHE_TTL),
+            set_leaf_stream_chunk_size: parse_env("KVPAIR_SET_LEAF_STREAM_CHUNK_SIZE")?
+                .unwrap_or(DEFAULT_SET_LEAF_STREAM_CHUNK_SIZE),
+            allow_destructive: std::env::var("KVPAIR_ALLOW_DESTRUCTIVE").is_ok(),
+            root_poll_interval: parse_env("KVPAIR_ROOT_POLL_INTERVAL_MS")?
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_ROOT_POLL_INTERVAL),
+            snapshot_dir: std::env::var("KVPAIR_SNAPSHOT_DIR").map(PathBuf::from).ok(),
+            max_snapshot_size: parse_env("KVPAIR_MAX_SNAPSHOT_SIZE")?
+                .unwrap_or(DEFAULT_MAX_SNAPSHOT_SIZE),
+            write_lock_timeout: parse_env("KVPAIR_WRITE_LOCK_TIMEOUT_MS")?
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_WRITE_LOCK_TIMEOUT),
+            audit_capture_data: std::env::var("KVPAIR_AUDIT_SKIP_DATA").is_err(),
+            require_existing_children: std::env::var("KVPAIR_REQUIRE_EXISTING_CHILDREN").is_ok(),
+            allow_raw_node_writes: std::env::var("KVPAIR_ALLOW_RAW_NODE_WRITES").is_ok(),
+            retention_interval: parse_env("KVPAIR_RETENTION_INTERVAL_SECS")?
+                .map(Duration::from_secs),
+            max_commit_time: parse_env("KVPAIR_MAX_COMMIT_TIME_MS")?.map(Duration::from_millis),
+            transaction_timeout: parse_env("KVPAIR_TRANSACTION_TIMEOUT_MS")?
+                .map(Duration::from_millis),
+            read_from_primary: std::env::var("KVPAIR_READ_FROM_PRIMARY").is_ok(),
+            signing_key: std::env::var("KVPAIR_SIGNING_KEY").map(PathBuf::from).ok(),
+            hash_config: HashConfig::from_env()?,
+            parallel_hash_threshold: parse_env("KVPAIR_PARALLEL_HASH_THRESHOLD")?
+                .unwrap_or(DEFAULT_PARALLEL_HASH_THRESHOLD),
+            watch_root_changes: std::env::var("KVPAIR_WATCH_ROOT_CHANGES").is_ok(),
+            sharded_layout: std::env::var("KVPAIR_SHARDED_LAYOUT").is_ok(),
+            shutdown_drain_period: parse_env("KVPAIR_SHUTDOWN_DRAIN_PERIOD_SECS")?
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_SHUTDOWN_DRAIN_PERIOD),
+            migrate_on_startup: std::env::var("KVPAIR_MIGRATE_ON_STARTUP").is_ok(),
+            idempotency_key_ttl: parse_env("KVPAIR_IDEMPOTENCY_KEY_TTL_SECS")?
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_IDEMPOTENCY_KEY_TTL),
+            max_request_time: parse_env("KVPAIR_MAX_REQUEST_SECONDS")?.map(Duration::from_secs),
+            // A comma separated list, empty to disable compression.
+            compression_encodings: match std::env::var("KVPAIR_COMPRESSION_ENCODINGS") {
+                Ok(encodings) => encodings
+                    .split(',')
+                    .filter(|encoding| !encoding.trim().is_empty())
+                    .map(parse_compression_encoding)
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| format!("Parse KVPAIR_COMPRESSION_ENCODINGS: {e}"))?,
+                Err(_) => vec![CompressionEncoding::Gzip],
+            },
+            // No request would ever be served with a limit of 0.
+            max_concurrent_requests: match parse_env("KVPAIR_MAX_CONCURRENT_REQUESTS")? {
+                Some(0) => return Err("KVPAIR_MAX_CONCURRENT_REQUESTS must not be 0".to_string()),
+                limit => limit,
+            },
+            max_concurrent_writes: parse_env("KVPAIR_MAX_CONCURRENT_WRITES")?,
+        })
+    }
+}
+
+/// Parse the environment variable name, if it is set.
+fn parse_env<T>(name: &str) -> Result<Option<T>, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    std::env::var(name)
+        .ok()
+        .map(|value| value.parse().map_err(|e| format!("Parse {name}: {e}")))
+        .transpose()
+}
+
+pub const DEFAULT_MONGODB_URI: &str = "mongodb://localhost:27017";
+
+/// The options of the MongoDB client which are set with environment variables instead of in
+/// MONGODB_URI (e.g. because the URI of an Atlas deployment is given as is). They are merged into
+/// the options parsed from the URI, and an option given both ways must have the same value.
+#[derive(Clone, Debug)]
+pub struct MongoClientConfig {
+    // A mongodb:// or mongodb+srv:// connection string.
+    pub uri: String,
+    pub max_pool_size: Option<u32>,
+    pub min_pool_size: Option<u32>,
+    // The database to authenticate against, i.e. authSource.
+    pub auth_source: Option<String>,
+    // Setting either of these enables TLS.
+    pub tls_ca_file: Option<PathBuf>,
+    pub tls_cert_key_file: Option<PathBuf>,
+}
+
+impl Default for MongoClientConfig {
+    fn default() -> Self {
+        Self {
+            uri: DEFAULT_MONGODB_URI.to_string(),
+            max_pool_size: None,
+            min_pool_size: None,
+            auth_source: None,
+            tls_ca_file: None,
+            tls_cert_key_file: None,
+        }
+    }
+}
+
+impl MongoClientConfig {
+    pub fn from_env() -> Result<Self, String> {
+        Ok(Self {
+            uri: std::env::var("MONGODB_URI").unwrap_or(DEFAULT_MONGODB_URI.to_string()),
+            max_pool_size: parse_env("MONGODB_MAX_POOL_SIZE")?,
+            min_pool_size: parse_env("MONGODB_MIN_POOL_SIZE")?,
+            auth_source: std::env::var("MONGODB_AUTH_SOURCE").ok(),
+            tls_ca_file: std::env::var("MONGODB_TLS_CA_FILE").map(PathBuf::from).ok(),
+            tls_cert_key_file: std::env::var("MONGODB_TLS_CERT_KEY_FILE")
+                .map(PathBuf::from)
+                .ok(),
+        })
+    }
+
+    /// Parse the URI and merge the other options into it. The SRV (and TXT) records of a
+    /// mongodb+srv:// URI are resolved here, so that a wrong host name fails at startup.
+    pub async fn client_options(&self) -> Result<ClientOptions, Error> {
+        // The URI is not included in the errors, as it may contain a password.
+        let mut options = ClientOptions::parse(&self.uri)
+            .await
+            .map_err(|e| match *e.kind {
+                ErrorKind::DnsResolve { .. } => Error::Precondition(format!(
+                    "Failed to resolve the SRV records of MONGODB_URI: {e}"
+                )),
+                _ => Error::InvalidArgument(format!("Invalid MONGODB_URI: {e}")),
+            })?;
+        self.merge_into(&mut options)?;
+        Ok(options)
+    }
+
+    /// Merge the options other than the URI into options parsed from the URI.
+    pub fn merge_into(&self, options: &mut ClientOptions) -> Result<(), Error> {
+        fn merge<T: Clone + PartialEq + std::fmt::Debug>(
+            name: &str,
+            from_uri: &mut Option<T>,
+            value: &Option<T>,
+        ) -> Result<(), Error> {
+            if let (Some(from_uri), Some(value)) = (from_uri.as_ref(), value) {
+                if from_uri != value {
+                    return Err(Error::InvalidArgument(format!(
+                        "Conflicting {name}: {from_uri:?} in MONGODB_URI, {value:?} configured"
+                    )));
+                }
+            }
+            if value.is_some() {
+                *from_uri = value.clone();
+            }
+            Ok(())
+        }
+
+        merge(
+            "maxPoolSize",
+            &mut options.max_pool_size,
+            &self.max_pool_size,
+        )?;
+        merge(
+            "minPoolSize",
+            &mut options.min_pool_size,
+            &self.min_pool_size,
+        )?;
+        if let (Some(min), Some(max)) = (options.min_pool_size, options.max_pool_size) {
+            if min > max {
+                return Err(Error::InvalidArgument(format!(
+                    "minPoolSize {min} is larger than maxPoolSize {max}"
+                )));
+            }
+        }
+        if self.auth_source.is_some() {
+            let credential = options.credential.as_mut().ok_or_else(|| {
+                Error::InvalidArgument(
+                    "authSource configured, but MONGODB_URI has no credentials".to_string(),
+                )
+            })?;
+            merge("authSource", &mut credential.source, &self.auth_source)?;
+        }
+        if self.tls_ca_file.is_none() && self.tls_cert_key_file.is_none() {
+            return Ok(());
+        }
+        let tls = options
+            .tls
+            .get_or_insert_with(|| Tls::Enabled(Default::default()));
+        let tls_options = match tls {
+            Tls::Enabled(tls_options) => tls_options,
+            Tls::Disabled => {
+                return Err(Error::InvalidArgument(
+                    "TLS files configured, but TLS is disabled in MONGODB_URI".to_string(),
+                ))
+            }
+        };
+        merge(
+            "tlsCAFile",
+            &mut tls_options.ca_file_path,
+            &self.tls_ca_file,
+        )?;
+        merge(
+            "tlsCertificateKeyFile",
+            &mut tls_options.cert_key_file_path,
+            &self.tls_cert_key_file,
+        )?;
+        Ok(())
+    }
+}
+
+/// Merkle records are content addressed, i.e. a record with given contract id, index and hash
+/// never changes once written. So we can safely cache them in memory.
+pub type MerkleRecordCache = Arc<Mutex<LruCache<(ContractId, u64, Hash), MerkleRecord>>>;
+
+// The node count, leaf count of each contract and when they are counted.
+type StatsCache = Arc<Mutex<HashMap<ContractId, (Instant, u64, u64)>>>;
+
+// How long watch_root_changes waits before reopening a failed change stream, doubled after each
+// failure in a row up to the maximum.
+const MIN_WATCH_RETRY_DELAY: Duration = Duration::from_millis(100);
+const MAX_WATCH_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+// The lock of each contract held by the writes in this process. The locks are never removed,
+// which costs a few bytes for each contract ever written.
+type WriteLocks = Arc<Mutex<HashMap<ContractId, Arc<tokio::sync::Mutex<()>>>>>;
+
+// The result of MongoKvPair::write_leaf: the previous root, the proof of the leaf under the new
+// root, the previous node of the leaf and whether it was the default leaf.
+type LeafWrite = (Hash, MerkleProof<Hash, MERKLE_TREE_HEIGHT>, Node, bool);
+
+// The state of a graceful shutdown, see MongoKvPair::shutdown.
+#[derive(Debug, Default)]
+struct ShutdownState {
+    // Set when the shutdown starts, new writes are then rejected.
+    draining: AtomicBool,
+    // The number of writes in flight, including those waiting for the lock of their contract.
+    writes: AtomicUsize,
+    // Notified when the last write in flight finishes.
+    drained: Notify,
+    // Set once the drain period has passed, the writes still in flight then fail before their
+    // next write or commit (see MongoCollection::with_shutdown).
+    aborted: Arc<AtomicBool>,
+}
+
+// Counts a write as in flight until dropped.
+struct InFlightWrite(Arc<ShutdownState>);
+
+impl InFlightWrite {
+    fn new(state: &Arc<ShutdownState>) -> Self {
+        state.writes.fetch_add(1, Ordering::SeqCst);
+        Self(state.clone())
+    }
+}
+
+impl Clone for InFlightWrite {
+    fn clone(&self) -> Self {
+        Self::new(&self.0)
+    }
+}
+
+impl Drop for InFlightWrite {
+    fn drop(&mut self) {
+        if self.0.writes.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.drained.notify_waiters();
+        }
+    }
+}
+
+// Held by a write until it finishes, see MongoKvPair::lock_contract.
+struct WriteGuard {
+    _lock: OwnedMutexGuard<()>,
+    _in_flight: InFlightWrite,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
 #[derive(Clone, Debug)]
 pub struct MongoKvPair {
     client: Client,
+    config: MongoKvPairConfig,
     test_config: Option<MongoKvPairTestConfig>,
+    cache: Option<MerkleRecordCache>,
+    stats_cache: StatsCache,
+    // The number of cached entries dropped by watch_root_changes.
+    cache_invalidations: Arc<AtomicU64>,
+    write_locks: WriteLocks,
+    // Bounds the writes in flight, see MongoKvPairConfig::max_concurrent_writes.
+    write_permits: Option<Arc<Semaphore>>,
+    shutdown: Arc<ShutdownState>,
+    // The schema versions of the contracts whose version is known to be supported, see
+    // open_contract.
+    opened_contracts: Arc<Mutex<HashMap<ContractId, u32>>>,
+    // The contracts whose collections are created, see create_contract_collections.
+    created_contracts: Arc<Mutex<HashSet<ContractId>>>,
+    // Whether the deployment supports transactions, see supports_transactions.
+    transactions_supported: Arc<tokio::sync::OnceCell<bool>>,
+    signer: Option<RootSigner>,
+}
+
+/// The limits of the transaction of a MongoCollection created with a session.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TransactionLimits {
+    // The maxTimeMS of commitTransaction.
+    pub max_commit_time: Option<Duration>,
+    // The transaction is aborted on commit if it has been running for longer than this.
+    pub timeout: Option<Duration>,
+}
+
+/// Which roots of a contract are kept in its merkle collection, see
+/// MongoCollection::run_retention. Without any limit, all roots are kept.
+#[derive(Clone, Debug, Default)]
+pub struct RetentionPolicy {
+    // The number of most recently used roots kept.
+    pub max_hot_roots: Option<u64>,
+    // Roots not used for longer than this are archived.
+    pub max_root_age: Option<Duration>,
+    // Roots which are never archived.
+    pub pinned_roots: Vec<Hash>,
+}
+
+impl RetentionPolicy {
+    pub fn from_metadata(metadata: &ContractMetadata) -> Self {
+        Self {
+            max_hot_roots: metadata.max_hot_roots,
+            max_root_age: metadata.max_root_age_secs.map(Duration::from_secs),
+            pinned_roots: metadata.pinned_roots.clone(),
+        }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.max_hot_roots.is_none() && self.max_root_age.is_none()
+    }
+}
+
+// A root in the history of a contract, see MongoCollection::find_root_history.
+#[derive(Deserialize)]
+struct RootHistoryEntry {
+    #[serde(rename = "_id")]
+    root: Hash,
+    last_used: DateTime,
+    // The root has been moved to the ROOTARCHIVE_ collection.
+    #[serde(default)]
+    archived: bool,
 }
 
 #[derive(Debug)]
-pub struct MongoCollection<T, R> {
+pub struct MongoCollection<T, R, const H: usize = MERKLE_TREE_HEIGHT> {
+    client: Client,
+    contract_id: ContractId,
     merkle_collection: Collection<T>,
     datahash_collection: Collection<R>,
+    audit_collection: Collection<AuditRecord>,
+    root_archive_collection: Collection<ArchivedRoot>,
+    leaf_index_collection: Collection<LeafIndexRecord>,
+    idempotency_collection: Collection<IdempotencyRecord>,
     session: Option<ClientSession>,
+    // Whether the session has a transaction, as opposed to a causally consistent session
+    // without one (see with_causal_consistency).
+    in_transaction: bool,
+    // The limits of the transaction of the session, and when the transaction was started.
+    transaction_limits: TransactionLimits,
+    transaction_started: Instant,
+    cache: Option<MerkleRecordCache>,
+    // Whether destructive operations (e.g. dropping the collections) are allowed.
+    allow_destructive: bool,
+    // The old and new root of the last successful update_root_merkle_record.
+    last_root_update: Option<(Hash, Hash)>,
+    // The deadline of the request (from grpc-timeout), see with_deadline.
+    deadline: Option<Instant>,
+    // The read preference of queries, see with_primary_reads.
+    selection_criteria: Option<SelectionCriteria>,
+    // The number of leaves from which set_leaves and compute_root hash in parallel, see
+    // with_parallel_hash_threshold.
+    parallel_hash_threshold: usize,
+    // Whether merkle records have a deterministic _id, see with_sharded_layout.
+    sharded_layout: bool,
+    // Set when the server aborts the writes in flight to shut down, see with_shutdown.
+    shutdown: Arc<AtomicBool>,
+    // The schema version of the contract, which decides the format of the merkle records
+    // written, see with_schema_version.
+    schema_version: u32,
+    // Inserted before the root update of a write without a transaction, see
+    // with_pending_idempotency_record.
+    pending_idempotency_record: Option<IdempotencyRecord>,
 }
 
-impl<T, R> MongoCollection<T, R> {
+impl<T, R, const H: usize> MongoCollection<T, R, H> {
     fn get_database_name() -> String {
         "zkwasm-mongo-merkle".to_string()
     }
 
+    // The metadata of all the contracts, see MongoKvPair::get_contract_metadata.
+    fn get_contracts_collection(client: &Client) -> Collection<ContractMetadata> {
+        client
+            .database(Self::get_database_name().as_str())
+            .collection::<ContractMetadata>("CONTRACTS")
+    }
+
     fn get_merkle_collection_name(contract_id: &ContractId) -> String {
         format!("MERKLEDATA_{}", hex::encode(contract_id.0))
     }
@@ -51,33 +661,188 @@ impl<T, R> MongoCollection<T, R> {
         format!("DATAHASH_{}", hex::encode(contract_id.0))
     }
 
+    fn get_audit_collection_name(contract_id: &ContractId) -> String {
+        format!("AUDIT_{}", hex::encode(contract_id.0))
+    }
+
+    fn get_root_archive_collection_name(contract_id: &ContractId) -> String {
+        format!("ROOTARCHIVE_{}", hex::encode(contract_id.0))
+    }
+
+    fn get_leaf_index_collection_name(contract_id: &ContractId) -> String {
+        format!("LEAFINDEX_{}", hex::encode(contract_id.0))
+    }
+
+    fn get_idempotency_collection_name(contract_id: &ContractId) -> String {
+        format!("IDEMPOTENCY_{}", hex::encode(contract_id.0))
+    }
+
+    /// The namespace (database.collection) and shard key of each collection of the contract.
+    /// Merkle records are sharded on their _id, which is only meaningful in the sharded layout
+    /// (see with_sharded_layout), and data hash records on the hash they are looked up by. The
+    /// other collections are small in comparison and sharded on _id to spread them evenly.
+    pub fn shard_keys(contract_id: &ContractId) -> Vec<(String, Document)> {
+        let database = Self::get_database_name();
+        [
+            (
+                Self::get_merkle_collection_name(contract_id),
+                doc! {"_id": "hashed"},
+            ),
+            (
+                Self::get_data_collection_name(contract_id),
+                doc! {"hash": "hashed"},
+            ),
+            (
+                Self::get_audit_collection_name(contract_id),
+                doc! {"_id": "hashed"},
+            ),
+            (
+                Self::get_root_archive_collection_name(contract_id),
+                doc! {"_id": "hashed"},
+            ),
+            (
+                Self::get_leaf_index_collection_name(contract_id),
+                doc! {"_id": "hashed"},
+            ),
+            (
+                Self::get_idempotency_collection_name(contract_id),
+                doc! {"_id": "hashed"},
+            ),
+        ]
+        .into_iter()
+        .map(|(name, key)| (format!("{database}.{name}"), key))
+        .collect()
+    }
+
+    // The collections of the contract which have a validator, with their validators.
+    fn validated_collections(contract_id: &ContractId) -> [(String, Document); 2] {
+        [
+            (
+                Self::get_merkle_collection_name(contract_id),
+                merkle_record_validator(),
+            ),
+            (
+                Self::get_data_collection_name(contract_id),
+                datahash_record_validator(),
+            ),
+        ]
+    }
+
+    /// Create the merkle and data hash collections of the contract with their validators,
+    /// unless they exist. Existing collections are left as they are, see ensure_validators.
+    pub async fn create_collections(
+        client: &Client,
+        contract_id: &ContractId,
+    ) -> Result<(), mongodb::error::Error> {
+        let database = client.database(Self::get_database_name().as_str());
+        for (name, validator) in Self::validated_collections(contract_id) {
+            // The moderate level only validates the updates of the documents which are valid,
+            // so that legacy documents can still be written, and are reported when read.
+            let options = CreateCollectionOptions::builder()
+                .validator(validator)
+                .validation_level(ValidationLevel::Moderate)
+                .validation_action(ValidationAction::Error)
+                .build();
+            match database.create_collection(&name, options).await {
+                Err(error) if is_namespace_exists_error(&error) => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Attach the validators to the merkle and data hash collections of the contract, creating
+    /// them if needed, and replacing the validators they have. Documents already in the
+    /// collections are not checked. Returns the namespaces of the collections.
+    pub async fn ensure_validators(
+        client: &Client,
+        contract_id: &ContractId,
+    ) -> Result<Vec<String>, mongodb::error::Error> {
+        Self::create_collections(client, contract_id).await?;
+        let database_name = Self::get_database_name();
+        let database = client.database(database_name.as_str());
+        let mut namespaces = vec![];
+        for (name, validator) in Self::validated_collections(contract_id) {
+            let command = doc! {
+                "collMod": name.as_str(),
+                "validator": validator,
+                "validationLevel": "moderate",
+                "validationAction": "error",
+            };
+            database.run_command(command, None).await?;
+            namespaces.push(format!("{database_name}.{name}"));
+        }
+        Ok(namespaces)
+    }
+
+    /// Create the TTL index of the IDEMPOTENCY_ collection of the contract, which removes the
+    /// entries once they expire, unless it exists.
+    pub async fn create_idempotency_index(
+        client: &Client,
+        contract_id: &ContractId,
+    ) -> Result<(), mongodb::error::Error> {
+        let collection = client
+            .database(Self::get_database_name().as_str())
+            .collection::<Document>(&Self::get_idempotency_collection_name(contract_id));
+        // Each entry records when it expires, so that changing the TTL does not change the index.
+        let options = IndexOptions::builder().expire_after(Duration::ZERO).build();
+        let index = IndexModel::builder()
+            .keys(doc! {"expires_at": 1})
+            .options(options)
+            .build();
+        collection.create_index(index, None).await?;
+        Ok(())
+    }
+
     pub async fn new(
         client: Client,
         contract_id: &ContractId,
         with_session: bool,
+    ) -> Result<Self, mongodb::error::Error> {
+        Self::new_with_transaction_limits(client, contract_id, with_session, Default::default())
+            .await
+    }
+
+    pub async fn new_with_transaction_limits(
+        client: Client,
+        contract_id: &ContractId,
+        with_session: bool,
+        transaction_limits: TransactionLimits,
     ) -> Result<Self, mongodb::error::Error> {
         let session = if with_session {
-            let mut session = client.start_session(None).await?;
-            let options = TransactionOptions::builder()
-                .read_concern(ReadConcern::majority())
-                .write_concern(WriteConcern::builder().w(Acknowledgment::Majority).build())
-                .build();
-            session.start_transaction(options).await?;
-            Some(session)
+            Some(Self::start_transaction(&client, &transaction_limits).await?)
         } else {
             None
         };
         let database = client.clone().database(Self::get_database_name().as_str());
+        let options = Self::collection_options();
         let merkle_collection_name = Self::get_merkle_collection_name(contract_id);
-        let merkle_collection = database.collection::<T>(merkle_collection_name.as_str());
+        let merkle_collection =
+            database.collection_with_options::<T>(&merkle_collection_name, options.clone());
         let datahash_collection_name = Self::get_data_collection_name(contract_id);
-        let datahash_collection = database.collection::<R>(datahash_collection_name.as_str());
+        let datahash_collection =
+            database.collection_with_options::<R>(&datahash_collection_name, options.clone());
+        let audit_collection_name = Self::get_audit_collection_name(contract_id);
+        let audit_collection = database
+            .collection_with_options::<AuditRecord>(&audit_collection_name, options.clone());
+        let root_archive_collection_name = Self::get_root_archive_collection_name(contract_id);
+        let root_archive_collection = database.collection_with_options::<ArchivedRoot>(
+            &root_archive_collection_name,
+            options.clone(),
+        );
+        let leaf_index_collection_name = Self::get_leaf_index_collection_name(contract_id);
+        let leaf_index_collection = database.collection_with_options::<LeafIndexRecord>(
+            &leaf_index_collection_name,
+            options.clone(),
+        );
+        let idempotency_collection_name = Self::get_idempotency_collection_name(contract_id);
+        let idempotency_collection = database
+            .collection_with_options::<IdempotencyRecord>(&idempotency_collection_name, options);
         if std::env::var("MONGODB_CREATE_INDEXES").is_ok() {
             merkle_collection
                 .create_indexes(
                     vec![
                         IndexModel::builder().keys(doc! { "hash": 1 }).build(),
-                        IndexModel::builder().keys(doc! { "data": 1 }).build(),
                         IndexModel::builder().keys(doc! { "index": 1 }).build(),
                         IndexModel::builder().keys(doc! { "left": 1 }).build(),
                         IndexModel::builder().keys(doc! { "right": 1 }).build(),
@@ -94,429 +859,4157 @@ impl<T, R> MongoCollection<T, R> {
                     CreateIndexOptions::builder().build(),
                 )
                 .await?;
+            audit_collection
+                .create_indexes(
+                    vec![
+                        IndexModel::builder()
+                            .keys(doc! { "nodes.index": 1 })
+                            .build(),
+                        IndexModel::builder().keys(doc! { "timestamp": 1 }).build(),
+                    ],
+                    CreateIndexOptions::builder().build(),
+                )
+                .await?;
+            root_archive_collection
+                .create_indexes(
+                    vec![IndexModel::builder()
+                        .keys(doc! { "record.hash": 1 })
+                        .build()],
+                    CreateIndexOptions::builder().build(),
+                )
+                .await?;
+            leaf_index_collection
+                .create_indexes(
+                    vec![IndexModel::builder().keys(doc! { "hash": 1 }).build()],
+                    CreateIndexOptions::builder().build(),
+                )
+                .await?;
         }
         dbg!(merkle_collection_name, datahash_collection_name);
         Ok(Self {
+            client,
+            contract_id: *contract_id,
             merkle_collection,
             datahash_collection,
+            audit_collection,
+            root_archive_collection,
+            leaf_index_collection,
+            idempotency_collection,
             session,
+            in_transaction: with_session,
+            transaction_limits,
+            transaction_started: Instant::now(),
+            cache: None,
+            allow_destructive: false,
+            last_root_update: None,
+            deadline: None,
+            selection_criteria: None,
+            parallel_hash_threshold: DEFAULT_PARALLEL_HASH_THRESHOLD,
+            sharded_layout: false,
+            shutdown: Default::default(),
+            schema_version: SCHEMA_VERSION,
+            pending_idempotency_record: None,
         })
     }
 
-    pub async fn commit(&mut self) -> Result<(), mongodb::error::Error> {
+    // Reads only observe writes acknowledged by a majority, which are never rolled back. These are
+    // the concerns of both the collections and the transactions, so that reads with and without a
+    // session are consistent with each other.
+    fn read_concern() -> ReadConcern {
+        ReadConcern::majority()
+    }
+
+    fn write_concern() -> WriteConcern {
+        WriteConcern::builder().w(Acknowledgment::Majority).build()
+    }
+
+    fn collection_options() -> CollectionOptions {
+        CollectionOptions::builder()
+            .read_concern(Self::read_concern())
+            .write_concern(Self::write_concern())
+            .build()
+    }
+
+    pub fn with_cache(mut self, cache: Option<MerkleRecordCache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    pub fn with_allow_destructive(mut self, allow_destructive: bool) -> Self {
+        self.allow_destructive = allow_destructive;
+        self
+    }
+
+    /// Bound the database work of this collection by the deadline of the request. Queries are
+    /// sent with maxTimeMS set to the remaining time, and nothing is written or committed once
+    /// the deadline has passed. Without a deadline, queries are not limited.
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Hash the ancestors of the leaves given to set_leaves and compute_root in parallel (on the
+    /// blocking thread pool of tokio) if there are at least threshold leaves, and sequentially
+    /// otherwise. The records computed are the same either way.
+    pub fn with_parallel_hash_threshold(mut self, threshold: usize) -> Self {
+        self.parallel_hash_threshold = threshold;
+        self
+    }
+
+    /// Store the merkle records written by this collection with a deterministic _id derived from
+    /// the contract id, index and hash (see merkle_record_id), and look them up by _id. Inserts
+    /// then no longer query for an existing record first, a duplicate _id means it exists. The
+    /// records written in the default layout (with an ObjectId) are not found by _id, they must
+    /// be converted with migrate_to_sharded_layout first.
+    pub fn with_sharded_layout(mut self, sharded_layout: bool) -> Self {
+        self.sharded_layout = sharded_layout;
+        self
+    }
+
+    /// Write the merkle records in the format of the given schema version (SCHEMA_VERSION by
+    /// default). The records of a contract at version 1 keep their unused data field, so that
+    /// the binaries which predate schema versions, and require it, still read them until the
+    /// contract is migrated.
+    pub fn with_schema_version(mut self, schema_version: u32) -> Self {
+        self.schema_version = schema_version;
+        self
+    }
+
+    /// Record the idempotency key of the write of this collection as pending (see
+    /// IdempotencyRecord::pending) right before the root is replaced, if the collection has no
+    /// transaction, so that a retry does not apply the write again once the root is replaced,
+    /// even if the response is never recorded (see record_idempotent_response). The entry is
+    /// removed if the root is not replaced because it changed concurrently. With a transaction,
+    /// the response is recorded in it instead, and this is ignored.
+    pub fn with_pending_idempotency_record(mut self, record: IdempotencyRecord) -> Self {
+        self.pending_idempotency_record = Some(IdempotencyRecord {
+            pending: true,
+            response: vec![],
+            ..record
+        });
+        self
+    }
+
+    /// Fail every write and commit of this collection with UNAVAILABLE once shutdown is set, as
+    /// when the deadline has passed. The transaction of the session is then aborted instead of
+    /// being left to time out on the server.
+    pub fn with_shutdown(mut self, shutdown: Arc<AtomicBool>) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Send all queries of this collection to the primary if primary is true, instead of
+    /// following the read preference of the client. Reads from secondaries may lag behind.
+    pub fn with_primary_reads(mut self, primary: bool) -> Self {
+        self.selection_criteria =
+            primary.then_some(SelectionCriteria::ReadPreference(ReadPreference::Primary));
+        self
+    }
+
+    /// Run the queries of this collection in a causally consistent session (without a
+    /// transaction), so that each read observes the writes acknowledged before it, including
+    /// those made by other collections sharing the client. This does nothing if the collection
+    /// already has a session.
+    pub async fn with_causal_consistency(mut self) -> Result<Self, mongodb::error::Error> {
+        if self.session.is_none() {
+            let options = SessionOptions::builder().causal_consistency(true).build();
+            self.session = Some(self.client.start_session(options).await?);
+        }
+        Ok(self)
+    }
+
+    // The maxTimeMS of a query, i.e. the given one limited by the time left before the deadline
+    // and before the transaction times out. This is at least one millisecond, as a maxTimeMS of
+    // zero means no limit.
+    fn max_time(&self, max_time: Option<Duration>) -> Option<Duration> {
+        let transaction_deadline = self
+            .transaction_limits
+            .timeout
+            .filter(|_| self.in_transaction)
+            .map(|timeout| self.transaction_started + timeout);
+        let deadline = match (self.deadline, transaction_deadline) {
+            (Some(deadline), Some(transaction_deadline)) => {
+                Some(deadline.min(transaction_deadline))
+            }
+            (deadline, transaction_deadline) => deadline.or(transaction_deadline),
+        };
+        let remaining = deadline.map(|deadline| {
+            deadline
+                .saturating_duration_since(Instant::now())
+                .max(Duration::from_millis(1))
+        });
+        match (remaining, max_time) {
+            (Some(remaining), Some(max_time)) => Some(remaining.min(max_time)),
+            (remaining, max_time) => remaining.or(max_time),
+        }
+    }
+
+    fn check_deadline(&self) -> Result<(), Error> {
+        if self.shutdown.load(Ordering::Relaxed) {
+            return Err(Error::Unavailable(format!(
+                "Server shut down during the request to contract {}",
+                hex::encode(self.contract_id.0)
+            ))
+            .with_detail("contract_id", hex::encode(self.contract_id.0)));
+        }
+        self.check_transaction_timeout()?;
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(Error::DeadlineExceeded(format!(
+                "Deadline of the request to contract {} has passed",
+                hex::encode(self.contract_id.0)
+            ))
+            .with_detail("contract_id", hex::encode(self.contract_id.0))),
+            _ => Ok(()),
+        }
+    }
+
+    // Start a session with a transaction with the given limits.
+    async fn start_transaction(
+        client: &Client,
+        transaction_limits: &TransactionLimits,
+    ) -> Result<ClientSession, mongodb::error::Error> {
+        let mut session = client.start_session(None).await?;
+        let options = TransactionOptions::builder()
+            .read_concern(Self::read_concern())
+            .write_concern(Self::write_concern())
+            .max_commit_time(transaction_limits.max_commit_time)
+            .build();
+        session.start_transaction(options).await?;
+        Ok(session)
+    }
+
+    /// Run f in a transaction, so that either all or none of its writes are made. f is given
+    /// this collection, whose operations then run in the transaction. The transaction is
+    /// committed (see commit) if f succeeds, and aborted if f fails, in which case the error of
+    /// f is returned. Errors are not retried, the caller may run f again if the error is
+    /// transient (e.g. Error::Aborted). This collection must not already have a session, and
+    /// transactions require MongoDB to be a replica set.
+    pub async fn with_transaction<F, V>(&mut self, f: F) -> Result<V, Error>
+    where
+        F: for<'a> FnOnce(&'a mut Self) -> BoxFuture<'a, Result<V, Error>>,
+    {
+        if self.session.is_some() {
+            return Err(Error::Precondition(
+                "Collection already has a transaction".to_string(),
+            ));
+        }
+        self.session = Some(Self::start_transaction(&self.client, &self.transaction_limits).await?);
+        self.in_transaction = true;
+        self.transaction_started = Instant::now();
+        let last_root_update = self.last_root_update;
+        match f(self).await {
+            Ok(value) => {
+                self.commit().await?;
+                Ok(value)
+            }
+            Err(error) => {
+                self.in_transaction = false;
+                if let Some(mut session) = self.session.take() {
+                    let _ = session.abort_transaction().await;
+                }
+                self.last_root_update = last_root_update;
+                Err(error)
+            }
+        }
+    }
+
+    pub async fn commit(&mut self) -> Result<(), Error> {
+        if !self.in_transaction {
+            return Ok(());
+        }
+        self.in_transaction = false;
         if let Some(mut session) = self.session.take() {
+            if let Err(error) = self.check_deadline() {
+                let _ = session.abort_transaction().await;
+                return Err(error);
+            }
+            if self.is_transaction_timed_out() {
+                // Nothing is written if the transaction is aborted before commit.
+                let _ = session.abort_transaction().await;
+                return Err(self.transaction_timeout_error("Transaction timed out before commit"));
+            }
             // A "TransientTransactionError" label indicates that the entire transaction can be retried
             // with a reasonable expectation that it will succeed.
             // An "UnknownTransactionCommitResult" label indicates that it is unknown whether the
             // commit has satisfied the write concern associated with the transaction. If an error
             // with this label is returned, it is safe to retry the commit until the write concern is
             // satisfied or an error without the label is returned.
+            // The retries stop once the commit exceeds max_commit_time or the transaction times
+            // out, in which case the result of the transaction is unknown.
             loop {
-                let result = session.commit_transaction().await;
-                if let Err(ref error) = result {
-                    if error.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT)
-                        || error.contains_label(TRANSIENT_TRANSACTION_ERROR)
-                    {
-                        continue;
-                    }
+                let error = match session.commit_transaction().await {
+                    Ok(()) => return Ok(()),
+                    Err(error) => error,
+                };
+                if is_max_time_expired_error(&error) || self.is_transaction_timed_out() {
+                    return Err(self.transaction_timeout_error(&format!("Commit failed: {error}")));
+                }
+                if error.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT)
+                    || error.contains_label(TRANSIENT_TRANSACTION_ERROR)
+                {
+                    continue;
                 }
-                result?
+                return Err(error.into());
             }
         }
         Ok(())
     }
 
-    pub async fn drop(&self) -> Result<(), mongodb::error::Error> {
-        let options = mongodb::options::DropCollectionOptions::builder().build();
-        self.merkle_collection.drop(options.clone()).await?;
-        self.datahash_collection.drop(options).await?;
+    // Checked before each operation rather than only on commit, so that a transaction running
+    // over its timeout is not continued only to be aborted.
+    fn check_transaction_timeout(&self) -> Result<(), Error> {
+        if self.in_transaction && self.is_transaction_timed_out() {
+            return Err(self.transaction_timeout_error("Transaction timed out"));
+        }
         Ok(())
     }
-}
 
-impl MongoCollection<MerkleRecord, DataHashRecord> {
-    // Special ObjectId to track current root.
-    pub fn get_current_root_object_id() -> mongodb::bson::oid::ObjectId {
-        mongodb::bson::oid::ObjectId::from_bytes([0; 12])
+    fn is_transaction_timed_out(&self) -> bool {
+        self.transaction_limits.timeout.map_or(false, |timeout| {
+            self.transaction_started.elapsed() > timeout
+        })
     }
 
-    pub async fn find_one_merkle_record(
-        &mut self,
-        filter: impl Into<Option<Document>>,
-        options: impl Into<Option<FindOneOptions>>,
-    ) -> Result<Option<MerkleRecord>, mongodb::error::Error> {
+    // An Aborted error (so that the client may retry) with the limits of the transaction, which
+    // are also logged.
+    fn transaction_timeout_error(&self, message: &str) -> Error {
+        let TransactionLimits {
+            max_commit_time,
+            timeout,
+        } = self.transaction_limits;
+        let elapsed = self.transaction_started.elapsed();
+        eprintln!(
+            "Transaction of contract {} aborted after {elapsed:?} (max_commit_time: \
+             {max_commit_time:?}, transaction_timeout: {timeout:?}): {message}",
+            hex::encode(self.contract_id.0)
+        );
+        let mut error = Error::Aborted(message.to_string())
+            .with_detail("contract_id", hex::encode(self.contract_id.0))
+            .with_detail("elapsed_ms", elapsed.as_millis());
+        if let Some(max_commit_time) = max_commit_time {
+            error = error.with_detail("max_commit_time_ms", max_commit_time.as_millis());
+        }
+        if let Some(timeout) = timeout {
+            error = error.with_detail("transaction_timeout_ms", timeout.as_millis());
+        }
+        error
+    }
+
+    pub async fn estimated_merkle_record_count(&self) -> Result<u64, mongodb::error::Error> {
+        self.merkle_collection.estimated_document_count(None).await
+    }
+
+    pub async fn count_datahash_records(&mut self) -> Result<u64, mongodb::error::Error> {
+        let options = CountOptions::builder()
+            .max_time(self.max_time(None))
+            .selection_criteria(self.selection_criteria.clone())
+            .build();
         let result = match self.session.as_mut() {
             Some(session) => {
-                self.merkle_collection
-                    .find_one_with_session(filter, options, session)
+                self.datahash_collection
+                    .count_documents_with_session(doc! {}, options, session)
+                    .await?
+            }
+            _ => {
+                self.datahash_collection
+                    .count_documents(doc! {}, options)
                     .await?
             }
-            _ => self.merkle_collection.find_one(filter, options).await?,
         };
         Ok(result)
     }
 
-    pub async fn insert_one_merkle_record(
-        &mut self,
-        doc: impl Borrow<MerkleRecord>,
-        options: impl Into<Option<InsertOneOptions>>,
-    ) -> Result<InsertOneResult, mongodb::error::Error> {
-        let result = match self.session.as_mut() {
+    /// Drop the collections of this contract. This refuses to run unless destructive operations
+    /// are explicitly allowed with `with_allow_destructive`.
+    pub async fn drop(&self) -> Result<(), Error> {
+        if !self.allow_destructive {
+            return Err(Error::Precondition(format!(
+                "Destructive operations not allowed, refusing to drop contract {}",
+                hex::encode(self.contract_id.0)
+            )));
+        }
+        let options = mongodb::options::DropCollectionOptions::builder().build();
+        self.merkle_collection.drop(options.clone()).await?;
+        self.datahash_collection.drop(options.clone()).await?;
+        self.audit_collection.drop(options.clone()).await?;
+        self.root_archive_collection.drop(options.clone()).await?;
+        self.leaf_index_collection.drop(options.clone()).await?;
+        self.idempotency_collection.drop(options).await?;
+        self.uncache_contract();
+        Ok(())
+    }
+
+    pub fn last_root_update(&self) -> Option<(Hash, Hash)> {
+        self.last_root_update
+    }
+
+    /// Append an entry to the audit log, within the transaction of this collection if any.
+    pub async fn insert_audit_record(&mut self, record: &AuditRecord) -> Result<(), Error> {
+        self.check_deadline()?;
+        match self.session.as_mut() {
             Some(session) => {
-                self.merkle_collection
-                    .insert_one_with_session(doc, options, session)
+                self.audit_collection
+                    .insert_one_with_session(record, None, session)
                     .await?
             }
-            _ => self.merkle_collection.insert_one(doc, options).await?,
+            _ => self.audit_collection.insert_one(record, None).await?,
         };
-        Ok(result)
+        Ok(())
     }
 
-    pub async fn replace_one_merkle_record(
+    /// Find at most limit entries of the audit log in the order they were recorded.
+    pub async fn find_audit_records(
         &mut self,
-        query: Document,
-        replacement: impl Borrow<MerkleRecord>,
-        options: impl Into<Option<ReplaceOptions>>,
-    ) -> Result<UpdateResult, mongodb::error::Error> {
+        filter: Document,
+        limit: i64,
+    ) -> Result<Vec<AuditRecord>, Error> {
+        let options = FindOptions::builder()
+            .sort(doc! {"_id": 1})
+            .limit(limit)
+            .max_time(self.max_time(None))
+            .selection_criteria(self.selection_criteria.clone())
+            .build();
         let result = match self.session.as_mut() {
             Some(session) => {
-                self.merkle_collection
-                    .replace_one_with_session(query, replacement, options, session)
-                    .await?
+                let mut cursor = self
+                    .audit_collection
+                    .find_with_session(filter, options, session)
+                    .await?;
+                cursor.stream(session).try_collect().await?
             }
             _ => {
-                self.merkle_collection
-                    .replace_one(query, replacement, options)
+                self.audit_collection
+                    .find(filter, options)
+                    .await?
+                    .try_collect()
                     .await?
             }
         };
         Ok(result)
     }
 
-    pub async fn update_one_merkle_record(
-        &mut self,
-        query: Document,
-        update: impl Into<UpdateModifications>,
-        options: impl Into<Option<UpdateOptions>>,
-    ) -> Result<UpdateResult, mongodb::error::Error> {
+    /// The last entry of the audit log, None if nothing has been recorded.
+    pub async fn find_last_audit_record(&mut self) -> Result<Option<AuditRecord>, Error> {
+        let options = FindOneOptions::builder()
+            .sort(doc! {"_id": -1})
+            .max_time(self.max_time(None))
+            .selection_criteria(self.selection_criteria.clone())
+            .build();
         let result = match self.session.as_mut() {
             Some(session) => {
-                self.merkle_collection
-                    .update_one_with_session(query, update, options, session)
+                self.audit_collection
+                    .find_one_with_session(doc! {}, options, session)
+                    .await?
+            }
+            _ => self.audit_collection.find_one(doc! {}, options).await?,
+        };
+        Ok(result)
+    }
+
+    /// The entry of the IDEMPOTENCY_ collection with the given key, None if there is none or it
+    /// has expired. MongoDB only removes the expired entries periodically. This always reads from
+    /// the primary, as a retry may reach another server right after the first request.
+    pub async fn find_idempotency_record(
+        &mut self,
+        key: &str,
+    ) -> Result<Option<IdempotencyRecord>, Error> {
+        let filter = doc! {"_id": key, "expires_at": {"$gt": DateTime::now()}};
+        let options = FindOneOptions::builder()
+            .max_time(self.max_time(None))
+            .selection_criteria(SelectionCriteria::ReadPreference(ReadPreference::Primary))
+            .build();
+        let result = match self.session.as_mut() {
+            Some(session) => {
+                self.idempotency_collection
+                    .find_one_with_session(filter, options, session)
                     .await?
             }
             _ => {
-                self.merkle_collection
-                    .update_one(query, update, options)
+                self.idempotency_collection
+                    .find_one(filter, options)
                     .await?
             }
         };
         Ok(result)
     }
 
-    pub async fn get_merkle_record(
+    /// Record the response of a request with an idempotency key, within the transaction of this
+    /// collection if any. An unexpired entry with the same key is kept as it is, i.e. the first
+    /// response recorded wins when the same request is applied concurrently by several servers.
+    pub async fn insert_idempotency_record(
         &mut self,
-        index: u64,
-        hash: &Hash,
-    ) -> Result<Option<MerkleRecord>, Error> {
-        dbg!(index, hash);
-        let mut filter = doc! {};
-        filter.insert("index", u64_to_bson(index));
-        filter.insert("hash", hash_to_bson(hash));
-        let record = self.find_one_merkle_record(filter, None).await?;
-        if record.is_some() {
-            return Ok(record);
-        }
-        let default_record = MerkleRecord::get_default_record(index)?;
-        dbg!(&default_record, hash);
-        if default_record.hash == *hash {
-            Ok(Some(default_record))
-        } else {
-            Ok(None)
+        record: &IdempotencyRecord,
+    ) -> Result<(), Error> {
+        self.check_deadline()?;
+        // Replace the expired entry with the key, if MongoDB has not removed it yet.
+        let filter = doc! {"_id": record.key.as_str(), "expires_at": {"$lte": DateTime::now()}};
+        let result = match self.session.as_mut() {
+            Some(session) => {
+                self.idempotency_collection
+                    .delete_one_with_session(filter, None, session)
+                    .await?;
+                self.idempotency_collection
+                    .insert_one_with_session(record, None, session)
+                    .await
+            }
+            _ => {
+                self.idempotency_collection.delete_one(filter, None).await?;
+                self.idempotency_collection.insert_one(record, None).await
+            }
+        };
+        match result {
+            Err(error) if is_duplicate_key_error(&error) => Ok(()),
+            result => result.map(drop).map_err(Into::into),
         }
     }
 
-    pub async fn must_get_merkle_record(
+    /// Record the response of the write of this collection made with an idempotency key, in the
+    /// transaction of the write if any, in which case this must be done before the commit.
+    /// Without a transaction, this replaces the entry inserted as pending, if any (see
+    /// with_pending_idempotency_record). Unlike insert_idempotency_record, this fails with
+    /// ABORTED if the key was recorded by a concurrent request, so that the write is not applied
+    /// twice.
+    pub async fn record_idempotent_response(
         &mut self,
-        index: u64,
-        hash: &Hash,
-    ) -> Result<MerkleRecord, Error> {
-        let record = self.get_merkle_record(index, hash).await?;
-        record.ok_or(Error::Precondition("Merkle record not found".to_string()))
+        record: &IdempotencyRecord,
+    ) -> Result<(), Error> {
+        self.check_deadline()?;
+        let result = match self.session.as_mut() {
+            Some(session) => {
+                let filter =
+                    doc! {"_id": record.key.as_str(), "expires_at": {"$lte": DateTime::now()}};
+                self.idempotency_collection
+                    .delete_one_with_session(filter, None, session)
+                    .await?;
+                self.idempotency_collection
+                    .insert_one_with_session(record, None, session)
+                    .await
+                    .map(drop)
+            }
+            // Inserted if the pending entry was not, e.g. if the root was not replaced.
+            _ => {
+                let filter = doc! {"_id": record.key.as_str(), "pending": true};
+                let options = ReplaceOptions::builder().upsert(true).build();
+                self.idempotency_collection
+                    .replace_one(filter, record, options)
+                    .await
+                    .map(drop)
+            }
+        };
+        self.pending_idempotency_record = None;
+        match result {
+            Err(error) if is_duplicate_key_error(&error) => Err(Error::Aborted(format!(
+                "Request with idempotency key {:?} applied concurrently",
+                record.key
+            ))
+            .with_detail("idempotency_key", record.key.as_str())),
+            result => result.map_err(Into::into),
+        }
     }
 
-    pub async fn get_root_merkle_record(&mut self) -> Result<Option<MerkleRecord>, Error> {
-        let filter = doc! {"_id": Self::get_current_root_object_id()};
-        let record = self.find_one_merkle_record(filter, None).await?;
-        dbg!(&record);
-        if record.is_some() {
-            return Ok(record);
+    // Insert the pending entry of with_pending_idempotency_record. An unexpired entry with the
+    // same key means that the request is applied concurrently, in which case it is not applied
+    // again.
+    async fn insert_pending_idempotency_record(&mut self) -> Result<(), Error> {
+        let record = match &self.pending_idempotency_record {
+            Some(record) if self.session.is_none() => record.clone(),
+            _ => return Ok(()),
+        };
+        let filter = doc! {"_id": record.key.as_str(), "expires_at": {"$lte": DateTime::now()}};
+        self.idempotency_collection.delete_one(filter, None).await?;
+        match self.idempotency_collection.insert_one(&record, None).await {
+            Err(error) if is_duplicate_key_error(&error) => Err(Error::Aborted(format!(
+                "Request with idempotency key {:?} applied concurrently",
+                record.key
+            ))
+            .with_detail("idempotency_key", record.key)),
+            result => result.map(drop).map_err(Into::into),
         }
-        Ok(MerkleRecord::get_default_record(0).ok())
     }
 
-    pub async fn must_get_root_merkle_record(&mut self) -> Result<MerkleRecord, Error> {
-        let record = self.get_root_merkle_record().await?;
-        assert!(record.is_some(), "BUG!!! Root record not found.");
-        Ok(record.unwrap())
+    // Remove the pending entry of with_pending_idempotency_record once the root was not replaced.
+    async fn remove_pending_idempotency_record(&mut self) -> Result<(), Error> {
+        let key = match &self.pending_idempotency_record {
+            Some(record) if self.session.is_none() => record.key.clone(),
+            _ => return Ok(()),
+        };
+        let filter = doc! {"_id": key, "pending": true};
+        self.idempotency_collection.delete_one(filter, None).await?;
+        Ok(())
     }
+}
 
-    pub async fn insert_merkle_record(
-        &mut self,
-        record: &MerkleRecord,
-    ) -> Result<MerkleRecord, Error> {
-        let mut filter = doc! {};
-        filter.insert("index", u64_to_bson(record.index));
-        filter.insert("hash", hash_to_bson(&record.hash));
-        let result = self.find_one_merkle_record(filter, None).await?;
-        match result {
-            Some(result) => Ok(result),
-            None => {
-                let result = self.insert_one_merkle_record(record, None).await?;
-                dbg!(&record, &result);
-                Ok(*record)
-            }
+// https://www.mongodb.com/docs/manual/core/index-unique/
+const DUPLICATE_KEY_ERROR_CODE: i32 = 11000;
+// https://www.mongodb.com/docs/manual/reference/error-codes/
+const NAMESPACE_NOT_FOUND_ERROR_CODE: i32 = 26;
+const INDEX_NOT_FOUND_ERROR_CODE: i32 = 27;
+const NAMESPACE_EXISTS_ERROR_CODE: i32 = 48;
+
+// The number of times a write is attempted when the root is changed concurrently by another
+// writer, see MongoCollection::update_root_merkle_record.
+pub const MAX_ROOT_UPDATE_ATTEMPTS: usize = 5;
+// The backoff before the second attempt of such a write, doubled for each following attempt.
+const ROOT_UPDATE_INITIAL_BACKOFF: Duration = Duration::from_millis(10);
+
+// Parse the value of the grpc-timeout header, i.e. at most 8 digits followed by the unit, see
+// https://github.com/grpc/grpc/blob/master/doc/PROTOCOL-HTTP2.md
+fn parse_grpc_timeout(timeout: &str) -> Option<Duration> {
+    if !timeout.is_ascii() || timeout.len() < 2 || timeout.len() > 9 {
+        return None;
+    }
+    let (value, unit) = timeout.split_at(timeout.len() - 1);
+    if !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let value: u64 = value.parse().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(value * 60 * 60)),
+        "M" => Some(Duration::from_secs(value * 60)),
+        "S" => Some(Duration::from_secs(value)),
+        "m" => Some(Duration::from_millis(value)),
+        "u" => Some(Duration::from_micros(value)),
+        "n" => Some(Duration::from_nanos(value)),
+        _ => None,
+    }
+}
+
+fn is_duplicate_key_error(error: &mongodb::error::Error) -> bool {
+    matches!(
+        error.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(e)) if e.code == DUPLICATE_KEY_ERROR_CODE
+    )
+}
+
+// A contract whose records were written by a newer binary, which this one may misread.
+fn newer_schema_version_error(contract_id: &ContractId, version: u32) -> Error {
+    Error::Precondition(format!(
+        "Contract {} has schema version {version}, newer than the latest supported version \
+         {SCHEMA_VERSION}",
+        hex::encode(contract_id.0)
+    ))
+    .with_detail("contract_id", hex::encode(contract_id.0))
+    .with_detail("schema_version", version)
+}
+
+// A contract whose merkle tree has another height than the one of this binary, e.g. written by
+// a binary built with another MERKLE_TREE_HEIGHT, whose proofs this one would get wrong. The
+// height of the tree is None if it is not known, only that it is not expected_height.
+fn tree_height_mismatch_error(
+    contract_id: &ContractId,
+    height: Option<usize>,
+    expected_height: usize,
+) -> Error {
+    let height_detail = height.map_or("unknown".to_string(), |height| height.to_string());
+    Error::Precondition(format!(
+        "Tree height mismatch: contract {} has a merkle tree of height {height_detail}, expected \
+         {expected_height}",
+        hex::encode(contract_id.0)
+    ))
+    .with_detail("contract_id", hex::encode(contract_id.0))
+    .with_detail("tree_height", height_detail)
+    .with_detail("expected_tree_height", expected_height)
+}
+
+// Whether a command failed because its collection or index does not exist.
+fn is_not_found_error(error: &mongodb::error::Error) -> bool {
+    matches!(
+        error.kind.as_ref(),
+        ErrorKind::Command(e)
+            if e.code == NAMESPACE_NOT_FOUND_ERROR_CODE || e.code == INDEX_NOT_FOUND_ERROR_CODE
+    )
+}
+
+fn is_namespace_exists_error(error: &mongodb::error::Error) -> bool {
+    matches!(
+        error.kind.as_ref(),
+        ErrorKind::Command(e) if e.code == NAMESPACE_EXISTS_ERROR_CODE
+    )
+}
+
+// The error of a read which failed to deserialize a document. The documents matching the filter
+// are read again as plain documents, to report the _id of the first one which can not be
+// deserialized. Only the failing reads pay for this.
+// The number of documents malformed_document_error checks at most.
+const MALFORMED_DOCUMENT_SCAN_LIMIT: i64 = 4096;
+
+async fn malformed_document_error<D: DeserializeOwned>(
+    collection: &Collection<D>,
+    filter: Option<Document>,
+    error: mongodb::error::Error,
+) -> Error {
+    if !matches!(error.kind.as_ref(), ErrorKind::BsonDeserialization(_)) {
+        return error.into();
+    }
+    let collection = collection.clone_with_type::<Document>();
+    let options = FindOptions::builder()
+        .limit(MALFORMED_DOCUMENT_SCAN_LIMIT)
+        .build();
+    let mut cursor = match collection.find(filter, options).await {
+        Ok(cursor) => cursor,
+        Err(_) => return error.into(),
+    };
+    while let Some(Ok(document)) = cursor.next().await {
+        let id = match document.get("_id") {
+            Some(Bson::ObjectId(id)) => id.to_hex(),
+            Some(Bson::String(id)) => id.clone(),
+            Some(id) => id.to_string(),
+            None => String::new(),
+        };
+        if let Err(e) = from_document::<D>(document) {
+            return Error::InconsistentData(format!(
+                "Malformed document {id} in collection {}: {e}",
+                collection.name()
+            ))
+            .with_detail("_id", id)
+            .with_detail("collection", collection.name());
         }
     }
+    error.into()
+}
 
-    pub async fn insert_non_leaf_node(
-        &mut self,
-        index: u64,
-        left: Hash,
-        right: Hash,
-    ) -> Result<MerkleRecord, Error> {
-        let record = MerkleRecord::new_non_leaf(index, left, right);
-        self.insert_merkle_record(&record).await
+// The validator of the merkle collections, see MongoCollection::ensure_validators. Besides
+// their types, the lengths of the binary fields are checked, which $jsonSchema can not do.
+fn merkle_record_validator() -> Document {
+    doc! {
+        "$jsonSchema": {
+            "bsonType": "object",
+            "required": ["index", "hash", "left", "right"],
+            "properties": {
+                "index": {"bsonType": "binData"},
+                "hash": {"bsonType": "binData"},
+                "left": {"bsonType": "binData"},
+                "right": {"bsonType": "binData"},
+                "created_at": {"bsonType": "date"},
+            },
+        },
+        "$expr": {"$and": [
+            {"$eq": [{"$binarySize": "$index"}, 8]},
+            {"$eq": [{"$binarySize": "$hash"}, 32]},
+            {"$eq": [{"$binarySize": "$left"}, 32]},
+            {"$eq": [{"$binarySize": "$right"}, 32]},
+        ]},
     }
+}
 
-    pub async fn update_root_merkle_record(
-        &mut self,
-        record: &MerkleRecord,
-    ) -> Result<MerkleRecord, Error> {
-        let filter = doc! {"_id": Self::get_current_root_object_id()};
-        let update = doc! {
-            "$set": {
-                "index": u64_to_bson(0),
-                "hash": to_bson(&record.hash).unwrap(),
-                "left": to_bson(&record.left).unwrap(),
-                "right": to_bson(&record.right).unwrap(),
-                "data": u256_to_bson(&record.data)
+// The validator of the data hash collections, see MongoCollection::ensure_validators.
+fn datahash_record_validator() -> Document {
+    doc! {
+        "$jsonSchema": {
+            "bsonType": "object",
+            "required": ["hash", "data"],
+            "properties": {
+                "hash": {"bsonType": "binData"},
+                "data": {"bsonType": "binData"},
+                "created_at": {"bsonType": "date"},
             },
-        };
-        let options = UpdateOptions::builder().upsert(true).build();
-        let result = self
-            .update_one_merkle_record(filter, update, options)
-            .await?;
-        dbg!(&result);
-        Ok(*record)
+        },
+        "$expr": {"$eq": [{"$binarySize": "$hash"}, 32]},
     }
+}
 
-    pub async fn get_leaf_and_proof(
-        &mut self,
-        index: u64,
-    ) -> Result<(MerkleRecord, MerkleProof<Hash, MERKLE_TREE_HEIGHT>), Error> {
-        leaf_check(index, MERKLE_TREE_HEIGHT)?;
-        let paths = get_path(index, MERKLE_TREE_HEIGHT)?;
-        // We push the search from the top
-        let mut acc = 0;
-        let mut acc_node = self.must_get_root_merkle_record().await?;
-        let root_hash = acc_node.hash;
-        let mut assist = Vec::with_capacity(MERKLE_TREE_HEIGHT);
-        for child in paths {
-            let is_left_child = (acc + 1) * 2 == child + 1;
-            let is_right_child = (acc + 1) * 2 == child;
-            assert!(is_left_child || is_right_child);
-            let (hash, sibling_hash) = if is_left_child {
-                (acc_node.left().unwrap(), acc_node.right().unwrap())
-            } else {
-                (acc_node.right().unwrap(), acc_node.left().unwrap())
-            };
-            let sibling = get_sibling_index(child);
-            let sibling_node = self.must_get_merkle_record(sibling, &sibling_hash).await?;
-            acc = child;
-            acc_node = self.must_get_merkle_record(acc, &hash).await?;
-            assist.push(sibling_node.hash());
+// Whether all the failures of an unordered insert_many are duplicate keys, i.e. all the records
+// not inserted already exist.
+fn is_bulk_duplicate_key_error(error: &mongodb::error::Error) -> bool {
+    match error.kind.as_ref() {
+        ErrorKind::BulkWrite(failure) => {
+            failure.write_concern_error.is_none()
+                && failure
+                    .write_errors
+                    .iter()
+                    .flatten()
+                    .all(|e| e.code == DUPLICATE_KEY_ERROR_CODE)
         }
-        let hash = acc_node.hash();
-        Ok((
-            acc_node,
-            MerkleProof {
-                source: hash,
-                root: root_hash,
-                assist,
-                index,
-            },
-        ))
+        _ => false,
     }
+}
 
-    pub async fn set_leaf_and_get_proof(
+/// The _id of a merkle record in the sharded layout: the contract id, the index (8 bytes, little
+/// endian as in the index field) and the hash. Records are content addressed, so a record has
+/// the same _id whoever writes it.
+pub fn merkle_record_id(contract_id: &ContractId, index: u64, hash: &Hash) -> Bson {
+    let mut bytes = Vec::with_capacity(32 + 8 + 32);
+    bytes.extend_from_slice(&contract_id.0);
+    bytes.extend_from_slice(&index.to_le_bytes());
+    bytes.extend_from_slice(&hash.0);
+    Bson::Binary(Binary {
+        subtype: BinarySubtype::Generic,
+        bytes,
+    })
+}
+
+fn is_root_changed(error: &Error) -> bool {
+    matches!(error.inner(), Error::RootChanged(_))
+}
+
+// Whether a transaction which failed with the error may succeed if it is run again from the
+// start: another writer has replaced the root, or MongoDB has aborted the transaction (e.g. on a
+// write conflict with another transaction).
+fn is_restartable_transaction_error(error: &Error) -> bool {
+    match error.inner() {
+        Error::RootChanged(_) => true,
+        Error::Mongodb(error) => error.contains_label(TRANSIENT_TRANSACTION_ERROR),
+        _ => false,
+    }
+}
+
+// How long to wait after the given failed attempt of a write whose root was changed by another
+// writer: an exponential backoff with full jitter, so that the writers failing at the same time
+// do not keep conflicting with each other.
+fn root_update_backoff(attempt: usize) -> Duration {
+    let backoff = ROOT_UPDATE_INITIAL_BACKOFF * 2_u32.pow(attempt.saturating_sub(1) as u32);
+    backoff.mul_f64(rand::random::<f64>())
+}
+
+impl<const H: usize> MongoCollection<MerkleRecord, DataHashRecord, H> {
+    // Special ObjectId to track current root.
+    pub fn get_current_root_object_id() -> mongodb::bson::oid::ObjectId {
+        mongodb::bson::oid::ObjectId::from_bytes([0; 12])
+    }
+
+    // Whether the merkle records are written with their data field, see with_schema_version.
+    fn writes_merkle_record_data(&self) -> bool {
+        self.schema_version < 2
+    }
+
+    // The document a merkle record is written as, in the format of the schema version of the
+    // contract.
+    fn merkle_record_document(&self, record: &MerkleRecord) -> Document {
+        let mut document = to_document(record).unwrap();
+        if self.writes_merkle_record_data() {
+            document.insert("data", u256_to_bson(&record.data));
+        }
+        document
+    }
+
+    pub async fn find_one_merkle_record(
         &mut self,
-        leaf: &MerkleRecord,
-    ) -> Result<MerkleProof<Hash, MERKLE_TREE_HEIGHT>, Error> {
-        let index = leaf.index();
-        let mut hash = leaf.hash();
-        let (_, mut proof) = self.get_leaf_and_proof(index).await?;
-        proof.source = hash;
-        let mut p = get_offset(index);
-        self.insert_merkle_record(leaf).await?;
-        for i in 0..MERKLE_TREE_HEIGHT {
-            let cur_hash = hash;
-            let depth = MERKLE_TREE_HEIGHT - i - 1;
-            let (left, right) = if p % 2 == 1 {
-                (proof.assist[depth], cur_hash)
-            } else {
-                (cur_hash, proof.assist[depth])
-            };
-            hash = Hash::hash_children(&left, &right);
-            p /= 2;
-            let index = p + (1 << depth) - 1;
-            let record = MerkleRecord::new_non_leaf(index, left, right);
-            assert_eq!(record.hash, hash);
-            self.insert_merkle_record(&record).await?;
-            if index == 0 {
-                self.update_root_merkle_record(&record).await?;
+        filter: impl Into<Option<Document>>,
+        options: impl Into<Option<FindOneOptions>>,
+    ) -> Result<Option<MerkleRecord>, Error> {
+        let filter = filter.into();
+        let mut options = options.into().unwrap_or_default();
+        options.max_time = self.max_time(options.max_time);
+        if options.selection_criteria.is_none() {
+            options.selection_criteria = self.selection_criteria.clone();
+        }
+        let result = match self.session.as_mut() {
+            Some(session) => {
+                self.merkle_collection
+                    .find_one_with_session(filter.clone(), options, session)
+                    .await
+            }
+            _ => {
+                self.merkle_collection
+                    .find_one(filter.clone(), options)
+                    .await
+            }
+        };
+        match result {
+            Ok(record) => Ok(record),
+            Err(error) => {
+                Err(malformed_document_error(&self.merkle_collection, filter, error).await)
             }
         }
-        Ok(proof)
     }
 
-    pub async fn find_one_datahash_record(
+    pub async fn find_merkle_records(
         &mut self,
         filter: impl Into<Option<Document>>,
-        options: impl Into<Option<FindOneOptions>>,
-    ) -> Result<Option<DataHashRecord>, mongodb::error::Error> {
+        options: impl Into<Option<FindOptions>>,
+    ) -> Result<Vec<MerkleRecord>, Error> {
+        let filter = filter.into();
+        let mut options = options.into().unwrap_or_default();
+        options.max_time = self.max_time(options.max_time);
+        if options.selection_criteria.is_none() {
+            options.selection_criteria = self.selection_criteria.clone();
+        }
         let result = match self.session.as_mut() {
             Some(session) => {
-                self.datahash_collection
-                    .find_one_with_session(filter, options, session)
+                match self
+                    .merkle_collection
+                    .find_with_session(filter.clone(), options, session)
+                    .await
+                {
+                    Ok(mut cursor) => cursor.stream(session).try_collect().await,
+                    Err(error) => Err(error),
+                }
+            }
+            _ => match self.merkle_collection.find(filter.clone(), options).await {
+                Ok(cursor) => cursor.try_collect().await,
+                Err(error) => Err(error),
+            },
+        };
+        match result {
+            Ok(records) => Ok(records),
+            Err(error) => {
+                Err(malformed_document_error(&self.merkle_collection, filter, error).await)
+            }
+        }
+    }
+
+    pub async fn insert_many_merkle_records(
+        &mut self,
+        docs: impl IntoIterator<Item = impl Borrow<MerkleRecord>>,
+        options: impl Into<Option<InsertManyOptions>>,
+    ) -> Result<InsertManyResult, mongodb::error::Error> {
+        let docs = docs
+            .into_iter()
+            .map(|doc| self.merkle_record_document(doc.borrow()))
+            .collect::<Vec<_>>();
+        self.insert_many_merkle_documents(docs, options).await
+    }
+
+    pub async fn insert_many_merkle_documents(
+        &mut self,
+        docs: impl IntoIterator<Item = Document>,
+        options: impl Into<Option<InsertManyOptions>>,
+    ) -> Result<InsertManyResult, mongodb::error::Error> {
+        let collection = self.merkle_collection.clone_with_type::<Document>();
+        let result = match self.session.as_mut() {
+            Some(session) => {
+                collection
+                    .insert_many_with_session(docs, options, session)
                     .await?
             }
-            _ => self.datahash_collection.find_one(filter, options).await?,
+            _ => collection.insert_many(docs, options).await?,
         };
         Ok(result)
     }
 
-    pub async fn insert_one_datahash_record(
+    pub async fn insert_one_merkle_record(
         &mut self,
-        doc: impl Borrow<DataHashRecord>,
+        doc: impl Borrow<MerkleRecord>,
         options: impl Into<Option<InsertOneOptions>>,
     ) -> Result<InsertOneResult, mongodb::error::Error> {
+        let doc = self.merkle_record_document(doc.borrow());
+        let collection = self.merkle_collection.clone_with_type::<Document>();
         let result = match self.session.as_mut() {
             Some(session) => {
-                self.datahash_collection
+                collection
                     .insert_one_with_session(doc, options, session)
                     .await?
             }
-            _ => self.datahash_collection.insert_one(doc, options).await?,
+            _ => collection.insert_one(doc, options).await?,
         };
         Ok(result)
     }
 
-    pub async fn insert_datahash_record(
+    pub async fn replace_one_merkle_record(
         &mut self,
-        record: &DataHashRecord,
-    ) -> Result<DataHashRecord, Error> {
-        let mut filter = doc! {};
-        filter.insert("hash", hash_to_bson(&record.hash));
-        dbg!(&record.hash, &filter);
-        let result = self.find_one_datahash_record(filter, None).await?;
-        dbg!(&result);
-        match result {
-            Some(result) => Ok(result),
-            None => {
-                let result = self.insert_one_datahash_record(record, None).await?;
-                dbg!(&record, &result);
-                Ok(record.clone())
+        query: Document,
+        replacement: impl Borrow<MerkleRecord>,
+        options: impl Into<Option<ReplaceOptions>>,
+    ) -> Result<UpdateResult, mongodb::error::Error> {
+        let replacement = self.merkle_record_document(replacement.borrow());
+        let collection = self.merkle_collection.clone_with_type::<Document>();
+        let result = match self.session.as_mut() {
+            Some(session) => {
+                collection
+                    .replace_one_with_session(query, replacement, options, session)
+                    .await?
             }
-        }
+            _ => collection.replace_one(query, replacement, options).await?,
+        };
+        Ok(result)
     }
 
-    pub async fn get_datahash_record(
+    pub async fn update_one_merkle_record(
         &mut self,
-        hash: &Hash,
-    ) -> Result<Option<DataHashRecord>, Error> {
-        dbg!(hash);
-        if *hash == Hash::empty() {
-            return Ok(Some(DataHashRecord::empty()));
+        query: Document,
+        update: impl Into<UpdateModifications>,
+        options: impl Into<Option<UpdateOptions>>,
+    ) -> Result<UpdateResult, mongodb::error::Error> {
+        let result = match self.session.as_mut() {
+            Some(session) => {
+                self.merkle_collection
+                    .update_one_with_session(query, update, options, session)
+                    .await?
+            }
+            _ => {
+                self.merkle_collection
+                    .update_one(query, update, options)
+                    .await?
+            }
+        };
+        Ok(result)
+    }
+
+    pub async fn update_many_merkle_records(
+        &mut self,
+        query: Document,
+        update: impl Into<UpdateModifications>,
+        options: impl Into<Option<UpdateOptions>>,
+    ) -> Result<UpdateResult, mongodb::error::Error> {
+        let result = match self.session.as_mut() {
+            Some(session) => {
+                self.merkle_collection
+                    .update_many_with_session(query, update, options, session)
+                    .await?
+            }
+            _ => {
+                self.merkle_collection
+                    .update_many(query, update, options)
+                    .await?
+            }
+        };
+        Ok(result)
+    }
+
+    pub async fn delete_many_merkle_records(
+        &mut self,
+        query: Document,
+        options: impl Into<Option<DeleteOptions>>,
+    ) -> Result<DeleteResult, mongodb::error::Error> {
+        let result = match self.session.as_mut() {
+            Some(session) => {
+                self.merkle_collection
+                    .delete_many_with_session(query, options, session)
+                    .await?
+            }
+            _ => self.merkle_collection.delete_many(query, options).await?,
+        };
+        Ok(result)
+    }
+
+    fn get_cached_merkle_record(&self, index: u64, hash: &Hash) -> Option<MerkleRecord> {
+        self.cache.as_ref().and_then(|cache| {
+            cache
+                .lock()
+                .unwrap()
+                .get(&(self.contract_id, index, *hash))
+                .copied()
+        })
+    }
+
+    fn cache_merkle_record(&self, record: &MerkleRecord) {
+        // Records read or written within a transaction may be rolled back later.
+        if self.in_transaction {
+            return;
+        }
+        if let Some(cache) = self.cache.as_ref() {
+            cache
+                .lock()
+                .unwrap()
+                .put((self.contract_id, record.index, record.hash), *record);
+        }
+    }
+
+    // Remove all the cached records of this contract, e.g. once its collections are dropped, so
+    // that they are not returned if the contract is recreated.
+    fn uncache_contract(&self) {
+        if let Some(cache) = self.cache.as_ref() {
+            let mut cache = cache.lock().unwrap();
+            let keys = cache
+                .iter()
+                .map(|(key, _)| *key)
+                .filter(|(contract_id, _, _)| *contract_id == self.contract_id)
+                .collect::<Vec<_>>();
+            for key in keys {
+                cache.pop(&key);
+            }
+        }
+    }
+
+    fn uncache_merkle_record(&self, index: u64, hash: &Hash) {
+        if let Some(cache) = self.cache.as_ref() {
+            cache.lock().unwrap().pop(&(self.contract_id, index, *hash));
+        }
+    }
+
+    // The filter matching the merkle record with the index and hash, by _id in the sharded layout.
+    fn merkle_record_filter(&self, index: u64, hash: &Hash) -> Document {
+        if self.sharded_layout {
+            return doc! {"_id": merkle_record_id(&self.contract_id, index, hash)};
         }
         let mut filter = doc! {};
+        filter.insert("index", u64_to_bson(index));
         filter.insert("hash", hash_to_bson(hash));
-        let record = self.find_one_datahash_record(filter, None).await?;
+        filter
+    }
+
+    pub async fn get_merkle_record(
+        &mut self,
+        index: u64,
+        hash: &Hash,
+    ) -> Result<Option<MerkleRecord>, Error> {
+        dbg!(index, hash);
+        self.check_transaction_timeout()?;
+        if let Some(record) = self.get_cached_merkle_record(index, hash) {
+            return Ok(Some(record));
+        }
+        let filter = self.merkle_record_filter(index, hash);
+        let record = self.find_one_merkle_record(filter, None).await?;
+        if let Some(record) = record.as_ref() {
+            self.cache_merkle_record(record);
+            return Ok(Some(*record));
+        }
+        let default_record = MerkleRecord::get_default_record_with_height::<H>(index)?;
+        dbg!(&default_record, hash);
+        if default_record.hash == *hash {
+            Ok(Some(default_record))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub async fn must_get_merkle_record(
+        &mut self,
+        index: u64,
+        hash: &Hash,
+    ) -> Result<MerkleRecord, Error> {
+        let record = self.get_merkle_record(index, hash).await?;
+        record.ok_or_else(|| {
+            Error::NotFound(format!(
+                "Merkle record {index} with hash {hash:?} not found"
+            ))
+            .with_detail("index", index)
+            .with_detail("hash", hex::encode(hash.0))
+        })
+    }
+
+    /// Check that the root record is well-formed, i.e. its children hash to its hash and are
+    /// records of this contract (or default records), so that it can be made the current root.
+    pub async fn verify_root_record(&mut self, record: &MerkleRecord) -> Result<(), Error> {
+        let invalid_root = |reason: String| {
+            Error::InvalidArgument(format!("Invalid root {:?}: {reason}", record.hash))
+                .with_detail("hash", hex::encode(record.hash.0))
+        };
+        if Hash::try_hash_children(&record.left, &record.right).ok() != Some(record.hash) {
+            return Err(invalid_root("children not hashing to the root".to_string())
+                .with_detail("left", hex::encode(record.left.0))
+                .with_detail("right", hex::encode(record.right.0)));
+        }
+        for (index, child) in [(1, record.left), (2, record.right)] {
+            if self.get_merkle_record(index, &child).await?.is_none() {
+                return Err(invalid_root(format!("child {index} not found"))
+                    .with_detail("index", index)
+                    .with_detail("child_hash", hex::encode(child.0)));
+            }
+        }
+        Ok(())
+    }
+
+    /// The current root record, None if the root document of the contract does not exist yet
+    /// (see must_get_root_merkle_record).
+    pub async fn get_root_merkle_record(&mut self) -> Result<Option<MerkleRecord>, Error> {
+        let filter = doc! {"_id": Self::get_current_root_object_id()};
+        let record = self.find_one_merkle_record(filter, None).await?;
+        dbg!(&record);
         Ok(record)
     }
 
-    pub async fn must_get_datahash_record(&mut self, hash: &Hash) -> Result<DataHashRecord, Error> {
-        let record = self.get_datahash_record(hash).await?;
-        record.ok_or(Error::Precondition("Datahash record not found".to_string()))
+    /// The current root record with its version, which is incremented each time the root is
+    /// replaced (see update_root_merkle_record). The initial root is at version 0, as are the
+    /// roots written before versions were recorded, until they are replaced.
+    pub async fn get_root_merkle_record_with_version(
+        &mut self,
+    ) -> Result<Option<(MerkleRecord, u64)>, Error> {
+        let filter = doc! {"_id": Self::get_current_root_object_id()};
+        let options = FindOneOptions::builder()
+            .max_time(self.max_time(None))
+            .selection_criteria(self.selection_criteria.clone())
+            .build();
+        let collection = self.merkle_collection.clone_with_type::<Document>();
+        let document = match self.session.as_mut() {
+            Some(session) => {
+                collection
+                    .find_one_with_session(filter, options, session)
+                    .await?
+            }
+            _ => collection.find_one(filter, options).await?,
+        };
+        let document = match document {
+            Some(document) => document,
+            None => return Ok(None),
+        };
+        let version = document.get_i64("version").unwrap_or(0) as u64;
+        let record = from_document(document).map_err(|e| {
+            Error::InconsistentData(format!(
+                "Malformed root record of contract {}: {e}",
+                hex::encode(self.contract_id.0)
+            ))
+        })?;
+        dbg!(&record, version);
+        Ok(Some((record, version)))
     }
-}
 
-impl MongoKvPair {
-    pub async fn new() -> Self {
-        let mongodb_uri: String =
-            std::env::var("MONGODB_URI").unwrap_or("mongodb://localhost:27017".to_string());
-        let client = Client::with_uri_str(&mongodb_uri).await.unwrap();
-        // Eagerly connect to mongodb server to fail faster.
-        let _ = client
-            .list_database_names(
-                doc! {
-                    "name": MongoCollection::<(), ()>::get_database_name(),
-                },
-                None,
-            )
-            .await
-            .expect("List databases");
-        MongoKvPair::new_with_client(client)
+    /// Same as must_get_root_merkle_record, with the version of the root.
+    pub async fn must_get_root_merkle_record_with_version(
+        &mut self,
+    ) -> Result<(MerkleRecord, u64), Error> {
+        match self.get_root_merkle_record_with_version().await? {
+            Some(root) => Ok(root),
+            None => Ok((self.get_default_root_merkle_record().await?, 0)),
+        }
+    }
+
+    /// Watch the changes of the current root record with a change stream. This fails if change
+    /// streams are not supported, e.g. by a standalone mongod.
+    pub async fn watch_root_merkle_record(
+        &self,
+    ) -> Result<ChangeStream<ChangeStreamEvent<MerkleRecord>>, Error> {
+        let pipeline = [doc! {"$match": {"documentKey._id": Self::get_current_root_object_id()}}];
+        let options = ChangeStreamOptions::builder()
+            .full_document(Some(FullDocumentType::UpdateLookup))
+            .build();
+        Ok(self.merkle_collection.watch(pipeline, options).await?)
+    }
+
+    /// The current root record. The root of a new contract is the default (empty) root, which
+    /// is not written by reads, but by the first root update or init_contract.
+    pub async fn must_get_root_merkle_record(&mut self) -> Result<MerkleRecord, Error> {
+        match self.get_root_merkle_record().await? {
+            Some(record) => Ok(record),
+            None => self.get_default_root_merkle_record().await,
+        }
+    }
+
+    // The default root of a contract without a root document. A contract whose root has been
+    // updated (i.e. with an audit log) never loses its root document, so it is reported as
+    // inconsistent instead of being silently reset to the empty tree.
+    async fn get_default_root_merkle_record(&mut self) -> Result<MerkleRecord, Error> {
+        if !self.find_audit_records(doc! {}, 1).await?.is_empty() {
+            let contract_id = hex::encode(self.contract_id.0);
+            return Err(Error::InconsistentData(format!(
+                "Root record of contract {contract_id} not found although its audit log is not \
+                 empty"
+            ))
+            .with_detail("contract_id", &contract_id));
+        }
+        MerkleRecord::get_default_record_with_height::<H>(0)
+    }
+
+    /// Create the collections of a new contract and write the default root as its root
+    /// document, which is otherwise only done by its first write. The root document of a
+    /// contract which has one is kept as it is. With preload_default_spine, the default records
+    /// of the leftmost path are also inserted when the root document is written, see
+    /// insert_default_spine. Returns the current root record.
+    pub async fn init_contract(
+        &mut self,
+        preload_default_spine: bool,
+    ) -> Result<MerkleRecord, Error> {
+        if let Some(record) = self.get_root_merkle_record().await? {
+            return Ok(record);
+        }
+        Self::create_collections(&self.client, &self.contract_id).await?;
+        Self::create_idempotency_index(&self.client, &self.contract_id).await?;
+        let record = self.get_default_root_merkle_record().await?;
+        // Concurrent requests may write the root document first, in which case it is kept.
+        let filter = doc! {"_id": Self::get_current_root_object_id()};
+        let mut root = doc! {
+            "index": u64_to_bson(0),
+            "hash": to_bson(&record.hash).unwrap(),
+            "left": to_bson(&record.left).unwrap(),
+            "right": to_bson(&record.right).unwrap(),
+            "version": 0_i64,
+        };
+        if self.writes_merkle_record_data() {
+            root.insert("data", u256_to_bson(&record.data));
+        }
+        let update = doc! {"$setOnInsert": root};
+        self.check_deadline()?;
+        let options = UpdateOptions::builder().upsert(true).build();
+        let result = self
+            .update_one_merkle_record(filter, update, options)
+            .await?;
+        if result.upserted_id.is_some() {
+            self.init_contract_metadata().await?;
+            if preload_default_spine {
+                self.insert_default_spine().await?;
+            }
+        }
+        self.get_root_merkle_record().await?.ok_or_else(|| {
+            Error::InconsistentData(format!(
+                "Root record of contract {} not found",
+                hex::encode(self.contract_id.0)
+            ))
+            .with_detail("contract_id", hex::encode(self.contract_id.0))
+        })
+    }
+
+    // Record the metadata of a new contract, once its root document is written: its records are
+    // all written in the current format, so its schema version is SCHEMA_VERSION (unless its
+    // metadata already has a version), and its LEAFINDEX_ collection is maintained from the
+    // start, so it is complete.
+    async fn init_contract_metadata(&self) -> Result<(), Error> {
+        self.set_leaf_index_complete(true).await?;
+        let collection = Self::get_contracts_collection(&self.client);
+        let contract_id = u256_to_bson(&self.contract_id.0);
+        let filter = doc! {
+            "contract_id": contract_id.clone(),
+            "schema_version": {"$exists": false},
+        };
+        let update = doc! {"$set": {"schema_version": SCHEMA_VERSION}};
+        let result = collection.update_one(filter, update, None).await?;
+        if result.matched_count == 0 {
+            // Either the contract has no metadata yet, or it already has a version.
+            let filter = doc! {"contract_id": contract_id};
+            let update = doc! {"$setOnInsert": {"schema_version": SCHEMA_VERSION}};
+            let options = UpdateOptions::builder().upsert(true).build();
+            collection.update_one(filter, update, options).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn insert_merkle_record(
+        &mut self,
+        record: &MerkleRecord,
+    ) -> Result<MerkleRecord, Error> {
+        if let Some(result) = self.get_cached_merkle_record(record.index, &record.hash) {
+            return Ok(result);
+        }
+        let record = &record.with_created_at(DateTime::now());
+        if self.sharded_layout {
+            self.insert_new_merkle_records(std::slice::from_ref(record), true)
+                .await?;
+            self.cache_merkle_record(record);
+            return Ok(*record);
+        }
+        let filter = self.merkle_record_filter(record.index, &record.hash);
+        let result = self.find_one_merkle_record(filter, None).await?;
+        let result = match result {
+            // The existing record is kept as it is, with its creation time.
+            Some(result) => result,
+            None => {
+                let result = self.insert_one_merkle_record(record, None).await?;
+                dbg!(&record, &result);
+                *record
+            }
+        };
+        self.cache_merkle_record(&result);
+        Ok(result)
+    }
+
+    /// Insert merkle records in bulk. Records already in the database are skipped. Since the
+    /// records are content addressed, duplicate key errors (e.g. when another request inserts
+    /// the same record concurrently) are ignored instead of aborting the whole batch. In the
+    /// sharded layout, existing records are only detected by these errors. The new records are
+    /// inserted with the current time as their created_at, existing records keep theirs.
+    pub async fn insert_merkle_records(&mut self, records: &[MerkleRecord]) -> Result<(), Error> {
+        const BATCH_SIZE: usize = 1000;
+        let now = DateTime::now();
+        for records in records.chunks(BATCH_SIZE) {
+            let mut new_records: Vec<MerkleRecord> = Vec::with_capacity(records.len());
+            for record in records {
+                let cached = self
+                    .get_cached_merkle_record(record.index, &record.hash)
+                    .is_some();
+                let duplicated = new_records
+                    .iter()
+                    .any(|r| r.index == record.index && r.hash == record.hash);
+                if !cached && !duplicated {
+                    new_records.push(record.with_created_at(now));
+                }
+            }
+            if new_records.is_empty() {
+                continue;
+            }
+            if self.sharded_layout {
+                self.insert_new_merkle_records(&new_records, true).await?;
+                for record in &new_records {
+                    self.cache_merkle_record(record);
+                }
+                continue;
+            }
+
+            let conditions = new_records
+                .iter()
+                .map(|record| {
+                    let mut filter = doc! {};
+                    filter.insert("index", u64_to_bson(record.index));
+                    filter.insert("hash", hash_to_bson(&record.hash));
+                    filter
+                })
+                .collect::<Vec<_>>();
+            let filter = doc! { "$or": conditions };
+            let existing_records = self.find_merkle_records(filter, None).await?;
+            for record in &existing_records {
+                self.cache_merkle_record(record);
+            }
+            new_records.retain(|record| {
+                !existing_records
+                    .iter()
+                    .any(|r| r.index == record.index && r.hash == record.hash)
+            });
+            if new_records.is_empty() {
+                continue;
+            }
+
+            self.insert_new_merkle_records(&new_records, false).await?;
+            for record in &new_records {
+                self.cache_merkle_record(record);
+            }
+        }
+        Ok(())
+    }
+
+    // Insert the records with a single unordered insert_many, ignoring the records which already
+    // exist (duplicate keys). With with_ids, the records are inserted with their _id in the
+    // sharded layout (see merkle_record_id).
+    async fn insert_new_merkle_records(
+        &mut self,
+        records: &[MerkleRecord],
+        with_ids: bool,
+    ) -> Result<(), Error> {
+        self.check_deadline()?;
+        let options = InsertManyOptions::builder().ordered(false).build();
+        let result = if with_ids {
+            let contract_id = self.contract_id;
+            let documents = records.iter().map(|record| {
+                let mut document = self.merkle_record_document(record);
+                document.insert(
+                    "_id",
+                    merkle_record_id(&contract_id, record.index, &record.hash),
+                );
+                document
+            });
+            self.insert_many_merkle_documents(documents, options).await
+        } else {
+            self.insert_many_merkle_records(records, options).await
+        };
+        match result {
+            Err(error) if !is_bulk_duplicate_key_error(&error) => Err(error.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Convert at most limit merkle records written in the default layout (with an ObjectId)
+    /// to the sharded layout (see with_sharded_layout), MIGRATE_LAYOUT_BATCH_SIZE records at a
+    /// time. Returns the number of records converted and whether all the records are now in the
+    /// sharded layout. Each batch is inserted with the new _id before the old documents are
+    /// deleted, so an interrupted migration leaves duplicates, which are removed when it is run
+    /// again, and never loses a record. The root document keeps its fixed _id.
+    pub async fn migrate_to_sharded_layout(
+        &mut self,
+        limit: usize,
+    ) -> Result<(usize, bool), Error> {
+        let collection = self.merkle_collection.clone_with_type::<Document>();
+        let filter = doc! {
+            "_id": {"$type": "objectId", "$ne": Self::get_current_root_object_id()},
+        };
+        let mut migrated = 0;
+        loop {
+            if migrated >= limit {
+                let options = CountOptions::builder()
+                    .limit(1)
+                    .max_time(self.max_time(None))
+                    .build();
+                let remaining = collection.count_documents(filter.clone(), options).await?;
+                return Ok((migrated, remaining == 0));
+            }
+            let batch_size = MIGRATE_LAYOUT_BATCH_SIZE.min(limit - migrated);
+            let options = FindOptions::builder()
+                .limit(batch_size as i64)
+                .max_time(self.max_time(None))
+                .build();
+            let documents: Vec<Document> = collection
+                .find(filter.clone(), options)
+                .await?
+                .try_collect()
+                .await?;
+            if documents.is_empty() {
+                return Ok((migrated, true));
+            }
+            let mut ids = Vec::with_capacity(documents.len());
+            let mut records = Vec::with_capacity(documents.len());
+            for mut document in documents {
+                ids.extend(document.remove("_id"));
+                let record: MerkleRecord = from_document(document).map_err(|e| {
+                    Error::InconsistentData(format!("Malformed merkle record: {e}"))
+                })?;
+                records.push(record);
+            }
+            self.insert_new_merkle_records(&records, true).await?;
+            self.check_deadline()?;
+            collection
+                .delete_many(doc! {"_id": {"$in": ids}}, None)
+                .await?;
+            migrated += records.len();
+        }
+    }
+
+    /// Remove the data field of at most limit merkle records, MIGRATION_BATCH_SIZE records at a
+    /// time (the migration to schema version 2, see crate::migrations). The field was always
+    /// zero, so reads are not affected. Returns the number of records changed and whether no
+    /// record has the field left, in which case its index (if any) is dropped too.
+    pub async fn remove_merkle_record_data(
+        &mut self,
+        limit: usize,
+    ) -> Result<(usize, bool), Error> {
+        let collection = self.merkle_collection.clone_with_type::<Document>();
+        let filter = doc! {"data": {"$exists": true}};
+        let mut removed = 0;
+        loop {
+            if removed >= limit {
+                let options = CountOptions::builder()
+                    .limit(1)
+                    .max_time(self.max_time(None))
+                    .build();
+                let remaining = collection.count_documents(filter.clone(), options).await?;
+                return Ok((removed, remaining == 0));
+            }
+            let batch_size = MIGRATION_BATCH_SIZE.min(limit - removed);
+            let options = FindOptions::builder()
+                .limit(batch_size as i64)
+                .projection(doc! {"_id": 1})
+                .max_time(self.max_time(None))
+                .build();
+            let ids = collection
+                .find(filter.clone(), options)
+                .await?
+                .try_collect::<Vec<Document>>()
+                .await?
+                .into_iter()
+                .filter_map(|mut document| document.remove("_id"))
+                .collect::<Vec<_>>();
+            if ids.is_empty() {
+                break;
+            }
+            removed += ids.len();
+            self.check_deadline()?;
+            collection
+                .update_many(
+                    doc! {"_id": {"$in": ids}},
+                    doc! {"$unset": {"data": ""}},
+                    None,
+                )
+                .await?;
+        }
+        // The index was created by MONGODB_CREATE_INDEXES, it may not exist.
+        match collection.drop_index("data_1", None).await {
+            Err(error) if !is_not_found_error(&error) => Err(error.into()),
+            _ => Ok((removed, true)),
+        }
+    }
+
+    pub async fn insert_non_leaf_node(
+        &mut self,
+        index: u64,
+        left: Hash,
+        right: Hash,
+    ) -> Result<MerkleRecord, Error> {
+        let record = MerkleRecord::new_non_leaf(index, left, right);
+        self.insert_merkle_record(&record).await
+    }
+
+    /// Replace the current root with the given record, if the current root is still old_root.
+    /// Fails with Error::RootChanged if another writer (possibly in another process) has
+    /// replaced the root since it was read, in which case the caller may read the new root
+    /// and retry.
+    pub async fn update_root_merkle_record(
+        &mut self,
+        old_root: &Hash,
+        record: &MerkleRecord,
+    ) -> Result<MerkleRecord, Error> {
+        // The root document does not exist before the first update, in which case it is
+        // inserted. If it exists with another root, the upsert fails with a duplicate _id.
+        let filter = doc! {
+            "_id": Self::get_current_root_object_id(),
+            "hash": hash_to_bson(old_root),
+        };
+        let mut root = doc! {
+            "index": u64_to_bson(0),
+            "hash": to_bson(&record.hash).unwrap(),
+            "left": to_bson(&record.left).unwrap(),
+            "right": to_bson(&record.right).unwrap(),
+        };
+        if self.writes_merkle_record_data() {
+            root.insert("data", u256_to_bson(&record.data));
+        }
+        let update = doc! {
+            "$set": root,
+            "$inc": {"version": 1_i64},
+        };
+        self.check_deadline()?;
+        self.insert_pending_idempotency_record().await?;
+        let options = UpdateOptions::builder().upsert(true).build();
+        let result = self.update_one_merkle_record(filter, update, options).await;
+        match result {
+            Err(error) if is_duplicate_key_error(&error) => {
+                self.remove_pending_idempotency_record().await?;
+                Err(Error::RootChanged(format!(
+                    "Root of contract {} is no longer {}",
+                    hex::encode(self.contract_id.0),
+                    hex::encode(old_root.0)
+                ))
+                .with_detail("contract_id", hex::encode(self.contract_id.0))
+                .with_detail("expected_hash", hex::encode(old_root.0)))
+            }
+            Err(error) => Err(error.into()),
+            Ok(result) => {
+                // The root document of a new contract is written by its first update.
+                if result.upserted_id.is_some() {
+                    self.init_contract_metadata().await?;
+                }
+                self.last_root_update = Some((*old_root, record.hash));
+                Ok(*record)
+            }
+        }
+    }
+
+    // Get the merkle records with the given indices and hashes from the cache, the default
+    // records or the database. The records not cached are fetched with a single query, instead
+    // of one round trip for each. This is used instead of joining one lookup per record, which
+    // would take as many round trips at once, but can not share the session of the collection
+    // nor its cache, both borrowed mutably by each lookup. Records are always looked up by hash,
+    // so that stale records with the same indices are never fetched.
+    async fn must_get_merkle_records_from<const N: usize>(
+        &mut self,
+        keys: [(u64, Hash); N],
+    ) -> Result<[MerkleRecord; N], Error> {
+        // must_get_merkle_records fails if any of the records is not found.
+        let records = self.must_get_merkle_records(&keys).await?;
+        Ok(keys.map(|key| records[&key]))
+    }
+
+    // Same as get_merkle_records, but fails if any of the records is not found.
+    async fn must_get_merkle_records(
+        &mut self,
+        keys: &[(u64, Hash)],
+    ) -> Result<HashMap<(u64, Hash), MerkleRecord>, Error> {
+        let records = self.get_merkle_records(keys).await?;
+        match keys.iter().find(|key| !records.contains_key(key)) {
+            Some((index, hash)) => Err(Error::NotFound(format!(
+                "Merkle record {index} with hash {hash:?} not found"
+            ))
+            .with_detail("index", index)
+            .with_detail("hash", hex::encode(hash.0))),
+            None => Ok(records),
+        }
+    }
+
+    /// Get the merkle records with the given indices and hashes from the cache, the default
+    /// records or the database (with one query for each batch of 1000 records). The records
+    /// not found are missing from the result.
+    pub async fn get_merkle_records(
+        &mut self,
+        keys: &[(u64, Hash)],
+    ) -> Result<HashMap<(u64, Hash), MerkleRecord>, Error> {
+        const BATCH_SIZE: usize = 1000;
+        self.check_transaction_timeout()?;
+        let mut records = HashMap::with_capacity(keys.len());
+        let mut missing = vec![];
+        for &(index, hash) in keys {
+            if let Some(record) = self.get_cached_merkle_record(index, &hash) {
+                records.insert((index, hash), record);
+                continue;
+            }
+            let default_record = MerkleRecord::get_default_record_with_height::<H>(index)?;
+            if default_record.hash == hash {
+                records.insert((index, hash), default_record);
+            } else {
+                missing.push((index, hash));
+            }
+        }
+        for keys in missing.chunks(BATCH_SIZE) {
+            let conditions = keys
+                .iter()
+                .map(|(index, hash)| self.merkle_record_filter(*index, hash))
+                .collect::<Vec<_>>();
+            let filter = doc! { "$or": conditions };
+            for record in self.find_merkle_records(filter, None).await? {
+                self.cache_merkle_record(&record);
+                records.insert((record.index, record.hash), record);
+            }
+        }
+        Ok(records)
+    }
+
+    pub async fn get_leaf_and_proof(
+        &mut self,
+        index: u64,
+    ) -> Result<(MerkleRecord, MerkleProof<Hash, H>), Error> {
+        leaf_check(index, H)?;
+        self.get_node_and_proof(index).await
+    }
+
+    /// Same as get_leaf_and_proof, but the index may be any node of the tree. The proof has one
+    /// assist hash for each level above the node, see MerkleProof::verify_node.
+    pub async fn get_node_and_proof(
+        &mut self,
+        index: u64,
+    ) -> Result<(MerkleRecord, MerkleProof<Hash, H>), Error> {
+        let root = self.must_get_root_merkle_record().await?;
+        self.get_node_and_proof_with_root(root, index).await
+    }
+
+    /// Same as get_node_and_proof, but in the tree of the given root instead of the current root.
+    pub async fn get_node_and_proof_with_root(
+        &mut self,
+        root: MerkleRecord,
+        index: u64,
+    ) -> Result<(MerkleRecord, MerkleProof<Hash, H>), Error> {
+        let paths = get_node_path(index, H)?;
+        // We push the search from the top
+        let mut acc = 0;
+        let mut acc_node = root;
+        let root_hash = acc_node.hash;
+        let mut assist = Vec::with_capacity(paths.len());
+        for child in paths {
+            let is_left_child = (acc + 1) * 2 == child + 1;
+            let is_right_child = (acc + 1) * 2 == child;
+            assert!(is_left_child || is_right_child);
+            let (hash, sibling_hash) = if is_left_child {
+                (acc_node.left().unwrap(), acc_node.right().unwrap())
+            } else {
+                (acc_node.right().unwrap(), acc_node.left().unwrap())
+            };
+            // The hashes of the node and its sibling are only known from their parent, so the path
+            // is resolved level by level, fetching the node and its sibling together.
+            let sibling = get_sibling_index(child);
+            let [sibling_node, node] = self
+                .must_get_merkle_records_from([(sibling, sibling_hash), (child, hash)])
+                .await?;
+            acc = child;
+            acc_node = node;
+            assist.push(sibling_node.hash());
+        }
+        let hash = acc_node.hash();
+        Ok((
+            acc_node,
+            MerkleProof {
+                source: hash,
+                root: root_hash,
+                assist,
+                index,
+            },
+        ))
+    }
+
+    /// Load the merkle records on the root-to-leaf paths of the given leaves, so that they
+    /// are cached for later requests. Nodes shared by multiple paths are only loaded once.
+    /// Returns the number of distinct nodes loaded (excluding the root).
+    pub async fn prefetch_paths(&mut self, indices: &[u64]) -> Result<usize, Error> {
+        let root = self.must_get_root_merkle_record().await?;
+        let mut warmed: HashMap<u64, MerkleRecord> = HashMap::new();
+        for &index in indices {
+            leaf_check(index, H)?;
+            let mut acc_node = root;
+            for child in get_path(index, H)? {
+                if let Some(record) = warmed.get(&child) {
+                    acc_node = *record;
+                    continue;
+                }
+                let hash = if (acc_node.index + 1) * 2 == child + 1 {
+                    acc_node.left
+                } else {
+                    acc_node.right
+                };
+                acc_node = self.must_get_merkle_record(child, &hash).await?;
+                warmed.insert(child, acc_node);
+            }
+        }
+        Ok(warmed.len())
+    }
+
+    /// Find the indices (in ascending order) of at most `limit` leaves with the given hash
+    /// in the tree of the current root. Leaves which had this hash but have been overwritten
+    /// are not included. The candidates are looked up in the LEAFINDEX_ collection and checked
+    /// against the tree, so a stale entry (e.g. left by a write outside of a transaction which
+    /// failed midway) is never returned. Unless the collection is complete, i.e. the leaves may
+    /// have been written before it was maintained, this falls back to scanning the merkle
+    /// records until the collection is rebuilt with rebuild_leaf_index.
+    pub async fn find_leaf_indices_by_hash(
+        &mut self,
+        hash: &Hash,
+        limit: usize,
+    ) -> Result<Vec<u64>, Error> {
+        let mut filter = doc! {};
+        filter.insert("hash", hash_to_bson(hash));
+        let mut candidates = self
+            .find_leaf_index_records(filter.clone())
+            .await?
+            .into_iter()
+            .map(|record| record.index)
+            .collect::<BTreeSet<_>>();
+        if candidates.is_empty() && !self.is_leaf_index_complete().await? {
+            let records = self.find_merkle_records(filter, None).await?;
+            candidates = records
+                .into_iter()
+                .map(|record| record.index)
+                .filter(|&index| get_node_type(index, H) == NodeType::NodeLeaf)
+                .collect();
+        }
+        let mut indices = vec![];
+        for index in candidates {
+            if indices.len() >= limit {
+                break;
+            }
+            let (record, _) = self.get_leaf_and_proof(index).await?;
+            if record.hash == *hash {
+                indices.push(index);
+            }
+        }
+        Ok(indices)
+    }
+
+    /// Rebuild the LEAFINDEX_ collection from the leaves of the current tree, and return the
+    /// number of its entries. The subtrees with only default leaves are skipped. The collection
+    /// is marked as complete once rebuilt, and not complete while it is.
+    pub async fn rebuild_leaf_index(&mut self) -> Result<usize, Error> {
+        self.set_leaf_index_complete(false).await?;
+        let root = self.must_get_root_merkle_record().await?;
+        let default_root = MerkleRecord::get_default_record_with_height::<H>(0)?;
+        let records = self
+            .find_changed_leaves(&default_root, &root)
+            .await?
+            .into_iter()
+            .map(|(index, hash)| LeafIndexRecord { index, hash })
+            .collect::<Vec<_>>();
+        self.replace_leaf_index_records(doc! {}, &records).await?;
+        self.set_leaf_index_complete(true).await?;
+        Ok(records.len())
+    }
+
+    // Whether the LEAFINDEX_ collection covers the current tree, see
+    // ContractMetadata::leaf_index_complete.
+    async fn is_leaf_index_complete(&self) -> Result<bool, Error> {
+        let filter = doc! {"contract_id": u256_to_bson(&self.contract_id.0)};
+        let metadata = Self::get_contracts_collection(&self.client)
+            .find_one(filter, None)
+            .await?;
+        Ok(metadata.map_or(false, |metadata| metadata.leaf_index_complete))
+    }
+
+    async fn set_leaf_index_complete(&self, complete: bool) -> Result<(), Error> {
+        self.check_deadline()?;
+        let filter = doc! {"contract_id": u256_to_bson(&self.contract_id.0)};
+        let update = doc! {"$set": {"leaf_index_complete": complete}};
+        let options = UpdateOptions::builder().upsert(true).build();
+        Self::get_contracts_collection(&self.client)
+            .update_one(filter, update, options)
+            .await?;
+        Ok(())
+    }
+
+    /// The entries of the LEAFINDEX_ collection matching the filter, e.g. `{"hash": ...}`.
+    pub async fn find_leaf_index_records(
+        &mut self,
+        filter: Document,
+    ) -> Result<Vec<LeafIndexRecord>, Error> {
+        let options = FindOptions::builder()
+            .max_time(self.max_time(None))
+            .selection_criteria(self.selection_criteria.clone())
+            .build();
+        let result = match self.session.as_mut() {
+            Some(session) => {
+                let mut cursor = self
+                    .leaf_index_collection
+                    .find_with_session(filter, options, session)
+                    .await?;
+                cursor.stream(session).try_collect().await?
+            }
+            _ => {
+                self.leaf_index_collection
+                    .find(filter, options)
+                    .await?
+                    .try_collect()
+                    .await?
+            }
+        };
+        Ok(result)
+    }
+
+    // Replace the entries of the LEAFINDEX_ collection matching the filter with the given ones,
+    // within the transaction of this collection if any. The entries are inserted in batches of
+    // 1000, e.g. when the index of a whole tree is rebuilt.
+    async fn replace_leaf_index_records(
+        &mut self,
+        filter: Document,
+        records: &[LeafIndexRecord],
+    ) -> Result<(), Error> {
+        const BATCH_SIZE: usize = 1000;
+        self.check_deadline()?;
+        match self.session.as_mut() {
+            Some(session) => {
+                self.leaf_index_collection
+                    .delete_many_with_session(filter, None, session)
+                    .await?;
+            }
+            _ => {
+                self.leaf_index_collection.delete_many(filter, None).await?;
+            }
+        }
+        for batch in records.chunks(BATCH_SIZE) {
+            self.check_deadline()?;
+            match self.session.as_mut() {
+                Some(session) => {
+                    self.leaf_index_collection
+                        .insert_many_with_session(batch, None, session)
+                        .await?;
+                }
+                _ => {
+                    self.leaf_index_collection.insert_many(batch, None).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Point the entries of the LEAFINDEX_ collection of the given leaves at their new hashes,
+    // removing those of the leaves reset to the default leaf. Later leaves of the same index win.
+    async fn update_leaf_index(
+        &mut self,
+        leaves: impl IntoIterator<Item = (u64, Hash)>,
+    ) -> Result<(), Error> {
+        let leaves = leaves.into_iter().collect::<HashMap<_, _>>();
+        if leaves.is_empty() {
+            return Ok(());
+        }
+        let default_hash = Hash::get_default_hash_for_depth_with_height::<H>(H)?;
+        let indices = leaves.keys().copied().map(u64_to_bson).collect::<Vec<_>>();
+        let records = leaves
+            .into_iter()
+            .filter(|(_, hash)| *hash != default_hash)
+            .map(|(index, hash)| LeafIndexRecord { index, hash })
+            .collect::<Vec<_>>();
+        self.replace_leaf_index_records(doc! {"_id": {"$in": indices}}, &records)
+            .await
+    }
+
+    // Update the LEAFINDEX_ collection after the root is switched by other means than setting
+    // leaves (e.g. SetRoot), with the leaves which differ between the old and the new tree.
+    async fn update_leaf_index_between(
+        &mut self,
+        old_root: &MerkleRecord,
+        new_root: &MerkleRecord,
+    ) -> Result<(), Error> {
+        let leaves = self.find_changed_leaves(old_root, new_root).await?;
+        self.update_leaf_index(leaves).await
+    }
+
+    // The leaves of the tree of new_root which differ from those of the tree of old_root, with
+    // their hashes in the new tree. Only the subtrees whose hashes differ are visited.
+    async fn find_changed_leaves(
+        &mut self,
+        old_root: &MerkleRecord,
+        new_root: &MerkleRecord,
+    ) -> Result<Vec<(u64, Hash)>, Error> {
+        let mut leaves = vec![];
+        let mut stack = vec![(0, old_root.hash, new_root.hash)];
+        while let Some((index, old_hash, new_hash)) = stack.pop() {
+            if old_hash == new_hash {
+                continue;
+            }
+            if get_node_type(index, H) == NodeType::NodeLeaf {
+                leaves.push((index, new_hash));
+                continue;
+            }
+            let (old, new) = if index == 0 {
+                (*old_root, *new_root)
+            } else {
+                (
+                    self.must_get_merkle_record(index, &old_hash).await?,
+                    self.must_get_merkle_record(index, &new_hash).await?,
+                )
+            };
+            stack.push((2 * index + 2, old.right, new.right));
+            stack.push((2 * index + 1, old.left, new.left));
+        }
+        Ok(leaves)
+    }
+
+    /// The proof of the leaf at index in the tree of the current root, which must have the
+    /// given hash.
+    pub async fn get_leaf_proof_with_hash(
+        &mut self,
+        index: u64,
+        hash: &Hash,
+    ) -> Result<MerkleProof<Hash, H>, Error> {
+        validate_leaf_index(index, H)?;
+        let (record, proof) = self.get_leaf_and_proof(index).await?;
+        if record.hash != *hash {
+            return Err(
+                Error::HashMismatch(format!("The hash of leaf {index} is not {:?}", hash))
+                    .with_detail("index", index)
+                    .with_detail("expected_hash", hex::encode(hash.0))
+                    .with_detail("actual_hash", hex::encode(record.hash.0)),
+            );
+        }
+        Ok(proof)
+    }
+
+    /// Set the leaf and return its proof under the new root. If the root is changed by another
+    /// writer in the meantime, the path is recomputed from the new root, up to
+    /// MAX_ROOT_UPDATE_ATTEMPTS times. Nothing is written if the leaf already has the hash.
+    pub async fn set_leaf_and_get_proof(
+        &mut self,
+        leaf: &MerkleRecord,
+    ) -> Result<MerkleProof<Hash, H>, Error> {
+        let (_, _, proof) = self.replace_leaf_and_get_proof(leaf).await?;
+        Ok(proof)
+    }
+
+    /// Same as set_leaf_and_get_proof, but also return the previous record of the leaf and the
+    /// previous root, which are read by the same traversal as the one used to set the leaf.
+    /// In a transaction, the failed write has aborted the transaction, so this is not retried:
+    /// the caller has to run the whole transaction again.
+    pub async fn replace_leaf_and_get_proof(
+        &mut self,
+        leaf: &MerkleRecord,
+    ) -> Result<(MerkleRecord, Hash, MerkleProof<Hash, H>), Error> {
+        let mut attempts = 1;
+        loop {
+            match self.try_replace_leaf_and_get_proof(leaf).await {
+                Err(e) if self.should_retry_root_update(&e, attempts) => {
+                    tokio::time::sleep(root_update_backoff(attempts)).await;
+                    attempts += 1
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn should_retry_root_update(&self, error: &Error, attempts: usize) -> bool {
+        is_root_changed(error) && !self.in_transaction && attempts < MAX_ROOT_UPDATE_ATTEMPTS
+    }
+
+    async fn try_replace_leaf_and_get_proof(
+        &mut self,
+        leaf: &MerkleRecord,
+    ) -> Result<(MerkleRecord, Hash, MerkleProof<Hash, H>), Error> {
+        let index = leaf.index();
+        let mut hash = leaf.hash();
+        let (previous, mut proof) = self.get_leaf_and_proof(index).await?;
+        let old_root = proof.root;
+        // Setting a leaf to the hash it already has (e.g. when a write is replayed) changes
+        // nothing, so the tree is left as is and the current proof is returned.
+        if previous.hash == hash {
+            return Ok((previous, old_root, proof));
+        }
+        proof.source = hash;
+        let mut p = get_offset(index);
+        let mut records = Vec::with_capacity(H + 1);
+        records.push(*leaf);
+        let mut root = None;
+        for i in 0..H {
+            let cur_hash = hash;
+            let depth = H - i - 1;
+            let (left, right) = if p % 2 == 1 {
+                (proof.assist[depth], cur_hash)
+            } else {
+                (cur_hash, proof.assist[depth])
+            };
+            // The assist hashes are read from the database.
+            hash = Hash::try_hash_children(&left, &right)?;
+            p /= 2;
+            let index = p + (1 << depth) - 1;
+            let record = MerkleRecord::new_non_leaf(index, left, right);
+            assert_eq!(record.hash, hash);
+            records.push(record);
+            if index == 0 {
+                root = Some(record);
+            }
+        }
+        self.insert_merkle_records(&records).await?;
+        self.update_leaf_index([(index, leaf.hash())]).await?;
+        if let Some(root) = root {
+            self.update_root_merkle_record(&old_root, &root).await?;
+        }
+        proof.root = hash;
+        Ok((previous, old_root, proof))
+    }
+
+    // Reset the leaf at index to the default leaf. The data hash record of the old leaf is kept,
+    // as it may be shared by other leaves.
+    pub async fn delete_leaf_and_get_proof(
+        &mut self,
+        index: u64,
+    ) -> Result<MerkleProof<Hash, H>, Error> {
+        let leaf = MerkleRecord::get_default_record_with_height::<H>(index)?;
+        self.set_leaf_and_get_proof(&leaf).await
+    }
+
+    /// Set the given leaves and return the new root hash. The nodes shared by the paths of
+    /// the leaves are only read and written once, so this is much faster than calling
+    /// set_leaf_and_get_proof for each leaf. If a leaf is given more than once, the last one wins.
+    /// Same as set_leaf_and_get_proof, this is retried if the root is changed by another writer,
+    /// except in a transaction.
+    pub async fn set_leaves(&mut self, leaves: &[MerkleRecord]) -> Result<Hash, Error> {
+        let mut attempts = 1;
+        loop {
+            match self.try_set_leaves(leaves).await {
+                Err(e) if self.should_retry_root_update(&e, attempts) => {
+                    tokio::time::sleep(root_update_backoff(attempts)).await;
+                    attempts += 1
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn try_set_leaves(&mut self, leaves: &[MerkleRecord]) -> Result<Hash, Error> {
+        let (old_root, records) = self.compute_leaves(leaves).await?;
+        // The root is the last record.
+        let root = match records.last() {
+            Some(root) => *root,
+            None => return Ok(old_root),
+        };
+        self.insert_merkle_records(&records).await?;
+        self.update_leaf_index(leaves.iter().map(|leaf| (leaf.index, leaf.hash)))
+            .await?;
+        self.update_root_merkle_record(&old_root, &root).await?;
+        Ok(root.hash)
+    }
+
+    /// Compute the root of the tree after setting the given leaves, without writing anything.
+    /// The root is computed the same way as set_leaves, so it is the root set_leaves would return
+    /// unless the tree is changed in the meantime. If with_proofs is true, also return the proofs
+    /// under the new root of the distinct leaves, in ascending order of index.
+    pub async fn compute_root(
+        &mut self,
+        leaves: &[MerkleRecord],
+        with_proofs: bool,
+    ) -> Result<(Hash, Vec<MerkleProof<Hash, H>>), Error> {
+        let (old_root, records) = self.compute_leaves(leaves).await?;
+        let root = records.last().map_or(old_root, |root| root.hash);
+        if !with_proofs {
+            return Ok((root, vec![]));
+        }
+        // Later records of the same index win, same as set_leaves.
+        let nodes = records
+            .iter()
+            .map(|record| (record.index, *record))
+            .collect::<HashMap<_, _>>();
+        let indices = leaves
+            .iter()
+            .map(|leaf| leaf.index)
+            .collect::<BTreeSet<_>>();
+        let proofs = indices
+            .into_iter()
+            .map(|index| Self::proof_from_nodes(&nodes, index))
+            .collect();
+        Ok((root, proofs))
+    }
+
+    /// The records and proofs of the given leaves in the tree of the current root. The root is
+    /// read once, so all the proofs are under the same root, and the nodes shared by the paths
+    /// of the leaves are only read once, same as set_leaves.
+    pub async fn get_leaves_and_proofs(
+        &mut self,
+        indices: &[u64],
+    ) -> Result<(Hash, Vec<(MerkleRecord, MerkleProof<Hash, H>)>), Error> {
+        for &index in indices {
+            validate_leaf_index(index, H)?;
+        }
+        let root = self.must_get_root_merkle_record().await?;
+        let nodes = self.read_paths(root, indices, H).await?;
+        let leaves = indices
+            .iter()
+            .map(|index| (nodes[index], Self::proof_from_nodes(&nodes, *index)))
+            .collect();
+        Ok((root.hash, leaves))
+    }
+
+    // The proof of the leaf at index, given the nodes on the path from the root (at index 0) to
+    // the leaf.
+    fn proof_from_nodes(nodes: &HashMap<u64, MerkleRecord>, index: u64) -> MerkleProof<Hash, H> {
+        let mut assist = Vec::with_capacity(H);
+        let mut child = index;
+        while child != 0 {
+            let parent = &nodes[&((child - 1) / 2)];
+            let sibling = if child % 2 == 1 {
+                parent.right
+            } else {
+                parent.left
+            };
+            assist.push(sibling);
+            child = (child - 1) / 2;
+        }
+        assist.reverse();
+        MerkleProof {
+            source: nodes[&index].hash,
+            root: nodes[&0].hash,
+            assist,
+            index,
+        }
+    }
+
+    // Read the records of the nodes on the paths from the root to the given leaves, down to
+    // max_depth, level by level from the top with one batch of queries per level.
+    async fn read_paths(
+        &mut self,
+        root: MerkleRecord,
+        leaves: &[u64],
+        max_depth: usize,
+    ) -> Result<HashMap<u64, MerkleRecord>, Error> {
+        let mut nodes = HashMap::from([(0, root)]);
+        for depth in 1..=max_depth {
+            let keys = leaves
+                .iter()
+                .map(|index| ((index + 1) >> (H - depth)) - 1)
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .map(|index| {
+                    let parent = &nodes[&((index - 1) / 2)];
+                    let hash = if index % 2 == 1 {
+                        parent.left
+                    } else {
+                        parent.right
+                    };
+                    (index, hash)
+                })
+                .collect::<Vec<_>>();
+            let records = self.must_get_merkle_records(&keys).await?;
+            nodes.extend(keys.iter().map(|key| (key.0, records[key])));
+        }
+        Ok(nodes)
+    }
+
+    // Compute the records set_leaves writes for the given leaves, i.e. the leaves followed by
+    // their new ancestors, level by level, with the new root last. The hash of the current root
+    // is also returned. Nothing is written here, so that compute_root can not diverge from
+    // set_leaves.
+    async fn compute_leaves(
+        &mut self,
+        leaves: &[MerkleRecord],
+    ) -> Result<(Hash, Vec<MerkleRecord>), Error> {
+        for leaf in leaves {
+            leaf_check(leaf.index, H)?;
+        }
+        let root = self.must_get_root_merkle_record().await?;
+        if leaves.is_empty() {
+            return Ok((root.hash, vec![]));
+        }
+
+        // Read the current records of the ancestors of the leaves.
+        let indices = leaves.iter().map(|leaf| leaf.index).collect::<Vec<_>>();
+        let ancestors = self.read_paths(root, &indices, H - 1).await?;
+
+        // Hashing is CPU-bound, so large batches are hashed off the threads of the runtime.
+        let records = if leaves.len() >= self.parallel_hash_threshold {
+            let leaves = leaves.to_vec();
+            tokio::task::spawn_blocking(move || hash_ancestors::<H>(&ancestors, &leaves, true))
+                .await
+                .map_err(std::io::Error::from)??
+        } else {
+            hash_ancestors::<H>(&ancestors, leaves, false)?
+        };
+        Ok((root.hash, records))
+    }
+
+    // The roots of the contract with when they were last made the current root, the most
+    // recently used first: the current root, the roots made current according to the audit log,
+    // the archived roots, and last the stored roots which are in neither (e.g. made current
+    // before the audit log existed), whose last use is unknown and taken as the epoch.
+    async fn find_root_history(
+        &mut self,
+        current_root: &Hash,
+    ) -> Result<Vec<RootHistoryEntry>, Error> {
+        let pipeline = vec![
+            doc! {"$group": {
+                "_id": "$new_root",
+                "last_id": {"$max": "$_id"},
+                "last_used": {"$max": "$timestamp"},
+            }},
+            doc! {"$sort": {"last_id": -1}},
+        ];
+        let options = AggregateOptions::builder()
+            .max_time(self.max_time(None))
+            .selection_criteria(self.selection_criteria.clone())
+            .build();
+        let documents: Vec<Document> = match self.session.as_mut() {
+            Some(session) => {
+                let mut cursor = self
+                    .audit_collection
+                    .aggregate_with_session(pipeline, options, session)
+                    .await?;
+                cursor.stream(session).try_collect().await?
+            }
+            _ => {
+                self.audit_collection
+                    .aggregate(pipeline, options)
+                    .await?
+                    .try_collect()
+                    .await?
+            }
+        };
+        let mut history = vec![RootHistoryEntry {
+            root: *current_root,
+            last_used: DateTime::now(),
+            archived: false,
+        }];
+        for document in documents {
+            let entry: RootHistoryEntry = from_document(document).map_err(|e| {
+                Error::InconsistentData(format!("Malformed root history entry: {e}"))
+            })?;
+            if entry.root != *current_root {
+                history.push(entry);
+            }
+        }
+
+        let options = FindOptions::builder()
+            .sort(doc! {"last_used": -1})
+            .max_time(self.max_time(None))
+            .selection_criteria(self.selection_criteria.clone())
+            .build();
+        let archived_roots: Vec<ArchivedRoot> = match self.session.as_mut() {
+            Some(session) => {
+                let mut cursor = self
+                    .root_archive_collection
+                    .find_with_session(None, options, session)
+                    .await?;
+                cursor.stream(session).try_collect().await?
+            }
+            _ => {
+                self.root_archive_collection
+                    .find(None, options)
+                    .await?
+                    .try_collect()
+                    .await?
+            }
+        };
+        for archived_root in archived_roots {
+            let root = archived_root.record.hash;
+            match history.iter_mut().find(|entry| entry.root == root) {
+                Some(entry) => entry.archived = true,
+                None => history.push(RootHistoryEntry {
+                    root,
+                    last_used: archived_root.last_used,
+                    archived: true,
+                }),
+            }
+        }
+
+        let filter = doc! {"index": u64_to_bson(0)};
+        let options = DistinctOptions::builder()
+            .max_time(self.max_time(None))
+            .selection_criteria(self.selection_criteria.clone())
+            .build();
+        let stored_roots = match self.session.as_mut() {
+            Some(session) => {
+                self.merkle_collection
+                    .distinct_with_session("hash", filter, options, session)
+                    .await?
+            }
+            _ => {
+                self.merkle_collection
+                    .distinct("hash", filter, options)
+                    .await?
+            }
+        };
+        for root in stored_roots {
+            let root: Hash = from_bson(root)
+                .map_err(|e| Error::InconsistentData(format!("Malformed root hash: {e}")))?;
+            if history.iter().all(|entry| entry.root != root) {
+                history.push(RootHistoryEntry {
+                    root,
+                    last_used: DateTime::from_millis(0),
+                    archived: false,
+                });
+            }
+        }
+        Ok(history)
+    }
+
+    // Find the stored nodes of the tree of root which are in none of the trees of the other
+    // roots, i.e. the nodes no longer reachable from the other roots once root is removed. The
+    // walk stops at the subtrees shared with any of the other trees, so its cost is proportional
+    // to the difference between the trees. Default nodes of empty subtrees are not stored, and
+    // nodes already missing (e.g. archived roots) are skipped.
+    async fn find_exclusive_nodes(
+        &mut self,
+        root: &Hash,
+        others: &[Hash],
+    ) -> Result<Vec<(u64, Hash)>, Error> {
+        let mut nodes = vec![];
+        // The index of a node of the tree of root, its hash, and the hashes of the nodes with
+        // the same index in the other trees.
+        let mut pending = vec![(0, *root, others.iter().copied().collect::<HashSet<_>>())];
+        while let Some((index, hash, other_hashes)) = pending.pop() {
+            if other_hashes.contains(&hash)
+                || MerkleRecord::get_default_record_with_height::<H>(index)?.hash == hash
+            {
+                continue;
+            }
+            let record = match self.get_merkle_record(index, &hash).await? {
+                Some(record) => record,
+                None => continue,
+            };
+            nodes.push((index, hash));
+            if get_node_type(index, H) == NodeType::NodeLeaf {
+                continue;
+            }
+            let (mut left, mut right) = (HashSet::new(), HashSet::new());
+            for other in other_hashes {
+                if let Some(other) = self.get_merkle_record(index, &other).await? {
+                    left.insert(other.left);
+                    right.insert(other.right);
+                }
+            }
+            pending.push((2 * index + 1, record.left, left));
+            pending.push((2 * index + 2, record.right, right));
+        }
+        Ok(nodes)
+    }
+
+    /// Move the roots beyond the retention policy from the merkle collection to the ROOTARCHIVE_
+    /// collection, after which they can no longer be read (e.g. by GetSiblings or SetRoot). The
+    /// history of the roots is mostly the audit log (see find_root_history): the most recently
+    /// used max_hot_roots roots other than the current and the pinned roots are kept, as are the
+    /// current root and the pinned roots, while roots not used for longer than max_root_age are
+    /// archived regardless. The nodes only reachable from the archived roots
+    /// are marked with gc_candidate, which a garbage collector must check again before deleting
+    /// them since a later write may make them reachable again. Returns the number of roots
+    /// archived and of the nodes marked.
+    pub async fn run_retention(
+        &mut self,
+        policy: &RetentionPolicy,
+    ) -> Result<(usize, usize), Error> {
+        if policy.is_unlimited() {
+            return Ok((0, 0));
+        }
+        let current_root = self.must_get_root_merkle_record().await?.hash;
+        // The empty tree is not stored, so there is nothing to archive.
+        let default_root = MerkleRecord::get_default_record_with_height::<H>(0)?.hash;
+        let now = DateTime::now();
+        let (mut retained, mut archived) = (vec![current_root], vec![]);
+        retained.extend(policy.pinned_roots.iter().copied());
+        let history = self.find_root_history(&current_root).await?;
+        let mut hot_roots = 0;
+        for RootHistoryEntry {
+            root,
+            last_used,
+            archived: already_archived,
+        } in history
+        {
+            if root == default_root || retained.contains(&root) {
+                continue;
+            }
+            // Archived roots are not counted as hot. They are kept in case their nodes have
+            // been written again since (e.g. by ImportSnapshot), which must not be marked.
+            if already_archived {
+                retained.push(root);
+                continue;
+            }
+            let age = Duration::from_millis(
+                (now.timestamp_millis() - last_used.timestamp_millis()).max(0) as u64,
+            );
+            let too_many = policy
+                .max_hot_roots
+                .map_or(false, |max_hot_roots| hot_roots >= max_hot_roots);
+            let too_old = policy.max_root_age.map_or(false, |max_age| age > max_age);
+            if too_many || too_old {
+                archived.push((root, last_used));
+            } else {
+                retained.push(root);
+                hot_roots += 1;
+            }
+        }
+
+        let mut unreachable = HashSet::new();
+        let mut records = vec![];
+        for (root, last_used) in archived {
+            // Roots archived earlier are still in the audit log.
+            let record = match self.get_merkle_record(0, &root).await? {
+                Some(record) => record,
+                None => continue,
+            };
+            unreachable.extend(self.find_exclusive_nodes(&root, &retained).await?);
+            records.push(ArchivedRoot {
+                record,
+                last_used,
+                archived_at: now,
+            });
+        }
+
+        // The document of the current root is never marked or removed.
+        let current_root_id = Self::get_current_root_object_id();
+        let unreachable = unreachable.into_iter().collect::<Vec<_>>();
+        for nodes in unreachable.chunks(1000) {
+            let conditions = nodes
+                .iter()
+                .map(|(index, hash)| {
+                    doc! {"index": u64_to_bson(*index), "hash": hash_to_bson(hash)}
+                })
+                .collect::<Vec<_>>();
+            let filter = doc! {"$or": conditions, "_id": {"$ne": current_root_id}};
+            let update = doc! {"$set": {"gc_candidate": true}};
+            self.check_deadline()?;
+            self.update_many_merkle_records(filter, update, None)
+                .await?;
+        }
+        for archived in &records {
+            self.check_deadline()?;
+            let hash = hash_to_bson(&archived.record.hash);
+            let filter = doc! {"record.hash": hash.clone()};
+            let options = ReplaceOptions::builder().upsert(true).build();
+            match self.session.as_mut() {
+                Some(session) => {
+                    self.root_archive_collection
+                        .replace_one_with_session(filter, archived, options, session)
+                        .await?
+                }
+                _ => {
+                    self.root_archive_collection
+                        .replace_one(filter, archived, options)
+                        .await?
+                }
+            };
+            let filter = doc! {
+                "index": u64_to_bson(0),
+                "hash": hash,
+                "_id": {"$ne": current_root_id},
+            };
+            self.delete_many_merkle_records(filter, None).await?;
+            self.uncache_merkle_record(0, &archived.record.hash);
+        }
+        Ok((records.len(), unreachable.len()))
+    }
+
+    pub async fn find_one_datahash_record(
+        &mut self,
+        filter: impl Into<Option<Document>>,
+        options: impl Into<Option<FindOneOptions>>,
+    ) -> Result<Option<DataHashRecord>, Error> {
+        let filter = filter.into();
+        let mut options = options.into().unwrap_or_default();
+        options.max_time = self.max_time(options.max_time);
+        if options.selection_criteria.is_none() {
+            options.selection_criteria = self.selection_criteria.clone();
+        }
+        let result = match self.session.as_mut() {
+            Some(session) => {
+                self.datahash_collection
+                    .find_one_with_session(filter.clone(), options, session)
+                    .await
+            }
+            _ => {
+                self.datahash_collection
+                    .find_one(filter.clone(), options)
+                    .await
+            }
+        };
+        match result {
+            Ok(record) => Ok(record),
+            Err(error) => {
+                Err(malformed_document_error(&self.datahash_collection, filter, error).await)
+            }
+        }
+    }
+
+    pub async fn insert_one_datahash_record(
+        &mut self,
+        doc: impl Borrow<DataHashRecord>,
+        options: impl Into<Option<InsertOneOptions>>,
+    ) -> Result<InsertOneResult, mongodb::error::Error> {
+        let result = match self.session.as_mut() {
+            Some(session) => {
+                self.datahash_collection
+                    .insert_one_with_session(doc, options, session)
+                    .await?
+            }
+            _ => self.datahash_collection.insert_one(doc, options).await?,
+        };
+        Ok(result)
+    }
+
+    /// Insert the data hash record with the current time as its created_at, unless there is
+    /// already a record with its hash. Returns the stored record, i.e. the existing one or a
+    /// copy of the given one. See insert_datahash_record_if_missing, which avoids reading back
+    /// and copying the data.
+    pub async fn insert_datahash_record(
+        &mut self,
+        record: &DataHashRecord,
+    ) -> Result<DataHashRecord, Error> {
+        self.check_deadline()?;
+        let mut filter = doc! {};
+        filter.insert("hash", hash_to_bson(&record.hash));
+        dbg!(&record.hash, &filter);
+        let result = self.find_one_datahash_record(filter, None).await?;
+        match result {
+            Some(result) => Ok(result),
+            None => {
+                let created_at = self.insert_new_datahash_record(record).await?;
+                Ok(DataHashRecord {
+                    created_at: Some(created_at),
+                    ..record.clone()
+                })
+            }
+        }
+    }
+
+    /// Same as insert_datahash_record, but only the existence of the record is checked, so that
+    /// the data (possibly large, see hash_leaf_data) is neither read back nor copied.
+    pub async fn insert_datahash_record_if_missing(
+        &mut self,
+        record: &DataHashRecord,
+    ) -> Result<(), Error> {
+        self.check_deadline()?;
+        let mut filter = doc! {};
+        filter.insert("hash", hash_to_bson(&record.hash));
+        dbg!(&record.hash, &filter);
+        let options = CountOptions::builder()
+            .limit(1)
+            .max_time(self.max_time(None))
+            .selection_criteria(self.selection_criteria.clone())
+            .build();
+        let count = match self.session.as_mut() {
+            Some(session) => {
+                self.datahash_collection
+                    .count_documents_with_session(filter, options, session)
+                    .await?
+            }
+            _ => {
+                self.datahash_collection
+                    .count_documents(filter, options)
+                    .await?
+            }
+        };
+        if count == 0 {
+            self.insert_new_datahash_record(record).await?;
+        }
+        Ok(())
+    }
+
+    // Insert the data hash record with the current time as its created_at, which is returned.
+    async fn insert_new_datahash_record(
+        &mut self,
+        record: &DataHashRecord,
+    ) -> Result<DateTime, Error> {
+        // Stamped on the document rather than on a copy of the record and its data.
+        let created_at = DateTime::now();
+        let mut document = to_document(record).unwrap();
+        document.insert("created_at", created_at);
+        let collection = self.datahash_collection.clone_with_type::<Document>();
+        match self.session.as_mut() {
+            Some(session) => {
+                collection
+                    .insert_one_with_session(document, None, session)
+                    .await?
+            }
+            _ => collection.insert_one(document, None).await?,
+        };
+        Ok(created_at)
+    }
+
+    pub async fn get_datahash_record(
+        &mut self,
+        hash: &Hash,
+    ) -> Result<Option<DataHashRecord>, Error> {
+        dbg!(hash);
+        if *hash == Hash::empty() {
+            return Ok(Some(DataHashRecord::empty()));
+        }
+        let mut filter = doc! {};
+        filter.insert("hash", hash_to_bson(hash));
+        let record = self.find_one_datahash_record(filter, None).await?;
+        Ok(record)
+    }
+
+    pub async fn must_get_datahash_record(&mut self, hash: &Hash) -> Result<DataHashRecord, Error> {
+        let record = self.get_datahash_record(hash).await?;
+        record.ok_or_else(|| {
+            Error::NotFound(format!("Datahash record with hash {hash:?} not found"))
+                .with_detail("hash", hex::encode(hash.0))
+        })
+    }
+
+    /// Insert the default records of the leftmost path, from the root down to the first leaf,
+    /// exactly as get_merkle_record synthesizes them (see MerkleRecord::get_default_record). The
+    /// first writes to a new contract then find them, instead of looking them up in vain before
+    /// falling back to the default records.
+    pub async fn insert_default_spine(&mut self) -> Result<(), Error> {
+        let records = (0..=H)
+            .map(|depth| MerkleRecord::get_default_record_with_height::<H>((1 << depth) - 1))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.insert_merkle_records(&records).await
+    }
+
+    /// Check that the merkle tree of the current root has height H: the records on a path from
+    /// the root must be non-leaf nodes (hashing their children) down to depth H, where the leaf
+    /// is found. A single path is followed, through the non-default subtrees.
+    pub async fn check_tree_height(&mut self) -> Result<(), Error> {
+        let root = self.must_get_root_merkle_record().await?;
+        if root.hash == Hash::get_default_hash_for_depth_with_height::<H>(0)? {
+            return Ok(());
+        }
+        // The default root of a tree of another height.
+        if let Some(height) = DEFAULT_HASH_VEC.iter().position(|hash| *hash == root.hash) {
+            return Err(tree_height_mismatch_error(
+                &self.contract_id,
+                Some(height),
+                H,
+            ));
+        }
+        let (mut index, mut record) = (0, root);
+        for depth in 0..=H {
+            let is_non_leaf = record.hash == Hash::hash_children(&record.left, &record.right);
+            if is_non_leaf != (depth < H) {
+                // A leaf above depth H, or a non-leaf node at depth H.
+                let height = (!is_non_leaf).then_some(depth);
+                return Err(tree_height_mismatch_error(&self.contract_id, height, H));
+            }
+            if depth == H {
+                break;
+            }
+            // The default subtrees are skipped whatever their height, their records are not
+            // stored.
+            let (child_index, child_hash) = if !DEFAULT_HASH_VEC.contains(&record.left) {
+                (2 * index + 1, record.left)
+            } else if !DEFAULT_HASH_VEC.contains(&record.right) {
+                (2 * index + 2, record.right)
+            } else {
+                break;
+            };
+            record = self
+                .must_get_merkle_record(child_index, &child_hash)
+                .await?;
+            index = child_index;
+        }
+        Ok(())
+    }
+
+    /// Collect the non default merkle records reachable from the current root and the data hash
+    /// records of the reachable leaves. The whole snapshot is kept in memory.
+    pub async fn export_snapshot(&mut self) -> Result<Snapshot, Error> {
+        let root = self.must_get_root_merkle_record().await?;
+        let mut snapshot = Snapshot {
+            tree_height: H,
+            contract_id: self.contract_id,
+            root: root.hash,
+            merkle_records: vec![],
+            datahash_records: vec![],
+        };
+        let mut datahashes = HashSet::new();
+        let mut stack = vec![(0, root.hash)];
+        while let Some((index, hash)) = stack.pop() {
+            // Skip the subtrees with only default leaves.
+            let depth = (index + 1).ilog2() as usize;
+            if hash == Hash::get_default_hash_for_depth_with_height::<H>(depth)? {
+                continue;
+            }
+            let record = if index == 0 {
+                root
+            } else {
+                self.must_get_merkle_record(index, &hash).await?
+            };
+            snapshot.merkle_records.push(record);
+            if get_node_type(index, H) == NodeType::NodeLeaf {
+                if datahashes.insert(hash) {
+                    if let Some(datahash_record) = self.get_datahash_record(&hash).await? {
+                        snapshot.datahash_records.push(datahash_record);
+                    }
+                }
+                continue;
+            }
+            stack.push((2 * index + 2, record.right));
+            stack.push((2 * index + 1, record.left));
+        }
+        Ok(snapshot)
+    }
+
+    /// Import the records of a snapshot and switch the current root to the root of the snapshot,
+    /// after checking that the snapshot is of this contract and verifies (see Snapshot::verify).
+    /// This refuses to import over a contract with a non default root unless overwrite is set.
+    /// The existing records are kept.
+    pub async fn import_snapshot(
+        &mut self,
+        snapshot: &Snapshot,
+        overwrite: bool,
+    ) -> Result<(), Error> {
+        if snapshot.contract_id != self.contract_id {
+            return Err(Error::InvalidArgument(format!(
+                "Snapshot of contract {}, refusing to import it into contract {}",
+                hex::encode(snapshot.contract_id.0),
+                hex::encode(self.contract_id.0)
+            ))
+            .with_detail("contract_id", hex::encode(self.contract_id.0))
+            .with_detail("snapshot_contract_id", hex::encode(snapshot.contract_id.0)));
+        }
+        snapshot.verify::<H>()?;
+        let current_root = self.must_get_root_merkle_record().await?;
+        let default_root = MerkleRecord::get_default_record_with_height::<H>(0)?;
+        if current_root.hash != default_root.hash && !overwrite {
+            return Err(Error::Precondition(format!(
+                "Contract {} is not empty, refusing to import the snapshot without overwrite",
+                hex::encode(self.contract_id.0)
+            ))
+            .with_detail("contract_id", hex::encode(self.contract_id.0)));
+        }
+        for record in &snapshot.datahash_records {
+            self.insert_datahash_record_if_missing(record).await?;
+        }
+        self.insert_merkle_records(&snapshot.merkle_records).await?;
+        let root = snapshot
+            .merkle_records
+            .iter()
+            .find(|record| record.index == 0 && record.hash == snapshot.root)
+            .unwrap_or(&default_root);
+        self.update_leaf_index_between(&current_root, root).await?;
+        self.update_root_merkle_record(&current_root.hash, root)
+            .await?;
+        Ok(())
+    }
+}
+
+impl MongoKvPair {
+    pub async fn new() -> Self {
+        Self::try_new()
+            .await
+            .unwrap_or_else(|e| panic!("Create MongoKvPair: {e}"))
+    }
+
+    /// Same as new, but returns an error instead of panicking if the configuration is invalid
+    /// or the MongoDB server is unreachable.
+    pub async fn try_new() -> Result<Self, Error> {
+        let options = MongoClientConfig::from_env()
+            .map_err(Error::InvalidArgument)?
+            .client_options()
+            .await?;
+        // The TLS files are read here.
+        let client = Client::with_options(options)
+            .map_err(|e| Error::InvalidArgument(format!("Invalid MongoDB client options: {e}")))?;
+        // Eagerly connect to mongodb server to fail faster.
+        client
+            .list_database_names(
+                doc! {
+                    "name": MongoCollection::<(), ()>::get_database_name(),
+                },
+                None,
+            )
+            .await
+            .map_err(|e| Error::Precondition(format!("Failed to connect to MongoDB: {e}")))?;
+        MongoKvPair::new_with_client(client)
+    }
+
+    fn new_merkle_record_cache(config: &MongoKvPairConfig) -> Option<MerkleRecordCache> {
+        NonZeroUsize::new(config.merkle_record_cache_size)
+            .map(|size| Arc::new(Mutex::new(LruCache::new(size))))
+    }
+
+    fn new_write_permits(config: &MongoKvPairConfig) -> Option<Arc<Semaphore>> {
+        config
+            .max_concurrent_writes
+            .map(|count| Arc::new(Semaphore::new(count)))
+    }
+
+    fn new_root_signer(config: &MongoKvPairConfig) -> Result<Option<RootSigner>, Error> {
+        config
+            .signing_key
+            .as_ref()
+            .map(|path| {
+                RootSigner::from_file(path).map_err(|e| {
+                    let path = path.display();
+                    Error::InvalidArgument(format!("Load KVPAIR_SIGNING_KEY {path}: {e}"))
+                })
+            })
+            .transpose()
+    }
+
+    pub async fn new_with_test_config(test_config: Option<MongoKvPairTestConfig>) -> Self {
+        let mut client = Self::new().await;
+        client.test_config = test_config;
+        client
+    }
+
+    pub async fn new_with_config(config: MongoKvPairConfig) -> Self {
+        Self::new_with_config_and_test_config(config, None).await
+    }
+
+    pub async fn new_with_config_and_test_config(
+        config: MongoKvPairConfig,
+        test_config: Option<MongoKvPairTestConfig>,
+    ) -> Self {
+        let mut client = Self::new().await;
+        crate::poseidon::set_hash_config(config.hash_config).expect("Set hash config");
+        client.cache = Self::new_merkle_record_cache(&config);
+        client.signer = Self::new_root_signer(&config).expect("Load KVPAIR_SIGNING_KEY");
+        client.write_permits = Self::new_write_permits(&config);
+        client.config = config;
+        client.test_config = test_config;
+        client
+    }
+
+    pub fn config(&self) -> &MongoKvPairConfig {
+        &self.config
+    }
+
+    // Destructive operations are only allowed in tests, unless explicitly enabled.
+    fn allow_destructive(&self) -> bool {
+        self.test_config.is_some() || self.config.allow_destructive
+    }
+
+    // Raw node writes (SetNonLeaf) are only allowed in tests, unless explicitly enabled.
+    fn allow_raw_node_writes(&self) -> bool {
+        self.test_config.is_some() || self.config.allow_raw_node_writes
+    }
+
+    fn root_signer(&self) -> Result<&RootSigner, Error> {
+        self.signer.as_ref().ok_or_else(|| {
+            Error::Precondition("Root signing not enabled (KVPAIR_SIGNING_KEY not set)".to_string())
+        })
+    }
+
+    // Sign the root as the root of the contract at the current time.
+    fn sign_root(&self, contract_id: &ContractId, root: Hash) -> Result<SignedRoot, Error> {
+        let attestation = RootAttestation {
+            contract_id: *contract_id,
+            root,
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64,
+        };
+        Ok(SignedRoot {
+            contract_id: contract_id.0.to_vec(),
+            root: root.into(),
+            timestamp_ms: attestation.timestamp_ms,
+            signature: self.root_signer()?.sign(&attestation),
+        })
+    }
+
+    // Resolve the name of a snapshot file of the contract, in its subdirectory of the snapshot
+    // directory. Only plain file names are accepted, so that clients can not read or write files
+    // elsewhere on the server, including the snapshots of other contracts.
+    fn snapshot_path(&self, contract_id: &ContractId, name: &str) -> Result<PathBuf, Error> {
+        let dir = self.config.snapshot_dir.as_ref().ok_or_else(|| {
+            Error::Precondition("Snapshot directory (KVPAIR_SNAPSHOT_DIR) not set".to_string())
+        })?;
+        if Path::new(name).file_name() != Some(OsStr::new(name)) {
+            return Err(Error::InvalidArgument(format!(
+                "Invalid snapshot file name {name:?}"
+            )));
+        }
+        Ok(dir.join(hex::encode(contract_id.0)).join(name))
+    }
+
+    fn new_with_client(client: Client) -> Result<Self, Error> {
+        let config = MongoKvPairConfig::from_env().map_err(Error::InvalidArgument)?;
+        crate::poseidon::set_hash_config(config.hash_config)?;
+        Ok(Self {
+            client,
+            cache: Self::new_merkle_record_cache(&config),
+            stats_cache: Default::default(),
+            cache_invalidations: Default::default(),
+            write_locks: Default::default(),
+            write_permits: Self::new_write_permits(&config),
+            shutdown: Default::default(),
+            opened_contracts: Default::default(),
+            created_contracts: Default::default(),
+            transactions_supported: Default::default(),
+            signer: Self::new_root_signer(&config)?,
+            config,
+            test_config: None,
+        })
+    }
+
+    pub async fn new_collection<T, R>(
+        &self,
+        contract_id: &ContractId,
+        with_session: bool,
+    ) -> Result<MongoCollection<T, R>, Error> {
+        let schema_version = self.open_contract(contract_id).await?;
+        Ok(self
+            .new_unchecked_collection(contract_id, with_session)
+            .await?
+            .with_schema_version(schema_version))
+    }
+
+    // A collection for the writes of a request, whose operations run in a transaction (committed
+    // by MongoCollection::commit) when MongoDB supports them. On a standalone mongod, which does
+    // not, a write failing midway may leave records which are not reachable from any root, but
+    // never a root whose records are missing.
+    async fn new_write_collection<T, R>(
+        &self,
+        contract_id: &ContractId,
+    ) -> Result<MongoCollection<T, R>, Error> {
+        let with_session = self.supports_transactions().await?;
+        // Checked before creating the collections, so that a refused contract gets none.
+        self.open_contract(contract_id).await?;
+        self.create_contract_collections(contract_id).await?;
+        self.new_collection(contract_id, with_session).await
+    }
+
+    // Transactions are supported by replica sets (including single node ones) and sharded
+    // clusters. This is checked on the first write, as MongoDB is not connected before.
+    async fn supports_transactions(&self) -> Result<bool, Error> {
+        let supported = self
+            .transactions_supported
+            .get_or_try_init(|| async {
+                let admin = self.client.database("admin");
+                let hello = admin.run_command(doc! {"hello": 1}, None).await?;
+                // mongos identifies itself with this message.
+                let supported =
+                    hello.contains_key("setName") || hello.get_str("msg").ok() == Some("isdbgrid");
+                if !supported {
+                    eprintln!("Transactions not supported by MongoDB, writes run without them");
+                }
+                Ok::<_, Error>(supported)
+            })
+            .await?;
+        Ok(*supported)
+    }
+
+    // Same as new_collection, without checking the schema version of the contract, for
+    // migrate_contract and the requests which do not read the records.
+    async fn new_unchecked_collection<T, R>(
+        &self,
+        contract_id: &ContractId,
+        with_session: bool,
+    ) -> Result<MongoCollection<T, R>, Error> {
+        let transaction_limits = TransactionLimits {
+            max_commit_time: self.config.max_commit_time,
+            timeout: self.config.transaction_timeout,
+        };
+        Ok(MongoCollection::new_with_transaction_limits(
+            self.client.clone(),
+            contract_id,
+            with_session,
+            transaction_limits,
+        )
+        .await?
+        .with_cache(self.cache.clone())
+        .with_allow_destructive(self.allow_destructive())
+        .with_primary_reads(self.config.read_from_primary)
+        .with_parallel_hash_threshold(self.config.parallel_hash_threshold)
+        .with_sharded_layout(self.config.sharded_layout)
+        .with_shutdown(self.shutdown.aborted.clone()))
+    }
+
+    // A collection for the reads of a request. With consistent_read, the reads are sent to the
+    // primary in a causally consistent session, so that they observe every write acknowledged
+    // before the request (see with_causal_consistency).
+    async fn new_read_collection<T, R>(
+        &self,
+        contract_id: &ContractId,
+        consistent_read: bool,
+    ) -> Result<MongoCollection<T, R>, Error> {
+        let collection = self.new_collection(contract_id, false).await?;
+        if !consistent_read {
+            return Ok(collection);
+        }
+        Ok(collection
+            .with_primary_reads(true)
+            .with_causal_consistency()
+            .await?)
+    }
+
+    // The leaves of a group of MultiContractGetLeaves, all read from the current root of the
+    // contract.
+    async fn get_contract_leaves(
+        &self,
+        contract_id: &ContractId,
+        indices: &[u64],
+        proof_type: i32,
+        consistent_read: bool,
+        deadline: Option<Instant>,
+    ) -> Result<ContractLeaves, Error> {
+        let mut collection = self
+            .new_read_collection(contract_id, consistent_read)
+            .await?
+            .with_deadline(deadline);
+        let (root, leaves) = collection.get_leaves_and_proofs(indices).await?;
+        let mut nodes = Vec::with_capacity(leaves.len());
+        let mut proofs = vec![];
+        for (record, proof) in leaves {
+            let (node, _) = get_leaf_node(&mut collection, record, false).await?;
+            nodes.push(node);
+            proofs.extend(encode_proof(proof_type, &proof));
+        }
+        collection.commit().await?;
+        Ok(ContractLeaves {
+            contract_id: (*contract_id).into(),
+            root: root.into(),
+            nodes,
+            proofs,
+        })
+    }
+
+    // Serialize the writes of a contract in this process. Every write reads the current root and
+    // then replaces it, so concurrent writes would otherwise silently discard each other's
+    // updates. Reads do not take the lock. The writes are rejected with UNAVAILABLE once the
+    // server is shutting down, and the guard is counted as in flight by shutdown until dropped.
+    // With max_concurrent_writes, the guard also holds one of the write permits, so that a flood
+    // of writes does not exhaust the MongoDB connection pool.
+    async fn lock_contract(&self, contract_id: &ContractId) -> Result<WriteGuard, Error> {
+        let in_flight = self.start_write()?;
+        self.lock_contract_in_flight(contract_id, in_flight).await
+    }
+
+    // Count a write as in flight until the returned value is dropped, or reject it with
+    // UNAVAILABLE if the server is shutting down.
+    fn start_write(&self) -> Result<InFlightWrite, Error> {
+        // Counted before checking draining, so that shutdown either sees the write or the write
+        // sees draining.
+        let in_flight = InFlightWrite::new(&self.shutdown);
+        if self.is_shutting_down() {
+            return Err(Error::Unavailable(
+                "Server is shutting down, retry with another server".to_string(),
+            ));
+        }
+        Ok(in_flight)
+    }
+
+    // Same as lock_contract, for a write already counted as in flight by start_write, which is
+    // therefore not rejected if the server started shutting down since.
+    async fn lock_contract_in_flight(
+        &self,
+        contract_id: &ContractId,
+        in_flight: InFlightWrite,
+    ) -> Result<WriteGuard, Error> {
+        let timeout = self.config.write_lock_timeout;
+        let permit = match &self.write_permits {
+            Some(permits) => {
+                let permit = tokio::time::timeout(timeout, permits.clone().acquire_owned())
+                    .await
+                    .map_err(|_| {
+                        Error::ResourceExhausted(format!(
+                            "Timed out after {timeout:?} waiting for other writes in flight"
+                        ))
+                        .with_detail("writes_in_flight", self.writes_in_flight())
+                    })?;
+                Some(permit.expect("Write permits never closed"))
+            }
+            None => None,
+        };
+        let lock = self
+            .write_locks
+            .lock()
+            .unwrap()
+            .entry(*contract_id)
+            .or_default()
+            .clone();
+        let lock = tokio::time::timeout(timeout, lock.lock_owned())
+            .await
+            .map_err(|_| {
+                Error::Aborted(format!(
+                    "Timed out after {timeout:?} waiting for other writes of contract {}",
+                    hex::encode(contract_id.0)
+                ))
+                .with_detail("contract_id", hex::encode(contract_id.0))
+            })?;
+        Ok(WriteGuard {
+            _lock: lock,
+            _in_flight: in_flight,
+            _permit: permit,
+        })
+    }
+
+    /// Start shutting down: new writes are rejected with UNAVAILABLE (as is Ping, so that load
+    /// balancers stop sending requests), and the writes in flight are given drain_period to
+    /// finish. The writes still in flight after that fail before their next write or commit, and
+    /// their transactions are aborted. Returns whether all writes finished in time.
+    pub async fn shutdown(&self, drain_period: Duration) -> bool {
+        self.shutdown.draining.store(true, Ordering::SeqCst);
+        let drained = async {
+            loop {
+                // Registered before checking the count, so that the notification of the last
+                // write is not missed.
+                let notified = self.shutdown.drained.notified();
+                tokio::pin!(notified);
+                notified.as_mut().enable();
+                if self.writes_in_flight() == 0 {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        if tokio::time::timeout(drain_period, drained).await.is_ok() {
+            return true;
+        }
+        self.shutdown.aborted.store(true, Ordering::Relaxed);
+        false
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.draining.load(Ordering::SeqCst)
+    }
+
+    /// The number of writes in flight, including those waiting for other writes of their
+    /// contract.
+    pub fn writes_in_flight(&self) -> usize {
+        self.shutdown.writes.load(Ordering::SeqCst)
+    }
+
+    // Who made the request, as recorded in the audit log: the contract id of the JWT, or the
+    // x-auth-contract-id header (only trusted in dev mode), both base64 encoded.
+    fn get_principal<T>(request: &Request<T>) -> String {
+        use base64::{engine::general_purpose, Engine as _};
+        if let Some(authenticated) = request.extensions().get::<AuthenticatedContractId>() {
+            return format!(
+                "jwt:{}",
+                general_purpose::STANDARD.encode(authenticated.0 .0)
+            );
+        }
+        match request.metadata().get("x-auth-contract-id") {
+            Some(id) => format!("header:{}", id.to_str().unwrap_or_default()),
+            None => "anonymous".to_string(),
+        }
+    }
+
+    // The deadline of the request: the one set by the client with the grpc-timeout header,
+    // capped to max_request_time, if any. The database work of the request is bounded by it, see
+    // MongoCollection::with_deadline.
+    fn get_deadline<T>(&self, request: &Request<T>) -> Option<Instant> {
+        let now = Instant::now();
+        let deadline = request
+            .metadata()
+            .get("grpc-timeout")
+            .and_then(|timeout| timeout.to_str().ok())
+            .and_then(parse_grpc_timeout)
+            .map(|timeout| now + timeout);
+        let max_deadline = self.config.max_request_time.map(|max_time| now + max_time);
+        match (deadline, max_deadline) {
+            (Some(deadline), Some(max_deadline)) => Some(deadline.min(max_deadline)),
+            (deadline, max_deadline) => deadline.or(max_deadline),
+        }
+    }
+
+    // The copy of the leaf data recorded by audit, None if the data is not captured.
+    fn audit_data(&self, data: Option<&[u8]>) -> Option<Vec<u8>> {
+        if self.config.audit_capture_data {
+            data.map(<[u8]>::to_vec)
+        } else {
+            None
+        }
+    }
+
+    // Record a successful mutation in the audit log of the contract. This must be called before
+    // committing the collection, so that the entry is committed together with the mutation when
+    // the collection has a transaction. Mutations not replacing the root (e.g. SetNonLeaf) are
+    // recorded with the current root as both the old and new root.
+    async fn audit(
+        &self,
+        collection: &mut MongoCollection<MerkleRecord, DataHashRecord>,
+        principal: String,
+        rpc: &str,
+        nodes: Vec<AuditNode>,
+    ) -> Result<(), Error> {
+        let mut record = self.new_audit_record(principal, rpc, nodes);
+        Self::insert_audit(collection, &mut record).await
+    }
+
+    // An audit record of the given nodes, whose roots are set by insert_audit.
+    fn new_audit_record(
+        &self,
+        principal: String,
+        rpc: &str,
+        mut nodes: Vec<AuditNode>,
+    ) -> AuditRecord {
+        if !self.config.audit_capture_data {
+            nodes.iter_mut().for_each(|node| node.data = None);
+        }
+        AuditRecord {
+            id: None,
+            timestamp: DateTime::now(),
+            rpc: rpc.to_string(),
+            nodes,
+            old_root: Hash::default(),
+            new_root: Hash::default(),
+            principal,
+        }
+    }
+
+    // Insert the audit record with the roots replaced by the last write of the collection, or
+    // its current root if the root has not been replaced.
+    async fn insert_audit(
+        collection: &mut MongoCollection<MerkleRecord, DataHashRecord>,
+        record: &mut AuditRecord,
+    ) -> Result<(), Error> {
+        (record.old_root, record.new_root) = match collection.last_root_update() {
+            Some(update) => update,
+            None => {
+                let root = collection.must_get_root_merkle_record().await?.hash;
+                (root, root)
+            }
+        };
+        record.timestamp = DateTime::now();
+        collection.insert_audit_record(record).await
+    }
+
+    // Whether a write which failed with the error should be run again from a new transaction,
+    // see is_restartable_transaction_error. Writes outside of transactions are retried in place
+    // instead, see MongoCollection::set_leaves.
+    async fn should_restart_write(&self, error: &Error, attempts: usize) -> Result<bool, Error> {
+        Ok(attempts < MAX_ROOT_UPDATE_ATTEMPTS
+            && is_restartable_transaction_error(error)
+            && self.supports_transactions().await?)
+    }
+
+    // Run the write, i.e. a function opening its own write collection and committing it, again
+    // from the start as long as it fails with an error for which should_restart_write holds.
+    async fn retry_write<V, F, Fut>(&self, mut write: F) -> Result<V, Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = Result<V, Error>>,
+    {
+        let mut attempts = 1;
+        loop {
+            match write().await {
+                Err(error) if self.should_restart_write(&error, attempts).await? => {
+                    tokio::time::sleep(root_update_backoff(attempts)).await;
+                    attempts += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Write a chunk of SetLeafStream, i.e. the leaves with their data and the audit record, in a
+    // transaction if supported, and return the new root.
+    async fn write_leaf_chunk(
+        &self,
+        contract_id: &ContractId,
+        deadline: Option<Instant>,
+        leaves: &[(MerkleRecord, Option<DataHashRecord>)],
+        audit_record: &mut AuditRecord,
+    ) -> Result<Hash, Error> {
+        let mut collection = self
+            .new_write_collection(contract_id)
+            .await?
+            .with_deadline(deadline);
+        for datahash_record in leaves.iter().filter_map(|(_, record)| record.as_ref()) {
+            collection
+                .insert_datahash_record_if_missing(datahash_record)
+                .await?;
+        }
+        let records = leaves.iter().map(|(record, _)| *record).collect::<Vec<_>>();
+        let root = collection.set_leaves(&records).await?;
+        Self::insert_audit(&mut collection, audit_record).await?;
+        collection.commit().await?;
+        Ok(root)
+    }
+
+    // Write a SetLeaf with its audit record, in a transaction if supported. With an idempotency
+    // key (and the digest of the request), the response built by respond is recorded with the
+    // key before the commit, see MongoCollection::record_idempotent_response, and returned. The
+    // data hash record is then moved into the response, and only left in datahash_record if the
+    // write fails, so that it can be attempted again without copying the data.
+    async fn write_leaf<F>(
+        &self,
+        contract_id: &ContractId,
+        deadline: Option<Instant>,
+        principal: &str,
+        merkle_record: &MerkleRecord,
+        datahash_record: &mut Option<DataHashRecord>,
+        idempotency: Option<(&(String, Vec<u8>), &F)>,
+    ) -> Result<(LeafWrite, Option<SetLeafResponse>), Error>
+    where
+        F: Fn(Node, LeafWrite) -> Result<SetLeafResponse, Error>,
+    {
+        let mut collection = self
+            .new_write_collection(contract_id)
+            .await?
+            .with_deadline(deadline);
+        if let Some(((key, request_digest), _)) = idempotency {
+            let record = self.new_idempotency_record(key, request_digest, vec![]);
+            collection = collection.with_pending_idempotency_record(record);
+        }
+        if let Some(datahash_record) = datahash_record.as_ref() {
+            collection
+                .insert_datahash_record_if_missing(datahash_record)
+                .await?;
+        }
+        let (previous, previous_root, proof) =
+            collection.replace_leaf_and_get_proof(merkle_record).await?;
+        // A leaf which has never been set (or has been deleted) is reported with empty data,
+        // even though the default hash is the hash of 32 zero bytes.
+        let previous_is_default = previous.hash == DEFAULT_HASH_VEC[0];
+        let previous_node = if previous_is_default {
+            Node {
+                index: previous.index,
+                hash: previous.hash.into(),
+                node_type: NodeType::NodeLeaf.into(),
+                node_data: Some(NodeData::Data(vec![])),
+            }
+        } else {
+            match collection.get_datahash_record(&previous.hash).await? {
+                Some(datahash_record) => (previous, datahash_record).try_into()?,
+                None => Node::new_simple_leaf(previous.index, previous.hash),
+            }
+        };
+        let nodes = vec![AuditNode {
+            index: merkle_record.index,
+            hash: merkle_record.hash,
+            data: self.audit_data(
+                datahash_record
+                    .as_ref()
+                    .map(|record| record.data.as_slice()),
+            ),
+        }];
+        self.audit(&mut collection, principal.to_string(), "SetLeaf", nodes)
+            .await?;
+        let write = (previous_root, proof, previous_node, previous_is_default);
+        let ((key, request_digest), respond) = match idempotency {
+            Some(idempotency) => idempotency,
+            None => {
+                collection.commit().await?;
+                return Ok((write, None));
+            }
+        };
+        let data_hash = datahash_record.as_ref().map(|record| record.hash);
+        let node = leaf_node(*merkle_record, datahash_record.take())?;
+        let mut response = respond(node, write.clone())?;
+        let encoded = prost::Message::encode_to_vec(&response);
+        let record = self.new_idempotency_record(key, request_digest, encoded);
+        let result = match collection.record_idempotent_response(&record).await {
+            Ok(()) => collection.commit().await,
+            Err(error) => Err(error),
+        };
+        if let Err(error) = result {
+            // Moved back for the next attempt.
+            let data = response.node.take().and_then(|node| node.node_data);
+            if let (Some(hash), Some(NodeData::Data(data))) = (data_hash, data) {
+                *datahash_record = Some(DataHashRecord::new(hash, data));
+            }
+            return Err(error);
+        }
+        Ok((write, Some(response)))
+    }
+
+    // The entry of the IDEMPOTENCY_ collection recording the response of a request made with the
+    // key, which expires after idempotency_key_ttl.
+    fn new_idempotency_record(
+        &self,
+        key: &str,
+        request_digest: &[u8],
+        response: Vec<u8>,
+    ) -> IdempotencyRecord {
+        let created_at = DateTime::now();
+        let ttl = self.config.idempotency_key_ttl.as_millis() as i64;
+        IdempotencyRecord {
+            key: key.to_string(),
+            request_digest: request_digest.to_vec(),
+            response,
+            created_at,
+            expires_at: DateTime::from_millis(created_at.timestamp_millis() + ttl),
+            pending: false,
+        }
+    }
+
+    // Write a DeleteLeaf with its audit record, in a transaction if supported, and return the
+    // proof of the default leaf.
+    async fn write_deleted_leaf(
+        &self,
+        contract_id: &ContractId,
+        deadline: Option<Instant>,
+        principal: &str,
+        index: u64,
+    ) -> Result<MerkleProof<Hash, MERKLE_TREE_HEIGHT>, Error> {
+        let mut collection = self
+            .new_write_collection(contract_id)
+            .await?
+            .with_deadline(deadline);
+        let proof = collection.delete_leaf_and_get_proof(index).await?;
+        let nodes = vec![AuditNode {
+            index,
+            hash: proof.source,
+            data: None,
+        }];
+        self.audit(&mut collection, principal.to_string(), "DeleteLeaf", nodes)
+            .await?;
+        collection.commit().await?;
+        Ok(proof)
+    }
+
+    // Write a SetNonLeaf with its audit record, in a transaction if supported. Returns the
+    // record and whether each of its children exists.
+    async fn write_non_leaf(
+        &self,
+        contract_id: &ContractId,
+        deadline: Option<Instant>,
+        principal: &str,
+        index: u64,
+        left: Hash,
+        right: Hash,
+    ) -> Result<(MerkleRecord, [bool; 2]), Error> {
+        let mut collection = self
+            .new_write_collection(contract_id)
+            .await?
+            .with_deadline(deadline);
+        let mut children_exist = [false; 2];
+        for (exists, (child, hash)) in children_exist
+            .iter_mut()
+            .zip([(2 * index + 1, &left), (2 * index + 2, &right)])
+        {
+            *exists = collection.get_merkle_record(child, hash).await?.is_some();
+            if !*exists && self.config.require_existing_children {
+                return Err(Error::Precondition(format!(
+                    "Child {child} with hash {hash:?} of node {index} not found"
+                ))
+                .with_detail("index", child)
+                .with_detail("hash", hex::encode(hash.0)));
+            }
+        }
+        let record = collection.insert_non_leaf_node(index, left, right).await?;
+        let nodes = vec![AuditNode {
+            index,
+            hash: record.hash,
+            data: None,
+        }];
+        self.audit(&mut collection, principal.to_string(), "SetNonLeaf", nodes)
+            .await?;
+        collection.commit().await?;
+        Ok((record, children_exist))
+    }
+
+    // Write a SetRoot with its audit record, in a transaction if supported.
+    async fn write_root(
+        &self,
+        contract_id: &ContractId,
+        deadline: Option<Instant>,
+        principal: &str,
+        hash: Hash,
+        verify: bool,
+    ) -> Result<(), Error> {
+        let mut collection = self
+            .new_write_collection(contract_id)
+            .await?
+            .with_deadline(deadline);
+        let record = collection
+            .get_merkle_record(0, &hash)
+            .await?
+            .ok_or_else(|| {
+                Error::RootNotFound(format!("Root {hash:?} not found"))
+                    .with_detail("hash", hex::encode(hash.0))
+            })?;
+        if verify {
+            collection.verify_root_record(&record).await?;
+        }
+        let current_root = collection.must_get_root_merkle_record().await?;
+        collection
+            .update_leaf_index_between(&current_root, &record)
+            .await?;
+        collection
+            .update_root_merkle_record(&current_root.hash, &record)
+            .await?;
+        self.audit(&mut collection, principal.to_string(), "SetRoot", vec![])
+            .await?;
+        collection.commit().await
+    }
+
+    // Write an ImportSnapshot, i.e. the records of the snapshot and the audit record of its
+    // leaves, in a transaction if supported.
+    async fn write_snapshot(
+        &self,
+        contract_id: &ContractId,
+        deadline: Option<Instant>,
+        principal: &str,
+        snapshot: &Snapshot,
+        overwrite: bool,
+    ) -> Result<(), Error> {
+        let mut collection = self
+            .new_write_collection(contract_id)
+            .await?
+            .with_deadline(deadline);
+        collection.import_snapshot(snapshot, overwrite).await?;
+        let data = snapshot
+            .datahash_records
+            .iter()
+            .map(|record| (record.hash, record.data.as_slice()))
+            .collect::<HashMap<_, _>>();
+        let nodes = snapshot
+            .merkle_records
+            .iter()
+            .filter(|record| get_node_type(record.index, MERKLE_TREE_HEIGHT) == NodeType::NodeLeaf)
+            .map(|record| AuditNode {
+                index: record.index,
+                hash: record.hash,
+                data: self.audit_data(data.get(&record.hash).copied()),
+            })
+            .collect();
+        self.audit(
+            &mut collection,
+            principal.to_string(),
+            "ImportSnapshot",
+            nodes,
+        )
+        .await?;
+        collection.commit().await
+    }
+
+    fn get_contracts_collection(&self) -> Collection<ContractMetadata> {
+        MongoCollection::<(), ()>::get_contracts_collection(&self.client)
+    }
+
+    pub async fn get_contract_metadata(
+        &self,
+        contract_id: &ContractId,
+    ) -> Result<Option<ContractMetadata>, Error> {
+        let filter = doc! {"contract_id": u256_to_bson(&contract_id.0)};
+        let result = self
+            .get_contracts_collection()
+            .find_one(filter, None)
+            .await?;
+        Ok(result)
+    }
+
+    /// Replace the metadata of the contract. Its schema version and tree height are kept, as they
+    /// are only changed by migrate_contract and open_contract.
+    pub async fn set_contract_metadata(&self, metadata: &ContractMetadata) -> Result<(), Error> {
+        let stored = self.get_contract_metadata(&metadata.contract_id).await?;
+        let metadata = ContractMetadata {
+            schema_version: stored.as_ref().and_then(|stored| stored.schema_version),
+            tree_height: stored.as_ref().and_then(|stored| stored.tree_height),
+            leaf_index_complete: stored.map_or(false, |stored| stored.leaf_index_complete),
+            ..metadata.clone()
+        };
+        let filter = doc! {"contract_id": u256_to_bson(&metadata.contract_id.0)};
+        let options = ReplaceOptions::builder().upsert(true).build();
+        self.get_contracts_collection()
+            .replace_one(filter, metadata, options)
+            .await?;
+        Ok(())
+    }
+
+    /// The schema version of the records of the contract, see crate::migrations.
+    pub async fn get_schema_version(&self, contract_id: &ContractId) -> Result<u32, Error> {
+        Ok(self
+            .get_contract_metadata(contract_id)
+            .await?
+            .and_then(|metadata| metadata.schema_version)
+            .unwrap_or(INITIAL_SCHEMA_VERSION))
+    }
+
+    /// Record the schema version of the records of the contract. This is done by
+    /// migrate_contract once the records are migrated, and otherwise only needed to repair the
+    /// metadata of a contract.
+    pub async fn set_schema_version(
+        &self,
+        contract_id: &ContractId,
+        version: u32,
+    ) -> Result<(), Error> {
+        let filter = doc! {"contract_id": u256_to_bson(&contract_id.0)};
+        let update = doc! {"$set": {"schema_version": version}};
+        let options = UpdateOptions::builder().upsert(true).build();
+        self.get_contracts_collection()
+            .update_one(filter, update, options)
+            .await?;
+        // Checked again by the next request.
+        self.opened_contracts.lock().unwrap().remove(contract_id);
+        Ok(())
+    }
+
+    // Refuse to serve a contract whose merkle tree has another height than MERKLE_TREE_HEIGHT.
+    // The height is checked on the records once (see MongoCollection::check_tree_height), and
+    // then recorded in the metadata of the contract.
+    async fn check_tree_height(&self, contract_id: &ContractId) -> Result<(), Error> {
+        let tree_height = self
+            .get_contract_metadata(contract_id)
+            .await?
+            .and_then(|metadata| metadata.tree_height);
+        match tree_height {
+            Some(height) if height as usize == MERKLE_TREE_HEIGHT => return Ok(()),
+            Some(height) => {
+                let height = Some(height as usize);
+                return Err(tree_height_mismatch_error(
+                    contract_id,
+                    height,
+                    MERKLE_TREE_HEIGHT,
+                ));
+            }
+            None => (),
+        }
+        self.new_unchecked_collection::<MerkleRecord, DataHashRecord>(contract_id, false)
+            .await?
+            .check_tree_height()
+            .await?;
+        let filter = doc! {"contract_id": u256_to_bson(&contract_id.0)};
+        let update = doc! {"$set": {"tree_height": MERKLE_TREE_HEIGHT as u32}};
+        let options = UpdateOptions::builder().upsert(true).build();
+        self.get_contracts_collection()
+            .update_one(filter, update, options)
+            .await?;
+        Ok(())
+    }
+
+    // Refuse to serve a contract whose records were written by a newer binary, which this one
+    // may misread, or in a format older than MIN_SCHEMA_VERSION, which it no longer reads.
+    // Opened contracts are remembered, so that this is done once per contract, unless the
+    // version is changed by set_schema_version. Returns the schema version of the contract,
+    // which is SCHEMA_VERSION for a contract which has not been written yet. Nothing is written
+    // for such a contract, so that reads of unknown contracts do not create them.
+    async fn open_contract(&self, contract_id: &ContractId) -> Result<u32, Error> {
+        if let Some(version) = self.opened_contracts.lock().unwrap().get(contract_id) {
+            return Ok(*version);
+        }
+        let recorded_version = self
+            .get_contract_metadata(contract_id)
+            .await?
+            .and_then(|metadata| metadata.schema_version);
+        if recorded_version.is_none() {
+            // The contracts without a recorded version are either written before schema
+            // versions, or not written yet, in which case their metadata is recorded with their
+            // first root. The version of the latter is not remembered, so that it is read again
+            // once recorded, or once a binary predating schema versions has written the contract.
+            let mut collection = self
+                .new_unchecked_collection::<MerkleRecord, DataHashRecord>(contract_id, false)
+                .await?;
+            if collection.get_root_merkle_record().await?.is_none() {
+                return Ok(SCHEMA_VERSION);
+            }
+        }
+        let version = recorded_version.unwrap_or(INITIAL_SCHEMA_VERSION);
+        if version > SCHEMA_VERSION {
+            return Err(newer_schema_version_error(contract_id, version));
+        }
+        if version < MIN_SCHEMA_VERSION {
+            return Err(Error::Precondition(format!(
+                "Contract {} has schema version {version}, older than {MIN_SCHEMA_VERSION}, it \
+                 must be upgraded with MigrateContract",
+                hex::encode(contract_id.0)
+            ))
+            .with_detail("contract_id", hex::encode(contract_id.0))
+            .with_detail("schema_version", version));
+        }
+        self.check_tree_height(contract_id).await?;
+        self.opened_contracts
+            .lock()
+            .unwrap()
+            .insert(*contract_id, version);
+        Ok(version)
+    }
+
+    // Create the collections of a contract with their validators, and its indexes, unless they
+    // exist. This is done before the first write of the contract in this process, so that only
+    // the contracts which are written get collections, and outside of transactions, in which
+    // collections can not be created with options.
+    async fn create_contract_collections(&self, contract_id: &ContractId) -> Result<(), Error> {
+        if self.created_contracts.lock().unwrap().contains(contract_id) {
+            return Ok(());
+        }
+        MongoCollection::<MerkleRecord, DataHashRecord>::create_collections(
+            &self.client,
+            contract_id,
+        )
+        .await?;
+        MongoCollection::<MerkleRecord, DataHashRecord>::create_idempotency_index(
+            &self.client,
+            contract_id,
+        )
+        .await?;
+        self.created_contracts.lock().unwrap().insert(*contract_id);
+        Ok(())
+    }
+
+    /// Upgrade the records of the contract to SCHEMA_VERSION by running the migrations after its
+    /// current version in order, changing at most limit records. The version is recorded after
+    /// each migration done, so a migration interrupted (or stopped by the limit) resumes where
+    /// it stopped when this is called again. Returns the schema version of the contract, the
+    /// number of records changed and whether the contract is at SCHEMA_VERSION.
+    pub async fn migrate_contract(
+        &self,
+        contract_id: &ContractId,
+        limit: usize,
+        deadline: Option<Instant>,
+    ) -> Result<(u32, usize, bool), Error> {
+        // Only one migration of the contract runs at a time in this process.
+        let _guard = self.lock_contract(contract_id).await?;
+        let mut version = self.get_schema_version(contract_id).await?;
+        if version > SCHEMA_VERSION {
+            return Err(newer_schema_version_error(contract_id, version));
+        }
+        // The contracts older than MIN_SCHEMA_VERSION are refused by new_collection.
+        let mut collection = self
+            .new_unchecked_collection::<MerkleRecord, DataHashRecord>(contract_id, false)
+            .await?
+            .with_deadline(deadline);
+        let mut migrated = 0;
+        for migration in pending_migrations(version) {
+            let (changed, done) = (migration.run)(&mut collection, limit - migrated).await?;
+            migrated += changed;
+            if !done {
+                return Ok((version, migrated, false));
+            }
+            println!(
+                "Contract {} migrated to schema version {}: {}",
+                hex::encode(contract_id.0),
+                migration.version,
+                migration.description
+            );
+            version = migration.version;
+            self.set_schema_version(contract_id, version).await?;
+        }
+        Ok((version, migrated, true))
+    }
+
+    /// Upgrade all the contracts to SCHEMA_VERSION, see migrate_contract. A failed contract is
+    /// logged and the others are still migrated. Returns the number of contracts which failed.
+    pub async fn migrate_all_contracts(&self) -> Result<usize, Error> {
+        let mut failed = 0;
+        for contract_id in self.list_contract_ids(None).await? {
+            if let Err(e) = self.migrate_contract(&contract_id, usize::MAX, None).await {
+                eprintln!(
+                    "Migration of contract {} failed: {e}",
+                    hex::encode(contract_id.0)
+                );
+                failed += 1;
+            }
+        }
+        Ok(failed)
+    }
+
+    async fn get_max_leaf_data_bytes(&self, contract_id: &ContractId) -> Result<usize, Error> {
+        Ok(self
+            .get_contract_metadata(contract_id)
+            .await?
+            .and_then(|metadata| metadata.max_leaf_data_bytes)
+            .map(|limit| limit as usize)
+            .unwrap_or(self.config.max_leaf_data_bytes))
+    }
+
+    // Apply the retention policy of the contract, see MongoCollection::run_retention. Returns the
+    // number of roots archived and of the nodes no longer reachable.
+    async fn run_contract_retention(
+        &self,
+        contract_id: &ContractId,
+        deadline: Option<Instant>,
+    ) -> Result<(usize, usize), Error> {
+        let policy = match self.get_contract_metadata(contract_id).await? {
+            Some(metadata) => RetentionPolicy::from_metadata(&metadata),
+            None => return Ok((0, 0)),
+        };
+        if policy.is_unlimited() {
+            return Ok((0, 0));
+        }
+        // The current root must not change while the roots to archive are chosen.
+        let _guard = self.lock_contract(contract_id).await?;
+        let mut collection = self
+            .new_collection::<MerkleRecord, DataHashRecord>(contract_id, false)
+            .await?
+            .with_deadline(deadline);
+        collection.run_retention(&policy).await
+    }
+
+    async fn find_contracts_with_retention(&self) -> Result<Vec<ContractMetadata>, Error> {
+        let filter = doc! {"$or": [
+            {"max_hot_roots": {"$exists": true}},
+            {"max_root_age_secs": {"$exists": true}},
+        ]};
+        let cursor = self.get_contracts_collection().find(filter, None).await?;
+        Ok(cursor.try_collect().await?)
+    }
+
+    /// Apply the retention policies of all the contracts which have one every interval, until
+    /// the process exits. Failures are logged and retried in the next round.
+    pub async fn run_retention_periodically(self, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            let contracts = match self.find_contracts_with_retention().await {
+                Ok(contracts) => contracts,
+                Err(e) => {
+                    eprintln!("Failed to find the contracts with a retention policy: {e}");
+                    continue;
+                }
+            };
+            for metadata in contracts {
+                let contract_id = metadata.contract_id;
+                match self.run_contract_retention(&contract_id, None).await {
+                    Ok((archived, unreachable)) => println!(
+                        "Archived {archived} roots of contract {}, {unreachable} nodes unreachable",
+                        hex::encode(contract_id.0)
+                    ),
+                    Err(e) => eprintln!(
+                        "Retention of contract {} failed: {e}",
+                        hex::encode(contract_id.0)
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Watch the roots of all the contracts with a single change stream on the database, and drop
+    /// the cached counts of a contract (see get_counts) as soon as its root changes, e.g. by a
+    /// write through another replica. A failed change stream is reopened after it, with
+    /// exponential backoff, and resumes where it stopped. This returns if change streams are not
+    /// supported (e.g. by a standalone mongod), in which case the counts are only refreshed
+    /// after stats_cache_ttl.
+    pub async fn watch_root_changes(self) {
+        let mut resume_token = None;
+        let mut delay = MIN_WATCH_RETRY_DELAY;
+        loop {
+            match self
+                .watch_root_changes_from(&mut resume_token, &mut delay)
+                .await
+            {
+                Err(e) if is_change_stream_unsupported(&e) => {
+                    println!("Change streams not supported, cached counts expire after the TTL");
+                    return;
+                }
+                Err(e) => eprintln!("Watching root changes failed: {e}, retrying in {delay:?}"),
+                // The change stream has been invalidated, e.g. the database is dropped.
+                Ok(()) => resume_token = None,
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_WATCH_RETRY_DELAY);
+        }
+    }
+
+    // Open a change stream of the root documents after resume_token, and invalidate the cache of
+    // the contract of each change until the stream fails or ends. resume_token is kept up to date
+    // so that the stream can be resumed, and delay is reset once the stream works.
+    async fn watch_root_changes_from(
+        &self,
+        resume_token: &mut Option<ResumeToken>,
+        delay: &mut Duration,
+    ) -> Result<(), mongodb::error::Error> {
+        let database = self
+            .client
+            .database(MongoCollection::<(), ()>::get_database_name().as_str());
+        let root_id = MongoCollection::<MerkleRecord, DataHashRecord>::get_current_root_object_id();
+        let pipeline = [doc! {"$match": {
+            "documentKey._id": root_id,
+            "ns.coll": {"$regex": "^MERKLEDATA_"},
+        }}];
+        let options = ChangeStreamOptions::builder()
+            .resume_after(resume_token.clone())
+            .build();
+        let mut change_stream = database.watch(pipeline, options).await?;
+        *delay = MIN_WATCH_RETRY_DELAY;
+        while let Some(event) = change_stream.try_next().await? {
+            let contract_id = event
+                .ns
+                .and_then(|ns| ns.coll)
+                .and_then(|name| contract_id_from_collection_name(&name));
+            if let Some(contract_id) = contract_id {
+                self.invalidate_cache(&contract_id);
+            }
+            *resume_token = change_stream.resume_token();
+        }
+        Ok(())
+    }
+
+    // Drop the cached counts of the contract, whose root has changed.
+    fn invalidate_cache(&self, contract_id: &ContractId) {
+        if self
+            .stats_cache
+            .lock()
+            .unwrap()
+            .remove(contract_id)
+            .is_some()
+        {
+            self.cache_invalidations.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The number of cached entries dropped by watch_root_changes since the server started.
+    pub fn cache_invalidations(&self) -> u64 {
+        self.cache_invalidations.load(Ordering::Relaxed)
+    }
+
+    // Validate the leaf to set and store its data. Returns the merkle record of the leaf and
+    // the node to respond with.
+    // Check the leaf of the request and compute its merkle record, without saving anything.
+    fn new_leaf_record(
+        max_leaf_data_bytes: usize,
+        request: &SetLeafRequest,
+    ) -> Result<MerkleRecord, Status> {
+        let index = resolve_leaf_index(request.index, request.address)?;
+        // Reject leaf data larger than the limit of this contract before doing anything
+        // expensive (e.g. hashing the data or saving it to the database).
+        if let Some(data) = request.data.as_ref() {
+            if data.len() > max_leaf_data_bytes {
+                return Err(Error::InvalidArgument(format!(
+                    "Leaf data too large, at most {max_leaf_data_bytes} bytes allowed, given {}",
+                    data.len()
+                ))
+                .with_detail("index", index)
+                .with_detail("data_size", data.len())
+                .with_detail("max_data_size", max_leaf_data_bytes)
+                .into());
+            }
+        }
+        let hash = match (request.data.as_ref(), request.hash.as_ref()) {
+            (_, Some(hash)) => Hash::try_from(hash.as_slice())?,
+            (Some(data), None) => crate::poseidon::hash_leaf_data(data).try_into().unwrap(),
+            (None, None) => {
+                return Err(Error::InvalidArgument(
+                    "Both data and data hash are not provided".to_string(),
+                )
+                .with_detail("index", index)
+                .into())
+            }
+        };
+        Ok(MerkleRecord::new_leaf(index, hash))
     }
 
-    pub async fn new_with_test_config(test_config: Option<MongoKvPairTestConfig>) -> Self {
-        let mut client = Self::new().await;
-        client.test_config = test_config;
-        client
+    // Returns the node count and leaf count of this contract, which may be cached.
+    async fn get_counts(&self, contract_id: &ContractId) -> Result<(u64, u64), Error> {
+        if let Some((counted_at, node_count, leaf_count)) =
+            self.stats_cache.lock().unwrap().get(contract_id)
+        {
+            if counted_at.elapsed() < self.config.stats_cache_ttl {
+                return Ok((*node_count, *leaf_count));
+            }
+        }
+        let mut collection = self
+            .new_unchecked_collection::<MerkleRecord, DataHashRecord>(contract_id, false)
+            .await?;
+        let node_count = collection.estimated_merkle_record_count().await?;
+        let leaf_count = collection.count_datahash_records().await?;
+        self.stats_cache
+            .lock()
+            .unwrap()
+            .insert(*contract_id, (Instant::now(), node_count, leaf_count));
+        Ok((node_count, leaf_count))
     }
 
-    fn new_with_client(client: Client) -> Self {
-        Self {
-            client,
-            test_config: None,
+    // The ids of the contracts with a merkle collection after the contract id `after`, in
+    // ascending order.
+    async fn list_contract_ids(
+        &self,
+        after: Option<&ContractId>,
+    ) -> Result<Vec<ContractId>, Error> {
+        const PREFIX: &str = "MERKLEDATA_";
+        let mut name = doc! {"$regex": format!("^{PREFIX}")};
+        if let Some(after) = after {
+            name.insert("$gt", format!("{PREFIX}{}", hex::encode(after.0)));
         }
+        let database = self
+            .client
+            .database(MongoCollection::<(), ()>::get_database_name().as_str());
+        let mut contract_ids = database
+            .list_collection_names(doc! {"name": name})
+            .await?
+            .iter()
+            .filter_map(|name| contract_id_from_collection_name(name))
+            .collect::<Vec<_>>();
+        contract_ids.sort_by_key(|contract_id| contract_id.0);
+        Ok(contract_ids)
     }
 
-    pub async fn new_collection<T, R>(
+    /// List the contracts of this server, i.e. those with a merkle collection, in ascending order
+    /// of contract id. At most page_size contracts after the contract id `after` are listed. The
+    /// contract id to list the next page after is also returned, None for the last page.
+    pub async fn list_contracts(
         &self,
-        contract_id: &ContractId,
-        with_session: bool,
-    ) -> Result<MongoCollection<T, R>, Error> {
-        Ok(MongoCollection::new(self.client.clone(), contract_id, with_session).await?)
+        page_size: usize,
+        after: Option<&ContractId>,
+    ) -> Result<(Vec<ContractInfo>, Option<ContractId>), Error> {
+        let mut contract_ids = self.list_contract_ids(after).await?;
+        let next = if contract_ids.len() > page_size {
+            contract_ids.truncate(page_size);
+            contract_ids.last().copied()
+        } else {
+            None
+        };
+        let mut contracts = Vec::with_capacity(contract_ids.len());
+        for contract_id in contract_ids {
+            contracts.push(self.get_contract_info(&contract_id).await?);
+        }
+        Ok((contracts, next))
+    }
+
+    /// Shard the collections of the contract on the keys of MongoCollection::shard_keys, creating
+    /// the collections and the indexes of the keys if needed, and return their namespaces. This
+    /// fails with Error::Precondition unless the server is connected to a sharded cluster (i.e.
+    /// to mongos). Collections already sharded on the same key are left as they are.
+    pub async fn prepare_sharding(&self, contract_id: &ContractId) -> Result<Vec<String>, Error> {
+        let admin = self.client.database("admin");
+        let hello = admin.run_command(doc! {"hello": 1}, None).await?;
+        // mongos identifies itself with this message.
+        if hello.get_str("msg").ok() != Some("isdbgrid") {
+            return Err(Error::Precondition(
+                "Sharding requires MONGODB_URI to point to mongos".to_string(),
+            ));
+        }
+        let database_name = MongoCollection::<(), ()>::get_database_name();
+        let database = self.client.database(&database_name);
+        admin
+            .run_command(doc! {"enableSharding": database_name.as_str()}, None)
+            .await?;
+        let shard_keys = MongoCollection::<(), ()>::shard_keys(contract_id);
+        for (namespace, key) in &shard_keys {
+            // Existing collections can only be sharded on an indexed key.
+            let name = &namespace[database_name.len() + 1..];
+            database
+                .collection::<Document>(name)
+                .create_index(IndexModel::builder().keys(key.clone()).build(), None)
+                .await?;
+            admin
+                .run_command(
+                    doc! {"shardCollection": namespace.as_str(), "key": key.clone()},
+                    None,
+                )
+                .await?;
+        }
+        Ok(shard_keys
+            .into_iter()
+            .map(|(namespace, _)| namespace)
+            .collect())
+    }
+
+    async fn get_contract_info(&self, contract_id: &ContractId) -> Result<ContractInfo, Error> {
+        // The schema version is not checked (the counts and the root do not depend on it), so
+        // that the contracts which are refused are still listed, with their version.
+        let (node_count, leaf_count) = self.get_counts(contract_id).await?;
+        let mut collection = self
+            .new_unchecked_collection::<MerkleRecord, DataHashRecord>(contract_id, false)
+            .await?;
+        // Unlike must_get_root_merkle_record, this does not write the root of a contract
+        // without one, listing contracts must not change them.
+        let current_root = match collection.get_root_merkle_record().await? {
+            Some(root) => root.hash,
+            None => MerkleRecord::get_default_record_with_height::<MERKLE_TREE_HEIGHT>(0)?.hash,
+        };
+        let last_modified_ms = collection
+            .find_last_audit_record()
+            .await?
+            .map(|record| record.timestamp.timestamp_millis());
+        Ok(ContractInfo {
+            contract_id: (*contract_id).into(),
+            current_root: current_root.into(),
+            node_count,
+            leaf_count,
+            last_modified_ms,
+            schema_version: self.get_schema_version(contract_id).await?,
+        })
     }
 
     pub async fn drop_test_collection(&self) -> Result<(), Error> {
         if let Some(test_config) = &self.test_config {
             let collection = self
-                .new_collection::<MerkleRecord, DataHashRecord>(&test_config.contract_id, false)
+                .new_unchecked_collection::<MerkleRecord, DataHashRecord>(
+                    &test_config.contract_id,
+                    false,
+                )
                 .await?;
             collection.drop().await?;
+            let filter = doc! {"contract_id": u256_to_bson(&test_config.contract_id.0)};
+            self.get_contracts_collection()
+                .delete_many(filter, None)
+                .await?;
+            self.opened_contracts
+                .lock()
+                .unwrap()
+                .remove(&test_config.contract_id);
+            self.created_contracts
+                .lock()
+                .unwrap()
+                .remove(&test_config.contract_id);
         }
         Ok(())
     }
 
-    // Validate the contract id passed from http request or gRPC request parameter.
-    // TODO: This function does nothing yet.
+    // Validate the contract id passed from http request or gRPC request parameter. If the
+    // request is authenticated with a JWT, it must be the contract id of the token.
     fn validate_contract_id<T>(
         &self,
-        _request: &Request<T>,
-        _contract_id: &ContractId,
+        request: &Request<T>,
+        contract_id: &ContractId,
     ) -> Result<(), Status> {
-        Ok(())
+        match request.extensions().get::<AuthenticatedContractId>() {
+            Some(authenticated) if authenticated.0 != *contract_id => Err(
+                Status::permission_denied("Contract id not matching the authenticated one"),
+            ),
+            _ => Ok(()),
+        }
     }
 
     fn get_contract_id_from_request_context<T>(
         &self,
         request: &Request<T>,
     ) -> Result<ContractId, Status> {
+        if let Some(authenticated) = request.extensions().get::<AuthenticatedContractId>() {
+            return Ok(authenticated.0);
+        }
+        // The raw header is not authenticated, so it is only trusted in dev mode.
         let id = request
             .metadata()
             .get("x-auth-contract-id")
+            .filter(|_| self.config.dev_mode)
             .ok_or(Status::unauthenticated("Contract id not found"))?;
         let contract_id = id
             .to_str()
@@ -538,18 +5031,19 @@ impl MongoKvPair {
         Ok(contract_id)
     }
 
-    // Ideally the contract id should be obtained from the request context (e.g. lookup the
-    // contract id coresponding to the token in the http header or use the contract id passed from http header directly).
+    // Ideally the contract id should be obtained from the request context, i.e. the contract id
+    // of the JWT validated by JwtAuth (or the x-auth-contract-id header in dev mode).
     // But we have to take care of a few things.
     // 1. When we are testing the functionality of this program, we hard code a contract id in the
     //    test config. If that is the case, we use this contract id directly.
     // 2. Since the construct meothod of MerkleTree trait expects a contract_id, we need a way for
     //    the client to specify the contract id directly. In this case, we use the contract id from
-    //    the gRPC request. We may need to validate the legality of this contract id. But we
-    //    currently do nothing.
-    // 3. Currently, if contract_id is not passed from any of these methods (test config, gRPC
-    //    request parameter and http header), we just use the default contract id. This is only
-    //    used to facliliate development. We MUST remove this when we are ready.
+    //    the gRPC request. If the request is authenticated, this contract id must be the
+    //    authenticated one.
+    // 3. If contract_id is not passed from any of these methods (test config, gRPC
+    //    request parameter, JWT and http header), we return an unauthenticated error. Only when
+    //    dev mode is explicitly enabled, we fall back to the default contract id to facilitate
+    //    development.
     fn get_contract_id<T>(
         &self,
         request: &Request<T>,
@@ -563,178 +5057,1096 @@ impl MongoKvPair {
             return self.get_contract_id_from_request_parameters(request, contract_id);
         }
 
-        Ok(self
-            .get_contract_id_from_request_context(request)
-            .unwrap_or_default())
+        if self.config.dev_mode
+            && request.metadata().get("x-auth-contract-id").is_none()
+            && request
+                .extensions()
+                .get::<AuthenticatedContractId>()
+                .is_none()
+        {
+            return Ok(ContractId::default());
+        }
+
+        self.get_contract_id_from_request_context(request)
+    }
+}
+
+// The indices given by clients are checked before use, so that an invalid index is reported
+// as an invalid argument instead of an internal merkle tree error.
+// The index of the leaf of a request, which is given either by its index or by its address.
+fn resolve_leaf_index(index: u64, address: Option<u64>) -> Result<u64, Error> {
+    let index = match address {
+        Some(address) if index != 0 => {
+            return Err(Error::InvalidArgument(format!(
+                "Both index {index} and address {address} given"
+            ))
+            .with_detail("index", index)
+            .with_detail("address", address))
+        }
+        Some(address) => leaf_index_from_address(address)?,
+        None => index,
+    };
+    validate_leaf_index(index, MERKLE_TREE_HEIGHT)?;
+    Ok(index)
+}
+
+fn check_idempotency_key(key: &str) -> Result<(), Error> {
+    if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_BYTES {
+        return Err(Error::InvalidArgument(format!(
+            "Idempotency key must have 1 to {MAX_IDEMPOTENCY_KEY_BYTES} bytes, given {}",
+            key.len()
+        ))
+        .with_detail("idempotency_key_size", key.len()));
+    }
+    Ok(())
+}
+
+// The digest of a SetLeaf request recorded with its idempotency key, see IdempotencyRecord.
+// The contract id is left out as it may be given by the request context instead. The fields left
+// out are taken from the request while it is encoded, instead of copying it with its data.
+fn set_leaf_request_digest(request: &mut SetLeafRequest) -> Vec<u8> {
+    let contract_id = request.contract_id.take();
+    let idempotency_key = request.idempotency_key.take();
+    let encoded = prost::Message::encode_to_vec(request);
+    request.contract_id = contract_id;
+    request.idempotency_key = idempotency_key;
+    ring::digest::digest(&ring::digest::SHA256, &encoded)
+        .as_ref()
+        .to_vec()
+}
+
+// The response recorded for the idempotency key of a SetLeaf request, after checking that it is
+// the response of the same request.
+fn recorded_set_leaf_response(
+    record: IdempotencyRecord,
+    request_digest: &[u8],
+) -> Result<SetLeafResponse, Error> {
+    if record.pending {
+        // The request is being applied without a transaction, or was interrupted after
+        // replacing the root, in which case it is not known whether the leaf was set.
+        return Err(Error::Aborted(format!(
+            "Request with idempotency key {:?} in progress, or interrupted after setting the \
+             leaf, check the leaf before retrying with another key",
+            record.key
+        ))
+        .with_detail("idempotency_key", record.key));
+    }
+    if record.request_digest != request_digest {
+        return Err(Error::InvalidArgument(format!(
+            "Idempotency key {:?} already used for another request",
+            record.key
+        ))
+        .with_detail("idempotency_key", record.key));
+    }
+    prost::Message::decode(record.response.as_slice()).map_err(|e| {
+        Error::InconsistentData(format!(
+            "Malformed response recorded for idempotency key {:?}: {e}",
+            record.key
+        ))
+        .with_detail("idempotency_key", record.key)
+    })
+}
+
+// The node of a leaf set by SetLeaf, with its data if given.
+fn leaf_node(
+    merkle_record: MerkleRecord,
+    datahash_record: Option<DataHashRecord>,
+) -> Result<Node, Error> {
+    match datahash_record {
+        Some(datahash_record) => (merkle_record, datahash_record).try_into(),
+        None => Ok(Node::new_simple_leaf(
+            merkle_record.index,
+            merkle_record.hash,
+        )),
+    }
+}
+
+fn is_proof_requested(proof_type: i32) -> bool {
+    !matches!(
+        ProofType::from_i32(proof_type),
+        None | Some(ProofType::ProofUnspecified | ProofType::ProofEmpty)
+    )
+}
+
+// Encode the merkle proof in the format of proof_type, None if no proof is requested.
+fn encode_proof(proof_type: i32, proof: &MerkleProof<Hash, MERKLE_TREE_HEIGHT>) -> Option<Proof> {
+    let (proof_bytes, proof_v1) = match ProofType::from_i32(proof_type)? {
+        ProofType::ProofUnspecified | ProofType::ProofEmpty => return None,
+        ProofType::ProofV0 => (bincode::serialize(proof).unwrap(), None),
+        // The data of the leaf is bound by bind_leaf_data, as only the leaf RPCs know it.
+        ProofType::ProofV1 | ProofType::ProofV1WithData => (vec![], Some(proof.into())),
+        ProofType::ProofJson => (proof.to_json().into_bytes(), None),
+        ProofType::ProofZkwasmV0 => {
+            let proof = ZkwasmMerkleProof::from(proof);
+            (bincode::serialize(&proof).unwrap(), None)
+        }
+    };
+    Some(Proof {
+        proof_type,
+        proof: proof_bytes,
+        proof_v1,
+    })
+}
+
+// Bind the data of a leaf to its proof if the proof is of type ProofV1WithData, see
+// MerkleProofWithData. Nothing is bound if the data of the leaf is not available, unless the
+// leaf is the default leaf, whose data is 32 zero bytes.
+fn bind_leaf_data(proof: &mut Option<Proof>, node: &Node, data_available: bool) {
+    let proof_v1 = match proof.as_mut() {
+        Some(proof) if proof.proof_type == ProofType::ProofV1WithData as i32 => {
+            proof.proof_v1.as_mut()
+        }
+        _ => None,
+    };
+    if let Some(proof_v1) = proof_v1 {
+        proof_v1.data = if proof_v1.source == DEFAULT_HASH_VEC[0].0 {
+            Some(vec![0; 32])
+        } else {
+            match &node.node_data {
+                Some(NodeData::Data(data)) if data_available => Some(data.clone()),
+                _ => None,
+            }
+        };
+    }
+}
+
+fn decode_proof(proof: Proof) -> Result<MerkleProof<Hash, MERKLE_TREE_HEIGHT>, Error> {
+    match ProofType::from_i32(proof.proof_type) {
+        Some(ProofType::ProofV0) => bincode::deserialize(&proof.proof)
+            .map_err(|e| Error::InvalidArgument(format!("Invalid bincode proof: {e}"))),
+        Some(ProofType::ProofV1) => proof
+            .proof_v1
+            .ok_or_else(|| Error::InvalidArgument("Field proof_v1 not provided".to_string()))?
+            .try_into(),
+        Some(ProofType::ProofJson) => MerkleProof::from_json(&proof.proof),
+        Some(ProofType::ProofZkwasmV0) => bincode::deserialize::<ZkwasmMerkleProof>(&proof.proof)
+            .map_err(|e| Error::InvalidArgument(format!("Invalid bincode proof: {e}")))?
+            .try_into(),
+        _ => Err(Error::InvalidArgument(format!(
+            "Unsupported proof type {}",
+            proof.proof_type
+        ))),
+    }
+}
+
+// The leaves followed by the new records of their ancestors, level by level from the bottom
+// with the new root last, given the current records of the ancestors. The nodes of a level only
+// depend on the level below, so with parallel they are hashed in parallel with rayon.
+fn hash_ancestors<const H: usize>(
+    ancestors: &HashMap<u64, MerkleRecord>,
+    leaves: &[MerkleRecord],
+    parallel: bool,
+) -> Result<Vec<MerkleRecord>, Error> {
+    use rayon::prelude::*;
+
+    let mut hashes = leaves
+        .iter()
+        .map(|leaf| (leaf.index, leaf.hash))
+        .collect::<HashMap<_, _>>();
+    let mut records = leaves.to_vec();
+    let mut level = hashes.keys().copied().collect::<BTreeSet<_>>();
+    for _ in 0..H {
+        let parents = level
+            .iter()
+            .map(|index| (index - 1) / 2)
+            .collect::<BTreeSet<_>>();
+        let hash_parent = |&parent: &u64| -> Result<MerkleRecord, Error> {
+            let current = &ancestors[&parent];
+            let left = hashes.get(&(2 * parent + 1)).copied();
+            let right = hashes.get(&(2 * parent + 2)).copied();
+            let mut record = MerkleRecord::new(parent);
+            record.left = left.unwrap_or(current.left);
+            record.right = right.unwrap_or(current.right);
+            // The hashes of the current records are read from the database.
+            record.hash = Hash::try_hash_children(&record.left, &record.right)?;
+            Ok(record)
+        };
+        let level_records = if parallel {
+            parents
+                .par_iter()
+                .map(hash_parent)
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            parents
+                .iter()
+                .map(hash_parent)
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        hashes.extend(
+            level_records
+                .iter()
+                .map(|record| (record.index, record.hash)),
+        );
+        records.extend(level_records);
+        level = parents;
+    }
+    Ok(records)
+}
+
+// The contract of a MERKLEDATA_ collection, None for any other collection.
+fn contract_id_from_collection_name(name: &str) -> Option<ContractId> {
+    let id = hex::decode(name.strip_prefix("MERKLEDATA_")?).ok()?;
+    ContractId::try_from(id.as_slice()).ok()
+}
+
+// Change streams are only supported by replica sets and sharded clusters, opening one on a
+// standalone mongod fails with this error code.
+fn is_change_stream_unsupported(error: &mongodb::error::Error) -> bool {
+    const CHANGE_STREAM_NOT_SUPPORTED: i32 = 40573;
+    match error.kind.as_ref() {
+        ErrorKind::Command(error) => error.code == CHANGE_STREAM_NOT_SUPPORTED,
+        _ => false,
+    }
+}
+
+// The node of a leaf as returned by GetLeaf, and whether its data is available.
+async fn get_leaf_node(
+    collection: &mut MongoCollection<MerkleRecord, DataHashRecord>,
+    mut record: MerkleRecord,
+    require_data: bool,
+) -> Result<(Node, bool), Error> {
+    // We now use [0u8; 32] to represent empty node hash, since
+    if record.hash == Hash::get_default_hash_for_depth(MERKLE_TREE_HEIGHT).unwrap() {
+        record.hash = [0u8; 32].try_into().unwrap();
+    }
+    let datahash_record = collection.get_datahash_record(&record.hash()).await?;
+    dbg!(&record, &datahash_record);
+    let data_available = datahash_record.is_some();
+    let node = match datahash_record {
+        Some(datahash_record) => (record, datahash_record).try_into()?,
+        // If the datahash record corresponding to this hash does not exists (e.g. the leaf
+        // is set with only its hash), then we assume the actual data is stored inline to the
+        // merkle record, unless the caller requires the data.
+        None if require_data => {
+            return Err(Error::LeafNotFound(format!(
+                "Data of leaf {} with hash {:?} not found",
+                record.index(),
+                record.hash()
+            ))
+            .with_detail("index", record.index())
+            .with_detail("hash", hex::encode(record.hash().0)))
+        }
+        None => Node::new_simple_leaf(record.index(), record.hash()),
+    };
+    Ok((node, data_available))
+}
+
+// Wait for the next root from the change stream, or poll the root if there is no change stream.
+async fn next_root(
+    collection: &mut MongoCollection<MerkleRecord, DataHashRecord>,
+    change_stream: &mut Option<ChangeStream<ChangeStreamEvent<MerkleRecord>>>,
+    poll_interval: Duration,
+) -> Result<Hash, Error> {
+    match change_stream {
+        Some(change_stream) => loop {
+            match change_stream.try_next().await? {
+                Some(event) => {
+                    if let Some(record) = event.full_document {
+                        return Ok(record.hash);
+                    }
+                }
+                None => {
+                    return Err(Error::Precondition(
+                        "Change stream of the root closed".to_string(),
+                    ))
+                }
+            }
+        },
+        None => {
+            tokio::time::sleep(poll_interval).await;
+            Ok(collection.must_get_root_merkle_record().await?.hash)
+        }
+    }
+}
+
+// Send the current root and then every new root to tx until the receiver is dropped.
+async fn send_root_updates(
+    mut collection: MongoCollection<MerkleRecord, DataHashRecord>,
+    tx: mpsc::Sender<Result<RootUpdate, Status>>,
+    poll_interval: Duration,
+) {
+    // Start watching before reading the current root, so that no change is missed in between.
+    let mut change_stream = match collection.watch_root_merkle_record().await {
+        Ok(change_stream) => Some(change_stream),
+        // Change streams are only supported by replica sets and sharded clusters.
+        Err(error) => {
+            dbg!(error);
+            None
+        }
+    };
+    let mut root = collection
+        .must_get_root_merkle_record()
+        .await
+        .map(|record| record.hash);
+    let mut last_root = None;
+    let mut sequence = 0;
+    loop {
+        match root {
+            Ok(root) if last_root != Some(root) => {
+                let update = RootUpdate {
+                    root: root.into(),
+                    sequence,
+                    timestamp_ms: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap()
+                        .as_millis() as u64,
+                };
+                if tx.send(Ok(update)).await.is_err() {
+                    return;
+                }
+                last_root = Some(root);
+                sequence += 1;
+            }
+            Ok(_) => {}
+            Err(error) => {
+                let _ = tx.send(Err(error.into())).await;
+                return;
+            }
+        }
+        root = tokio::select! {
+            root = next_root(&mut collection, &mut change_stream, poll_interval) => root,
+            // The client is disconnected.
+            _ = tx.closed() => return,
+        };
     }
 }
 
+// Send the non-default leaves of the tree with the given root to tx in index order, until the
+// receiver is dropped. As merkle records never change, the tree of a root never changes either.
+async fn send_leaves(
+    collection: &mut MongoCollection<MerkleRecord, DataHashRecord>,
+    tx: &mpsc::Sender<Result<LeafEntry, Status>>,
+    root: MerkleRecord,
+) -> Result<(), Error> {
+    let mut stack = vec![root];
+    while let Some(record) = stack.pop() {
+        if get_node_type(record.index, MERKLE_TREE_HEIGHT) == NodeType::NodeLeaf {
+            let data = collection
+                .get_datahash_record(&record.hash)
+                .await?
+                .map(|record| record.data);
+            let entry = LeafEntry {
+                index: record.index,
+                hash: record.hash.into(),
+                data,
+            };
+            if tx.send(Ok(entry)).await.is_err() {
+                // The client is disconnected.
+                return Ok(());
+            }
+            continue;
+        }
+        // Push the right child first, so that the leaves are sent in index order.
+        let children = [
+            (2 * record.index + 2, record.right),
+            (2 * record.index + 1, record.left),
+        ];
+        for (index, hash) in children {
+            // Skip the subtrees with only default leaves.
+            let depth = (index + 1).ilog2() as usize;
+            if hash == Hash::get_default_hash_for_depth(depth)? {
+                continue;
+            }
+            stack.push(collection.must_get_merkle_record(index, &hash).await?);
+        }
+    }
+    Ok(())
+}
+
 #[tonic::async_trait]
 impl KvPair for MongoKvPair {
+    type SubscribeRootStream = Pin<Box<dyn Stream<Item = Result<RootUpdate, Status>> + Send>>;
+    type ExportLeavesStream = Pin<Box<dyn Stream<Item = Result<LeafEntry, Status>> + Send>>;
+    type ExportSnapshotStream = Pin<Box<dyn Stream<Item = Result<SnapshotChunk, Status>> + Send>>;
+
     async fn get_root(
         &self,
         request: Request<GetRootRequest>,
     ) -> std::result::Result<Response<GetRootResponse>, Status> {
         dbg!(&request);
         let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
-        let mut collection = self.new_collection(&contract_id, false).await?;
-        let record = collection.must_get_root_merkle_record().await?;
+        let deadline = self.get_deadline(&request);
+        let consistent_read = request.get_ref().consistent_read;
+        let mut collection = self
+            .new_read_collection(&contract_id, consistent_read)
+            .await?
+            .with_deadline(deadline);
+        if !request.get_ref().include_node {
+            let record = collection.must_get_root_merkle_record().await?;
+            return Ok(Response::new(GetRootResponse {
+                root: record.hash().into(),
+                node: None,
+                version: None,
+            }));
+        }
+        let (record, version) = collection
+            .must_get_root_merkle_record_with_version()
+            .await?;
         Ok(Response::new(GetRootResponse {
             root: record.hash().into(),
+            node: Some(record.try_into()?),
+            version: Some(version),
+        }))
+    }
+
+    async fn subscribe_root(
+        &self,
+        request: Request<SubscribeRootRequest>,
+    ) -> std::result::Result<Response<Self::SubscribeRootStream>, Status> {
+        dbg!(&request);
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
+        let collection = self
+            .new_collection(&contract_id, false)
+            .await?
+            .with_deadline(deadline);
+        let (tx, rx) = mpsc::channel(16);
+        let poll_interval = self.config.root_poll_interval;
+        tokio::spawn(send_root_updates(collection, tx, poll_interval));
+        let stream = futures::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|update| (update, rx))
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn set_root(
+        &self,
+        request: Request<SetRootRequest>,
+    ) -> std::result::Result<Response<SetRootResponse>, Status> {
+        dbg!(&request);
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
+        let principal = Self::get_principal(&request);
+        let request = request.into_inner();
+        let hash: Hash = request.hash.as_slice().try_into()?;
+        let _guard = self.lock_contract(&contract_id).await?;
+        self.retry_write(|| {
+            self.write_root(&contract_id, deadline, &principal, hash, request.verify)
+        })
+        .await?;
+        Ok(Response::new(SetRootResponse { root: hash.into() }))
+    }
+
+    async fn get_leaf(
+        &self,
+        request: Request<GetLeafRequest>,
+    ) -> std::result::Result<Response<GetLeafResponse>, Status> {
+        dbg!(&request);
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
+        let request = request.into_inner();
+        let index = resolve_leaf_index(request.index, request.address)?;
+        let mut collection = self
+            .new_read_collection(&contract_id, request.consistent_read)
+            .await?
+            .with_deadline(deadline);
+        let (record, mut proof) = match (request.hash.as_ref(), request.proof_type) {
+            // Get merkle records in a faster way
+            (Some(hash), proof_type) if !is_proof_requested(proof_type) => {
+                let hash: Hash = hash.as_slice().try_into()?;
+                let record = collection.must_get_merkle_record(index, &hash).await?;
+                (record, None)
+            }
+            (_, _) => {
+                let (record, proof) = collection.get_leaf_and_proof(index).await?;
+                if request.hash.is_some() {
+                    let hash: Hash = request.hash.unwrap().as_slice().try_into()?;
+                    if hash != proof.source {
+                        return Err(Error::HashMismatch("Leaf not in current root".to_string())
+                            .with_detail("index", index)
+                            .with_detail("expected_hash", hex::encode(hash.0))
+                            .with_detail("actual_hash", hex::encode(proof.source.0))
+                            .into());
+                    }
+                }
+                let proof = encode_proof(request.proof_type, &proof);
+                dbg!(&record, &proof);
+                (record, proof)
+            }
+        };
+        dbg!(&record, &proof);
+        let is_default =
+            record.hash == Hash::get_default_hash_for_depth(MERKLE_TREE_HEIGHT).unwrap();
+        let (node, data_available) =
+            get_leaf_node(&mut collection, record, request.require_data).await?;
+        dbg!(&node);
+        bind_leaf_data(&mut proof, &node, data_available);
+        collection.commit().await?;
+        Ok(Response::new(GetLeafResponse {
+            node: Some(node),
+            proof,
+            data_available,
+            is_default,
+        }))
+    }
+
+    async fn multi_contract_get_leaves(
+        &self,
+        request: Request<MultiContractGetLeavesRequest>,
+    ) -> std::result::Result<Response<MultiContractGetLeavesResponse>, Status> {
+        dbg!(&request);
+        let deadline = self.get_deadline(&request);
+        // The contract id of every group must be allowed for the caller, otherwise the whole
+        // request is denied. Unlike the other RPCs, the contract id of the test config is not
+        // substituted, as the groups are of different contracts.
+        let mut groups = Vec::with_capacity(request.get_ref().contracts.len());
+        for group in &request.get_ref().contracts {
+            let contract_id =
+                self.get_contract_id_from_request_parameters(&request, &group.contract_id)?;
+            groups.push((contract_id, group.indices.clone()));
+        }
+        let leaves = groups
+            .iter()
+            .map(|(_, indices)| indices.len())
+            .sum::<usize>();
+        if leaves > MAX_MULTI_CONTRACT_LEAVES {
+            return Err(Error::InvalidArgument(format!(
+                "Too many leaves requested, {leaves} > {MAX_MULTI_CONTRACT_LEAVES}"
+            ))
+            .into());
+        }
+        let request = request.into_inner();
+        let contracts = join_all(groups.iter().map(|(contract_id, indices)| {
+            self.get_contract_leaves(
+                contract_id,
+                indices,
+                request.proof_type,
+                request.consistent_read,
+                deadline,
+            )
+        }))
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+        Ok(Response::new(MultiContractGetLeavesResponse { contracts }))
+    }
+
+    async fn set_leaf(
+        &self,
+        request: Request<SetLeafRequest>,
+    ) -> std::result::Result<Response<SetLeafResponse>, Status> {
+        dbg!(&request);
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
+        let principal = Self::get_principal(&request);
+        let mut request = request.into_inner();
+        resolve_leaf_index(request.index, request.address)?;
+        let idempotency_key = match request.idempotency_key.clone() {
+            Some(key) => {
+                check_idempotency_key(&key)?;
+                Some((key, set_leaf_request_digest(&mut request)))
+            }
+            None => None,
+        };
+        let sign_root = request.sign_root;
+        if sign_root {
+            // Fail before writing anything.
+            self.root_signer()?;
+        }
+        let _guard = self.lock_contract(&contract_id).await?;
+        let mut collection = self
+            .new_collection(&contract_id, false)
+            .await?
+            .with_deadline(deadline);
+        if let Some((key, request_digest)) = idempotency_key.as_ref() {
+            if let Some(record) = collection.find_idempotency_record(key).await? {
+                return Ok(Response::new(recorded_set_leaf_response(
+                    record,
+                    request_digest,
+                )?));
+            }
+        }
+        let proof_type = request.proof_type;
+        let data_available = request.data.is_some();
+        let max_leaf_data_bytes = self.get_max_leaf_data_bytes(&contract_id).await?;
+        let merkle_record = Self::new_leaf_record(max_leaf_data_bytes, &request)?;
+        // If data are not passed here, we assume that hash is the actual data.
+        // This corresponds to the simple_set in zkWasm-rust.
+        let datahash_record = request
+            .data
+            .map(|data| DataHashRecord::new(merkle_record.hash, data));
+
+        dbg!(&merkle_record);
+        let respond = |node: Node, write: LeafWrite| -> Result<SetLeafResponse, Error> {
+            let (previous_root, proof, previous_node, previous_is_default) = write;
+            let root = proof.root;
+            let mut proof = encode_proof(proof_type, &proof);
+            bind_leaf_data(&mut proof, &node, data_available);
+            dbg!(&node);
+            let signed_root = if sign_root {
+                Some(self.sign_root(&contract_id, root)?)
+            } else {
+                None
+            };
+            Ok(SetLeafResponse {
+                node: Some(node),
+                proof,
+                previous_node: Some(previous_node),
+                previous_root: previous_root.into(),
+                previous_is_default,
+                signed_root,
+            })
+        };
+        // With an idempotency key, the response is recorded with the key in the transaction of
+        // the write, so that a retry never applies the write again once it is committed. The
+        // data hash record is taken by each attempt, and put back if the attempt fails.
+        let datahash_record = Mutex::new(datahash_record);
+        let (write, response) = self
+            .retry_write(|| async {
+                let mut record = datahash_record.lock().unwrap().take();
+                let result = self
+                    .write_leaf(
+                        &contract_id,
+                        deadline,
+                        &principal,
+                        &merkle_record,
+                        &mut record,
+                        idempotency_key.as_ref().map(|key| (key, &respond)),
+                    )
+                    .await;
+                *datahash_record.lock().unwrap() = record;
+                result
+            })
+            .await?;
+        let response = match response {
+            Some(response) => response,
+            None => {
+                let datahash_record = datahash_record.into_inner().unwrap();
+                respond(leaf_node(merkle_record, datahash_record)?, write)?
+            }
+        };
+        Ok(Response::new(response))
+    }
+
+    async fn set_leaf_stream(
+        &self,
+        mut request: Request<Streaming<SetLeafRequest>>,
+    ) -> std::result::Result<Response<SetLeafStreamResponse>, Status> {
+        // The stream is in flight until its last chunk is written, so that shutdown waits for
+        // the whole stream instead of failing its remaining chunks once a chunk is written.
+        let in_flight = self.start_write()?;
+        let first = request.get_mut().message().await?;
+        let first_contract_id = first.as_ref().and_then(|leaf| leaf.contract_id.clone());
+        let contract_id = self.get_contract_id(&request, &first_contract_id)?;
+        let deadline = self.get_deadline(&request);
+        let principal = Self::get_principal(&request);
+        let max_leaf_data_bytes = self.get_max_leaf_data_bytes(&contract_id).await?;
+        let chunk_size = self.config.set_leaf_stream_chunk_size.max(1);
+        let mut leaves = futures::stream::iter(first.map(Ok)).chain(request.into_inner());
+
+        // The root returned if no leaves are given.
+        let mut root = self
+            .new_collection::<MerkleRecord, DataHashRecord>(&contract_id, false)
+            .await?
+            .with_deadline(deadline)
+            .must_get_root_merkle_record()
+            .await?
+            .hash;
+        let mut chunk = Vec::with_capacity(chunk_size);
+        let mut nodes = Vec::with_capacity(chunk_size);
+        let mut leaf_count = 0;
+        let mut chunk_durations_us = vec![];
+        let mut done = false;
+        // Only one chunk is kept in memory, the client is blocked by flow control while the
+        // chunk is being applied.
+        while !done {
+            match leaves.try_next().await? {
+                Some(leaf) => {
+                    let index = leaf.index;
+                    let data = self.audit_data(leaf.data.as_deref());
+                    let mismatched =
+                        leaf.contract_id.is_some() && leaf.contract_id != first_contract_id;
+                    let result = if mismatched {
+                        Err(Error::InvalidArgument(
+                            "All leaves must be of the same contract".to_string(),
+                        )
+                        .with_detail("index", index)
+                        .into())
+                    } else {
+                        Self::new_leaf_record(max_leaf_data_bytes, &leaf)
+                    };
+                    let record = result.map_err(|status| {
+                        Status::with_details(
+                            status.code(),
+                            format!("Failed to set leaf {index}: {}", status.message()),
+                            status.details().to_vec().into(),
+                        )
+                    })?;
+                    let datahash_record =
+                        leaf.data.map(|data| DataHashRecord::new(record.hash, data));
+                    chunk.push((record, datahash_record));
+                    nodes.push(AuditNode {
+                        index: record.index,
+                        hash: record.hash,
+                        data,
+                    });
+                    leaf_count += 1;
+                    if chunk.len() < chunk_size {
+                        continue;
+                    }
+                }
+                None => done = true,
+            }
+            if chunk.is_empty() {
+                continue;
+            }
+            let start = Instant::now();
+            // The lock is held for each chunk, so that other writes are not blocked until the
+            // client finishes streaming.
+            let guard = self
+                .lock_contract_in_flight(&contract_id, in_flight.clone())
+                .await?;
+            // Each chunk is written in its own transaction, so that its data, root and audit
+            // record are either all written or not at all. Each chunk is recorded as a separate
+            // mutation, as it replaces the root separately.
+            let chunk_nodes = std::mem::take(&mut nodes);
+            let mut audit_record =
+                self.new_audit_record(principal.clone(), "SetLeafStream", chunk_nodes);
+            let mut attempts = 1;
+            root = loop {
+                let result = self
+                    .write_leaf_chunk(&contract_id, deadline, &chunk, &mut audit_record)
+                    .await;
+                match result {
+                    Err(error) if self.should_restart_write(&error, attempts).await? => {
+                        tokio::time::sleep(root_update_backoff(attempts)).await;
+                        attempts += 1;
+                    }
+                    result => break result?,
+                }
+            };
+            drop(guard);
+            chunk.clear();
+            chunk_durations_us.push(start.elapsed().as_micros() as u64);
+        }
+        Ok(Response::new(SetLeafStreamResponse {
+            root: root.into(),
+            leaf_count,
+            chunk_durations_us,
+        }))
+    }
+
+    async fn delete_leaf(
+        &self,
+        request: Request<DeleteLeafRequest>,
+    ) -> std::result::Result<Response<DeleteLeafResponse>, Status> {
+        dbg!(&request);
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
+        let principal = Self::get_principal(&request);
+        let request = request.into_inner();
+        let index = request.index;
+        validate_leaf_index(index, MERKLE_TREE_HEIGHT)?;
+        let _guard = self.lock_contract(&contract_id).await?;
+        let proof = self
+            .retry_write(|| self.write_deleted_leaf(&contract_id, deadline, &principal, index))
+            .await?;
+        let root = proof.root.into();
+        let proof = encode_proof(request.proof_type, &proof);
+        // Same as get_leaf, the empty leaf is represented by [0u8; 32].
+        let node = Node::new_simple_leaf(index, [0u8; 32].try_into().unwrap());
+        Ok(Response::new(DeleteLeafResponse {
+            node: Some(node),
+            proof,
+            root,
+        }))
+    }
+
+    async fn export_leaves(
+        &self,
+        request: Request<ExportRequest>,
+    ) -> std::result::Result<Response<Self::ExportLeavesStream>, Status> {
+        dbg!(&request);
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
+        let request = request.into_inner();
+        let mut collection = self
+            .new_collection(&contract_id, false)
+            .await?
+            .with_deadline(deadline);
+        // The root is captured here, so that later updates are not exported.
+        let root = match request.root_hash {
+            Some(hash) => {
+                let hash: Hash = hash.as_slice().try_into()?;
+                collection
+                    .get_merkle_record(0, &hash)
+                    .await?
+                    .ok_or_else(|| {
+                        Error::RootNotFound(format!("Root {hash:?} not found"))
+                            .with_detail("hash", hex::encode(hash.0))
+                    })?
+            }
+            None => collection.must_get_root_merkle_record().await?,
+        };
+        let (tx, rx) = mpsc::channel(256);
+        tokio::spawn(async move {
+            if let Err(error) = send_leaves(&mut collection, &tx, root).await {
+                let _ = tx.send(Err(error.into())).await;
+            }
+        });
+        let stream = futures::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|entry| (entry, rx))
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn export_snapshot(
+        &self,
+        request: Request<ExportSnapshotRequest>,
+    ) -> std::result::Result<Response<Self::ExportSnapshotStream>, Status> {
+        dbg!(&request);
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
+        let request = request.into_inner();
+        if request.path.is_some() && !self.allow_destructive() {
+            return Err(Status::permission_denied(
+                "ExportSnapshot to a file not allowed (KVPAIR_ALLOW_DESTRUCTIVE not set)",
+            ));
+        }
+        let mut collection = self
+            .new_collection(&contract_id, false)
+            .await?
+            .with_deadline(deadline);
+        let bytes = collection.export_snapshot().await?.to_bytes();
+        let chunks: Vec<Result<SnapshotChunk, Status>> = match request.path {
+            Some(name) => {
+                let path = self.snapshot_path(&contract_id, &name)?;
+                tokio::fs::create_dir_all(path.parent().unwrap())
+                    .await
+                    .map_err(Error::from)?;
+                tokio::fs::write(path, bytes).await.map_err(Error::from)?;
+                vec![]
+            }
+            None => bytes
+                .chunks(SNAPSHOT_CHUNK_SIZE)
+                .map(|chunk| {
+                    Ok(SnapshotChunk {
+                        data: chunk.to_vec(),
+                    })
+                })
+                .collect(),
+        };
+        Ok(Response::new(Box::pin(futures::stream::iter(chunks))))
+    }
+
+    async fn import_snapshot(
+        &self,
+        mut request: Request<Streaming<ImportSnapshotRequest>>,
+    ) -> std::result::Result<Response<ImportSnapshotResponse>, Status> {
+        let first = request
+            .get_mut()
+            .message()
+            .await?
+            .ok_or_else(|| Error::InvalidArgument("Snapshot not provided".to_string()))?;
+        let contract_id = self.get_contract_id(&request, &first.contract_id)?;
+        let deadline = self.get_deadline(&request);
+        let principal = Self::get_principal(&request);
+        if first.path.is_some() && !self.allow_destructive() {
+            return Err(Status::permission_denied(
+                "ImportSnapshot from a file not allowed (KVPAIR_ALLOW_DESTRUCTIVE not set)",
+            ));
+        }
+        let max_snapshot_size = self.config.max_snapshot_size;
+        let mut bytes = first.data;
+        let mut chunks = request.into_inner();
+        loop {
+            if bytes.len() > max_snapshot_size {
+                return Err(Error::ResourceExhausted(format!(
+                    "Snapshot larger than {max_snapshot_size} bytes (KVPAIR_MAX_SNAPSHOT_SIZE)"
+                ))
+                .with_detail("max_snapshot_size", max_snapshot_size.to_string())
+                .into());
+            }
+            match chunks.message().await? {
+                Some(chunk) => bytes.extend(chunk.data),
+                None => break,
+            }
+        }
+        if let Some(name) = first.path {
+            let path = self.snapshot_path(&contract_id, &name)?;
+            bytes = tokio::fs::read(path).await.map_err(Error::from)?;
+        }
+        let snapshot = Snapshot::from_bytes(&bytes)?;
+
+        let _guard = self.lock_contract(&contract_id).await?;
+        let overwrite = first.overwrite;
+        self.retry_write(|| {
+            self.write_snapshot(&contract_id, deadline, &principal, &snapshot, overwrite)
+        })
+        .await?;
+        Ok(Response::new(ImportSnapshotResponse {
+            root: snapshot.root.into(),
+            merkle_record_count: snapshot.merkle_records.len() as u64,
+            datahash_record_count: snapshot.datahash_records.len() as u64,
         }))
     }
 
-    async fn set_root(
+    async fn get_leaf_by_data_hash(
         &self,
-        request: Request<SetRootRequest>,
-    ) -> std::result::Result<Response<SetRootResponse>, Status> {
+        request: Request<GetLeafByDataHashRequest>,
+    ) -> std::result::Result<Response<GetLeafByDataHashResponse>, Status> {
+        const DEFAULT_LIMIT: usize = 100;
         dbg!(&request);
         let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
         let request = request.into_inner();
-        let mut collection = self.new_collection(&contract_id, false).await?;
+        let mut collection = self
+            .new_collection(&contract_id, false)
+            .await?
+            .with_deadline(deadline);
         let hash: Hash = request.hash.as_slice().try_into()?;
-        let record = collection.must_get_merkle_record(0, &hash).await?;
-        dbg!(&record);
-        collection.update_root_merkle_record(&record).await?;
-        Ok(Response::new(SetRootResponse {
-            root: record.hash.into(),
+        let limit = match request.limit {
+            0 => DEFAULT_LIMIT,
+            limit => limit as usize,
+        };
+        let indices = collection.find_leaf_indices_by_hash(&hash, limit).await?;
+        let data = collection
+            .get_datahash_record(&hash)
+            .await?
+            .map(|record| record.data);
+        let proof = match request.proof_index {
+            Some(index) => {
+                let proof = collection.get_leaf_proof_with_hash(index, &hash).await?;
+                encode_proof(request.proof_type, &proof)
+            }
+            None => None,
+        };
+        dbg!(&indices, &proof);
+        Ok(Response::new(GetLeafByDataHashResponse {
+            indices,
+            data,
+            proof,
         }))
     }
 
-    async fn get_leaf(
+    async fn get_proof_by_leaf_hash(
         &self,
-        request: Request<GetLeafRequest>,
-    ) -> std::result::Result<Response<GetLeafResponse>, Status> {
+        request: Request<GetProofByLeafHashRequest>,
+    ) -> std::result::Result<Response<GetProofByLeafHashResponse>, Status> {
+        const MAX_INDICES: usize = 100;
         dbg!(&request);
         let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
         let request = request.into_inner();
-        let mut collection = self.new_collection(&contract_id, false).await?;
-        let index = request.index;
-        let proof_v0 = ProofType::ProofV0 as i32;
-        let (mut record, proof) = match (request.hash.as_ref(), request.proof_type) {
-            // Get merkle records in a faster way
-            (Some(hash), _) if request.proof_type != proof_v0 => {
-                let hash: Hash = hash.as_slice().try_into()?;
-                let record = collection.must_get_merkle_record(index, &hash).await?;
-                (record, None)
-            }
-            (_, _) => {
-                let (record, proof) = collection.get_leaf_and_proof(index).await?;
-                if request.hash.is_some() {
-                    let hash: Hash = request.hash.unwrap().as_slice().try_into()?;
-                    if hash != proof.source {
-                        return Err(
-                            Error::InvalidArgument("Leaf not in current root".to_string()).into(),
-                        );
-                    }
-                }
-                let proof_bytes = if request.proof_type == proof_v0 {
-                    Some(Proof {
-                        proof_type: request.proof_type,
-                        proof: bincode::serialize(&proof).unwrap(),
-                    })
-                } else {
-                    None
-                };
-                dbg!(&record, &proof_bytes);
-                (record, proof_bytes)
-            }
-        };
-        // We now use [0u8; 32] to represent empty node hash, since
-        if record.hash == Hash::get_default_hash_for_depth(MERKLE_TREE_HEIGHT).unwrap() {
-            record.hash = [0u8; 32].try_into().unwrap();
+        let mut collection = self
+            .new_collection(&contract_id, false)
+            .await?
+            .with_deadline(deadline);
+        let hash: Hash = request.hash.as_slice().try_into()?;
+        let indices = collection
+            .find_leaf_indices_by_hash(&hash, MAX_INDICES)
+            .await?;
+        if indices.is_empty() {
+            return Err(Error::LeafNotFound(format!(
+                "No leaf with hash {} in the current tree",
+                hex::encode(hash.0)
+            ))
+            .with_detail("hash", hex::encode(hash.0))
+            .into());
         }
-        let datahash_record = collection.get_datahash_record(&record.hash()).await?;
-        dbg!(&record, &proof, &datahash_record);
-        let node = match datahash_record {
-            Some(datahash_record) => (record, datahash_record).try_into()?,
-            // If the datahash record corresponding to this hash does not exists,
-            // then we assume the actual data is stored inline to the merkle record.
-            None => Node::new_simple_leaf(record.index(), record.hash()),
+        // The caller has to choose among several leaves with the hash.
+        let index = match (request.index, indices.as_slice()) {
+            (Some(index), _) => Some(index),
+            (None, [index]) => Some(*index),
+            (None, _) => None,
         };
-        dbg!(&node);
-        collection.commit().await.map_err(Error::from)?;
-        Ok(Response::new(GetLeafResponse {
-            node: Some(node),
+        let proof = match index {
+            Some(index) => {
+                let proof = collection.get_leaf_proof_with_hash(index, &hash).await?;
+                encode_proof(request.proof_type, &proof)
+            }
+            None => None,
+        };
+        dbg!(&indices, &proof);
+        Ok(Response::new(GetProofByLeafHashResponse {
+            index,
             proof,
+            indices,
         }))
     }
 
-    async fn set_leaf(
+    async fn get_non_leaf(
         &self,
-        request: Request<SetLeafRequest>,
-    ) -> std::result::Result<Response<SetLeafResponse>, Status> {
+        request: Request<GetNonLeafRequest>,
+    ) -> std::result::Result<Response<GetNonLeafResponse>, Status> {
         dbg!(&request);
         let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
         let request = request.into_inner();
-        // TODO: Should use session here
-        let mut collection = self.new_collection(&contract_id, false).await?;
         let index = request.index;
-
-        let (merkle_record, node): (MerkleRecord, Node) = match (request.data, request.hash) {
-            (Some(data), hash) => {
-                let hash = if let Some(hash) = hash {
-                    hash.try_into()?
-                } else {
-                    crate::poseidon::hash(&data)?.try_into().unwrap()
-                };
-                let merkle_record = MerkleRecord::new_leaf(index, hash);
-
-                let datahash_record = DataHashRecord {
-                    hash,
-                    data: data.clone(),
-                };
-                collection.insert_datahash_record(&datahash_record).await?;
-                let node = (merkle_record, datahash_record).try_into()?;
-                (merkle_record, node)
-            }
-            (None, Some(hash)) => {
-                // If data are not passed here, we assume that hash is the actual data.
-                // This corresponds to the simple_set in zkWasm-rust.
-                let hash = Hash::try_from(hash)?;
-                let merkle_record = MerkleRecord::new_leaf(index, hash);
-                (merkle_record, Node::new_simple_leaf(index, hash))
-            }
-            (None, None) => {
-                return Err(Status::invalid_argument(
-                    "Both data and data hash are not provided",
-                ))
+        validate_internal_index(index, MERKLE_TREE_HEIGHT)?;
+        let mut collection = self
+            .new_collection(&contract_id, false)
+            .await?
+            .with_deadline(deadline);
+        let hash: Hash = request.hash.as_slice().try_into()?;
+        let (record, proof) = if is_proof_requested(request.proof_type) {
+            let (record, proof) = collection.get_node_and_proof(index).await?;
+            if hash != proof.source {
+                return Err(Error::HashMismatch("Node not in current root".to_string())
+                    .with_detail("index", index)
+                    .with_detail("expected_hash", hex::encode(hash.0))
+                    .with_detail("actual_hash", hex::encode(proof.source.0))
+                    .into());
             }
-        };
-
-        dbg!(&merkle_record);
-        let proof = collection.set_leaf_and_get_proof(&merkle_record).await?;
-        let proof = if request.proof_type == ProofType::ProofV0 as i32 {
-            Some(Proof {
-                proof_type: request.proof_type,
-                proof: bincode::serialize(&proof).unwrap(),
-            })
+            (record, encode_proof(request.proof_type, &proof))
         } else {
-            None
+            (collection.must_get_merkle_record(index, &hash).await?, None)
         };
-        collection.commit().await.map_err(Error::from)?;
+        dbg!(&record, &proof);
+        let node = record.try_into()?;
         dbg!(&node);
-        Ok(Response::new(SetLeafResponse {
+        Ok(Response::new(GetNonLeafResponse {
             node: Some(node),
             proof,
+            left_child_index: 2 * index + 1,
+            right_child_index: 2 * index + 2,
         }))
     }
 
-    async fn get_non_leaf(
+    async fn get_non_leaves(
         &self,
-        request: Request<GetNonLeafRequest>,
-    ) -> std::result::Result<Response<GetNonLeafResponse>, Status> {
+        request: Request<GetNonLeavesRequest>,
+    ) -> std::result::Result<Response<GetNonLeavesResponse>, Status> {
         dbg!(&request);
         let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
         let request = request.into_inner();
-        let mut collection = self.new_collection(&contract_id, false).await?;
-        let index = request.index;
-        let hash: Hash = request.hash.as_slice().try_into()?;
-        let record = collection.must_get_merkle_record(index, &hash).await?;
-        dbg!(&record);
-        let node = record.try_into()?;
-        dbg!(&node);
-        Ok(Response::new(GetNonLeafResponse { node: Some(node) }))
+        if request.nodes.len() > MAX_NON_LEAVES {
+            return Err(Error::InvalidArgument(format!(
+                "Too many nodes requested, {} > {MAX_NON_LEAVES}",
+                request.nodes.len()
+            ))
+            .into());
+        }
+        let keys = request
+            .nodes
+            .iter()
+            .map(|node| {
+                validate_internal_index(node.index, MERKLE_TREE_HEIGHT)?;
+                let hash: Hash = node.hash.as_slice().try_into()?;
+                Ok((node.index, hash))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let mut collection = self
+            .new_collection::<MerkleRecord, DataHashRecord>(&contract_id, false)
+            .await?
+            .with_deadline(deadline);
+        let records = collection.get_merkle_records(&keys).await?;
+        let nodes = keys
+            .iter()
+            .map(|&(index, hash)| match records.get(&(index, hash)) {
+                Some(record) => Node::try_from(*record),
+                // A node not found only has the index and hash of the request.
+                None => Ok(Node {
+                    index,
+                    hash: hash.into(),
+                    node_type: NodeType::NodeInvalid.into(),
+                    node_data: None,
+                }),
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(Response::new(GetNonLeavesResponse { nodes }))
     }
 
     async fn set_non_leaf(
@@ -743,20 +6155,90 @@ impl KvPair for MongoKvPair {
     ) -> std::result::Result<Response<SetNonLeafResponse>, Status> {
         dbg!(&request);
         let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
+        if !self.allow_raw_node_writes() {
+            return Err(Status::permission_denied(
+                "SetNonLeaf not allowed (KVPAIR_ALLOW_RAW_NODE_WRITES not set)",
+            ));
+        }
+        let principal = Self::get_principal(&request);
         let request = request.into_inner();
-        // TODO: Should use session here
-        let mut collection = self.new_collection(&contract_id, false).await?;
         let index = request.index;
+        validate_internal_index(index, MERKLE_TREE_HEIGHT)?;
         let left: Hash = request.left_child_hash.as_slice().try_into()?;
         let right: Hash = request.right_child_hash.as_slice().try_into()?;
-        if let Some(hash) = request.hash {
-            Hash::validate_children(&hash.as_slice().try_into()?, &left, &right)?;
-        }
-        let record = collection.insert_non_leaf_node(index, left, right).await?;
+        // The hash of the node is the one given, or the one computed from the children.
+        let hash: Hash = match request.hash {
+            Some(hash) => hash.as_slice().try_into()?,
+            None => Hash::hash_children(&left, &right),
+        };
+        Hash::validate_children(&hash, &left, &right)?;
+        let _guard = self.lock_contract(&contract_id).await?;
+        let (record, children_exist) = self
+            .retry_write(|| {
+                self.write_non_leaf(&contract_id, deadline, &principal, index, left, right)
+            })
+            .await?;
         dbg!(&record);
         let node = record.try_into()?;
         dbg!(&node);
-        Ok(Response::new(SetNonLeafResponse { node: Some(node) }))
+        let [left_child_exists, right_child_exists] = children_exist;
+        Ok(Response::new(SetNonLeafResponse {
+            node: Some(node),
+            left_child_exists,
+            right_child_exists,
+        }))
+    }
+
+    async fn dump_record(
+        &self,
+        request: Request<DumpRecordRequest>,
+    ) -> std::result::Result<Response<DumpRecordResponse>, Status> {
+        dbg!(&request);
+        if !self.config.dev_mode {
+            return Err(Error::Precondition(
+                "DumpRecord is only available in dev mode (KVPAIR_DEV_MODE)".to_string(),
+            )
+            .into());
+        }
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
+        let request = request.into_inner();
+        // Read the records from the database, with their created_at.
+        let mut collection = self
+            .new_collection(&contract_id, false)
+            .await?
+            .with_cache(None)
+            .with_deadline(deadline);
+        let index = request.index;
+        let is_leaf = match get_node_type(index, MERKLE_TREE_HEIGHT) {
+            NodeType::NodeLeaf => true,
+            NodeType::NodeNonLeaf => false,
+            _ => {
+                return Err(
+                    Error::InvalidIndex(format!("Index {index} is out of the tree"))
+                        .with_detail("index", index)
+                        .into(),
+                )
+            }
+        };
+        let hash: Hash = request.hash.as_slice().try_into()?;
+        let record = collection.must_get_merkle_record(index, &hash).await?;
+        let datahash_record = if is_leaf {
+            collection.get_datahash_record(&hash).await?
+        } else {
+            None
+        };
+        let node: Node = match datahash_record.clone() {
+            Some(datahash_record) => (record, datahash_record).try_into()?,
+            None if is_leaf => Node::new_simple_leaf(index, hash),
+            None => record.try_into()?,
+        };
+        Ok(Response::new(DumpRecordResponse {
+            merkle_record: record.to_json(),
+            node: node.to_json(),
+            datahash_record: datahash_record.map(|record| record.to_json()),
+        }))
     }
 
     async fn poseidon_hash(
@@ -766,10 +6248,83 @@ impl KvPair for MongoKvPair {
         dbg!(&request);
         let _contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
         let request = request.into_inner();
-        // TODO: Should use session here
         let data_to_hash = request.data;
-        let hash = crate::poseidon::hash(&data_to_hash)?;
-        Ok(Response::new(PoseidonHashResponse { hash: hash.into() }))
+        let algorithm = PoseidonHashAlgorithm::from_i32(request.algorithm).ok_or_else(|| {
+            Error::InvalidArgument(format!(
+                "Invalid poseidon hash algorithm {}",
+                request.algorithm
+            ))
+        })?;
+        let hash = crate::poseidon::hash_with_algorithm(algorithm, &data_to_hash)?;
+        Ok(Response::new(PoseidonHashResponse {
+            hash: hash.into(),
+            algorithm: algorithm.into(),
+        }))
+    }
+
+    async fn poseidon_hash_batch(
+        &self,
+        request: Request<PoseidonHashBatchRequest>,
+    ) -> std::result::Result<Response<PoseidonHashBatchResponse>, Status> {
+        use rayon::prelude::*;
+
+        let _contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let items = request.into_inner().items;
+        // Poseidon hashing is CPU-bound, so we hash the items in parallel on the blocking pool.
+        let results = tokio::task::spawn_blocking(move || {
+            items
+                .par_iter()
+                .map(|item| {
+                    let algorithm =
+                        PoseidonHashAlgorithm::from_i32(item.algorithm).ok_or_else(|| {
+                            Error::InvalidArgument(format!(
+                                "Invalid poseidon hash algorithm {}",
+                                item.algorithm
+                            ))
+                        })?;
+                    crate::poseidon::hash_with_algorithm(algorithm, &item.data)
+                })
+                .collect::<Vec<_>>()
+        })
+        .await
+        .map_err(|e| Status::internal(format!("Hashing task failed: {e}")))?;
+        let hashes = results
+            .into_iter()
+            .enumerate()
+            .map(|(i, result)| {
+                result.map(|hash| hash.into()).map_err(|e| {
+                    Status::from(Error::InvalidArgument(format!(
+                        "Failed to hash item {i}: {e}"
+                    )))
+                })
+            })
+            .collect::<Result<Vec<Vec<u8>>, Status>>()?;
+        Ok(Response::new(PoseidonHashBatchResponse { hashes }))
+    }
+
+    async fn verify_proof(
+        &self,
+        request: Request<VerifyProofRequest>,
+    ) -> std::result::Result<Response<VerifyProofResponse>, Status> {
+        dbg!(&request);
+        let _contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let proof = request
+            .into_inner()
+            .proof
+            .ok_or_else(|| Error::InvalidArgument("Proof not provided".to_string()))?;
+        let valid = match ProofType::from_i32(proof.proof_type) {
+            Some(ProofType::ProofV1WithData) => {
+                let proof: MerkleProofWithData<MERKLE_TREE_HEIGHT> = proof
+                    .proof_v1
+                    .ok_or_else(|| {
+                        Error::InvalidArgument("Field proof_v1 not provided".to_string())
+                    })?
+                    .try_into()?;
+                proof.verify()
+            }
+            _ => decode_proof(proof)?.verify_node(),
+        };
+        Ok(Response::new(VerifyProofResponse { valid }))
     }
 
     async fn data_hash_record(
@@ -778,8 +6333,12 @@ impl KvPair for MongoKvPair {
     ) -> std::result::Result<Response<DataHashRecordResponse>, Status> {
         dbg!(&request);
         let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
         let request = request.into_inner();
-        let mut collection = self.new_collection(&contract_id, false).await?;
+        let mut collection = self
+            .new_collection(&contract_id, false)
+            .await?
+            .with_deadline(deadline);
         let record = match request.mode {
             Some(mode) if mode == DataHashRecordMode::ModeFetch as i32 => match request.hash {
                 Some(hash) => {
@@ -787,28 +6346,38 @@ impl KvPair for MongoKvPair {
                         .must_get_datahash_record(&hash.try_into()?)
                         .await?
                 }
-                _ => return Err(Status::invalid_argument("Hash is required for fetch mode")),
+                _ => {
+                    return Err(Error::InvalidArgument(
+                        "Hash is required for fetch mode".to_string(),
+                    )
+                    .into())
+                }
             },
             Some(mode) if mode == DataHashRecordMode::ModeStore as i32 => {
                 match (request.data, request.hash) {
                     (Some(data), Some(hash)) => {
                         let record = DataHashRecord::new(hash.try_into()?, data);
                         dbg!(&record);
-                        collection.insert_datahash_record(&record).await?;
+                        self.create_contract_collections(&contract_id).await?;
+                        collection
+                            .insert_datahash_record_if_missing(&record)
+                            .await?;
                         record
                     }
                     _ => {
-                        return Err(Status::invalid_argument(
-                            "Both data and hash are required for store mode",
-                        ))
+                        return Err(Error::InvalidArgument(
+                            "Both data and hash are required for store mode".to_string(),
+                        )
+                        .into())
                     }
                 }
             }
             _ => {
-                return Err(Status::invalid_argument(format!(
+                return Err(Error::InvalidArgument(format!(
                     "Invalid mode for data hash record, fetch or store expected, given {:?}",
                     request.mode
-                )))
+                ))
+                .into())
             }
         };
         Ok(Response::new(DataHashRecordResponse {
@@ -816,4 +6385,437 @@ impl KvPair for MongoKvPair {
             data: record.data,
         }))
     }
+
+    async fn ping(
+        &self,
+        request: Request<PingRequest>,
+    ) -> std::result::Result<Response<PingResponse>, Status> {
+        dbg!(&request);
+        // Ping is used as the readiness probe, failing it takes the server out of rotation.
+        if self.is_shutting_down() {
+            return Err(Error::Unavailable("Server is shutting down".to_string()).into());
+        }
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
+        let unavailable = |e: Error| Status::unavailable(format!("MongoDB unavailable: {e}"));
+        let mut collection = self
+            .new_collection::<MerkleRecord, DataHashRecord>(&contract_id, false)
+            .await
+            .map_err(unavailable)?
+            .with_deadline(deadline);
+        let start = Instant::now();
+        let record = collection
+            .must_get_root_merkle_record()
+            .await
+            .map_err(unavailable)?;
+        let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+        Ok(Response::new(PingResponse {
+            root: record.hash.into(),
+            latency_ms,
+        }))
+    }
+
+    async fn get_stats(
+        &self,
+        request: Request<GetStatsRequest>,
+    ) -> std::result::Result<Response<GetStatsResponse>, Status> {
+        dbg!(&request);
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
+        let (node_count, leaf_count) = self.get_counts(&contract_id).await?;
+        let mut collection = self
+            .new_collection(&contract_id, false)
+            .await?
+            .with_deadline(deadline);
+        let root = collection.must_get_root_merkle_record().await?;
+        Ok(Response::new(GetStatsResponse {
+            node_count,
+            leaf_count,
+            current_root: root.hash.into(),
+            tree_height: MERKLE_TREE_HEIGHT as u32,
+        }))
+    }
+
+    async fn list_contracts(
+        &self,
+        request: Request<ListContractsRequest>,
+    ) -> std::result::Result<Response<ListContractsResponse>, Status> {
+        dbg!(&request);
+        // A caller authenticated for a contract must not learn about the other contracts.
+        if request
+            .extensions()
+            .get::<AuthenticatedContractId>()
+            .is_some()
+        {
+            return Err(Status::permission_denied(
+                "ListContracts not allowed for a contract",
+            ));
+        }
+        let request = request.into_inner();
+        let after = match request.page_token.as_ref() {
+            Some(token) => Some(
+                hex::decode(token)
+                    .map_err(|e| Error::InvalidArgument(format!("Invalid page token: {e}")))
+                    .and_then(|id| ContractId::try_from(id.as_slice()))
+                    .map_err(|e| e.with_detail("page_token", token))?,
+            ),
+            None => None,
+        };
+        let page_size = match request.page_size {
+            0 => DEFAULT_LIST_CONTRACTS_PAGE_SIZE,
+            size => (size as usize).min(MAX_LIST_CONTRACTS_PAGE_SIZE),
+        };
+        let (contracts, next) =
+            MongoKvPair::list_contracts(self, page_size, after.as_ref()).await?;
+        Ok(Response::new(ListContractsResponse {
+            contracts,
+            next_page_token: next.map(|contract_id| hex::encode(contract_id.0)),
+        }))
+    }
+
+    async fn prepare_sharding(
+        &self,
+        request: Request<PrepareShardingRequest>,
+    ) -> std::result::Result<Response<PrepareShardingResponse>, Status> {
+        dbg!(&request);
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let namespaces = MongoKvPair::prepare_sharding(self, &contract_id).await?;
+        Ok(Response::new(PrepareShardingResponse { namespaces }))
+    }
+
+    async fn migrate_layout(
+        &self,
+        request: Request<MigrateLayoutRequest>,
+    ) -> std::result::Result<Response<MigrateLayoutResponse>, Status> {
+        dbg!(&request);
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
+        let limit = match request.get_ref().limit {
+            0 => usize::MAX,
+            limit => limit as usize,
+        };
+        // Writes of this process are blocked meanwhile, the writes of other processes must be
+        // paused by the operator (see the README).
+        let _guard = self.lock_contract(&contract_id).await?;
+        let mut collection = self
+            .new_collection::<MerkleRecord, DataHashRecord>(&contract_id, false)
+            .await?
+            .with_deadline(deadline);
+        let (migrated, done) = collection.migrate_to_sharded_layout(limit).await?;
+        Ok(Response::new(MigrateLayoutResponse {
+            migrated: migrated as u64,
+            done,
+        }))
+    }
+
+    async fn migrate_contract(
+        &self,
+        request: Request<MigrateContractRequest>,
+    ) -> std::result::Result<Response<MigrateContractResponse>, Status> {
+        dbg!(&request);
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
+        let limit = match request.get_ref().limit {
+            0 => usize::MAX,
+            limit => limit as usize,
+        };
+        let (schema_version, migrated, done) =
+            MongoKvPair::migrate_contract(self, &contract_id, limit, deadline).await?;
+        Ok(Response::new(MigrateContractResponse {
+            schema_version,
+            migrated: migrated as u64,
+            done,
+        }))
+    }
+
+    async fn ensure_validators(
+        &self,
+        request: Request<EnsureValidatorsRequest>,
+    ) -> std::result::Result<Response<EnsureValidatorsResponse>, Status> {
+        dbg!(&request);
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let namespaces = MongoCollection::<MerkleRecord, DataHashRecord>::ensure_validators(
+            &self.client,
+            &contract_id,
+        )
+        .await
+        .map_err(Error::from)?;
+        Ok(Response::new(EnsureValidatorsResponse { namespaces }))
+    }
+
+    async fn prefetch(
+        &self,
+        request: Request<PrefetchRequest>,
+    ) -> std::result::Result<Response<PrefetchResponse>, Status> {
+        dbg!(&request);
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
+        let request = request.into_inner();
+        for &index in &request.indices {
+            validate_leaf_index(index, MERKLE_TREE_HEIGHT)?;
+        }
+        let mut collection = self
+            .new_collection(&contract_id, false)
+            .await?
+            .with_deadline(deadline);
+        let warmed_count = collection.prefetch_paths(&request.indices).await?;
+        Ok(Response::new(PrefetchResponse {
+            warmed_count: warmed_count as u64,
+        }))
+    }
+
+    async fn compute_root(
+        &self,
+        request: Request<ComputeRootRequest>,
+    ) -> std::result::Result<Response<ComputeRootResponse>, Status> {
+        dbg!(&request);
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
+        let request = request.into_inner();
+        let max_leaf_data_bytes = self.get_max_leaf_data_bytes(&contract_id).await?;
+        // Only the records of the leaves are computed, the data is neither saved nor checked
+        // against the existing data hash records.
+        let leaves = request
+            .leaves
+            .iter()
+            .map(|leaf| {
+                let index = leaf.index;
+                let mismatched = leaf
+                    .contract_id
+                    .as_ref()
+                    .is_some_and(|id| id.as_slice() != contract_id.0.as_slice());
+                let result = if mismatched {
+                    Err(Error::InvalidArgument(
+                        "All leaves must be of the same contract".to_string(),
+                    )
+                    .with_detail("index", index)
+                    .into())
+                } else {
+                    Self::new_leaf_record(max_leaf_data_bytes, leaf)
+                };
+                result.map_err(|status| {
+                    Status::with_details(
+                        status.code(),
+                        format!("Invalid leaf {index}: {}", status.message()),
+                        status.details().to_vec().into(),
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>, Status>>()?;
+        let with_proofs = is_proof_requested(request.proof_type);
+        let mut collection = self
+            .new_collection(&contract_id, false)
+            .await?
+            .with_deadline(deadline);
+        let (root, proofs) = collection.compute_root(&leaves, with_proofs).await?;
+        let proofs = proofs
+            .iter()
+            .filter_map(|proof| encode_proof(request.proof_type, proof))
+            .collect();
+        Ok(Response::new(ComputeRootResponse {
+            root: root.into(),
+            proofs,
+        }))
+    }
+
+    async fn get_default_hashes(
+        &self,
+        request: Request<GetDefaultHashesRequest>,
+    ) -> std::result::Result<Response<GetDefaultHashesResponse>, Status> {
+        dbg!(&request);
+        let hashes_leaf_to_root = DEFAULT_HASH_VEC.iter().map(|&hash| hash.into()).collect();
+        Ok(Response::new(GetDefaultHashesResponse {
+            hashes_leaf_to_root,
+        }))
+    }
+
+    async fn get_siblings(
+        &self,
+        request: Request<GetSiblingsRequest>,
+    ) -> std::result::Result<Response<GetSiblingsResponse>, Status> {
+        dbg!(&request);
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
+        let request = request.into_inner();
+        let index = request.index;
+        validate_leaf_index(index, MERKLE_TREE_HEIGHT)?;
+        let mut collection = self
+            .new_collection(&contract_id, false)
+            .await?
+            .with_deadline(deadline);
+        let root = match request.root_hash {
+            Some(hash) => {
+                let hash: Hash = hash.as_slice().try_into()?;
+                collection
+                    .get_merkle_record(0, &hash)
+                    .await?
+                    .ok_or_else(|| {
+                        Error::RootNotFound(format!("Root {hash:?} not found"))
+                            .with_detail("hash", hex::encode(hash.0))
+                    })?
+            }
+            None => collection.must_get_root_merkle_record().await?,
+        };
+        // Only the merkle records on the path are read, not the data of the leaf.
+        let (_, proof) = collection.get_node_and_proof_with_root(root, index).await?;
+        Ok(Response::new(GetSiblingsResponse {
+            siblings: proof.assist.into_iter().map(Into::into).collect(),
+            root: proof.root.into(),
+        }))
+    }
+
+    async fn get_mutations(
+        &self,
+        request: Request<GetMutationsRequest>,
+    ) -> std::result::Result<Response<GetMutationsResponse>, Status> {
+        dbg!(&request);
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
+        let request = request.into_inner();
+        let mut collection = self
+            .new_collection::<MerkleRecord, DataHashRecord>(&contract_id, false)
+            .await?
+            .with_deadline(deadline);
+        let mut filter = doc! {};
+        if let Some(index) = request.index {
+            filter.insert("nodes.index", u64_to_bson(index));
+        }
+        let mut timestamp = doc! {};
+        if let Some(start) = request.start_time_ms {
+            timestamp.insert("$gte", DateTime::from_millis(start));
+        }
+        if let Some(end) = request.end_time_ms {
+            timestamp.insert("$lt", DateTime::from_millis(end));
+        }
+        if !timestamp.is_empty() {
+            filter.insert("timestamp", timestamp);
+        }
+        if let Some(token) = request.page_token {
+            let id = ObjectId::parse_str(&token).map_err(|e| {
+                Error::InvalidArgument(format!("Invalid page token: {e}"))
+                    .with_detail("page_token", token)
+            })?;
+            filter.insert("_id", doc! {"$gt": id});
+        }
+        let page_size = match request.page_size {
+            0 => DEFAULT_MUTATIONS_PAGE_SIZE,
+            size => (size as usize).min(MAX_MUTATIONS_PAGE_SIZE),
+        };
+        // Fetch one more entry to know whether there is a next page.
+        let mut records = collection
+            .find_audit_records(filter, page_size as i64 + 1)
+            .await?;
+        let next_page_token = if records.len() > page_size {
+            records.truncate(page_size);
+            records
+                .last()
+                .and_then(|record| record.id)
+                .map(|id| id.to_hex())
+        } else {
+            None
+        };
+        Ok(Response::new(GetMutationsResponse {
+            mutations: records.into_iter().map(Into::into).collect(),
+            next_page_token,
+        }))
+    }
+
+    async fn run_retention(
+        &self,
+        request: Request<RunRetentionRequest>,
+    ) -> std::result::Result<Response<RunRetentionResponse>, Status> {
+        dbg!(&request);
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
+        if !self.allow_destructive() {
+            return Err(Status::permission_denied(
+                "RunRetention not allowed (KVPAIR_ALLOW_DESTRUCTIVE not set)",
+            ));
+        }
+        let (archived_roots, unreachable_nodes) =
+            self.run_contract_retention(&contract_id, deadline).await?;
+        Ok(Response::new(RunRetentionResponse {
+            archived_roots: archived_roots as u64,
+            unreachable_nodes: unreachable_nodes as u64,
+        }))
+    }
+
+    async fn rebuild_index(
+        &self,
+        request: Request<RebuildIndexRequest>,
+    ) -> std::result::Result<Response<RebuildIndexResponse>, Status> {
+        dbg!(&request);
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
+        // Leaves set while the index is rebuilt would otherwise be missing from it. This is not
+        // done in a transaction, which may not fit the index of a large tree. Lookups fall back
+        // to scanning the merkle records until the index is complete again.
+        let _guard = self.lock_contract(&contract_id).await?;
+        let mut collection = self
+            .new_collection::<MerkleRecord, DataHashRecord>(&contract_id, false)
+            .await?
+            .with_deadline(deadline);
+        let leaves = collection.rebuild_leaf_index().await?;
+        Ok(Response::new(RebuildIndexResponse {
+            leaves: leaves as u64,
+        }))
+    }
+
+    async fn get_signed_root(
+        &self,
+        request: Request<GetSignedRootRequest>,
+    ) -> std::result::Result<Response<GetSignedRootResponse>, Status> {
+        dbg!(&request);
+        let contract_id = self.get_contract_id(&request, &request.get_ref().contract_id)?;
+        let deadline = self.get_deadline(&request);
+        self.root_signer()?;
+        let mut collection = self
+            .new_collection(&contract_id, false)
+            .await?
+            .with_deadline(deadline);
+        let root = collection.must_get_root_merkle_record().await?.hash;
+        Ok(Response::new(GetSignedRootResponse {
+            signed_root: Some(self.sign_root(&contract_id, root)?),
+        }))
+    }
+
+    async fn verify_root_signature(
+        &self,
+        request: Request<VerifyRootSignatureRequest>,
+    ) -> std::result::Result<Response<VerifyRootSignatureResponse>, Status> {
+        dbg!(&request);
+        let request = request.into_inner();
+        let signed_root = request
+            .signed_root
+            .ok_or_else(|| Error::InvalidArgument("Signed root not provided".to_string()))?;
+        let public_key = match request.public_key {
+            Some(public_key) => public_key,
+            None => self.root_signer()?.public_key().to_vec(),
+        };
+        let attestation = RootAttestation {
+            contract_id: signed_root.contract_id.as_slice().try_into()?,
+            root: signed_root.root.as_slice().try_into()?,
+            timestamp_ms: signed_root.timestamp_ms,
+        };
+        Ok(Response::new(VerifyRootSignatureResponse {
+            valid: attestation.verify(&public_key, &signed_root.signature),
+        }))
+    }
+
+    async fn get_server_info(
+        &self,
+        request: Request<GetServerInfoRequest>,
+    ) -> std::result::Result<Response<GetServerInfoResponse>, Status> {
+        dbg!(&request);
+        Ok(Response::new(GetServerInfoResponse {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            signing_public_key: self
+                .signer
+                .as_ref()
+                .map(|signer| signer.public_key().to_vec()),
+            cache_invalidations: self.cache_invalidations(),
+            schema_version: SCHEMA_VERSION,
+            min_schema_version: MIN_SCHEMA_VERSION,
+        }))
+    }
 }
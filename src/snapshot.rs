@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+
+use ripemd::{Digest, Ripemd160};
+use serde::{Deserialize, Serialize};
+
+use crate::kvpair::{ContractId, DataHashRecord, Hash, MerkleRecord};
+use crate::merkle::get_node_type;
+use crate::proto::NodeType;
+use crate::Error;
+
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 1;
+
+/// The reachable state of a contract at a given root, which can be restored onto another
+/// database (see `MongoCollection::export_snapshot` and `MongoCollection::import_snapshot`).
+///
+/// A snapshot is serialized in the JSON lines format. The first line is a header with the format
+/// version, the tree height, the contract id, the root, the record counts and a checksum (the
+/// RIPEMD-160 digest of all the following lines). Each of the following lines is a merkle
+/// record (the non default nodes reachable from the root) or a data hash record (the data of the
+/// reachable leaves). All hashes, ids and data are hex encoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub tree_height: usize,
+    pub contract_id: ContractId,
+    pub root: Hash,
+    pub merkle_records: Vec<MerkleRecord>,
+    pub datahash_records: Vec<DataHashRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotHeader {
+    format_version: u32,
+    tree_height: usize,
+    contract_id: String,
+    root: String,
+    merkle_record_count: u64,
+    datahash_record_count: u64,
+    checksum: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum SnapshotEntry {
+    Leaf {
+        index: u64,
+        hash: String,
+    },
+    Node {
+        index: u64,
+        hash: String,
+        left: String,
+        right: String,
+    },
+    Data {
+        hash: String,
+        data: String,
+    },
+}
+
+fn decode_hash(hash: &str) -> Result<Hash, Error> {
+    hex::decode(hash)
+        .map_err(|e| Error::InvalidHash(format!("Invalid hex hash: {e}")))?
+        .try_into()
+}
+
+fn corrupted(reason: impl std::fmt::Display) -> Error {
+    Error::InvalidArgument(format!("Snapshot corrupted: {reason}"))
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut body = vec![];
+        for record in &self.merkle_records {
+            let entry = if get_node_type(record.index, self.tree_height) == NodeType::NodeLeaf {
+                SnapshotEntry::Leaf {
+                    index: record.index,
+                    hash: hex::encode(record.hash.0),
+                }
+            } else {
+                SnapshotEntry::Node {
+                    index: record.index,
+                    hash: hex::encode(record.hash.0),
+                    left: hex::encode(record.left.0),
+                    right: hex::encode(record.right.0),
+                }
+            };
+            serde_json::to_writer(&mut body, &entry).unwrap();
+            body.push(b'\n');
+        }
+        for record in &self.datahash_records {
+            let entry = SnapshotEntry::Data {
+                hash: hex::encode(record.hash.0),
+                data: hex::encode(&record.data),
+            };
+            serde_json::to_writer(&mut body, &entry).unwrap();
+            body.push(b'\n');
+        }
+        let header = SnapshotHeader {
+            format_version: SNAPSHOT_FORMAT_VERSION,
+            tree_height: self.tree_height,
+            contract_id: hex::encode(self.contract_id.0),
+            root: hex::encode(self.root.0),
+            merkle_record_count: self.merkle_records.len() as u64,
+            datahash_record_count: self.datahash_records.len() as u64,
+            checksum: hex::encode(Ripemd160::digest(&body)),
+        };
+        let mut bytes = serde_json::to_vec(&header).unwrap();
+        bytes.push(b'\n');
+        bytes.extend(body);
+        bytes
+    }
+
+    /// Parse a snapshot and check its checksum and record counts. Note that this does not check
+    /// the records are consistent with the root, see `verify`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let split = bytes
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or_else(|| corrupted("header not found"))?;
+        let (header, body) = (&bytes[..split], &bytes[split + 1..]);
+        let header: SnapshotHeader = serde_json::from_slice(header)
+            .map_err(|e| corrupted(format!("invalid header: {e}")))?;
+        if header.format_version != SNAPSHOT_FORMAT_VERSION {
+            return Err(Error::InvalidArgument(format!(
+                "Unsupported snapshot format version {}, {SNAPSHOT_FORMAT_VERSION} expected",
+                header.format_version
+            )));
+        }
+        if header.checksum != hex::encode(Ripemd160::digest(body)) {
+            return Err(corrupted("checksum mismatch"));
+        }
+
+        let contract_id = hex::decode(&header.contract_id)
+            .map_err(|e| corrupted(format!("invalid contract id: {e}")))?;
+        let mut snapshot = Snapshot {
+            tree_height: header.tree_height,
+            contract_id: contract_id.as_slice().try_into()?,
+            root: decode_hash(&header.root)?,
+            merkle_records: vec![],
+            datahash_records: vec![],
+        };
+        for (i, line) in body.split(|&b| b == b'\n').enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let entry: SnapshotEntry = serde_json::from_slice(line)
+                .map_err(|e| corrupted(format!("invalid record on line {}: {e}", i + 2)))?;
+            match entry {
+                SnapshotEntry::Leaf { index, hash } => snapshot
+                    .merkle_records
+                    .push(MerkleRecord::new_leaf(index, decode_hash(&hash)?)),
+                SnapshotEntry::Node {
+                    index,
+                    hash,
+                    left,
+                    right,
+                } => {
+                    let mut record = MerkleRecord::new(index);
+                    record.hash = decode_hash(&hash)?;
+                    record.left = decode_hash(&left)?;
+                    record.right = decode_hash(&right)?;
+                    snapshot.merkle_records.push(record);
+                }
+                SnapshotEntry::Data { hash, data } => {
+                    let data =
+                        hex::decode(data).map_err(|e| corrupted(format!("invalid data: {e}")))?;
+                    snapshot
+                        .datahash_records
+                        .push(DataHashRecord::new(decode_hash(&hash)?, data));
+                }
+            }
+        }
+        if snapshot.merkle_records.len() as u64 != header.merkle_record_count
+            || snapshot.datahash_records.len() as u64 != header.datahash_record_count
+        {
+            return Err(corrupted("record counts mismatch"));
+        }
+        Ok(snapshot)
+    }
+
+    /// Check that the root recomputes from the merkle records, i.e. every non default node
+    /// reachable from the root is in this snapshot and is the hash of its children, and that the
+    /// data of every data hash record hashes to its hash.
+    pub fn verify<const H: usize>(&self) -> Result<(), Error> {
+        if self.tree_height != H {
+            return Err(Error::InvalidArgument(format!(
+                "Snapshot of a merkle tree of height {}, {H} expected",
+                self.tree_height
+            )));
+        }
+        if let Some(record) = self
+            .datahash_records
+            .iter()
+            .find(|record| !record.is_valid())
+        {
+            return Err(corrupted(format!(
+                "data of hash {:?} does not match its hash",
+                record.hash
+            )));
+        }
+        let records = self
+            .merkle_records
+            .iter()
+            .map(|record| ((record.index, record.hash), record))
+            .collect::<HashMap<_, _>>();
+        let mut stack = vec![(0, self.root)];
+        while let Some((index, hash)) = stack.pop() {
+            let depth = (index + 1).ilog2() as usize;
+            if hash == Hash::get_default_hash_for_depth_with_height::<H>(depth)? {
+                continue;
+            }
+            let record = records.get(&(index, hash)).ok_or_else(|| {
+                corrupted(format!(
+                    "merkle record {index} with hash {hash:?} not found"
+                ))
+            })?;
+            if get_node_type(index, H) == NodeType::NodeLeaf {
+                continue;
+            }
+            Hash::validate_children(&record.hash, &record.left, &record.right)?;
+            stack.push((2 * index + 1, record.left));
+            stack.push((2 * index + 2, record.right));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kvpair::MERKLE_TREE_HEIGHT;
+
+    // A tree of height 2 with the leaves 3 and 6 set.
+    fn sample_snapshot() -> Snapshot {
+        let default_leaf = Hash::get_default_hash_for_depth_with_height::<2>(2).unwrap();
+        let leaf3 = MerkleRecord::new_leaf(3, Hash::hash_data(&[1; 32]));
+        let leaf6 = MerkleRecord::new_leaf(6, Hash::hash_data(&[2; 32]));
+        let node1 = MerkleRecord::new_non_leaf(1, leaf3.hash, default_leaf);
+        let node2 = MerkleRecord::new_non_leaf(2, default_leaf, leaf6.hash);
+        let root = MerkleRecord::new_root(node1.hash, node2.hash);
+        Snapshot {
+            tree_height: 2,
+            contract_id: [7; 32].into(),
+            root: root.hash,
+            merkle_records: vec![root, node1, leaf3, node2, leaf6],
+            datahash_records: vec![DataHashRecord::new(leaf3.hash, vec![1; 32])],
+        }
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let snapshot = sample_snapshot();
+        snapshot.verify::<2>().unwrap();
+        let bytes = snapshot.to_bytes();
+        assert_eq!(Snapshot::from_bytes(&bytes).unwrap(), snapshot);
+        assert!(snapshot.verify::<MERKLE_TREE_HEIGHT>().is_err());
+    }
+
+    #[test]
+    fn test_snapshot_corrupted() {
+        let bytes = sample_snapshot().to_bytes();
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() = b' ';
+        assert!(Snapshot::from_bytes(&corrupted).is_err());
+        assert!(Snapshot::from_bytes(&bytes[..bytes.len() / 2]).is_err());
+
+        // A snapshot with a consistent checksum but missing a node does not verify.
+        let mut snapshot = sample_snapshot();
+        snapshot.merkle_records.remove(3);
+        let snapshot = Snapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+        assert!(snapshot.verify::<2>().is_err());
+
+        // Nor does a snapshot whose data does not match its hash.
+        let mut snapshot = sample_snapshot();
+        snapshot.datahash_records[0].data = vec![3; 32];
+        let snapshot = Snapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+        assert!(snapshot.verify::<2>().is_err());
+    }
+}
@@ -0,0 +1,117 @@
+// Counts the allocations made while the leaf data of a SetLeaf request goes from the request
+// into the response node. This is a test binary of its own, as the allocator counts the
+// allocations of every test running in the binary.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use rand::{thread_rng, RngCore};
+use tonic::Request;
+use zkc_state_manager::kvpair::{DataHashRecord, Hash, MerkleRecord, MERKLE_TREE_HEIGHT};
+use zkc_state_manager::poseidon::hash_leaf_data;
+use zkc_state_manager::proto::kv_pair_server::KvPair;
+use zkc_state_manager::proto::node::NodeData;
+use zkc_state_manager::proto::{Node, ProofType, SetLeafRequest};
+use zkc_state_manager::service::{MongoKvPair, MongoKvPairTestConfig};
+
+// Not a power of two, so that the buffers growing by doubling their capacity are not counted.
+const DATA_SIZE: usize = 60_000;
+
+// Counts the allocations of exactly DATA_SIZE bytes, i.e. the copies of the leaf data, while
+// COUNTING is set. The encodings of the data (e.g. in BSON or protobuf) are larger.
+struct CountingAllocator;
+
+static COUNTING: AtomicBool = AtomicBool::new(false);
+static DATA_COPIES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if COUNTING.load(Ordering::SeqCst) && layout.size() == DATA_SIZE {
+            DATA_COPIES.fetch_add(1, Ordering::SeqCst);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if COUNTING.load(Ordering::SeqCst) && new_size == DATA_SIZE {
+            DATA_COPIES.fetch_add(1, Ordering::SeqCst);
+        }
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// The number of copies of the leaf data made by future.
+async fn count_data_copies<T>(future: impl Future<Output = T>) -> (T, usize) {
+    DATA_COPIES.store(0, Ordering::SeqCst);
+    COUNTING.store(true, Ordering::SeqCst);
+    let result = future.await;
+    COUNTING.store(false, Ordering::SeqCst);
+    (result, DATA_COPIES.load(Ordering::SeqCst))
+}
+
+#[tokio::test]
+async fn test_leaf_data_copies() {
+    let index = 2_u64.pow(MERKLE_TREE_HEIGHT.try_into().unwrap()) - 1;
+    let data = vec![7_u8; DATA_SIZE];
+    let hash = Hash::try_from(hash_leaf_data(&data)).unwrap();
+    let record = MerkleRecord::new_leaf(index, hash);
+
+    // The counter sees a copy of the data.
+    let (copy, copies) = count_data_copies(async { data.clone() }).await;
+    assert_eq!(copies, 1);
+
+    // The data is moved from the request into the data hash record, and from the record into
+    // the response node, without being copied.
+    let (node, copies) = count_data_copies(async {
+        let datahash_record = DataHashRecord::new(hash, copy);
+        Node::try_from((record, datahash_record)).unwrap()
+    })
+    .await;
+    assert_eq!(copies, 0);
+    assert_eq!(node.node_data, Some(NodeData::Data(data)));
+}
+
+#[tokio::test]
+async fn test_set_leaf_data_copies() {
+    let mut contract_id = [0u8; 32];
+    thread_rng().fill_bytes(&mut contract_id);
+    let test_config = MongoKvPairTestConfig {
+        contract_id: contract_id.into(),
+    };
+    let server = MongoKvPair::new_with_test_config(Some(test_config)).await;
+    let first_index = 2_u64.pow(MERKLE_TREE_HEIGHT.try_into().unwrap()) - 1;
+    let set_leaf = |offset: u64, data: Vec<u8>, idempotency_key: Option<String>| {
+        server.set_leaf(Request::new(SetLeafRequest {
+            index: first_index + offset,
+            address: None,
+            data: Some(data),
+            proof_type: ProofType::ProofV0.into(),
+            contract_id: None,
+            hash: None,
+            sign_root: false,
+            idempotency_key,
+        }))
+    };
+    // The collections of the contract are created by its first write.
+    set_leaf(0, vec![1_u8; 32], None).await.unwrap();
+
+    // The data is stored (e.g. copied into a BSON document) the same way with or without an
+    // idempotency key: the recorded response holds the data moved from the request, not a copy.
+    let (_, copies) = count_data_copies(set_leaf(1, vec![7_u8; DATA_SIZE], None)).await;
+    let data = vec![8_u8; DATA_SIZE];
+    let key = Some("data-copies".to_string());
+    let (response, idempotent_copies) = count_data_copies(set_leaf(2, data.clone(), key)).await;
+    assert_eq!(idempotent_copies, copies);
+    let node = response.unwrap().into_inner().node.unwrap();
+    assert_eq!(node.node_data, Some(NodeData::Data(data)));
+
+    assert!(server.drop_test_collection().await.is_ok());
+}
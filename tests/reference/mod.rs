@@ -0,0 +1,74 @@
+//! A reference sparse merkle tree for the differential tests. Unlike MongoMerkle, it does not
+//! update the path of a leaf when it is set, but recomputes the tree from its leaves each time,
+//! the empty subtrees being the default ones of DEFAULT_HASH_VEC.
+
+use std::collections::BTreeMap;
+
+use zkc_state_manager::kvpair::{Hash, DEFAULT_HASH_VEC, MERKLE_TREE_HEIGHT};
+use zkc_state_manager::merkle::MerkleProof;
+
+#[derive(Debug, Default)]
+pub struct ReferenceMerkle {
+    // The hashes of the non-default leaves by index.
+    leaves: BTreeMap<u64, Hash>,
+}
+
+impl ReferenceMerkle {
+    pub fn set_leaf(&mut self, index: u64, hash: Hash) {
+        if hash == DEFAULT_HASH_VEC[0] {
+            self.leaves.remove(&index);
+        } else {
+            self.leaves.insert(index, hash);
+        }
+    }
+
+    pub fn delete_leaf(&mut self, index: u64) {
+        self.leaves.remove(&index);
+    }
+
+    pub fn leaf(&self, index: u64) -> Hash {
+        self.node(index)
+    }
+
+    pub fn root(&self) -> Hash {
+        self.node(0)
+    }
+
+    /// The proof of the leaf at index, with the siblings of its path from the top.
+    pub fn proof(&self, index: u64) -> MerkleProof<Hash, MERKLE_TREE_HEIGHT> {
+        let mut assist = vec![];
+        let mut node = index;
+        while node != 0 {
+            let sibling = if node % 2 == 1 { node + 1 } else { node - 1 };
+            assist.push(self.node(sibling));
+            node = (node - 1) / 2;
+        }
+        assist.reverse();
+        MerkleProof {
+            source: self.node(index),
+            root: self.root(),
+            assist,
+            index,
+        }
+    }
+
+    // The hash of the node at index, computed from the leaves below it.
+    fn node(&self, index: u64) -> Hash {
+        let depth = (index + 1).ilog2() as usize;
+        let height = MERKLE_TREE_HEIGHT - depth;
+        // The leaves below the node are those in [first, first + 2^height).
+        let first = (index + 1) * (1 << height) - 1;
+        if self
+            .leaves
+            .range(first..first + (1 << height))
+            .next()
+            .is_none()
+        {
+            return DEFAULT_HASH_VEC[height];
+        }
+        if height == 0 {
+            return self.leaves[&index];
+        }
+        Hash::hash_children(&self.node(2 * index + 1), &self.node(2 * index + 2))
+    }
+}